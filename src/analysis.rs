@@ -0,0 +1,610 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Static analysis of workflow code.
+
+use crate::bindings::{PLUGIN_MODULE_PREFIX, binding_name};
+use crate::permission::{minimal_permissions, missing_permissions};
+use crate::plugin::CorePluginPackage;
+use crate::proto::sapphillon::v1::Permission;
+use crate::transpile::WorkflowModule;
+use deno_ast::swc::ast::{
+    Expr, Ident, IdentName, ImportDecl, ImportSpecifier, Lit, MemberExpr, MemberProp,
+    ObjectPatProp, Pat, PropName, Str, VarDeclarator,
+};
+use deno_ast::swc::ecma_visit::{Visit, VisitWith};
+use deno_ast::{MediaType, ModuleSpecifier, ParseParams};
+use std::collections::{HashMap, HashSet};
+
+/// Permissions a workflow needs according to the plugin functions its code may invoke.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PermissionInference {
+    /// IDs of the plugin functions the code may invoke, in package order
+    pub invoked_function_ids: Vec<String>,
+    /// Minimal permissions that cover the permissions of every invoked plugin function
+    pub required_permissions: Vec<Permission>,
+    /// Inferred permissions that are not covered by the declared permissions
+    pub missing_permissions: Vec<Permission>,
+    /// Declared permissions that are not needed by any invoked plugin function
+    pub unused_permissions: Vec<Permission>,
+}
+
+/// Infers the permissions a workflow module needs from the plugin functions it may invoke.
+///
+/// A plugin function is treated as invoked when the name of its op appears in the code as an
+/// identifier, property name or string literal, e.g. `Deno.core.ops.op_read_file(...)` or
+/// `ops["op_read_file"]`, or when both its package and its binding name appear, e.g.
+/// `plugins["fs"].read(...)` or `import { read } from "sapphillon:plugin/fs"`.
+/// Comments and template literal text are ignored. The inference is
+/// conservative: referencing a function without calling it also requires its permissions.
+///
+/// When the functions reached through `Deno.core.ops`, `plugins` or the namespace of a package
+/// cannot be known statically, e.g. `Deno.core.ops[name]()` or `Object.values(plugins.fs)`, every
+/// function that may be reached is treated as invoked. Code that cannot be parsed is treated as
+/// invoking every plugin function. Resources that ops check at runtime beyond the permissions
+/// declared by their plugin function cannot be inferred.
+///
+/// # Arguments
+/// * `module` - Workflow module, transpiled if the code is TypeScript
+/// * `plugin_packages` - Plugin packages available to the workflow
+/// * `declared` - Permissions declared in the workflow's `required_permissions`
+pub fn infer_permissions(
+    module: &WorkflowModule,
+    plugin_packages: &[CorePluginPackage],
+    declared: &[Permission],
+) -> PermissionInference {
+    let references = collect_references(module).unwrap_or_else(|| References {
+        dynamic: HashSet::from([PluginObject::Plugins]),
+        ..Default::default()
+    });
+    let names = &references.names;
+    let invoked: Vec<_> = plugin_packages
+        .iter()
+        .flat_map(|pkg| {
+            let package_referenced = names.contains(&pkg.id)
+                || names.contains(&format!("{PLUGIN_MODULE_PREFIX}{}", pkg.id));
+            let package_dynamic = references.dynamic.contains(&PluginObject::Plugins)
+                || references
+                    .dynamic
+                    .contains(&PluginObject::Package(pkg.id.clone()));
+            let ops_dynamic = references.dynamic.contains(&PluginObject::Ops);
+            pkg.functions.iter().filter(move |func| {
+                package_dynamic
+                    || func
+                        .op()
+                        .is_some_and(|op| ops_dynamic || names.contains(op.name))
+                    || (package_referenced && names.contains(binding_name(&pkg.id, &func.id)))
+            })
+        })
+        .collect();
+
+    let required_permissions = minimal_permissions(invoked.iter().flat_map(|f| &f.permissions));
+    PermissionInference {
+        invoked_function_ids: invoked.iter().map(|f| f.id.clone()).collect(),
+        missing_permissions: missing_permissions(declared, &required_permissions),
+        unused_permissions: missing_permissions(&required_permissions, declared),
+        required_permissions,
+    }
+}
+
+/// Object through which workflow code reaches plugin functions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PluginObject {
+    /// The global `Deno` object
+    Deno,
+    /// `Deno.core`, whose `ops` hold the ops of every package
+    Core,
+    /// `Deno.core.ops`
+    Ops,
+    /// The global `plugins` object
+    Plugins,
+    /// The bindings of one package, e.g. `plugins.fs` or `import * as fs from "sapphillon:plugin/fs"`
+    Package(String),
+}
+
+/// Names referenced by workflow code, and the plugin objects whose functions it reaches dynamically.
+#[derive(Debug, Default)]
+struct References {
+    names: HashSet<String>,
+    dynamic: HashSet<PluginObject>,
+}
+
+/// Parses the code of `module` and collects its references, or returns `None` if it cannot be parsed.
+fn collect_references(module: &WorkflowModule) -> Option<References> {
+    let parsed = deno_ast::parse_module(ParseParams {
+        specifier: ModuleSpecifier::parse(&module.specifier).ok()?,
+        text: module.code.as_str().into(),
+        media_type: MediaType::JavaScript,
+        capture_tokens: false,
+        scope_analysis: false,
+        maybe_syntax: None,
+    })
+    .ok()?;
+    let program = parsed.program_ref();
+
+    // Aliases may refer to each other, so they are collected until no new one is found
+    let mut aliases = AliasCollector::default();
+    loop {
+        let found = aliases.aliases.len();
+        program.visit_with(&mut aliases);
+        if aliases.aliases.len() == found {
+            break;
+        }
+    }
+
+    let mut collector = ReferenceCollector {
+        aliases: aliases.aliases,
+        references: References::default(),
+    };
+    program.visit_with(&mut collector);
+    Some(collector.references)
+}
+
+/// Returns the name of a property that is known statically, e.g. `a.b` or `a["b"]`.
+fn static_property(prop: &MemberProp) -> Option<&str> {
+    match prop {
+        MemberProp::Ident(name) => Some(&name.sym),
+        MemberProp::Computed(computed) => match &*computed.expr {
+            Expr::Lit(Lit::Str(name)) => Some(&name.value),
+            _ => None,
+        },
+        MemberProp::PrivateName(_) => None,
+    }
+}
+
+/// Returns the plugin object `expr` evaluates to, if any.
+fn resolve(aliases: &HashMap<String, PluginObject>, expr: &Expr) -> Option<PluginObject> {
+    match expr {
+        Expr::Paren(paren) => resolve(aliases, &paren.expr),
+        Expr::Ident(ident) => aliases.get(&*ident.sym).cloned().or(match &*ident.sym {
+            "Deno" => Some(PluginObject::Deno),
+            "plugins" => Some(PluginObject::Plugins),
+            _ => None,
+        }),
+        Expr::Member(member) => {
+            let property = static_property(&member.prop)?;
+            resolve_property(resolve(aliases, &member.obj)?, property)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the plugin object a property of `object` evaluates to, if any.
+fn resolve_property(object: PluginObject, property: &str) -> Option<PluginObject> {
+    match (object, property) {
+        (PluginObject::Deno, "core") => Some(PluginObject::Core),
+        (PluginObject::Core, "ops") => Some(PluginObject::Ops),
+        (PluginObject::Plugins, package_id) => Some(PluginObject::Package(package_id.to_string())),
+        _ => None,
+    }
+}
+
+/// Returns the key of a property of an object pattern that is known statically.
+fn pattern_key(prop: &ObjectPatProp) -> Option<String> {
+    match prop {
+        ObjectPatProp::KeyValue(prop) => match &prop.key {
+            PropName::Ident(name) => Some(name.sym.to_string()),
+            PropName::Str(name) => Some(name.value.to_string()),
+            _ => None,
+        },
+        ObjectPatProp::Assign(prop) => Some(prop.key.sym.to_string()),
+        ObjectPatProp::Rest(_) => None,
+    }
+}
+
+/// Collects the variables that hold a plugin object, e.g. `const ops = Deno.core.ops` or
+/// `const { ops } = Deno.core`.
+#[derive(Default)]
+struct AliasCollector {
+    aliases: HashMap<String, PluginObject>,
+}
+
+impl AliasCollector {
+    /// Records the variables that `pattern` binds to `object` or to its plugin objects.
+    fn collect_pattern(&mut self, pattern: &Pat, object: PluginObject) {
+        match pattern {
+            Pat::Ident(binding) => {
+                self.aliases.insert(binding.id.sym.to_string(), object);
+            }
+            Pat::Assign(assign) => self.collect_pattern(&assign.left, object),
+            Pat::Object(pattern) => {
+                for prop in &pattern.props {
+                    let property =
+                        pattern_key(prop).and_then(|key| resolve_property(object.clone(), &key));
+                    match (prop, property) {
+                        (ObjectPatProp::KeyValue(prop), Some(property)) => {
+                            self.collect_pattern(&prop.value, property);
+                        }
+                        (ObjectPatProp::Assign(prop), Some(property)) => {
+                            self.aliases.insert(prop.key.sym.to_string(), property);
+                        }
+                        // The rest holds the remaining properties of the object
+                        (ObjectPatProp::Rest(rest), _) => {
+                            self.collect_pattern(&rest.arg, object.clone());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Visit for AliasCollector {
+    fn visit_var_declarator(&mut self, declarator: &VarDeclarator) {
+        if let Some(init) = &declarator.init
+            && let Some(object) = resolve(&self.aliases, init)
+        {
+            self.collect_pattern(&declarator.name, object);
+        }
+        declarator.visit_children_with(self);
+    }
+
+    fn visit_import_decl(&mut self, import: &ImportDecl) {
+        let Some(package_id) = import.src.value.strip_prefix(PLUGIN_MODULE_PREFIX) else {
+            return;
+        };
+        for specifier in &import.specifiers {
+            let local = match specifier {
+                ImportSpecifier::Namespace(namespace) => &namespace.local,
+                ImportSpecifier::Default(default) => &default.local,
+                ImportSpecifier::Named(_) => continue,
+            };
+            self.aliases.insert(
+                local.sym.to_string(),
+                PluginObject::Package(package_id.to_string()),
+            );
+        }
+    }
+}
+
+/// Collects the identifiers, property names and string literals of the code, and the plugin
+/// objects that are accessed with computed keys or used as values.
+struct ReferenceCollector {
+    aliases: HashMap<String, PluginObject>,
+    references: References,
+}
+
+impl ReferenceCollector {
+    /// Records a static access to `object`.
+    fn accessed(&mut self, object: PluginObject) {
+        if let PluginObject::Package(package_id) = object {
+            self.references.names.insert(package_id);
+        }
+    }
+
+    /// Records that any function reached through `object` may be invoked.
+    fn accessed_dynamically(&mut self, object: PluginObject) {
+        let object = match object {
+            PluginObject::Deno | PluginObject::Core => PluginObject::Ops,
+            object => object,
+        };
+        self.accessed(object.clone());
+        self.references.dynamic.insert(object);
+    }
+}
+
+impl Visit for ReferenceCollector {
+    fn visit_expr(&mut self, expr: &Expr) {
+        // A plugin object used as a value, e.g. passed to a function, may have any of its functions invoked
+        match resolve(&self.aliases, expr) {
+            Some(object) => self.accessed_dynamically(object),
+            None => expr.visit_children_with(self),
+        }
+    }
+
+    fn visit_member_expr(&mut self, member: &MemberExpr) {
+        let Some(object) = resolve(&self.aliases, &member.obj) else {
+            return member.visit_children_with(self);
+        };
+        match static_property(&member.prop) {
+            Some(property) => {
+                self.references.names.insert(property.to_string());
+                self.accessed(object);
+            }
+            None => {
+                self.accessed_dynamically(object);
+                member.prop.visit_with(self);
+            }
+        }
+    }
+
+    fn visit_var_declarator(&mut self, declarator: &VarDeclarator) {
+        let object = declarator
+            .init
+            .as_deref()
+            .and_then(|init| resolve(&self.aliases, init));
+        match (&declarator.name, object) {
+            // Aliases are resolved where they are used
+            (Pat::Ident(_), Some(object)) => self.accessed(object),
+            // Destructuring with static keys accesses exactly those keys
+            (Pat::Object(pattern), Some(object)) => {
+                for prop in &pattern.props {
+                    match pattern_key(prop) {
+                        Some(key) => {
+                            self.references.names.insert(key);
+                        }
+                        None => self.accessed_dynamically(object.clone()),
+                    }
+                }
+                self.accessed(object);
+                pattern.visit_with(self);
+            }
+            _ => declarator.visit_children_with(self),
+        }
+    }
+
+    fn visit_ident(&mut self, ident: &Ident) {
+        self.references.names.insert(ident.sym.to_string());
+    }
+
+    fn visit_ident_name(&mut self, name: &IdentName) {
+        self.references.names.insert(name.sym.to_string());
+    }
+
+    fn visit_str(&mut self, value: &Str) {
+        self.references.names.insert(value.value.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::CorePluginFunction;
+    use crate::proto::sapphillon::v1::PermissionType;
+    use deno_core::op2;
+
+    #[op2(fast)]
+    fn op_read_file() -> u32 {
+        1
+    }
+
+    #[op2(fast)]
+    fn op_write_file() -> u32 {
+        2
+    }
+
+    #[op2(fast)]
+    fn op_exec() -> u32 {
+        3
+    }
+
+    fn permission(permission_type: PermissionType, resource: &[&str]) -> Permission {
+        Permission {
+            permission_type: permission_type as i32,
+            resource: resource.iter().map(|r| r.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn plugin_packages() -> Vec<CorePluginPackage> {
+        vec![CorePluginPackage::new(
+            "fs".to_string(),
+            "File System".to_string(),
+            vec![
+                CorePluginFunction::new_with_permissions(
+                    "read".to_string(),
+                    "Read File".to_string(),
+                    "desc".to_string(),
+                    op_read_file(),
+                    vec![
+                        permission(PermissionType::Read, &["/data/a"]),
+                        permission(PermissionType::Read, &["/data"]),
+                    ],
+                ),
+                CorePluginFunction::new_with_permissions(
+                    "write".to_string(),
+                    "Write File".to_string(),
+                    "desc".to_string(),
+                    op_write_file(),
+                    vec![permission(PermissionType::Write, &["/data"])],
+                ),
+                CorePluginFunction::new_with_permissions(
+                    "exec".to_string(),
+                    "Exec".to_string(),
+                    "desc".to_string(),
+                    op_exec(),
+                    vec![permission(PermissionType::Execute, &["git"])],
+                ),
+            ],
+        )]
+    }
+
+    fn references(code: &str) -> References {
+        collect_references(&WorkflowModule::from_javascript(code)).unwrap()
+    }
+
+    #[test]
+    fn test_collect_references() {
+        let references = references(
+            r#"
+            // Deno.core.ops.op_in_line_comment()
+            /* op_in_block_comment() */
+            const ops = Deno.core.ops;
+            const s = `op_in_template ${ops["op_in_substitution"]({a: 1})} ${`nested ${op_nested}`}`;
+            const r = /op_in_regex/g;
+            const half = total / count / 2;
+            ops.op_called('op_in_string');
+            "#,
+        );
+        for name in [
+            "ops",
+            "op_in_substitution",
+            "op_nested",
+            "op_called",
+            "op_in_string",
+            "total",
+            "count",
+        ] {
+            assert!(references.names.contains(name), "{name} not found");
+        }
+        for name in [
+            "op_in_line_comment",
+            "op_in_block_comment",
+            "op_in_template",
+            "op_in_regex",
+        ] {
+            assert!(!references.names.contains(name), "{name} found");
+        }
+        assert!(references.dynamic.is_empty());
+    }
+
+    #[test]
+    fn test_collect_references_dynamic() {
+        for (code, object) in [
+            ("Deno.core.ops[name]();", PluginObject::Ops),
+            ("const ops = Deno.core.ops; ops[name]();", PluginObject::Ops),
+            ("Object.values(Deno.core.ops);", PluginObject::Ops),
+            ("const { [name]: f } = Deno.core.ops;", PluginObject::Ops),
+            (
+                "const core = Deno.core; core.ops[name]();",
+                PluginObject::Ops,
+            ),
+            (
+                "const { core } = Deno; core.ops[name]();",
+                PluginObject::Ops,
+            ),
+            ("const { ops } = Deno.core; ops[k]();", PluginObject::Ops),
+            (
+                "const { core: { ops: o } } = Deno; o[k]();",
+                PluginObject::Ops,
+            ),
+            (
+                "const { fs } = plugins; fs[name]();",
+                PluginObject::Package("fs".to_string()),
+            ),
+            ("plugins[name].read();", PluginObject::Plugins),
+            (
+                "plugins.fs[name]();",
+                PluginObject::Package("fs".to_string()),
+            ),
+            (
+                r#"import * as fs from "sapphillon:plugin/fs"; fs[name]();"#,
+                PluginObject::Package("fs".to_string()),
+            ),
+            (
+                r#"import fs from "sapphillon:plugin/fs"; run(fs);"#,
+                PluginObject::Package("fs".to_string()),
+            ),
+        ] {
+            let references = references(code);
+            assert_eq!(
+                references.dynamic,
+                HashSet::from([object]),
+                "unexpected references of {code}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_infer_permissions() {
+        let module = WorkflowModule::from_javascript(
+            r#"
+            const data = Deno.core.ops.op_read_file();
+            Deno.core.ops["op_write_file"](data);
+            // Deno.core.ops.op_exec();
+            "#,
+        );
+        let declared = vec![
+            permission(PermissionType::Read, &["/data"]),
+            permission(PermissionType::Execute, &["git"]),
+        ];
+        let inference = infer_permissions(&module, &plugin_packages(), &declared);
+        assert_eq!(inference.invoked_function_ids, vec!["read", "write"]);
+        assert_eq!(
+            inference.required_permissions,
+            vec![
+                permission(PermissionType::Read, &["/data"]),
+                permission(PermissionType::Write, &["/data"]),
+            ]
+        );
+        assert_eq!(
+            inference.missing_permissions,
+            vec![permission(PermissionType::Write, &["/data"])]
+        );
+        assert_eq!(
+            inference.unused_permissions,
+            vec![permission(PermissionType::Execute, &["git"])]
+        );
+    }
+
+    #[test]
+    fn test_infer_permissions_plugin_bindings() {
+        let module = WorkflowModule::from_javascript(
+            r#"
+            import { exec } from "sapphillon:plugin/fs";
+            plugins.fs.write(exec("git"));
+            // plugins.fs.read()
+            "#,
+        );
+        let inference = infer_permissions(&module, &plugin_packages(), &[]);
+        assert_eq!(inference.invoked_function_ids, vec!["write", "exec"]);
+
+        // Binding names alone do not refer to a package
+        let module = WorkflowModule::from_javascript("const read = 1; write(read);");
+        let inference = infer_permissions(&module, &plugin_packages(), &[]);
+        assert!(inference.invoked_function_ids.is_empty());
+
+        let module = WorkflowModule::from_javascript("const { read } = plugins.fs; read();");
+        let inference = infer_permissions(&module, &plugin_packages(), &[]);
+        assert_eq!(inference.invoked_function_ids, vec!["read"]);
+    }
+
+    #[test]
+    fn test_infer_permissions_dynamic() {
+        for code in [
+            "const name = 'op_' + 'exec'; Deno.core.ops[name]();",
+            "const f = 'exec'; plugins.fs[f]();",
+            r#"import * as fs from "sapphillon:plugin/fs"; for (const f of Object.values(fs)) f();"#,
+            "const ops = Deno.core.ops; call(ops);",
+            "const { core } = Deno; core.ops[name]();",
+            "const { ops } = Deno.core; ops[k]();",
+            "this is not javascript",
+        ] {
+            let module = WorkflowModule::from_javascript(code);
+            let inference = infer_permissions(&module, &plugin_packages(), &[]);
+            assert_eq!(
+                inference.invoked_function_ids,
+                vec!["read", "write", "exec"],
+                "unexpected functions invoked by {code}"
+            );
+        }
+
+        // Computed accesses of other packages do not reach the functions of `fs`
+        let module = WorkflowModule::from_javascript("plugins.net[name]();");
+        let inference = infer_permissions(&module, &plugin_packages(), &[]);
+        assert!(inference.invoked_function_ids.is_empty());
+    }
+
+    #[test]
+    fn test_infer_permissions_typescript() {
+        let module = WorkflowModule::new(
+            "const run = (cmd: string): number => Deno.core.ops.op_exec(cmd); run('git');",
+            crate::proto::sapphillon::v1::WorkflowLanguage::Typescript,
+        )
+        .unwrap();
+        let inference = infer_permissions(&module, &plugin_packages(), &[]);
+        assert_eq!(inference.invoked_function_ids, vec!["exec"]);
+        assert_eq!(
+            inference.missing_permissions,
+            vec![permission(PermissionType::Execute, &["git"])]
+        );
+        assert!(inference.unused_permissions.is_empty());
+    }
+}
@@ -0,0 +1,266 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Audit events of the permission checks made during workflow runs.
+//!
+//! Every check is recorded as a `google.rpc.context.AttributeContext`:
+//! - `source.principal`: ID of the workflow, with the ID of the plugin function in the
+//!   `plugin_function_id` label when the check was made by a plugin function
+//! - `api.operation`: name of the op or plugin function that made the check
+//! - `resource`: the checked resource in `name` (`*` if every resource of the type was checked),
+//!   the permission type in `type`, and the permission level in the `permission_level` label
+//! - `request.time`: time of the check
+//! - `request.reason`: how the check was decided, see `PermissionCheckReason`
+//! - `request.id`: ID of the `WorkflowResult` of the run, set when the run finishes
+//! - `response.code`: `OK` if access was granted, `PERMISSION_DENIED` otherwise
+
+use crate::config::redact_secrets;
+use crate::permission::{PermissionDecisionRecord, PermissionDecisionSource};
+use crate::proto::google::rpc::Code;
+use crate::proto::google::rpc::context::AttributeContext;
+use crate::proto::google::rpc::context::attribute_context::{
+    Api, Peer, Request, Resource, Response,
+};
+use crate::proto::sapphillon::v1::Permission;
+use prost_types::Timestamp;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Service name recorded in the `api` and `resource` of audit events.
+pub const AUDIT_SERVICE_NAME: &str = "sapphillon";
+
+/// Label of `source` that holds the ID of the plugin function that made the check.
+pub const PLUGIN_FUNCTION_ID_LABEL: &str = "plugin_function_id";
+
+/// Label of `resource` that holds the `PermissionLevel` of the checked permission.
+pub const PERMISSION_LEVEL_LABEL: &str = "permission_level";
+
+/// How a permission check was decided, recorded in `request.reason` of its audit event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PermissionCheckReason {
+    /// Covered by the permissions granted to the workflow up front.
+    Granted,
+    /// Covered by a permission allowed with `AllowAlways` earlier in the run.
+    AllowedForRun,
+    /// Decided by the `PermissionPolicy` or `PermissionApprover` of the run.
+    Decided(PermissionDecisionRecord),
+    /// Not granted, and there was no one to ask.
+    NotGranted,
+}
+
+impl PermissionCheckReason {
+    /// Returns the value recorded in `request.reason`, e.g. `granted` or `policy:<rule>`.
+    pub fn as_reason(&self) -> String {
+        match self {
+            PermissionCheckReason::Granted => "granted".to_string(),
+            PermissionCheckReason::AllowedForRun => "allowed_for_run".to_string(),
+            PermissionCheckReason::Decided(record) => match &record.decided_by {
+                PermissionDecisionSource::Approver => {
+                    format!("approver:{:?}", record.decision)
+                }
+                PermissionDecisionSource::Policy(rule) => format!("policy:{rule}"),
+            },
+            PermissionCheckReason::NotGranted => "not_granted".to_string(),
+        }
+    }
+}
+
+/// Receives the audit events of finished workflow runs, e.g. to forward them to a SIEM.
+pub trait AuditSink: Send + Sync {
+    /// Exports the audit events of a run.
+    ///
+    /// # Arguments
+    /// * `workflow_result_id` - ID of the `WorkflowResult` of the run
+    /// * `events` - Permission checks made during the run, in order
+    fn export(&self, workflow_result_id: &str, events: &[AttributeContext]);
+}
+
+fn now() -> Timestamp {
+    let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    Timestamp {
+        seconds: epoch.as_secs() as i64,
+        nanos: epoch.subsec_nanos() as i32,
+    }
+}
+
+/// Creates the audit events of a permission check, one for each resource of the permission.
+///
+/// # Arguments
+/// * `workflow_id` - ID of the workflow that was checked
+/// * `plugin_function_id` - ID of the plugin function that made the check, if known
+/// * `api_name` - Name of the op or plugin function that made the check
+/// * `permission` - Checked permission
+/// * `granted` - Whether access was granted
+/// * `reason` - How the check was decided
+pub fn permission_check_events(
+    workflow_id: &str,
+    plugin_function_id: Option<&str>,
+    api_name: &str,
+    permission: &Permission,
+    granted: bool,
+    reason: &PermissionCheckReason,
+) -> Vec<AttributeContext> {
+    let time = now();
+    let code = if granted {
+        Code::Ok
+    } else {
+        Code::PermissionDenied
+    };
+    let resources: Vec<&str> = match permission.resource.is_empty() {
+        true => vec!["*"],
+        false => permission.resource.iter().map(String::as_str).collect(),
+    };
+
+    resources
+        .into_iter()
+        .map(|resource| AttributeContext {
+            source: Some(Peer {
+                principal: workflow_id.to_string(),
+                labels: plugin_function_id
+                    .map(|id| {
+                        HashMap::from([(PLUGIN_FUNCTION_ID_LABEL.to_string(), id.to_string())])
+                    })
+                    .unwrap_or_default(),
+                ..Default::default()
+            }),
+            request: Some(Request {
+                method: api_name.to_string(),
+                time: Some(time),
+                reason: reason.as_reason(),
+                ..Default::default()
+            }),
+            response: Some(Response {
+                code: code as i64,
+                time: Some(time),
+                ..Default::default()
+            }),
+            resource: Some(Resource {
+                service: AUDIT_SERVICE_NAME.to_string(),
+                name: resource.to_string(),
+                r#type: permission.permission_type().as_str_name().to_string(),
+                labels: HashMap::from([(
+                    PERMISSION_LEVEL_LABEL.to_string(),
+                    permission.permission_level().as_str_name().to_string(),
+                )]),
+                display_name: permission.display_name.clone(),
+                ..Default::default()
+            }),
+            api: Some(Api {
+                service: AUDIT_SERVICE_NAME.to_string(),
+                operation: api_name.to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Replaces the given secrets in the resource and reason of an audit event with `REDACTED`.
+///
+/// # Arguments
+/// * `event` - Audit event to redact
+/// * `secrets` - Secret values to hide
+pub(crate) fn redact_audit_event(event: &mut AttributeContext, secrets: &[&str]) {
+    if let Some(resource) = event.resource.as_mut() {
+        resource.name = redact_secrets(&resource.name, secrets);
+        resource.display_name = redact_secrets(&resource.display_name, secrets);
+    }
+    if let Some(request) = event.request.as_mut() {
+        request.reason = redact_secrets(&request.reason, secrets);
+    }
+}
+
+/// Returns true if the audit event records a granted access.
+pub fn is_granted(event: &AttributeContext) -> bool {
+    event
+        .response
+        .as_ref()
+        .is_some_and(|response| response.code == Code::Ok as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permission::{PermissionDecision, PermissionRequest};
+    use crate::proto::sapphillon::v1::{PermissionLevel, PermissionType};
+
+    #[test]
+    fn test_permission_check_events() {
+        let permission = Permission {
+            display_name: "Logs".to_string(),
+            permission_type: PermissionType::Read as i32,
+            permission_level: PermissionLevel::High as i32,
+            resource: vec!["/var/log/a".to_string(), "/var/log/b".to_string()],
+            ..Default::default()
+        };
+        let events = permission_check_events(
+            "wid",
+            Some("fid"),
+            "op_read",
+            &permission,
+            true,
+            &PermissionCheckReason::Granted,
+        );
+        assert_eq!(events.len(), 2);
+
+        let event = &events[1];
+        assert!(is_granted(event));
+        let source = event.source.as_ref().unwrap();
+        assert_eq!(source.principal, "wid");
+        assert_eq!(source.labels[PLUGIN_FUNCTION_ID_LABEL], "fid");
+        let resource = event.resource.as_ref().unwrap();
+        assert_eq!(resource.name, "/var/log/b");
+        assert_eq!(resource.r#type, "PERMISSION_TYPE_READ");
+        assert_eq!(
+            resource.labels[PERMISSION_LEVEL_LABEL],
+            "PERMISSION_LEVEL_HIGH"
+        );
+        let request = event.request.as_ref().unwrap();
+        assert_eq!(request.method, "op_read");
+        assert_eq!(request.reason, "granted");
+        assert!(request.time.is_some());
+        assert_eq!(event.api.as_ref().unwrap().operation, "op_read");
+    }
+
+    #[test]
+    fn test_permission_check_events_denied_without_resources() {
+        let permission = Permission {
+            permission_type: PermissionType::Execute as i32,
+            ..Default::default()
+        };
+        let reason = PermissionCheckReason::Decided(PermissionDecisionRecord {
+            request: PermissionRequest {
+                workflow_id: "wid".to_string(),
+                api_name: "op_exec".to_string(),
+                permission: permission.clone(),
+            },
+            decision: PermissionDecision::Deny,
+            decided_by: PermissionDecisionSource::Policy("no exec".to_string()),
+        });
+        let events = permission_check_events("wid", None, "op_exec", &permission, false, &reason);
+        assert_eq!(events.len(), 1);
+        assert!(!is_granted(&events[0]));
+        assert_eq!(
+            events[0].response.as_ref().unwrap().code,
+            Code::PermissionDenied as i64
+        );
+        assert_eq!(events[0].resource.as_ref().unwrap().name, "*");
+        assert_eq!(events[0].request.as_ref().unwrap().reason, "policy:no exec");
+        assert!(events[0].source.as_ref().unwrap().labels.is_empty());
+    }
+}
@@ -0,0 +1,755 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! JavaScript bindings of plugin packages.
+//!
+//! Each plugin package is exposed to workflows in two ways, both keyed by package ID:
+//! - the global `plugins` object: `plugins["com.example.notifications"].send(...)`
+//! - an ES module: `import { send } from "sapphillon:plugin/com.example.notifications"`,
+//!   whose default export is the same object as in `plugins`
+//!
+//! Functions are exposed under their function ID, without the `<package_id>.` prefix if the
+//! ID has one. The bindings call the ops of the functions, which stay available in
+//! `Deno.core.ops` under their op names, or the functions exported by the source modules of
+//! their package, which are loaded under `sapphillon-plugin://<package_id>/<path>`. WASM
+//! modules are identified by the same specifiers, and instantiated the first time one of their
+//! functions is called. Functions served by a gRPC plugin process return promises, see the
+//! `grpc` module, and functions of native plugins are called synchronously, see the `native`
+//! module.
+//! Functions with a `FunctionSchema` validate their arguments and return values, see the
+//! `schema` module.
+//!
+//! The bindings are a convenience, not an isolation boundary: workflow code can call every op
+//! they call through `Deno.core.ops` as well. Restrictions are enforced by the ops instead. The
+//! ops of functions that are not listed or not allowed are replaced by stubs that throw, ops
+//! with a schema are validated in `Deno.core.ops` itself, and the ops that invoke WASM, gRPC
+//! and native functions by ID only find the functions whose bindings call them, see
+//! `wasm_plugins`, `grpc_plugin_processes` and `native_plugin_libraries`.
+
+use crate::config::{PluginConfig, PluginConfigs, PluginSecrets};
+use crate::grpc::{GrpcPluginProcess, GrpcPluginProcesses};
+use crate::lifecycle::CorePluginLifecycle;
+use crate::native::{NativePluginLibraries, NativePluginLibrary};
+use crate::plugin::{CorePluginFunctionBody, CorePluginPackage};
+use crate::proto::sapphillon::v1::Permission;
+use crate::schema::{FunctionSchema, PluginFunctionSchemas};
+use crate::transpile::{TranspileDiagnostic, TranspileError, WorkflowModule};
+use crate::wasm::{WASM_HOST_FUNCTIONS, WasmPluginFunction, WasmPlugins};
+use deno_core::ModuleSpecifier;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Name of the global object that holds the bindings of all plugin packages.
+pub const PLUGINS_GLOBAL: &str = "plugins";
+
+/// Specifier prefix of the modules that export the bindings of a plugin package.
+pub const PLUGIN_MODULE_PREFIX: &str = "sapphillon:plugin/";
+
+/// Scheme of the specifiers the source modules of plugin packages are loaded under.
+pub const PLUGIN_SOURCE_SCHEME: &str = "sapphillon-plugin";
+
+/// Specifier of the module that defines the global `plugins` object.
+pub(crate) const PLUGINS_MODULE_SPECIFIER: &str = "sapphillon:plugins";
+
+/// JavaScript binding of a plugin package.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginBinding {
+    /// ID of the package, its key in `plugins`
+    pub package_id: String,
+    /// Bindings of the functions of the package
+    pub functions: Vec<PluginFunctionBinding>,
+    /// Source modules of the package, transpiled to JavaScript
+    pub modules: Vec<WorkflowModule>,
+    /// Instrumented binaries of the WASM modules of the package, keyed by specifier
+    pub wasm_modules: HashMap<String, Vec<u8>>,
+    /// Lifecycle of the package, set up before and torn down after the run
+    pub lifecycle: Option<CorePluginLifecycle>,
+    /// Configuration of the package, placed into the `OpState` for its ops
+    pub config: PluginConfig,
+}
+
+/// JavaScript binding of a plugin function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginFunctionBinding {
+    /// ID of the function
+    pub function_id: String,
+    /// Name under which the function is exposed in the object of its package
+    pub name: String,
+    /// Function the binding calls
+    pub target: PluginFunctionTarget,
+    /// Schema the arguments and return value of the function are validated against
+    pub schema: Option<FunctionSchema>,
+    /// Permissions the function declares, checked before the run starts
+    pub permissions: Vec<Permission>,
+}
+
+/// Function a plugin function binding calls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginFunctionTarget {
+    /// Op in `Deno.core.ops`, by name
+    Op(String),
+    /// Export of a source module of the package
+    Export {
+        /// Specifier of the module
+        specifier: String,
+        /// Name of the export
+        name: String,
+    },
+    /// Export of a WASM module of the package
+    Wasm {
+        /// Specifier of the module
+        specifier: String,
+        /// Name of the export
+        name: String,
+        /// Host functions the function may call, according to its permissions
+        host_functions: Vec<String>,
+    },
+    /// Function served by a gRPC plugin process, called through `op_grpc_plugin_invoke`
+    Grpc(Arc<GrpcPluginProcess>),
+    /// Function of a native plugin, called through `op_native_plugin_invoke`
+    Native(Arc<NativePluginLibrary>),
+    /// Function that throws a `PermissionDenied` error, for functions without an op whose
+    /// permissions are not granted to the workflow
+    Denied,
+    /// Function that throws a `PluginFunctionNotListed` error, for functions that the workflow's
+    /// `plugin_function_ids` does not list
+    NotListed,
+}
+
+/// Returns the name under which a plugin function is exposed in the object of its package.
+///
+/// # Arguments
+/// * `package_id` - ID of the package of the function
+/// * `function_id` - ID of the function
+pub fn binding_name<'a>(package_id: &str, function_id: &'a str) -> &'a str {
+    function_id
+        .strip_prefix(package_id)
+        .and_then(|name| name.strip_prefix('.'))
+        .filter(|name| !name.is_empty())
+        .unwrap_or(function_id)
+}
+
+/// Returns the specifier of the module that exports the bindings of a plugin package,
+/// or `None` if the package ID cannot be part of a specifier.
+///
+/// # Arguments
+/// * `package_id` - ID of the package
+pub fn plugin_module_specifier(package_id: &str) -> Option<ModuleSpecifier> {
+    ModuleSpecifier::parse(&format!("{PLUGIN_MODULE_PREFIX}{package_id}")).ok()
+}
+
+/// Returns the specifier of a source module of a plugin package, or `None` if the package ID
+/// cannot be the host of a specifier.
+///
+/// # Arguments
+/// * `package_id` - ID of the package
+/// * `path` - Path of the module in the package
+pub fn plugin_source_specifier(package_id: &str, path: &str) -> Option<ModuleSpecifier> {
+    let specifier =
+        ModuleSpecifier::parse(&format!("{PLUGIN_SOURCE_SCHEME}://{package_id}/{path}")).ok()?;
+    (specifier.host_str() == Some(package_id)).then_some(specifier)
+}
+
+/// Returns a JavaScript string literal of the given value.
+fn js_string(value: &str) -> String {
+    // JSON strings are valid JavaScript string literals
+    serde_json::to_string(value).unwrap()
+}
+
+impl PluginBinding {
+    /// Creates the binding of a plugin package, transpiling its TypeScript source modules.
+    ///
+    /// # Arguments
+    /// * `package` - Plugin package to expose
+    ///
+    /// # Errors
+    /// Returns a `TranspileError` if a source module fails to transpile, or if it cannot be given
+    /// a specifier because of the package ID or its path.
+    pub fn new_from_plugin_package(package: &CorePluginPackage) -> Result<Self, TranspileError> {
+        let specifier = |path: &str| {
+            plugin_source_specifier(&package.id, path).ok_or_else(|| TranspileError {
+                diagnostics: vec![TranspileDiagnostic {
+                    specifier: path.to_string(),
+                    line: 0,
+                    column: 0,
+                    message: format!("Module {path} of {} cannot be loaded", package.id),
+                }],
+            })
+        };
+        let modules = package
+            .modules
+            .iter()
+            .map(|module| {
+                WorkflowModule::new_with_specifier(
+                    &specifier(&module.path)?,
+                    &module.code,
+                    module.language,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let functions = package
+            .functions
+            .iter()
+            .map(|func| {
+                let target = match &func.func {
+                    CorePluginFunctionBody::Op(op) => PluginFunctionTarget::Op(op.name.to_string()),
+                    CorePluginFunctionBody::Export { module, name } => {
+                        PluginFunctionTarget::Export {
+                            specifier: specifier(module)?.to_string(),
+                            name: name.clone(),
+                        }
+                    }
+                    CorePluginFunctionBody::Grpc(process) => {
+                        PluginFunctionTarget::Grpc(process.clone())
+                    }
+                    CorePluginFunctionBody::Native(library) => {
+                        PluginFunctionTarget::Native(library.clone())
+                    }
+                    CorePluginFunctionBody::WasmExport { module, name } => {
+                        let declared: Vec<_> = func
+                            .permissions
+                            .iter()
+                            .map(|p| p.permission_type())
+                            .collect();
+                        PluginFunctionTarget::Wasm {
+                            specifier: specifier(module)?.to_string(),
+                            name: name.clone(),
+                            host_functions: WASM_HOST_FUNCTIONS
+                                .iter()
+                                .filter(|(_, required)| {
+                                    required.is_none_or(|required| declared.contains(&required))
+                                })
+                                .map(|(name, _)| name.to_string())
+                                .collect(),
+                        }
+                    }
+                };
+                Ok(PluginFunctionBinding {
+                    function_id: func.id.clone(),
+                    name: binding_name(&package.id, &func.id).to_string(),
+                    target,
+                    schema: func.schema.clone(),
+                    permissions: func.permissions.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, TranspileError>>()?;
+        let wasm_modules = package
+            .wasm_modules
+            .iter()
+            .map(|module| {
+                Ok((
+                    specifier(&module.path)?.to_string(),
+                    module.instrumented().to_vec(),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, TranspileError>>()?;
+        Ok(Self {
+            package_id: package.id.clone(),
+            functions,
+            modules,
+            wasm_modules,
+            lifecycle: package.lifecycle.clone(),
+            config: package.config.clone(),
+        })
+    }
+
+    /// Returns the source of the module that exports the functions of the package.
+    pub(crate) fn module_source(&self) -> String {
+        let mut source = format!(
+            "const plugin = globalThis[{}][{}];\n",
+            js_string(PLUGINS_GLOBAL),
+            js_string(&self.package_id)
+        );
+        let mut exports = Vec::new();
+        for (i, func) in self.functions.iter().enumerate() {
+            source.push_str(&format!(
+                "const f{i} = plugin[{}];\n",
+                js_string(&func.name)
+            ));
+            exports.push(format!("f{i} as {}", js_string(&func.name)));
+        }
+        if !exports.is_empty() {
+            source.push_str(&format!("export {{ {} }};\n", exports.join(", ")));
+        }
+        source.push_str("export default plugin;\n");
+        source
+    }
+}
+
+/// Script that defines `exported`, which returns a function exported by a source module.
+const EXPORTED_SCRIPT: &str = r#"const exported = (module, name, id) => {
+  if (typeof module[name] !== "function") {
+    throw new TypeError(`Plugin function ${id} is not exported as a function`);
+  }
+  return module[name];
+};
+"#;
+
+/// Script that defines `grpc`, which returns a function that calls a function served by a gRPC
+/// plugin process and returns a promise of its return value.
+const GRPC_SCRIPT: &str = r#"const grpc = (id, name) => ({
+  [name](...args) {
+    return ops.op_grpc_plugin_invoke(id, args);
+  },
+})[name];
+"#;
+
+/// Script that defines `native`, which returns a function that calls a function of a native
+/// plugin.
+const NATIVE_SCRIPT: &str = r#"const native = (id, name) => ({
+  [name](...args) {
+    return ops.op_native_plugin_invoke(id, args);
+  },
+})[name];
+"#;
+
+/// Returns the source of the module that defines the global `plugins` object for the given
+/// bindings.
+///
+/// The module imports the source modules that functions are exported from, so they are
+/// evaluated before it. Functions with a schema that are not ops are wrapped by
+/// `op_validated_plugin_function`, ops are validated in `Deno.core.ops`, see
+/// `plugin_ops_with_schemas`. The object and the objects of the packages are frozen, so
+/// workflows cannot replace functions. The ops stay reachable through `Deno.core.ops`, see the
+/// module documentation.
+pub(crate) fn plugins_module_source(bindings: &[PluginBinding]) -> String {
+    let functions = || bindings.iter().flat_map(|binding| &binding.functions);
+    let mut source = String::new();
+    let mut imports: Vec<&str> = Vec::new();
+    for func in functions() {
+        if let PluginFunctionTarget::Export { specifier, .. } = &func.target
+            && !imports.contains(&specifier.as_str())
+        {
+            source.push_str(&format!(
+                "import * as m{} from {};\n",
+                imports.len(),
+                js_string(specifier)
+            ));
+            imports.push(specifier);
+        }
+    }
+    source.push_str("const ops = Deno.core.ops;\n");
+    if !imports.is_empty() {
+        source.push_str(EXPORTED_SCRIPT);
+    }
+    if functions().any(|func| matches!(func.target, PluginFunctionTarget::Grpc(_))) {
+        source.push_str(GRPC_SCRIPT);
+    }
+    if functions().any(|func| matches!(func.target, PluginFunctionTarget::Native(_))) {
+        source.push_str(NATIVE_SCRIPT);
+    }
+    let entries: Vec<String> = bindings
+        .iter()
+        .map(|binding| {
+            let functions: Vec<String> = binding
+                .functions
+                .iter()
+                .map(|func| {
+                    let value = match &func.target {
+                        PluginFunctionTarget::Op(op_name) => {
+                            return format!(
+                                "[{}, ops[{}]]",
+                                js_string(&func.name),
+                                js_string(op_name)
+                            );
+                        }
+                        PluginFunctionTarget::Denied => {
+                            return format!(
+                                "[{}, ops.op_permission_denied]",
+                                js_string(&func.name)
+                            );
+                        }
+                        PluginFunctionTarget::NotListed => {
+                            return format!(
+                                "[{}, ops.op_plugin_function_not_listed]",
+                                js_string(&func.name)
+                            );
+                        }
+                        PluginFunctionTarget::Export { specifier, name } => {
+                            let index = imports.iter().position(|s| s == specifier).unwrap();
+                            format!(
+                                "exported(m{index}, {}, {})",
+                                js_string(name),
+                                js_string(&func.function_id)
+                            )
+                        }
+                        PluginFunctionTarget::Wasm { .. } => format!(
+                            "ops.op_wasm_plugin_function({})",
+                            js_string(&func.function_id)
+                        ),
+                        PluginFunctionTarget::Grpc(_) => format!(
+                            "grpc({}, {})",
+                            js_string(&func.function_id),
+                            js_string(&func.name)
+                        ),
+                        PluginFunctionTarget::Native(_) => format!(
+                            "native({}, {})",
+                            js_string(&func.function_id),
+                            js_string(&func.name)
+                        ),
+                    };
+                    // Ops are validated in `Deno.core.ops`, other functions where they are exposed
+                    let value = match &func.schema {
+                        Some(_) => format!(
+                            "ops.op_validated_plugin_function({}, {value})",
+                            js_string(&func.function_id)
+                        ),
+                        None => value,
+                    };
+                    format!("[{}, {value}]", js_string(&func.name))
+                })
+                .collect();
+            format!(
+                "[{}, Object.freeze(Object.fromEntries([{}]))]",
+                js_string(&binding.package_id),
+                functions.join(", ")
+            )
+        })
+        .collect();
+    source.push_str(&format!(
+        "Object.defineProperty(globalThis, {}, {{\n  value: Object.freeze(Object.fromEntries([{}])),\n}});\n",
+        js_string(PLUGINS_GLOBAL),
+        entries.join(", ")
+    ));
+    source
+}
+
+/// Returns the schemas of the functions of the given bindings, keyed by function ID.
+pub(crate) fn plugin_function_schemas(bindings: &[PluginBinding]) -> PluginFunctionSchemas {
+    PluginFunctionSchemas(
+        bindings
+            .iter()
+            .flat_map(|binding| &binding.functions)
+            .filter_map(|func| {
+                func.schema
+                    .as_ref()
+                    .map(|schema| (func.function_id.clone(), Rc::new(schema.clone())))
+            })
+            .collect(),
+    )
+}
+
+/// Returns the IDs and op names of the functions of the given bindings that are ops with a
+/// schema, which are validated in `Deno.core.ops` before any code of the run is evaluated.
+pub(crate) fn plugin_ops_with_schemas(bindings: &[PluginBinding]) -> Vec<(&str, &str)> {
+    bindings
+        .iter()
+        .flat_map(|binding| &binding.functions)
+        .filter_map(|func| match (&func.target, &func.schema) {
+            (PluginFunctionTarget::Op(op_name), Some(_)) => {
+                Some((func.function_id.as_str(), op_name.as_str()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the WASM plugin modules and functions of the given bindings.
+pub(crate) fn wasm_plugins(bindings: &[PluginBinding]) -> WasmPlugins {
+    WasmPlugins::new(
+        bindings
+            .iter()
+            .flat_map(|binding| binding.wasm_modules.clone())
+            .collect(),
+        bindings
+            .iter()
+            .flat_map(|binding| &binding.functions)
+            .filter_map(|func| match &func.target {
+                PluginFunctionTarget::Wasm {
+                    specifier,
+                    name,
+                    host_functions,
+                } => Some((
+                    func.function_id.clone(),
+                    WasmPluginFunction {
+                        specifier: specifier.clone(),
+                        name: name.clone(),
+                        host_functions: host_functions.clone(),
+                    },
+                )),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+/// Returns the gRPC plugin processes of the functions of the given bindings, keyed by function ID.
+pub(crate) fn grpc_plugin_processes(bindings: &[PluginBinding]) -> GrpcPluginProcesses {
+    GrpcPluginProcesses(
+        bindings
+            .iter()
+            .flat_map(|binding| &binding.functions)
+            .filter_map(|func| match &func.target {
+                PluginFunctionTarget::Grpc(process) => {
+                    Some((func.function_id.clone(), process.clone()))
+                }
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+/// Returns the configurations of the packages of the given bindings, keyed by the op names of
+/// their functions.
+pub(crate) fn plugin_configs(bindings: &[PluginBinding]) -> PluginConfigs {
+    let mut configs = HashMap::new();
+    for binding in bindings {
+        let config = Arc::new(binding.config.clone());
+        for func in &binding.functions {
+            if let PluginFunctionTarget::Op(op_name) = &func.target {
+                configs.insert(op_name.clone(), config.clone());
+            }
+        }
+    }
+    PluginConfigs(configs)
+}
+
+/// Returns the secret values of the configurations of the packages of the given bindings.
+pub(crate) fn plugin_secrets(bindings: &[PluginBinding]) -> PluginSecrets {
+    PluginSecrets(
+        bindings
+            .iter()
+            .flat_map(|binding| binding.config.secrets())
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+/// Returns the native plugin libraries of the functions of the given bindings, keyed by function
+/// ID.
+pub(crate) fn native_plugin_libraries(bindings: &[PluginBinding]) -> NativePluginLibraries {
+    NativePluginLibraries(
+        bindings
+            .iter()
+            .flat_map(|binding| &binding.functions)
+            .filter_map(|func| match &func.target {
+                PluginFunctionTarget::Native(library) => {
+                    Some((func.function_id.clone(), library.clone()))
+                }
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function_binding(name: &str, op_name: &str) -> PluginFunctionBinding {
+        PluginFunctionBinding {
+            function_id: format!("com.example.notifications.{name}"),
+            name: name.to_string(),
+            target: PluginFunctionTarget::Op(op_name.to_string()),
+            schema: None,
+            permissions: Vec::new(),
+        }
+    }
+
+    fn binding() -> PluginBinding {
+        PluginBinding {
+            package_id: "com.example.notifications".to_string(),
+            functions: vec![
+                function_binding("send", "op_send"),
+                function_binding("send-later", "op_send_later"),
+            ],
+            modules: Vec::new(),
+            wasm_modules: HashMap::new(),
+            lifecycle: None,
+            config: PluginConfig::default(),
+        }
+    }
+
+    #[test]
+    fn test_binding_name() {
+        assert_eq!(binding_name("com.example", "com.example.send"), "send");
+        assert_eq!(binding_name("com.example", "send"), "send");
+        assert_eq!(binding_name("com.example", "com.examples"), "com.examples");
+        assert_eq!(binding_name("com.example", "com.example."), "com.example.");
+    }
+
+    #[test]
+    fn test_plugin_module_specifier() {
+        let specifier = plugin_module_specifier("com.example.notifications").unwrap();
+        assert_eq!(
+            specifier.as_str(),
+            "sapphillon:plugin/com.example.notifications"
+        );
+    }
+
+    #[test]
+    fn test_plugin_source_specifier() {
+        let specifier = plugin_source_specifier("com.example", "lib/../mod.ts").unwrap();
+        assert_eq!(specifier.as_str(), "sapphillon-plugin://com.example/mod.ts");
+        assert!(plugin_source_specifier("a/b", "mod.ts").is_none());
+    }
+
+    #[test]
+    fn test_plugin_binding_new_from_plugin_package() {
+        use crate::plugin::{CorePluginFunction, CorePluginModule};
+        use crate::proto::sapphillon::v1::WorkflowLanguage;
+
+        let package = CorePluginPackage::new_with_modules(
+            "com.example".to_string(),
+            "Example".to_string(),
+            "1.0.0".to_string(),
+            vec![CorePluginFunction::new_with_export(
+                "com.example.greet".to_string(),
+                "Greet".to_string(),
+                "".to_string(),
+                "./mod.js".to_string(),
+                "greet".to_string(),
+                vec![],
+            )],
+            vec![CorePluginModule::new(
+                "mod.js".to_string(),
+                "export const greet = (name) => `Hello, ${name}`;".to_string(),
+                WorkflowLanguage::Javascript,
+            )],
+        );
+        let binding = PluginBinding::new_from_plugin_package(&package).unwrap();
+        assert_eq!(
+            binding.functions[0].target,
+            PluginFunctionTarget::Export {
+                specifier: "sapphillon-plugin://com.example/mod.js".to_string(),
+                name: "greet".to_string(),
+            }
+        );
+        assert_eq!(
+            binding.modules[0].specifier,
+            "sapphillon-plugin://com.example/mod.js"
+        );
+
+        let source = plugins_module_source(&[binding]);
+        assert!(
+            source.starts_with("import * as m0 from \"sapphillon-plugin://com.example/mod.js\";\n")
+        );
+        assert!(source.contains(r#"[["greet", exported(m0, "greet", "com.example.greet")]]"#));
+    }
+
+    #[test]
+    fn test_plugin_binding_grpc() {
+        use crate::grpc::GrpcPluginEndpoint;
+        use crate::proto::sapphillon::v1::{PluginFunction, PluginPackage};
+
+        let process = Arc::new(GrpcPluginProcess::new(GrpcPluginEndpoint::Connect(
+            "http://127.0.0.1:50051".to_string(),
+        )));
+        let package = CorePluginPackage::new_from_grpc_plugin(
+            &PluginPackage {
+                package_id: "remote".to_string(),
+                functions: vec![PluginFunction {
+                    function_id: "remote.fetch".to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            process.clone(),
+        );
+        assert!(package.functions[0].is_async());
+        let binding = PluginBinding::new_from_plugin_package(&package).unwrap();
+        assert_eq!(
+            binding.functions[0].target,
+            PluginFunctionTarget::Grpc(process)
+        );
+        assert_eq!(
+            grpc_plugin_processes(std::slice::from_ref(&binding))
+                .0
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["remote.fetch"]
+        );
+
+        let source = plugins_module_source(&[binding]);
+        assert!(source.contains(GRPC_SCRIPT));
+        assert!(source.contains(r#"[["fetch", grpc("remote.fetch", "fetch")]]"#));
+    }
+
+    #[test]
+    fn test_plugin_binding_native() {
+        let (library, plugin_package) = crate::native::tests::math_library();
+        let library = Arc::new(library);
+        let package = CorePluginPackage::new_from_native_plugin(&plugin_package, library.clone());
+        assert!(!package.functions[0].is_async());
+        let binding = PluginBinding::new_from_plugin_package(&package).unwrap();
+        assert_eq!(
+            binding.functions[0].target,
+            PluginFunctionTarget::Native(library)
+        );
+        assert_eq!(
+            native_plugin_libraries(std::slice::from_ref(&binding))
+                .0
+                .keys()
+                .collect::<Vec<_>>(),
+            vec!["math.sum"]
+        );
+
+        let source = plugins_module_source(&[binding]);
+        assert!(source.contains(NATIVE_SCRIPT));
+        assert!(source.contains(r#"[["sum", native("math.sum", "sum")]]"#));
+    }
+
+    #[test]
+    fn test_plugin_binding_sources() {
+        let binding = binding();
+        assert_eq!(
+            binding.module_source(),
+            "const plugin = globalThis[\"plugins\"][\"com.example.notifications\"];\n\
+             const f0 = plugin[\"send\"];\n\
+             const f1 = plugin[\"send-later\"];\n\
+             export { f0 as \"send\", f1 as \"send-later\" };\n\
+             export default plugin;\n"
+        );
+        let source = plugins_module_source(&[binding]);
+        assert!(source.contains(
+            r#"["com.example.notifications", Object.freeze(Object.fromEntries([["send", ops["op_send"]], ["send-later", ops["op_send_later"]]]))]"#
+        ));
+        assert!(!source.contains("validated("));
+        assert!(!source.contains("import"));
+    }
+
+    #[test]
+    fn test_plugins_module_source_validated() {
+        let mut binding = binding();
+        binding.functions[1].schema = Some(FunctionSchema::new(Vec::new(), None).unwrap());
+        binding.functions[0].target = PluginFunctionTarget::Export {
+            specifier: "sapphillon-plugin://com.example.notifications/main.js".to_string(),
+            name: "send".to_string(),
+        };
+        binding.functions[0].schema = binding.functions[1].schema.clone();
+        let source = plugins_module_source(std::slice::from_ref(&binding));
+        // Ops are validated in `Deno.core.ops` rather than in the module
+        assert!(source.contains(r#"["send-later", ops["op_send_later"]]"#));
+        assert!(source.contains(
+            r#"["send", ops.op_validated_plugin_function("com.example.notifications.send", exported(m0, "send", "com.example.notifications.send"))]"#
+        ));
+        assert_eq!(
+            plugin_ops_with_schemas(std::slice::from_ref(&binding)),
+            vec![("com.example.notifications.send-later", "op_send_later")]
+        );
+
+        let schemas = plugin_function_schemas(&[binding]);
+        assert_eq!(schemas.0.len(), 1);
+        assert!(
+            schemas
+                .0
+                .contains_key("com.example.notifications.send-later")
+        );
+    }
+}
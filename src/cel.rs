@@ -0,0 +1,1384 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Parser and evaluator for the subset of the Common Expression Language (CEL) used by
+//! permission policies.
+//!
+//! Expressions are parsed into the `google.api.expr.v1alpha1` AST and evaluate to
+//! `google.api.expr.v1alpha1.Value`s. Only the subset below is supported; anything else is
+//! rejected when the expression is parsed, so a policy never loads with rules that cannot run.
+//!
+//! | Syntax | Supported |
+//! |---|---|
+//! | Literals | `true`, `false`, decimal `int`s, single- or double-quoted strings with the escapes `\\`, `\"`, `\'`, `\n`, `\r` and `\t`, lists and maps |
+//! | Variables | identifiers, field selection `a.b` and indexing `a[i]` |
+//! | Logical | `!`, `&&`, `\|\|` and `? :`; `&&` and `\|\|` absorb errors when the other side decides the result |
+//! | Relational | `==`, `!=`, `<`, `<=`, `>`, `>=` and `in` |
+//! | Arithmetic | unary `-` on `int`, and `+` on `int`s, strings and lists |
+//! | Macros | `has(a.b)`, `list.all(x, p)` and `list.exists(x, p)`, also on map keys |
+//! | Functions | `size`, `contains`, `startsWith`, `endsWith`, `matches`, `int` and `string` |
+//!
+//! The values are `bool`, `int`, `string`, `list` and `map`; `uint`, `double`, `bytes`, `null`
+//! and messages are not supported. Equality between values of different types is `false`, and
+//! ordering them is an error. Expressions are not type checked before evaluation.
+
+use crate::proto::google::api::expr::v1alpha1::{
+    Constant, Expr, ListValue, MapValue, ParsedExpr, SourceInfo, Value,
+    constant::ConstantKind,
+    expr::{
+        Call, Comprehension, CreateList, CreateStruct, ExprKind, Ident, Select,
+        create_struct::{Entry, entry::KeyKind},
+    },
+    map_value,
+    value::Kind,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Error returned when a CEL expression cannot be parsed or evaluated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CelError {
+    /// The expression is not valid CEL. Line and column are 1-based.
+    Syntax {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// The expression failed to evaluate, e.g. because of a missing key or mismatched types.
+    Evaluation(String),
+}
+
+impl fmt::Display for CelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CelError::Syntax {
+                line,
+                column,
+                message,
+            } => write!(f, "Syntax error at {line}:{column}: {message}"),
+            CelError::Evaluation(message) => write!(f, "Evaluation error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for CelError {}
+
+fn eval_error<T>(message: impl Into<String>) -> Result<T, CelError> {
+    Err(CelError::Evaluation(message.into()))
+}
+
+/// Accumulator variable used by macro expansions.
+const ACCUMULATOR: &str = "__result__";
+
+/// Functions called without a receiver, with their number of arguments.
+const GLOBAL_FUNCTIONS: [(&str, usize); 3] = [("size", 1), ("int", 1), ("string", 1)];
+
+/// Functions called on a receiver, with their number of arguments.
+const RECEIVER_FUNCTIONS: [(&str, usize); 5] = [
+    ("size", 0),
+    ("contains", 1),
+    ("startsWith", 1),
+    ("endsWith", 1),
+    ("matches", 1),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(u64),
+    Str(String),
+    Ident(String),
+    True,
+    False,
+    In,
+    Punct(&'static str),
+    Eof,
+}
+
+const PUNCTUATION: [&str; 19] = [
+    "<=", ">=", "==", "!=", "&&", "||", "(", ")", "[", "]", "{", "}", ".", ",", ":", "?", "!", "-",
+    "+",
+];
+
+/// Splits the source into tokens. Offsets are counted in code points, as in CEL source positions.
+fn tokenize(chars: &[char], line_offsets: &[i32]) -> Result<Vec<(Token, usize)>, CelError> {
+    let syntax_error = |offset: usize, message: &str| {
+        let (line, column) = line_column(line_offsets, offset);
+        CelError::Syntax {
+            line,
+            column,
+            message: message.to_string(),
+        }
+    };
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            if chars
+                .get(i)
+                .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '_')
+            {
+                return Err(syntax_error(
+                    start,
+                    "only decimal int literals are supported",
+                ));
+            }
+            let digits: String = chars[start..i].iter().collect();
+            let value = digits
+                .parse()
+                .map_err(|_| syntax_error(start, "integer literal out of range"))?;
+            tokens.push((Token::Int(value), start));
+        } else if c == '"' || c == '\'' {
+            let (value, end) = lex_string(chars, i).map_err(|m| syntax_error(start, m))?;
+            tokens.push((Token::Str(value), start));
+            i = end;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let token = match word.as_str() {
+                "true" => Token::True,
+                "false" => Token::False,
+                "in" => Token::In,
+                "null" => return Err(syntax_error(start, "null is not supported")),
+                _ => Token::Ident(word),
+            };
+            tokens.push((token, start));
+        } else {
+            let punct = PUNCTUATION
+                .iter()
+                .find(|p| {
+                    p.chars()
+                        .enumerate()
+                        .all(|(k, pc)| chars.get(i + k) == Some(&pc))
+                })
+                .ok_or_else(|| syntax_error(start, &format!("unexpected character '{c}'")))?;
+            tokens.push((Token::Punct(punct), start));
+            i += punct.len();
+        }
+    }
+    tokens.push((Token::Eof, chars.len()));
+    Ok(tokens)
+}
+
+/// Lexes a single-quoted or double-quoted string whose opening quote is at `quote_index`.
+fn lex_string(chars: &[char], quote_index: usize) -> Result<(String, usize), &'static str> {
+    let quote = chars[quote_index];
+    let mut value = String::new();
+    let mut i = quote_index + 1;
+    loop {
+        match chars.get(i) {
+            None | Some('\n') => return Err("unterminated string literal"),
+            Some(&c) if c == quote => return Ok((value, i + 1)),
+            Some('\\') => {
+                let c = match chars.get(i + 1) {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some(c @ ('\\' | '"' | '\'')) => *c,
+                    _ => return Err("unsupported escape sequence"),
+                };
+                value.push(c);
+                i += 2;
+            }
+            Some(&c) => {
+                value.push(c);
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Returns the 1-based line and column of a code point offset.
+fn line_column(line_offsets: &[i32], offset: usize) -> (usize, usize) {
+    let line_index = line_offsets
+        .iter()
+        .take_while(|&&line_end| line_end as usize <= offset)
+        .count();
+    let line_start = match line_index {
+        0 => 0,
+        n => line_offsets[n - 1] as usize,
+    };
+    (line_index + 1, offset - line_start + 1)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    next_id: i64,
+    positions: HashMap<i64, i32>,
+    line_offsets: Vec<i32>,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    fn advance(&mut self) -> (Token, usize) {
+        let token = self.tokens[self.pos].clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Token::Punct(p) if *p == punct)
+    }
+
+    fn eat_punct(&mut self, punct: &str) -> bool {
+        let found = self.is_punct(punct);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> Result<(), CelError> {
+        if self.eat_punct(punct) {
+            Ok(())
+        } else {
+            Err(self.error(self.offset(), &format!("expected '{punct}'")))
+        }
+    }
+
+    fn error(&self, offset: usize, message: &str) -> CelError {
+        let (line, column) = line_column(&self.line_offsets, offset);
+        CelError::Syntax {
+            line,
+            column,
+            message: message.to_string(),
+        }
+    }
+
+    fn new_expr(&mut self, offset: usize, kind: ExprKind) -> Expr {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.positions.insert(id, offset as i32);
+        Expr {
+            id,
+            expr_kind: Some(kind),
+        }
+    }
+
+    fn call(
+        &mut self,
+        offset: usize,
+        function: &str,
+        target: Option<Expr>,
+        args: Vec<Expr>,
+    ) -> Expr {
+        self.new_expr(
+            offset,
+            ExprKind::CallExpr(Box::new(Call {
+                target: target.map(Box::new),
+                function: function.to_string(),
+                args,
+            })),
+        )
+    }
+
+    fn constant(&mut self, offset: usize, constant: ConstantKind) -> Expr {
+        self.new_expr(
+            offset,
+            ExprKind::ConstExpr(Constant {
+                constant_kind: Some(constant),
+            }),
+        )
+    }
+
+    fn ident(&mut self, offset: usize, name: &str) -> Expr {
+        self.new_expr(
+            offset,
+            ExprKind::IdentExpr(Ident {
+                name: name.to_string(),
+            }),
+        )
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, CelError> {
+        let condition = self.parse_or()?;
+        if self.is_punct("?") {
+            let (_, offset) = self.advance();
+            let if_true = self.parse_or()?;
+            self.expect_punct(":")?;
+            let if_false = self.parse_expr()?;
+            return Ok(self.call(offset, "_?_:_", None, vec![condition, if_true, if_false]));
+        }
+        Ok(condition)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, CelError> {
+        let mut left = self.parse_and()?;
+        while self.is_punct("||") {
+            let (_, offset) = self.advance();
+            let right = self.parse_and()?;
+            left = self.call(offset, "_||_", None, vec![left, right]);
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, CelError> {
+        let mut left = self.parse_relation()?;
+        while self.is_punct("&&") {
+            let (_, offset) = self.advance();
+            let right = self.parse_relation()?;
+            left = self.call(offset, "_&&_", None, vec![left, right]);
+        }
+        Ok(left)
+    }
+
+    fn parse_relation(&mut self) -> Result<Expr, CelError> {
+        let mut left = self.parse_addition()?;
+        loop {
+            let function = match self.peek() {
+                Token::Punct("<") => "_<_",
+                Token::Punct("<=") => "_<=_",
+                Token::Punct(">") => "_>_",
+                Token::Punct(">=") => "_>=_",
+                Token::Punct("==") => "_==_",
+                Token::Punct("!=") => "_!=_",
+                Token::In => "@in",
+                _ => return Ok(left),
+            };
+            let (_, offset) = self.advance();
+            let right = self.parse_addition()?;
+            left = self.call(offset, function, None, vec![left, right]);
+        }
+    }
+
+    fn parse_addition(&mut self) -> Result<Expr, CelError> {
+        let mut left = self.parse_unary()?;
+        while self.is_punct("+") {
+            let (_, offset) = self.advance();
+            let right = self.parse_unary()?;
+            left = self.call(offset, "_+_", None, vec![left, right]);
+        }
+        if self.is_punct("-") {
+            return Err(self.error(self.offset(), "binary '-' is not supported"));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, CelError> {
+        if self.is_punct("!") {
+            let (_, offset) = self.advance();
+            let operand = self.parse_unary()?;
+            return Ok(self.call(offset, "!_", None, vec![operand]));
+        }
+        if self.is_punct("-") {
+            let (_, offset) = self.advance();
+            // Negative literals are folded so that the minimum int64 can be written
+            if let Token::Int(value) = *self.peek()
+                && !self.is_member_follow(1)
+            {
+                self.advance();
+                let value = i64::try_from(-(value as i128))
+                    .map_err(|_| self.error(offset, "integer literal out of range"))?;
+                return Ok(self.constant(offset, ConstantKind::Int64Value(value)));
+            }
+            let operand = self.parse_unary()?;
+            return Ok(self.call(offset, "-_", None, vec![operand]));
+        }
+        self.parse_member()
+    }
+
+    /// Returns true if the token `ahead` positions after the current one starts a member access.
+    fn is_member_follow(&self, ahead: usize) -> bool {
+        matches!(
+            self.tokens.get(self.pos + ahead).map(|(t, _)| t),
+            Some(Token::Punct(".")) | Some(Token::Punct("["))
+        )
+    }
+
+    fn parse_member(&mut self) -> Result<Expr, CelError> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            if self.is_punct(".") {
+                let (_, offset) = self.advance();
+                let field = match self.advance() {
+                    (Token::Ident(name), _) => name,
+                    (_, offset) => return Err(self.error(offset, "expected field name")),
+                };
+                if self.eat_punct("(") {
+                    let args = self.parse_list_elements(")")?;
+                    expr = self.receiver_call(offset, expr, &field, args)?;
+                } else {
+                    expr = self.new_expr(
+                        offset,
+                        ExprKind::SelectExpr(Box::new(Select {
+                            operand: Some(Box::new(expr)),
+                            field,
+                            test_only: false,
+                        })),
+                    );
+                }
+            } else if self.is_punct("[") {
+                let (_, offset) = self.advance();
+                let index = self.parse_expr()?;
+                self.expect_punct("]")?;
+                expr = self.call(offset, "_[_]", None, vec![expr, index]);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, CelError> {
+        let (token, offset) = self.advance();
+        match token {
+            Token::Int(value) => {
+                let value = i64::try_from(value)
+                    .map_err(|_| self.error(offset, "integer literal out of range"))?;
+                Ok(self.constant(offset, ConstantKind::Int64Value(value)))
+            }
+            Token::Str(value) => Ok(self.constant(offset, ConstantKind::StringValue(value))),
+            Token::True => Ok(self.constant(offset, ConstantKind::BoolValue(true))),
+            Token::False => Ok(self.constant(offset, ConstantKind::BoolValue(false))),
+            Token::Ident(name) => {
+                if !self.eat_punct("(") {
+                    return Ok(self.ident(offset, &name));
+                }
+                let args = self.parse_list_elements(")")?;
+                if name == "has" {
+                    return self.has_macro(offset, args);
+                }
+                if !GLOBAL_FUNCTIONS.contains(&(name.as_str(), args.len())) {
+                    return Err(self.error(offset, &format!("unsupported function '{name}'")));
+                }
+                Ok(self.call(offset, &name, None, args))
+            }
+            Token::Punct("(") => {
+                let expr = self.parse_expr()?;
+                self.expect_punct(")")?;
+                Ok(expr)
+            }
+            Token::Punct("[") => {
+                let elements = self.parse_list_elements("]")?;
+                Ok(self.new_expr(
+                    offset,
+                    ExprKind::ListExpr(CreateList {
+                        elements,
+                        optional_indices: vec![],
+                    }),
+                ))
+            }
+            Token::Punct("{") => self.parse_map(offset),
+            Token::Eof => Err(self.error(offset, "unexpected end of expression")),
+            _ => Err(self.error(offset, "unexpected token")),
+        }
+    }
+
+    /// Parses comma-separated expressions up to the closing punctuation, allowing a trailing comma.
+    fn parse_list_elements(&mut self, close: &str) -> Result<Vec<Expr>, CelError> {
+        let mut elements = Vec::new();
+        while !self.eat_punct(close) {
+            elements.push(self.parse_expr()?);
+            if !self.eat_punct(",") {
+                self.expect_punct(close)?;
+                break;
+            }
+        }
+        Ok(elements)
+    }
+
+    fn parse_map(&mut self, offset: usize) -> Result<Expr, CelError> {
+        let mut entries = Vec::new();
+        while !self.eat_punct("}") {
+            let entry_offset = self.offset();
+            let key = self.parse_expr()?;
+            self.expect_punct(":")?;
+            let value = self.parse_expr()?;
+            let id = self
+                .new_expr(entry_offset, ExprKind::IdentExpr(Ident::default()))
+                .id;
+            entries.push(Entry {
+                id,
+                value: Some(value),
+                optional_entry: false,
+                key_kind: Some(KeyKind::MapKey(key)),
+            });
+            if !self.eat_punct(",") {
+                self.expect_punct("}")?;
+                break;
+            }
+        }
+        Ok(self.new_expr(
+            offset,
+            ExprKind::StructExpr(CreateStruct {
+                message_name: String::new(),
+                entries,
+            }),
+        ))
+    }
+
+    /// Expands `has(a.b)` into a test-only selection.
+    fn has_macro(&mut self, offset: usize, mut args: Vec<Expr>) -> Result<Expr, CelError> {
+        match args.pop() {
+            Some(Expr {
+                id,
+                expr_kind: Some(ExprKind::SelectExpr(mut select)),
+            }) if args.is_empty() => {
+                select.test_only = true;
+                Ok(Expr {
+                    id,
+                    expr_kind: Some(ExprKind::SelectExpr(select)),
+                })
+            }
+            _ => Err(self.error(offset, "invalid argument to has() macro")),
+        }
+    }
+
+    /// Builds a receiver-style call, expanding the `all` and `exists` macros.
+    fn receiver_call(
+        &mut self,
+        offset: usize,
+        target: Expr,
+        function: &str,
+        mut args: Vec<Expr>,
+    ) -> Result<Expr, CelError> {
+        if !matches!(function, "all" | "exists") || args.len() != 2 {
+            if !RECEIVER_FUNCTIONS.contains(&(function, args.len())) {
+                return Err(self.error(offset, &format!("unsupported function '{function}'")));
+            }
+            return Ok(self.call(offset, function, Some(target), args));
+        }
+
+        let body = args.pop().unwrap();
+        let iter_var = match args.pop().unwrap().expr_kind {
+            Some(ExprKind::IdentExpr(ident)) => ident.name,
+            _ => return Err(self.error(offset, "argument is not an identifier")),
+        };
+
+        // all: true until a step is false; exists: false until a step is true
+        let is_all = function == "all";
+        let accu = self.ident(offset, ACCUMULATOR);
+        let accu_init = self.constant(offset, ConstantKind::BoolValue(is_all));
+        let condition_arg = if is_all {
+            accu.clone()
+        } else {
+            self.call(offset, "!_", None, vec![accu.clone()])
+        };
+        let loop_condition = self.call(offset, "@not_strictly_false", None, vec![condition_arg]);
+        let op = if is_all { "_&&_" } else { "_||_" };
+        let loop_step = self.call(offset, op, None, vec![accu.clone(), body]);
+
+        Ok(self.new_expr(
+            offset,
+            ExprKind::ComprehensionExpr(Box::new(Comprehension {
+                iter_var,
+                iter_var2: String::new(),
+                iter_range: Some(Box::new(target)),
+                accu_var: ACCUMULATOR.to_string(),
+                accu_init: Some(Box::new(accu_init)),
+                loop_condition: Some(Box::new(loop_condition)),
+                loop_step: Some(Box::new(loop_step)),
+                result: Some(Box::new(accu)),
+            })),
+        ))
+    }
+}
+
+/// Parses a CEL expression into its `google.api.expr.v1alpha1` representation.
+///
+/// # Arguments
+/// * `expression` - Source of the CEL expression
+///
+/// # Errors
+/// Returns `CelError::Syntax` with the position of the first syntax error, including syntax
+/// outside of the supported subset.
+pub fn parse(expression: &str) -> Result<ParsedExpr, CelError> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut line_offsets: Vec<i32> = chars
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c == '\n')
+        .map(|(i, _)| i as i32 + 1)
+        .collect();
+    line_offsets.push(chars.len() as i32 + 1);
+
+    let tokens = tokenize(&chars, &line_offsets)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        next_id: 1,
+        positions: HashMap::new(),
+        line_offsets,
+    };
+    let expr = parser.parse_expr()?;
+    if parser.peek() != &Token::Eof {
+        return Err(parser.error(parser.offset(), "unexpected token"));
+    }
+
+    Ok(ParsedExpr {
+        expr: Some(expr),
+        source_info: Some(SourceInfo {
+            syntax_version: String::new(),
+            location: String::new(),
+            line_offsets: parser.line_offsets,
+            positions: parser.positions,
+            macro_calls: HashMap::new(),
+            extensions: vec![],
+        }),
+    })
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value {
+            kind: Some(Kind::BoolValue(value)),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value {
+            kind: Some(Kind::Int64Value(value)),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value {
+            kind: Some(Kind::StringValue(value.to_string())),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value {
+            kind: Some(Kind::StringValue(value)),
+        }
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        Value {
+            kind: Some(Kind::ListValue(ListValue { values })),
+        }
+    }
+}
+
+/// Creates a CEL map value with string keys.
+///
+/// # Arguments
+/// * `entries` - Key and value of each entry
+pub fn map_value<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Value {
+    Value {
+        kind: Some(Kind::MapValue(MapValue {
+            entries: entries
+                .into_iter()
+                .map(|(key, value)| map_value::Entry {
+                    key: Some(key.into()),
+                    value: Some(value),
+                })
+                .collect(),
+        })),
+    }
+}
+
+/// Variables available to an expression during evaluation.
+#[derive(Debug, Clone, Default)]
+pub struct Activation {
+    variables: HashMap<String, Value>,
+}
+
+impl Activation {
+    /// Creates an empty activation.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds a variable, replacing any previous value.
+    ///
+    /// # Arguments
+    /// * `name` - Name of the variable
+    /// * `value` - Value of the variable
+    pub fn insert(&mut self, name: &str, value: Value) {
+        self.variables.insert(name.to_string(), value);
+    }
+
+    /// Returns the value of a variable.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.variables.get(name)
+    }
+}
+
+/// Evaluates a parsed expression against the given variables.
+///
+/// # Arguments
+/// * `parsed` - Expression returned by `parse`
+/// * `activation` - Variables referenced by the expression
+///
+/// # Errors
+/// Returns `CelError::Evaluation` for undeclared variables, missing keys, mismatched types,
+/// values outside of the supported subset and overflows.
+pub fn evaluate(parsed: &ParsedExpr, activation: &Activation) -> Result<Value, CelError> {
+    let expr = parsed
+        .expr
+        .as_ref()
+        .ok_or_else(|| CelError::Evaluation("empty expression".to_string()))?;
+    Evaluator {
+        activation,
+        locals: Vec::new(),
+    }
+    .eval(expr)
+}
+
+struct Evaluator<'a> {
+    activation: &'a Activation,
+    /// Variables bound by comprehensions, innermost last. The accumulator may hold an error that
+    /// a later step absorbs.
+    locals: Vec<(String, Result<Value, CelError>)>,
+}
+
+fn kind(value: &Value) -> Result<&Kind, CelError> {
+    value
+        .kind
+        .as_ref()
+        .ok_or_else(|| CelError::Evaluation("value without kind".to_string()))
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value.kind {
+        Some(Kind::BoolValue(_)) => "bool",
+        Some(Kind::Int64Value(_)) => "int",
+        Some(Kind::StringValue(_)) => "string",
+        Some(Kind::MapValue(_)) => "map",
+        Some(Kind::ListValue(_)) => "list",
+        Some(_) => "unsupported",
+        None => "unknown",
+    }
+}
+
+fn as_bool(value: &Value) -> Result<bool, CelError> {
+    match kind(value)? {
+        Kind::BoolValue(b) => Ok(*b),
+        _ => eval_error(format!("expected bool, found {}", type_name(value))),
+    }
+}
+
+fn map_get<'v>(map: &'v MapValue, key: &Value) -> Option<&'v Value> {
+    map.entries
+        .iter()
+        .find(|entry| entry.key.as_ref().is_some_and(|k| values_equal(k, key)))
+        .and_then(|entry| entry.value.as_ref())
+}
+
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (&left.kind, &right.kind) {
+        (Some(Kind::ListValue(l)), Some(Kind::ListValue(r))) => {
+            l.values.len() == r.values.len()
+                && l.values
+                    .iter()
+                    .zip(&r.values)
+                    .all(|(l, r)| values_equal(l, r))
+        }
+        (Some(Kind::MapValue(l)), Some(Kind::MapValue(r))) => {
+            l.entries.len() == r.entries.len()
+                && l.entries
+                    .iter()
+                    .all(|entry| match (&entry.key, &entry.value) {
+                        (Some(key), Some(value)) => {
+                            map_get(r, key).is_some_and(|other| values_equal(value, other))
+                        }
+                        _ => false,
+                    })
+        }
+        (l, r) => l == r,
+    }
+}
+
+fn compare(left: &Value, right: &Value) -> Result<std::cmp::Ordering, CelError> {
+    match (kind(left)?, kind(right)?) {
+        (Kind::Int64Value(l), Kind::Int64Value(r)) => Ok(l.cmp(r)),
+        (Kind::StringValue(l), Kind::StringValue(r)) => Ok(l.cmp(r)),
+        (Kind::BoolValue(l), Kind::BoolValue(r)) => Ok(l.cmp(r)),
+        _ => eval_error(format!(
+            "no such overload: {} < {}",
+            type_name(left),
+            type_name(right)
+        )),
+    }
+}
+
+fn add(left: &Value, right: &Value) -> Result<Value, CelError> {
+    match (kind(left)?, kind(right)?) {
+        (Kind::Int64Value(l), Kind::Int64Value(r)) => Ok(l
+            .checked_add(*r)
+            .ok_or_else(|| CelError::Evaluation("overflow in _+_".to_string()))?
+            .into()),
+        (Kind::StringValue(l), Kind::StringValue(r)) => Ok(format!("{l}{r}").into()),
+        (Kind::ListValue(l), Kind::ListValue(r)) => {
+            Ok([l.values.as_slice(), r.values.as_slice()].concat().into())
+        }
+        _ => eval_error(format!(
+            "no such overload: {} + {}",
+            type_name(left),
+            type_name(right)
+        )),
+    }
+}
+
+fn index(container: &Value, key: &Value) -> Result<Value, CelError> {
+    match (kind(container)?, kind(key)?) {
+        (Kind::ListValue(list), Kind::Int64Value(position)) => usize::try_from(*position)
+            .ok()
+            .and_then(|i| list.values.get(i))
+            .cloned()
+            .ok_or_else(|| CelError::Evaluation(format!("index out of range: {position}"))),
+        (Kind::ListValue(_), _) => {
+            eval_error(format!("invalid list index of type {}", type_name(key)))
+        }
+        (Kind::MapValue(map), _) => map_get(map, key)
+            .cloned()
+            .ok_or_else(|| CelError::Evaluation("no such key".to_string())),
+        _ => eval_error(format!(
+            "cannot index a value of type {}",
+            type_name(container)
+        )),
+    }
+}
+
+fn contains(container: &Value, element: &Value) -> Result<bool, CelError> {
+    match kind(container)? {
+        Kind::ListValue(list) => Ok(list.values.iter().any(|v| values_equal(v, element))),
+        Kind::MapValue(map) => Ok(map_get(map, element).is_some()),
+        _ => eval_error(format!(
+            "no such overload: {} in {}",
+            type_name(element),
+            type_name(container)
+        )),
+    }
+}
+
+fn size(value: &Value) -> Result<Value, CelError> {
+    let size = match kind(value)? {
+        Kind::StringValue(s) => s.chars().count(),
+        Kind::ListValue(l) => l.values.len(),
+        Kind::MapValue(m) => m.entries.len(),
+        _ => return eval_error(format!("no such overload: size({})", type_name(value))),
+    };
+    Ok((size as i64).into())
+}
+
+fn string_function(function: &str, target: &str, arg: &str) -> Result<Value, CelError> {
+    let result = match function {
+        "contains" => target.contains(arg),
+        "startsWith" => target.starts_with(arg),
+        "endsWith" => target.ends_with(arg),
+        _ => regex::Regex::new(arg)
+            .map_err(|e| CelError::Evaluation(format!("invalid regular expression: {e}")))?
+            .is_match(target),
+    };
+    Ok(result.into())
+}
+
+fn convert(function: &str, value: &Value) -> Result<Value, CelError> {
+    let invalid = || {
+        CelError::Evaluation(format!(
+            "no such overload: {function}({})",
+            type_name(value)
+        ))
+    };
+    match (function, kind(value)?) {
+        ("int", Kind::Int64Value(i)) => Ok((*i).into()),
+        ("int", Kind::StringValue(s)) => Ok(s.parse::<i64>().map_err(|_| invalid())?.into()),
+        ("string", Kind::StringValue(s)) => Ok(s.clone().into()),
+        ("string", Kind::Int64Value(i)) => Ok(i.to_string().into()),
+        ("string", Kind::BoolValue(b)) => Ok(b.to_string().into()),
+        _ => Err(invalid()),
+    }
+}
+
+impl Evaluator<'_> {
+    fn lookup(&self, name: &str) -> Result<Value, CelError> {
+        match self.locals.iter().rev().find(|(local, _)| local == name) {
+            Some((_, value)) => value.clone(),
+            None => {
+                self.activation.get(name).cloned().ok_or_else(|| {
+                    CelError::Evaluation(format!("undeclared reference to '{name}'"))
+                })
+            }
+        }
+    }
+
+    fn eval_child(&mut self, expr: &Option<Box<Expr>>) -> Result<Value, CelError> {
+        match expr {
+            Some(expr) => self.eval(expr),
+            None => eval_error("incomplete expression"),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, CelError> {
+        match expr.expr_kind.as_ref() {
+            Some(ExprKind::ConstExpr(constant)) => match constant.constant_kind.as_ref() {
+                Some(ConstantKind::BoolValue(b)) => Ok((*b).into()),
+                Some(ConstantKind::Int64Value(i)) => Ok((*i).into()),
+                Some(ConstantKind::StringValue(s)) => Ok(s.clone().into()),
+                _ => eval_error("unsupported constant"),
+            },
+            Some(ExprKind::IdentExpr(ident)) => self.lookup(&ident.name),
+            Some(ExprKind::SelectExpr(select)) => {
+                let operand = self.eval_child(&select.operand)?;
+                let Kind::MapValue(map) = kind(&operand)? else {
+                    return eval_error(format!(
+                        "cannot select field '{}' from {}",
+                        select.field,
+                        type_name(&operand)
+                    ));
+                };
+                let value = map_get(map, &select.field.as_str().into());
+                if select.test_only {
+                    return Ok(value.is_some().into());
+                }
+                value
+                    .cloned()
+                    .ok_or_else(|| CelError::Evaluation(format!("no such key: {}", select.field)))
+            }
+            Some(ExprKind::CallExpr(call)) => self.eval_call(call),
+            Some(ExprKind::ListExpr(list)) => Ok(list
+                .elements
+                .iter()
+                .map(|e| self.eval(e))
+                .collect::<Result<Vec<Value>, CelError>>()?
+                .into()),
+            Some(ExprKind::StructExpr(create)) if create.message_name.is_empty() => {
+                let mut map = MapValue::default();
+                for entry in &create.entries {
+                    let key = match &entry.key_kind {
+                        Some(KeyKind::MapKey(key)) => self.eval(key)?,
+                        _ => return eval_error("map entry without key"),
+                    };
+                    if map_get(&map, &key).is_some() {
+                        return eval_error("duplicate map key");
+                    }
+                    let value = match &entry.value {
+                        Some(value) => self.eval(value)?,
+                        None => return eval_error("map entry without value"),
+                    };
+                    map.entries.push(map_value::Entry {
+                        key: Some(key),
+                        value: Some(value),
+                    });
+                }
+                Ok(Value {
+                    kind: Some(Kind::MapValue(map)),
+                })
+            }
+            Some(ExprKind::ComprehensionExpr(comprehension)) => {
+                self.eval_comprehension(comprehension)
+            }
+            _ => eval_error("unsupported expression"),
+        }
+    }
+
+    fn eval_comprehension(&mut self, comprehension: &Comprehension) -> Result<Value, CelError> {
+        let range = self.eval_child(&comprehension.iter_range)?;
+        let items = match kind(&range)? {
+            Kind::ListValue(list) => list.values.clone(),
+            Kind::MapValue(map) => map.entries.iter().filter_map(|e| e.key.clone()).collect(),
+            _ => {
+                return eval_error(format!(
+                    "cannot iterate over a value of type {}",
+                    type_name(&range)
+                ));
+            }
+        };
+
+        // A failed step is kept in the accumulator, so that a later step can still decide the result
+        let mut accu = self.eval_child(&comprehension.accu_init);
+        for item in items {
+            self.locals
+                .push((comprehension.accu_var.clone(), accu.clone()));
+            self.locals.push((comprehension.iter_var.clone(), Ok(item)));
+            let proceed = self
+                .eval_child(&comprehension.loop_condition)
+                .and_then(|condition| as_bool(&condition));
+            let step = match proceed {
+                Ok(true) => Some(self.eval_child(&comprehension.loop_step)),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            };
+            self.locals.truncate(self.locals.len() - 2);
+            match step {
+                Some(next) => accu = next,
+                None => break,
+            }
+        }
+
+        self.locals.push((comprehension.accu_var.clone(), accu));
+        let result = self.eval_child(&comprehension.result);
+        self.locals.pop();
+        result
+    }
+
+    fn eval_call(&mut self, call: &Call) -> Result<Value, CelError> {
+        match (call.function.as_str(), call.args.as_slice()) {
+            // Logical operators absorb errors when the other side decides the result
+            ("_&&_", [left, right]) | ("_||_", [left, right]) => {
+                let decisive = call.function == "_||_";
+                let left = self.eval(left).and_then(|v| as_bool(&v));
+                if left == Ok(decisive) {
+                    return Ok(decisive.into());
+                }
+                let right = self.eval(right).and_then(|v| as_bool(&v));
+                match (left, right) {
+                    (_, Ok(r)) if r == decisive => Ok(decisive.into()),
+                    (Ok(_), Ok(_)) => Ok((!decisive).into()),
+                    (Err(e), _) | (_, Err(e)) => Err(e),
+                }
+            }
+            ("_?_:_", [condition, if_true, if_false]) => {
+                let condition = self.eval(condition)?;
+                if as_bool(&condition)? {
+                    self.eval(if_true)
+                } else {
+                    self.eval(if_false)
+                }
+            }
+            ("@not_strictly_false", [arg]) => {
+                let value = self.eval(arg);
+                Ok((!matches!(value.map(|v| v.kind), Ok(Some(Kind::BoolValue(false))))).into())
+            }
+            _ => {
+                let target = match &call.target {
+                    Some(target) => Some(self.eval(target)?),
+                    None => None,
+                };
+                let args = call
+                    .args
+                    .iter()
+                    .map(|a| self.eval(a))
+                    .collect::<Result<Vec<Value>, CelError>>()?;
+                call_function(&call.function, target.as_ref(), &args)
+            }
+        }
+    }
+}
+
+fn call_function(
+    function: &str,
+    target: Option<&Value>,
+    args: &[Value],
+) -> Result<Value, CelError> {
+    use std::cmp::Ordering;
+
+    match (function, target, args) {
+        ("!_", None, [operand]) => Ok((!as_bool(operand)?).into()),
+        ("-_", None, [operand]) => match kind(operand)? {
+            Kind::Int64Value(i) => Ok(i
+                .checked_neg()
+                .ok_or_else(|| CelError::Evaluation("overflow in -_".to_string()))?
+                .into()),
+            _ => eval_error(format!("no such overload: -{}", type_name(operand))),
+        },
+        ("_==_", None, [left, right]) => Ok(values_equal(left, right).into()),
+        ("_!=_", None, [left, right]) => Ok((!values_equal(left, right)).into()),
+        ("_<_", None, [left, right]) => Ok((compare(left, right)? == Ordering::Less).into()),
+        ("_<=_", None, [left, right]) => Ok((compare(left, right)? != Ordering::Greater).into()),
+        ("_>_", None, [left, right]) => Ok((compare(left, right)? == Ordering::Greater).into()),
+        ("_>=_", None, [left, right]) => Ok((compare(left, right)? != Ordering::Less).into()),
+        ("_+_", None, [left, right]) => add(left, right),
+        ("_[_]", None, [container, key]) => index(container, key),
+        ("@in", None, [element, container]) => Ok(contains(container, element)?.into()),
+        ("size", None, [value]) | ("size", Some(value), []) => size(value),
+        ("contains" | "startsWith" | "endsWith" | "matches", Some(target), [arg]) => {
+            match (kind(target)?, kind(arg)?) {
+                (Kind::StringValue(target), Kind::StringValue(arg)) => {
+                    string_function(function, target, arg)
+                }
+                _ => eval_error(format!(
+                    "no such overload: {}.{function}({})",
+                    type_name(target),
+                    type_name(arg)
+                )),
+            }
+        }
+        ("int" | "string", None, [value]) => convert(function, value),
+        _ => eval_error(format!("no such overload: {function}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_with(expression: &str, activation: &Activation) -> Result<Value, CelError> {
+        evaluate(&parse(expression)?, activation)
+    }
+
+    fn eval(expression: &str) -> Result<Value, CelError> {
+        eval_with(expression, &Activation::new())
+    }
+
+    /// Evaluates each case, expecting its value or, for `None`, an evaluation error.
+    fn assert_cases(cases: &[(&str, Option<Value>)]) {
+        for (expression, expected) in cases {
+            match (eval(expression), expected) {
+                (Ok(value), Some(expected)) => assert_eq!(&value, expected, "{expression}"),
+                (Err(CelError::Evaluation(_)), None) => {}
+                (result, _) => panic!("{expression}: unexpected {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_builds_call_ast() {
+        let parsed = parse("a && b.c == 1").unwrap();
+        let Some(ExprKind::CallExpr(call)) = parsed.expr.unwrap().expr_kind else {
+            panic!("expected a call");
+        };
+        assert_eq!(call.function, "_&&_");
+        let Some(ExprKind::CallExpr(eq)) = &call.args[1].expr_kind else {
+            panic!("expected a call");
+        };
+        assert_eq!(eq.function, "_==_");
+        assert!(matches!(
+            eq.args[0].expr_kind,
+            Some(ExprKind::SelectExpr(_))
+        ));
+        assert_eq!(parsed.source_info.unwrap().positions.len(), 6);
+    }
+
+    #[test]
+    fn test_parse_syntax_error_position() {
+        let err = parse("a &&\n  (b ||").unwrap_err();
+        assert_eq!(
+            err,
+            CelError::Syntax {
+                line: 2,
+                column: 8,
+                message: "unexpected end of expression".to_string()
+            }
+        );
+        assert!(parse("'unterminated").is_err());
+        assert!(parse("a b").is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_unsupported_syntax() {
+        for expression in [
+            "1.5 > 1",
+            "1u == 1",
+            "0x10 == 16",
+            "b'abc' == b'abc'",
+            r#"r"a\d" == 'a'"#,
+            r#"'\x41' == 'A'"#,
+            "null == null",
+            "2 * 3 == 6",
+            "3 - 1 == 2",
+            "5 / 5 == 1",
+            "5 % 2 == 1",
+            "[1].map(x, x)",
+            "[1].filter(x, x > 0)",
+            "[1].exists_one(x, x > 0)",
+            "double(1) == 1",
+            "'a'.lowerAscii() == 'a'",
+            "timestamp('2025-01-01T00:00:00Z')",
+        ] {
+            assert!(
+                matches!(parse(expression), Err(CelError::Syntax { .. })),
+                "{expression}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_conformance_logic() {
+        assert_cases(&[
+            ("true && true", Some(true.into())),
+            ("true && false", Some(false.into())),
+            ("false || true", Some(true.into())),
+            ("false || false", Some(false.into())),
+            ("!true", Some(false.into())),
+            ("!!true", Some(true.into())),
+            ("true ? 1 : 2", Some(1i64.into())),
+            ("false ? 1 : 2", Some(2i64.into())),
+            ("false ? missing : 'b'", Some("b".into())),
+            // Errors are absorbed when the other operand decides the result
+            ("false && missing", Some(false.into())),
+            ("missing && false", Some(false.into())),
+            ("true || missing", Some(true.into())),
+            ("missing || true", Some(true.into())),
+            ("missing || false", None),
+            ("true && missing", None),
+            ("1 && true", None),
+            ("!1", None),
+            ("'a' ? 1 : 2", None),
+        ]);
+    }
+
+    #[test]
+    fn test_conformance_comparisons() {
+        assert_cases(&[
+            ("1 == 1", Some(true.into())),
+            ("1 != 2", Some(true.into())),
+            ("'a' == 'a'", Some(true.into())),
+            ("'a' != 'b'", Some(true.into())),
+            ("[1, 'a'] == [1, 'a']", Some(true.into())),
+            ("[1] == [1, 2]", Some(false.into())),
+            ("{'a': 1, 'b': 2} == {'b': 2, 'a': 1}", Some(true.into())),
+            ("{'a': 1} != {'a': 2}", Some(true.into())),
+            // Values of different types are never equal
+            ("1 == '1'", Some(false.into())),
+            ("true != 1", Some(true.into())),
+            ("1 < 2", Some(true.into())),
+            ("2 <= 2", Some(true.into())),
+            ("3 > 2", Some(true.into())),
+            ("-1 >= 0", Some(false.into())),
+            ("'abc' < 'abd'", Some(true.into())),
+            ("'b' > 'abc'", Some(true.into())),
+            ("false < true", Some(true.into())),
+            ("1 < '2'", None),
+            ("[1] < [2]", None),
+        ]);
+    }
+
+    #[test]
+    fn test_conformance_membership_and_access() {
+        assert_cases(&[
+            ("2 in [1, 2]", Some(true.into())),
+            ("3 in [1, 2]", Some(false.into())),
+            ("'a' in {'a': 1}", Some(true.into())),
+            ("'b' in {'a': 1}", Some(false.into())),
+            ("'a' in 'abc'", None),
+            ("[1, 2][1]", Some(2i64.into())),
+            ("[1, 2][2]", None),
+            ("[1, 2][-1]", None),
+            ("[1, 2]['0']", None),
+            ("{'a': 1}['a']", Some(1i64.into())),
+            ("{'a': 1}['b']", None),
+            ("{'a': {'b': 'c'}}.a.b", Some("c".into())),
+            ("{'a': 1}.b", None),
+            ("has({'a': 1}.a)", Some(true.into())),
+            ("has({'a': 1}.b)", Some(false.into())),
+            ("{'a': 1, 'a': 2}", None),
+        ]);
+    }
+
+    #[test]
+    fn test_conformance_arithmetic() {
+        assert_cases(&[
+            ("1 + 2", Some(3i64.into())),
+            ("-9223372036854775808", Some(i64::MIN.into())),
+            ("-(1 + 1)", Some((-2i64).into())),
+            ("9223372036854775807 + 1", None),
+            ("-(-9223372036854775808)", None),
+            ("'ab' + 'c'", Some("abc".into())),
+            ("[1] + [2] == [1, 2]", Some(true.into())),
+            ("1 + '1'", None),
+            ("-'a'", None),
+        ]);
+        assert!(parse("9223372036854775808").is_err());
+    }
+
+    #[test]
+    fn test_conformance_strings_and_functions() {
+        assert_cases(&[
+            (r#""a\tb\\" + '\'c\''"#, Some("a\tb\\'c'".into())),
+            (
+                "'buckets/logs/a'.startsWith('buckets/logs')",
+                Some(true.into()),
+            ),
+            ("'a.txt'.endsWith('.txt')", Some(true.into())),
+            ("'abc'.contains('b')", Some(true.into())),
+            ("'abc'.contains('d')", Some(false.into())),
+            ("'abc123'.matches('^[a-z]+[0-9]+$')", Some(true.into())),
+            ("'abc'.matches('[')", None),
+            ("'abc'.startsWith(1)", None),
+            ("size('héllo')", Some(5i64.into())),
+            ("'abc'.size()", Some(3i64.into())),
+            ("size([1, 2, 3])", Some(3i64.into())),
+            ("size({'a': 1})", Some(1i64.into())),
+            ("size(1)", None),
+            ("string(12) + string(true)", Some("12true".into())),
+            ("int('-5')", Some((-5i64).into())),
+            ("int('5.0')", None),
+            ("int(true)", None),
+        ]);
+    }
+
+    #[test]
+    fn test_conformance_macros() {
+        assert_cases(&[
+            ("[1, 2, 3].all(x, x > 0)", Some(true.into())),
+            ("[1, 2, 3].all(x, x > 1)", Some(false.into())),
+            ("[].all(x, x > 1)", Some(true.into())),
+            ("[1, 2, 3].exists(x, x > 2)", Some(true.into())),
+            ("[].exists(x, x > 2)", Some(false.into())),
+            ("{'a': 1}.all(k, k == 'a')", Some(true.into())),
+            // Errors are absorbed by a deciding element
+            ("['a', 1].exists(x, x > 0)", Some(true.into())),
+            ("['a', 0].all(x, x > 0)", Some(false.into())),
+            ("[1, 'a'].all(x, x > 0)", None),
+            ("'a'.all(x, x)", None),
+            ("[[1], [2]].all(x, x.exists(y, y > 0))", Some(true.into())),
+        ]);
+    }
+
+    #[test]
+    fn test_evaluate_with_activation() {
+        let mut activation = Activation::new();
+        activation.insert("HIGH", 2i64.into());
+        activation.insert(
+            "permission",
+            map_value([
+                ("level", 2i64.into()),
+                ("type", 1i64.into()),
+                ("resource", vec!["/tmp/a".into(), "/tmp/b".into()].into()),
+            ]),
+        );
+        activation.insert("resource", "buckets/logs/2025".into());
+
+        let expression = r#"permission.level <= HIGH && resource.startsWith("buckets/logs")"#;
+        assert_eq!(
+            eval_with(expression, &activation).unwrap(),
+            Value::from(true)
+        );
+        assert_eq!(
+            eval_with(
+                "permission.resource.all(r, r.startsWith('/tmp/'))",
+                &activation
+            )
+            .unwrap(),
+            Value::from(true)
+        );
+
+        let err = eval_with("permission.owner == 'me'", &activation).unwrap_err();
+        assert_eq!(err, CelError::Evaluation("no such key: owner".to_string()));
+        assert!(eval_with("unknown", &activation).is_err());
+    }
+}
@@ -0,0 +1,529 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Configuration of plugin packages.
+//!
+//! Each `CorePluginPackage` has a `PluginConfig`, such as the endpoint and API token of the
+//! service it calls. The configuration is never exposed to workflow code. It is passed to the
+//! lifecycle hooks of the package, and the ops of the package read it from the `OpState` with
+//! `plugin_config`:
+//!
+//! - `plugin_config(state)` returns the configuration of the package of the op being
+//!   dispatched, which the runtime tracks with op metrics, so ops cannot read the
+//!   configuration of other packages. Async ops must read it before their first `.await`;
+//!   afterwards it returns `None`.
+//!
+//! Secret values are redacted from what a run prints, whether captured or written to stdout
+//! and stderr, from the error messages and audit events recorded for the run, and from the
+//! `Debug` output of the configuration.
+
+use deno_core::{OpMetricsEvent, OpMetricsFactoryFn, OpMetricsFn, OpMetricsSource, OpState};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// Text that secret values are replaced with.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Value of a plugin configuration entry.
+#[derive(Clone, PartialEq)]
+pub enum PluginConfigValue {
+    /// Text, e.g. an endpoint
+    String(String),
+    /// Integer, e.g. a pool size
+    Integer(i64),
+    /// Floating-point number
+    Float(f64),
+    /// Flag
+    Boolean(bool),
+    /// Text that must not be revealed, e.g. an API token
+    Secret(String),
+}
+
+impl PluginConfigValue {
+    /// Returns the name of the type of the value, used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            PluginConfigValue::String(_) => "string",
+            PluginConfigValue::Integer(_) => "integer",
+            PluginConfigValue::Float(_) => "float",
+            PluginConfigValue::Boolean(_) => "boolean",
+            PluginConfigValue::Secret(_) => "secret",
+        }
+    }
+}
+
+impl fmt::Debug for PluginConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginConfigValue::String(value) => f.debug_tuple("String").field(value).finish(),
+            PluginConfigValue::Integer(value) => f.debug_tuple("Integer").field(value).finish(),
+            PluginConfigValue::Float(value) => f.debug_tuple("Float").field(value).finish(),
+            PluginConfigValue::Boolean(value) => f.debug_tuple("Boolean").field(value).finish(),
+            PluginConfigValue::Secret(_) => f.debug_tuple("Secret").field(&REDACTED).finish(),
+        }
+    }
+}
+
+/// Error returned when a plugin configuration entry is missing or has another type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginConfigError {
+    /// The entry is not set.
+    Missing { key: String },
+    /// The entry has another type than the one requested.
+    InvalidType {
+        key: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+}
+
+impl fmt::Display for PluginConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginConfigError::Missing { key } => {
+                write!(f, "Plugin configuration {key} is not set")
+            }
+            PluginConfigError::InvalidType {
+                key,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Plugin configuration {key} must be a {expected}, but is a {found}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PluginConfigError {}
+
+/// Typed configuration of a plugin package, keyed by name.
+#[derive(Clone, Default, PartialEq)]
+pub struct PluginConfig {
+    values: BTreeMap<String, PluginConfigValue>,
+}
+
+impl fmt::Debug for PluginConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(&self.values).finish()
+    }
+}
+
+impl PluginConfig {
+    /// Creates an empty PluginConfig.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets an entry, replacing its previous value.
+    ///
+    /// # Arguments
+    /// * `key` - Name of the entry
+    /// * `value` - Value of the entry
+    pub fn insert(&mut self, key: String, value: PluginConfigValue) {
+        self.values.insert(key, value);
+    }
+
+    /// Returns the value of an entry, or `None` if it is not set.
+    ///
+    /// # Arguments
+    /// * `key` - Name of the entry
+    pub fn get(&self, key: &str) -> Option<&PluginConfigValue> {
+        self.values.get(key)
+    }
+
+    /// Returns the names of the entries, in order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.values.keys().map(String::as_str)
+    }
+
+    /// Returns true if no entry is set.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the values of the secret entries.
+    pub fn secrets(&self) -> impl Iterator<Item = &str> {
+        self.values.values().filter_map(|value| match value {
+            PluginConfigValue::Secret(secret) => Some(secret.as_str()),
+            _ => None,
+        })
+    }
+
+    fn typed<'a, T>(
+        &'a self,
+        key: &str,
+        expected: &'static str,
+        typed: impl Fn(&'a PluginConfigValue) -> Option<T>,
+    ) -> Result<T, PluginConfigError> {
+        let value = self.get(key).ok_or_else(|| PluginConfigError::Missing {
+            key: key.to_string(),
+        })?;
+        typed(value).ok_or_else(|| PluginConfigError::InvalidType {
+            key: key.to_string(),
+            expected,
+            found: value.type_name(),
+        })
+    }
+
+    /// Returns the value of a string entry.
+    ///
+    /// # Arguments
+    /// * `key` - Name of the entry
+    ///
+    /// # Errors
+    /// Returns a `PluginConfigError` if the entry is not set or is not a string.
+    pub fn get_string(&self, key: &str) -> Result<&str, PluginConfigError> {
+        self.typed(key, "string", |value| match value {
+            PluginConfigValue::String(value) => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Returns the value of an integer entry.
+    ///
+    /// # Arguments
+    /// * `key` - Name of the entry
+    ///
+    /// # Errors
+    /// Returns a `PluginConfigError` if the entry is not set or is not an integer.
+    pub fn get_integer(&self, key: &str) -> Result<i64, PluginConfigError> {
+        self.typed(key, "integer", |value| match value {
+            PluginConfigValue::Integer(value) => Some(*value),
+            _ => None,
+        })
+    }
+
+    /// Returns the value of a number entry. Integer entries are converted.
+    ///
+    /// # Arguments
+    /// * `key` - Name of the entry
+    ///
+    /// # Errors
+    /// Returns a `PluginConfigError` if the entry is not set or is not a number.
+    pub fn get_float(&self, key: &str) -> Result<f64, PluginConfigError> {
+        self.typed(key, "float", |value| match value {
+            PluginConfigValue::Float(value) => Some(*value),
+            PluginConfigValue::Integer(value) => Some(*value as f64),
+            _ => None,
+        })
+    }
+
+    /// Returns the value of a boolean entry.
+    ///
+    /// # Arguments
+    /// * `key` - Name of the entry
+    ///
+    /// # Errors
+    /// Returns a `PluginConfigError` if the entry is not set or is not a boolean.
+    pub fn get_boolean(&self, key: &str) -> Result<bool, PluginConfigError> {
+        self.typed(key, "boolean", |value| match value {
+            PluginConfigValue::Boolean(value) => Some(*value),
+            _ => None,
+        })
+    }
+
+    /// Returns the value of a secret entry. The value must not be returned to the workflow.
+    ///
+    /// # Arguments
+    /// * `key` - Name of the entry
+    ///
+    /// # Errors
+    /// Returns a `PluginConfigError` if the entry is not set or is not a secret.
+    pub fn get_secret(&self, key: &str) -> Result<&str, PluginConfigError> {
+        self.typed(key, "secret", |value| match value {
+            PluginConfigValue::Secret(value) => Some(value.as_str()),
+            _ => None,
+        })
+    }
+}
+
+/// Configurations of the plugin packages of a run, placed into the `OpState` and keyed by the
+/// op names of their functions.
+#[derive(Default)]
+pub(crate) struct PluginConfigs(pub(crate) HashMap<String, Arc<PluginConfig>>);
+
+/// Op of a plugin function being dispatched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CallingOp {
+    /// Name of the op
+    name: &'static str,
+    /// Whether the op is async. Async ops that are still pending when their dispatch returns
+    /// are not removed by any event, so they are removed by the next event of another op.
+    is_async: bool,
+    /// Whether the op can read its configuration. Async ops dispatched while another op is
+    /// dispatched cannot, since that op continues when they return pending.
+    scoped: bool,
+}
+
+/// Ops of plugin functions being dispatched, innermost last. Shared between the `OpState` and
+/// the op metrics of the run, see `calling_op_metrics`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CallingOps(Rc<RefCell<Vec<CallingOp>>>);
+
+impl CallingOps {
+    /// Forgets all ops. Called when no op is being dispatched: before each poll of the event
+    /// loop, which polls the futures of pending async ops, and after the run.
+    pub(crate) fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+
+    /// Returns the name of the op whose configuration can be read, if any.
+    fn current(&self) -> Option<&'static str> {
+        self.0
+            .borrow()
+            .last()
+            .filter(|op| op.scoped)
+            .map(|op| op.name)
+    }
+
+    /// Records an op metrics event of the op with the given name.
+    fn record(&self, name: &'static str, event: OpMetricsEvent, source: OpMetricsSource) {
+        let mut ops = self.0.borrow_mut();
+        // Async ops on top have returned if another op is dispatched or returns
+        let mut pop_returned = |skip: Option<&str>| {
+            while ops
+                .last()
+                .is_some_and(|op| op.is_async && Some(op.name) != skip)
+            {
+                ops.pop();
+            }
+        };
+        match event {
+            OpMetricsEvent::Dispatched => {
+                pop_returned(None);
+                let is_async = source == OpMetricsSource::Async;
+                let scoped = !is_async || ops.is_empty();
+                ops.push(CallingOp {
+                    name,
+                    is_async,
+                    scoped,
+                });
+            }
+            OpMetricsEvent::Completed | OpMetricsEvent::Error => {
+                pop_returned(Some(name));
+                if ops.last().is_some_and(|op| op.name == name) {
+                    ops.pop();
+                }
+            }
+            // Async ops are removed when their dispatch returns, not when their future completes
+            OpMetricsEvent::CompletedAsync | OpMetricsEvent::ErrorAsync => {}
+        }
+    }
+}
+
+/// Returns the op metrics factory of a run, which records the dispatches of the ops with a
+/// configuration in `calling_ops`.
+///
+/// # Arguments
+/// * `calling_ops` - Ops being dispatched, also placed into the `OpState` of the run
+/// * `configs` - Configurations of the packages of the run, keyed by op name
+pub(crate) fn calling_op_metrics(
+    calling_ops: &CallingOps,
+    configs: &PluginConfigs,
+) -> OpMetricsFactoryFn {
+    let calling_ops = calling_ops.clone();
+    let op_names: Vec<String> = configs.0.keys().cloned().collect();
+    Box::new(move |_, _, decl| {
+        if !op_names.iter().any(|op_name| op_name == decl.name) {
+            return None;
+        }
+        let calling_ops = calling_ops.clone();
+        let name = decl.name;
+        let metrics: OpMetricsFn =
+            Rc::new(move |_: &_, event, source| calling_ops.record(name, event, source));
+        Some(metrics)
+    })
+}
+
+/// Returns the configuration of the package of the op being dispatched, or `None` if no op of a
+/// plugin package of the run is being dispatched.
+///
+/// Async ops must read their configuration before their first `.await`.
+///
+/// # Arguments
+/// * `state` - `OpState` of the run
+pub fn plugin_config(state: &OpState) -> Option<&PluginConfig> {
+    let op_name = state.try_borrow::<CallingOps>()?.current()?;
+    state
+        .try_borrow::<PluginConfigs>()
+        .and_then(|configs| configs.0.get(op_name))
+        .map(Arc::as_ref)
+}
+
+/// Secret values of the configurations of the plugin packages of a run, placed into the
+/// `OpState` so that they are redacted from what the run prints.
+#[derive(Default)]
+pub(crate) struct PluginSecrets(pub(crate) Vec<String>);
+
+impl PluginSecrets {
+    /// Replaces the secrets in a text with `REDACTED`.
+    pub(crate) fn redact(&self, text: &str) -> String {
+        let secrets: Vec<&str> = self.0.iter().map(String::as_str).collect();
+        redact_secrets(text, &secrets)
+    }
+}
+
+/// Replaces the given secrets in a text with `REDACTED`.
+///
+/// # Arguments
+/// * `text` - Text to redact
+/// * `secrets` - Secret values to hide
+pub(crate) fn redact_secrets(text: &str, secrets: &[&str]) -> String {
+    // Replace longer secrets first, so secrets that contain others are hidden entirely
+    let mut secrets: Vec<&str> = secrets
+        .iter()
+        .copied()
+        .filter(|secret| !secret.is_empty())
+        .collect();
+    secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    secrets.iter().fold(text.to_string(), |text, secret| {
+        text.replace(secret, REDACTED)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> PluginConfig {
+        let mut config = PluginConfig::new();
+        config.insert(
+            "endpoint".to_string(),
+            PluginConfigValue::String("https://api.example.com".to_string()),
+        );
+        config.insert("retries".to_string(), PluginConfigValue::Integer(3));
+        config.insert("verbose".to_string(), PluginConfigValue::Boolean(true));
+        config.insert(
+            "token".to_string(),
+            PluginConfigValue::Secret("s3cr3t".to_string()),
+        );
+        config
+    }
+
+    #[test]
+    fn test_plugin_config_get() {
+        let config = config();
+        assert_eq!(config.get_string("endpoint"), Ok("https://api.example.com"));
+        assert_eq!(config.get_integer("retries"), Ok(3));
+        assert_eq!(config.get_float("retries"), Ok(3.0));
+        assert_eq!(config.get_boolean("verbose"), Ok(true));
+        assert_eq!(config.get_secret("token"), Ok("s3cr3t"));
+        assert_eq!(
+            config.get_string("token"),
+            Err(PluginConfigError::InvalidType {
+                key: "token".to_string(),
+                expected: "string",
+                found: "secret",
+            })
+        );
+        assert_eq!(
+            config.get_secret("password").unwrap_err().to_string(),
+            "Plugin configuration password is not set"
+        );
+        assert_eq!(
+            config.keys().collect::<Vec<_>>(),
+            vec!["endpoint", "retries", "token", "verbose"]
+        );
+        assert_eq!(config.secrets().collect::<Vec<_>>(), vec!["s3cr3t"]);
+    }
+
+    #[test]
+    fn test_plugin_config_debug_redacts_secrets() {
+        let debug = format!("{:?}", config());
+        assert!(debug.contains(r#""endpoint": String("https://api.example.com")"#));
+        assert!(debug.contains(r#""token": Secret("[REDACTED]")"#));
+        assert!(!debug.contains("s3cr3t"));
+    }
+
+    #[test]
+    fn test_plugin_config_in_op_state() {
+        let mut state = OpState::new(None);
+        let calling_ops = CallingOps::default();
+        state.put(calling_ops.clone());
+        state.put(PluginConfigs(HashMap::from([
+            ("op_fetch".to_string(), Arc::new(config())),
+            ("op_other".to_string(), Arc::new(PluginConfig::new())),
+        ])));
+        assert!(plugin_config(&state).is_none());
+
+        calling_ops.record(
+            "op_fetch",
+            OpMetricsEvent::Dispatched,
+            OpMetricsSource::Slow,
+        );
+        assert_eq!(
+            plugin_config(&state).map(|config| config.get_integer("retries")),
+            Some(Ok(3))
+        );
+        calling_ops.record("op_fetch", OpMetricsEvent::Completed, OpMetricsSource::Slow);
+        assert!(plugin_config(&state).is_none());
+
+        calling_ops.record(
+            "op_other",
+            OpMetricsEvent::Dispatched,
+            OpMetricsSource::Fast,
+        );
+        assert_eq!(plugin_config(&state), Some(&PluginConfig::new()));
+        calling_ops.record("op_other", OpMetricsEvent::Error, OpMetricsSource::Fast);
+        assert!(plugin_config(&state).is_none());
+    }
+
+    #[test]
+    fn test_calling_ops_async() {
+        let calling_ops = CallingOps::default();
+        let record = |name, event| calling_ops.record(name, event, OpMetricsSource::Async);
+
+        // A pending async op is removed when another op is dispatched
+        record("op_a", OpMetricsEvent::Dispatched);
+        assert_eq!(calling_ops.current(), Some("op_a"));
+        calling_ops.record("op_b", OpMetricsEvent::Dispatched, OpMetricsSource::Slow);
+        assert_eq!(calling_ops.current(), Some("op_b"));
+        calling_ops.record("op_b", OpMetricsEvent::Completed, OpMetricsSource::Slow);
+        assert_eq!(calling_ops.current(), None);
+
+        // An async op dispatched by another op cannot read a configuration, and neither can the
+        // other op once the async op is pending, since they cannot be told apart
+        calling_ops.record("op_b", OpMetricsEvent::Dispatched, OpMetricsSource::Slow);
+        record("op_a", OpMetricsEvent::Dispatched);
+        assert_eq!(calling_ops.current(), None);
+        calling_ops.record("op_b", OpMetricsEvent::Completed, OpMetricsSource::Slow);
+        assert!(calling_ops.0.borrow().is_empty());
+
+        // An async op that completes eagerly is removed
+        record("op_a", OpMetricsEvent::Dispatched);
+        record("op_a", OpMetricsEvent::Completed);
+        assert!(calling_ops.0.borrow().is_empty());
+
+        record("op_a", OpMetricsEvent::Dispatched);
+        calling_ops.clear();
+        assert_eq!(calling_ops.current(), None);
+    }
+
+    #[test]
+    fn test_redact_secrets() {
+        assert_eq!(
+            redact_secrets("token=abc, key=abcdef, empty=", &["abc", "abcdef", ""]),
+            "token=[REDACTED], key=[REDACTED], empty="
+        );
+        assert_eq!(redact_secrets("nothing to hide", &[]), "nothing to hide");
+    }
+}
@@ -16,17 +16,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::config::PluginSecrets;
 use crate::runtime::{OpStateWorkflowData, WorkflowStdout};
 use deno_core::{OpState, op2};
 use std::io::{Write, stderr, stdout};
 use std::sync::{Arc, Mutex};
 
-#[op2(fast)]
-pub(crate) fn op_print_wrapper(
-    state: &mut OpState,
-    #[string] msg: &str,
-    is_err: bool,
-) -> Result<(), std::io::Error> {
+/// Prints a message to the output of the running workflow, or to stdout or stderr if the
+/// output is not captured. Secrets of the plugin packages are redacted.
+pub(crate) fn print(state: &mut OpState, msg: &str, is_err: bool) -> Result<(), std::io::Error> {
+    let msg = match state.try_borrow::<PluginSecrets>() {
+        Some(secrets) => secrets.redact(msg),
+        None => msg.to_string(),
+    };
+    let msg = msg.as_str();
     let mut data = state
         .borrow_mut::<Arc<Mutex<OpStateWorkflowData>>>()
         .lock()
@@ -49,3 +52,12 @@ pub(crate) fn op_print_wrapper(
 
     Ok(())
 }
+
+#[op2(fast)]
+pub(crate) fn op_print_wrapper(
+    state: &mut OpState,
+    #[string] msg: &str,
+    is_err: bool,
+) -> Result<(), std::io::Error> {
+    print(state, msg, is_err)
+}
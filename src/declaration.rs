@@ -0,0 +1,515 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! TypeScript declarations of plugin packages.
+//!
+//! The generated `.d.ts` is a global script that declares the `plugins` object and a
+//! `sapphillon:plugin/<package_id>` module for each package, matching the bindings created
+//! by the `bindings` module. Descriptions and required permissions become JSDoc comments.
+
+use crate::bindings::{PLUGIN_MODULE_PREFIX, PLUGINS_GLOBAL, binding_name};
+use crate::plugin::CorePluginPackage;
+use crate::proto::sapphillon::v1::{Permission, PluginPackage};
+use crate::schema::FunctionSchema;
+use std::collections::HashMap;
+
+/// Name of the interface that declares the type of the `plugins` object.
+pub const PLUGINS_INTERFACE: &str = "SapphillonPlugins";
+
+/// Parameter of a plugin function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionParameter {
+    /// Parameter name
+    pub name: String,
+    /// TypeScript type of the parameter, e.g. `string` or `{ path: string }`
+    pub type_name: String,
+    /// Description of the parameter
+    pub description: String,
+    /// Whether the parameter may be omitted
+    pub optional: bool,
+}
+
+impl FunctionParameter {
+    /// Creates a new required FunctionParameter.
+    ///
+    /// # Arguments
+    /// * `name` - Parameter name
+    /// * `type_name` - TypeScript type of the parameter
+    /// * `description` - Description of the parameter
+    pub fn new(name: String, type_name: String, description: String) -> Self {
+        Self {
+            name,
+            type_name,
+            description,
+            optional: false,
+        }
+    }
+}
+
+/// Parameter and return types of a plugin function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionSignature {
+    /// Parameters of the function, in order
+    pub parameters: Vec<FunctionParameter>,
+    /// TypeScript type of the return value. Async ops are wrapped in `Promise` automatically.
+    pub return_type: String,
+    /// Description of the return value
+    pub return_description: String,
+}
+
+impl FunctionSignature {
+    /// Creates a new FunctionSignature without a description of the return value.
+    ///
+    /// # Arguments
+    /// * `parameters` - Parameters of the function, in order
+    /// * `return_type` - TypeScript type of the return value
+    pub fn new(parameters: Vec<FunctionParameter>, return_type: String) -> Self {
+        Self {
+            parameters,
+            return_type,
+            return_description: String::new(),
+        }
+    }
+}
+
+/// Plugin package to declare, from either a `CorePluginPackage` or a proto `PluginPackage`.
+struct PackageDeclaration<'a> {
+    id: &'a str,
+    name: &'a str,
+    version: &'a str,
+    description: &'a str,
+    functions: Vec<FunctionDeclaration<'a>>,
+}
+
+struct FunctionDeclaration<'a> {
+    id: &'a str,
+    name: &'a str,
+    description: &'a str,
+    permissions: &'a [Permission],
+    /// Argument count of the op, known for `CorePluginFunction`s with an op
+    arg_count: Option<u8>,
+    /// Whether calling the function returns a promise
+    is_async: bool,
+    schema: Option<&'a FunctionSchema>,
+}
+
+/// Generates the TypeScript declarations of the given plugin packages.
+///
+/// Functions without a signature are declared from their `FunctionSchema` if they have one.
+/// Otherwise they take `unknown` arguments, as many as their op does, and return `unknown`,
+/// or `Promise<unknown>` if they are async, like async ops and functions served over gRPC.
+///
+/// # Arguments
+/// * `packages` - Plugin packages to declare
+/// * `signatures` - Signatures of the plugin functions, keyed by function ID
+pub fn declarations_from_plugin_packages(
+    packages: &[CorePluginPackage],
+    signatures: &HashMap<String, FunctionSignature>,
+) -> String {
+    let packages: Vec<PackageDeclaration> = packages
+        .iter()
+        .map(|pkg| PackageDeclaration {
+            id: &pkg.id,
+            name: &pkg.name,
+            version: &pkg.version,
+            description: "",
+            functions: pkg
+                .functions
+                .iter()
+                .map(|func| FunctionDeclaration {
+                    id: &func.id,
+                    name: &func.name,
+                    description: &func.description,
+                    permissions: &func.permissions,
+                    arg_count: func.op().map(|op| op.arg_count),
+                    is_async: func.is_async(),
+                    schema: func.schema.as_ref(),
+                })
+                .collect(),
+        })
+        .collect();
+    declarations(&packages, signatures)
+}
+
+/// Generates the TypeScript declarations of the given proto plugin packages.
+///
+/// Functions without a signature take any arguments and return `unknown`.
+///
+/// # Arguments
+/// * `packages` - PluginPackages defined in proto
+/// * `signatures` - Signatures of the plugin functions, keyed by function ID
+pub fn declarations_from_proto(
+    packages: &[PluginPackage],
+    signatures: &HashMap<String, FunctionSignature>,
+) -> String {
+    let packages: Vec<PackageDeclaration> = packages
+        .iter()
+        .map(|pkg| PackageDeclaration {
+            id: &pkg.package_id,
+            name: &pkg.package_name,
+            version: &pkg.package_version,
+            description: &pkg.description,
+            functions: pkg
+                .functions
+                .iter()
+                .map(|func| FunctionDeclaration {
+                    id: &func.function_id,
+                    name: &func.function_name,
+                    description: &func.description,
+                    permissions: &func.permissions,
+                    arg_count: None,
+                    is_async: false,
+                    schema: None,
+                })
+                .collect(),
+        })
+        .collect();
+    declarations(&packages, signatures)
+}
+
+const RESERVED_WORDS: [&str; 42] = [
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "instanceof",
+    "interface",
+    "let",
+    "new",
+    "null",
+    "package",
+    "return",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+];
+
+/// Returns true if the name is an identifier name, which may be a reserved word.
+fn is_identifier_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+}
+
+/// Returns true if the name can be used as a JavaScript binding name.
+fn is_identifier(name: &str) -> bool {
+    is_identifier_name(name) && !RESERVED_WORDS.contains(&name)
+}
+
+/// Returns the name as a property key, quoted if it is not an identifier name.
+fn property_key(name: &str) -> String {
+    if is_identifier_name(name) {
+        name.to_string()
+    } else {
+        // JSON strings are valid TypeScript string literals
+        serde_json::to_string(name).unwrap()
+    }
+}
+
+/// Writes a JSDoc comment with the given paragraphs and tags.
+fn write_jsdoc(out: &mut String, indent: &str, paragraphs: &[String], tags: &[String]) {
+    let mut lines: Vec<String> = Vec::new();
+    for paragraph in paragraphs.iter().filter(|p| !p.trim().is_empty()) {
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.extend(paragraph.trim().lines().map(str::to_string));
+    }
+    if !tags.is_empty() && !lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines.extend(tags.iter().cloned());
+    if lines.is_empty() {
+        return;
+    }
+    out.push_str(&format!("{indent}/**\n"));
+    for line in lines {
+        let line = line.replace("*/", "*\\/");
+        match line.is_empty() {
+            true => out.push_str(&format!("{indent} *\n")),
+            false => out.push_str(&format!("{indent} * {}\n", line.trim_end())),
+        }
+    }
+    out.push_str(&format!("{indent} */\n"));
+}
+
+fn permission_line(permission: &Permission) -> String {
+    let resources = match permission.resource.is_empty() {
+        true => "*".to_string(),
+        false => permission.resource.join(", "),
+    };
+    format!(
+        "Requires {} ({}): {resources}",
+        permission.permission_type().as_str_name(),
+        permission.permission_level().as_str_name()
+    )
+}
+
+/// Writes the method signature of a plugin function, with its JSDoc comment.
+fn write_function(
+    out: &mut String,
+    package_id: &str,
+    func: &FunctionDeclaration,
+    signature: Option<&FunctionSignature>,
+) {
+    let permissions: Vec<String> = func.permissions.iter().map(permission_line).collect();
+    let paragraphs = [
+        func.name.to_string(),
+        func.description.to_string(),
+        permissions.join("\n"),
+    ];
+    let mut tags = Vec::new();
+    let (parameters, return_type) = match signature {
+        Some(signature) => {
+            for p in &signature.parameters {
+                if !p.description.is_empty() {
+                    tags.push(format!("@param {} {}", p.name, p.description));
+                }
+            }
+            if !signature.return_description.is_empty() {
+                tags.push(format!("@returns {}", signature.return_description));
+            }
+            let parameters: Vec<String> = signature
+                .parameters
+                .iter()
+                .map(|p| {
+                    let optional = if p.optional { "?" } else { "" };
+                    format!("{}{optional}: {}", p.name, p.type_name)
+                })
+                .collect();
+            (parameters.join(", "), signature.return_type.clone())
+        }
+        None => match func.arg_count {
+            Some(arg_count) => (
+                (0..arg_count)
+                    .map(|i| format!("arg{i}: unknown"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                "unknown".to_string(),
+            ),
+            None => ("...args: unknown[]".to_string(), "unknown".to_string()),
+        },
+    };
+    let return_type = match func.is_async {
+        true if !return_type.starts_with("Promise<") => {
+            format!("Promise<{return_type}>")
+        }
+        _ => return_type,
+    };
+
+    write_jsdoc(out, "    ", &paragraphs, &tags);
+    out.push_str(&format!(
+        "    {}({parameters}): {return_type};\n",
+        property_key(binding_name(package_id, func.id))
+    ));
+}
+
+fn declarations(
+    packages: &[PackageDeclaration],
+    signatures: &HashMap<String, FunctionSignature>,
+) -> String {
+    let mut out = String::from(
+        "// Type declarations of Sapphillon plugin packages, generated by Sapphillon-Core.\n\n",
+    );
+
+    out.push_str(&format!("interface {PLUGINS_INTERFACE} {{\n"));
+    for pkg in packages {
+        let title = match pkg.version.is_empty() {
+            true => pkg.name.to_string(),
+            false => format!("{} {}", pkg.name, pkg.version),
+        };
+        write_jsdoc(&mut out, "  ", &[title, pkg.description.to_string()], &[]);
+        out.push_str(&format!("  readonly {}: {{\n", property_key(pkg.id)));
+        for func in &pkg.functions {
+            let signature = signatures
+                .get(func.id)
+                .cloned()
+                .or_else(|| func.schema.map(FunctionSchema::signature));
+            write_function(&mut out, pkg.id, func, signature.as_ref());
+        }
+        out.push_str("  };\n");
+    }
+    out.push_str("}\n\n");
+    out.push_str(&format!(
+        "declare const {PLUGINS_GLOBAL}: {PLUGINS_INTERFACE};\n"
+    ));
+
+    for pkg in packages {
+        out.push_str(&format!(
+            "\ndeclare module {} {{\n",
+            serde_json::to_string(&format!("{PLUGIN_MODULE_PREFIX}{}", pkg.id)).unwrap()
+        ));
+        out.push_str(&format!(
+            "  const plugin: {PLUGINS_INTERFACE}[{}];\n",
+            serde_json::to_string(pkg.id).unwrap()
+        ));
+        out.push_str("  export default plugin;\n");
+        for func in &pkg.functions {
+            let name = binding_name(pkg.id, func.id);
+            if is_identifier(name) {
+                out.push_str(&format!("  export const {name}: typeof plugin.{name};\n"));
+            }
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::CorePluginFunction;
+    use crate::proto::sapphillon::v1::{PermissionLevel, PermissionType, PluginFunction};
+    use deno_core::op2;
+
+    #[op2(async)]
+    async fn op_send(#[string] _title: String) {}
+
+    #[op2(fast)]
+    fn op_count(_a: u32, _b: u32) -> u32 {
+        0
+    }
+
+    #[test]
+    fn test_declarations_from_plugin_packages() {
+        let pkg = CorePluginPackage::new(
+            "com.example.notifications".to_string(),
+            "Notifications".to_string(),
+            vec![
+                CorePluginFunction::new(
+                    "com.example.notifications.send".to_string(),
+                    "Send".to_string(),
+                    "Sends a notification.\nDelivery is not guaranteed.".to_string(),
+                    op_send(),
+                ),
+                CorePluginFunction::new(
+                    "count-unread".to_string(),
+                    "Count".to_string(),
+                    "".to_string(),
+                    op_count(),
+                ),
+            ],
+        );
+        let signatures = HashMap::from([(
+            "com.example.notifications.send".to_string(),
+            FunctionSignature {
+                parameters: vec![FunctionParameter::new(
+                    "title".to_string(),
+                    "string".to_string(),
+                    "Title of the notification".to_string(),
+                )],
+                return_type: "void".to_string(),
+                return_description: "".to_string(),
+            },
+        )]);
+
+        let d_ts = declarations_from_plugin_packages(&[pkg], &signatures);
+        let expected = r#"interface SapphillonPlugins {
+  /**
+   * Notifications
+   */
+  readonly "com.example.notifications": {
+    /**
+     * Send
+     *
+     * Sends a notification.
+     * Delivery is not guaranteed.
+     *
+     * @param title Title of the notification
+     */
+    send(title: string): Promise<void>;
+    /**
+     * Count
+     */
+    "count-unread"(arg0: unknown, arg1: unknown): unknown;
+  };
+}
+
+declare const plugins: SapphillonPlugins;
+
+declare module "sapphillon:plugin/com.example.notifications" {
+  const plugin: SapphillonPlugins["com.example.notifications"];
+  export default plugin;
+  export const send: typeof plugin.send;
+}
+"#;
+        assert!(d_ts.ends_with(expected), "{d_ts}");
+    }
+
+    #[test]
+    fn test_declarations_from_proto() {
+        let pkg = PluginPackage {
+            package_id: "fs".to_string(),
+            package_name: "File System".to_string(),
+            package_version: "1.2.0".to_string(),
+            description: "Files */ and directories".to_string(),
+            functions: vec![PluginFunction {
+                function_id: "delete".to_string(),
+                function_name: "Delete".to_string(),
+                description: "".to_string(),
+                permissions: vec![Permission {
+                    permission_type: PermissionType::Write as i32,
+                    permission_level: PermissionLevel::High as i32,
+                    resource: vec!["/tmp".to_string(), "/var/tmp".to_string()],
+                    ..Default::default()
+                }],
+            }],
+            ..Default::default()
+        };
+
+        let d_ts = declarations_from_proto(&[pkg], &HashMap::new());
+        assert!(d_ts.contains("   * File System 1.2.0\n   *\n   * Files *\\/ and directories\n"));
+        assert!(d_ts.contains(
+            "     * Requires PERMISSION_TYPE_WRITE (PERMISSION_LEVEL_HIGH): /tmp, /var/tmp\n"
+        ));
+        assert!(d_ts.contains("  readonly fs: {\n"));
+        assert!(d_ts.contains("    delete(...args: unknown[]): unknown;\n"));
+        // Reserved words are only exported through the default export
+        assert!(!d_ts.contains("export const delete"));
+    }
+}
@@ -0,0 +1,686 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Plugin packages served by a separate process over gRPC.
+//!
+//! The process implements `sapphillon.plugin.v1.PluginService` for a single package. The
+//! runtime either connects to a process that is already running or spawns it, asks it for its
+//! package once, and proxies every call of one of its functions as an async op:
+//! - the arguments are sent as a JSON array or a `ListValue`, and the promise returned to the
+//!   workflow resolves to the return value of the function;
+//! - the log entries of the invocation are streamed back and printed to the output of the workflow;
+//! - an error of the function, or a crash of the process, rejects the promise of that call only.
+//!
+//! A spawned process that exits is spawned again by the next call, and killed once the last
+//! package that uses it is dropped.
+
+use crate::proto::sapphillon::plugin::v1::plugin_service_client::PluginServiceClient;
+use crate::proto::sapphillon::plugin::v1::{
+    DescribePackageRequest, InvokeFunctionRequest, InvokeFunctionResponse, LogEntry, LogLevel,
+    StreamLogsRequest, invoke_function_request, invoke_function_response,
+};
+use crate::proto::sapphillon::v1::PluginPackage;
+use crate::runtime::{OpStateWorkflowData, WorkflowStdout};
+use deno_core::{OpState, op2};
+use deno_error::JsErrorBox;
+use prost_types::value::Kind;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Write, stderr, stdout};
+use std::process::{Child, Command, Stdio};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
+
+/// Environment variable that tells a spawned plugin process the address to listen on.
+pub const PLUGIN_ADDRESS_ENV: &str = "SAPPHILLON_PLUGIN_ADDRESS";
+
+/// Time a spawned process is given to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval between attempts to connect to a process that is starting.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time the log entries of an invocation are awaited for once it has completed.
+const LOG_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sequence number of the last invocation, unique within the host process.
+static INVOCATION_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// How the runtime reaches the process that serves a gRPC plugin package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GrpcPluginEndpoint {
+    /// Process that is already running, by URI, e.g. `http://127.0.0.1:50051`
+    Connect(String),
+    /// Program the runtime spawns. The address to listen on is passed in `PLUGIN_ADDRESS_ENV`.
+    Spawn {
+        /// Path of the program
+        program: String,
+        /// Arguments of the program
+        args: Vec<String>,
+        /// Address the program listens on, e.g. `127.0.0.1:50051`
+        address: String,
+    },
+}
+
+impl GrpcPluginEndpoint {
+    /// Returns the URI the runtime connects to.
+    pub fn uri(&self) -> String {
+        match self {
+            GrpcPluginEndpoint::Connect(uri) => uri.clone(),
+            GrpcPluginEndpoint::Spawn { address, .. } => format!("http://{address}"),
+        }
+    }
+}
+
+/// Encoding of the arguments sent to a gRPC plugin process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GrpcArgumentEncoding {
+    /// JSON array in `json_arguments`
+    #[default]
+    Json,
+    /// `ListValue` in `proto_arguments`
+    Proto,
+}
+
+/// Error returned when a gRPC plugin process cannot serve a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GrpcPluginError {
+    /// The program of the process could not be spawned.
+    Spawn { program: String, message: String },
+    /// The process could not be reached.
+    Connect { uri: String, message: String },
+    /// The process returned an error, or the connection broke during the call.
+    Status { code: tonic::Code, message: String },
+    /// The process returned a response that does not follow the contract.
+    InvalidResponse(String),
+}
+
+impl fmt::Display for GrpcPluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrpcPluginError::Spawn { program, message } => {
+                write!(f, "Failed to spawn plugin process {program}: {message}")
+            }
+            GrpcPluginError::Connect { uri, message } => {
+                write!(f, "Failed to connect to plugin process at {uri}: {message}")
+            }
+            GrpcPluginError::Status { code, message } => write!(f, "{code:?}: {message}"),
+            GrpcPluginError::InvalidResponse(message) => {
+                write!(f, "Invalid response from plugin process: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GrpcPluginError {}
+
+impl From<tonic::Status> for GrpcPluginError {
+    fn from(status: tonic::Status) -> Self {
+        GrpcPluginError::Status {
+            code: status.code(),
+            message: status.message().to_string(),
+        }
+    }
+}
+
+/// Returns the message of an error followed by the messages of its sources.
+fn error_message(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        message.push_str(&format!(": {e}"));
+        source = e.source();
+    }
+    message
+}
+
+/// Process that serves a gRPC plugin package, shared by the functions of the package.
+#[derive(Debug)]
+pub struct GrpcPluginProcess {
+    endpoint: GrpcPluginEndpoint,
+    encoding: GrpcArgumentEncoding,
+    /// Spawned process and the time it was spawned at
+    child: Mutex<Option<(Child, Instant)>>,
+}
+
+impl PartialEq for GrpcPluginProcess {
+    fn eq(&self, other: &Self) -> bool {
+        self.endpoint == other.endpoint && self.encoding == other.encoding
+    }
+}
+
+impl Eq for GrpcPluginProcess {}
+
+impl Drop for GrpcPluginProcess {
+    fn drop(&mut self) {
+        if let Ok(child) = self.child.get_mut()
+            && let Some((child, _)) = child
+        {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl GrpcPluginProcess {
+    /// Creates a new GrpcPluginProcess that sends arguments as JSON.
+    /// A process is not spawned until it is first needed.
+    ///
+    /// # Arguments
+    /// * `endpoint` - How the process is reached
+    pub fn new(endpoint: GrpcPluginEndpoint) -> Self {
+        Self::new_with_encoding(endpoint, GrpcArgumentEncoding::default())
+    }
+
+    /// Creates a new GrpcPluginProcess that sends arguments in the given encoding.
+    ///
+    /// # Arguments
+    /// * `endpoint` - How the process is reached
+    /// * `encoding` - Encoding of the arguments
+    pub fn new_with_encoding(endpoint: GrpcPluginEndpoint, encoding: GrpcArgumentEncoding) -> Self {
+        Self {
+            endpoint,
+            encoding,
+            child: Mutex::new(None),
+        }
+    }
+
+    /// Returns how the process is reached.
+    pub fn endpoint(&self) -> &GrpcPluginEndpoint {
+        &self.endpoint
+    }
+
+    /// Returns the encoding of the arguments sent to the process.
+    pub fn encoding(&self) -> GrpcArgumentEncoding {
+        self.encoding
+    }
+
+    /// Spawns the program of the endpoint unless it is running, and returns the time the
+    /// running process was spawned at, or `None` if the endpoint is not spawned.
+    fn ensure_running(&self) -> Result<Option<Instant>, GrpcPluginError> {
+        let GrpcPluginEndpoint::Spawn {
+            program,
+            args,
+            address,
+        } = &self.endpoint
+        else {
+            return Ok(None);
+        };
+        let mut child = self.child.lock().unwrap();
+        if let Some((running, spawned_at)) = child.as_mut()
+            && matches!(running.try_wait(), Ok(None))
+        {
+            return Ok(Some(*spawned_at));
+        }
+        let spawned = Command::new(program)
+            .args(args)
+            .env(PLUGIN_ADDRESS_ENV, address)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| GrpcPluginError::Spawn {
+                program: program.clone(),
+                message: e.to_string(),
+            })?;
+        if let Some((previous, _)) = child.as_mut() {
+            log::warn!("Plugin process {program} exited, spawning it again");
+            let _ = previous.wait();
+        }
+        let spawned_at = Instant::now();
+        *child = Some((spawned, spawned_at));
+        Ok(Some(spawned_at))
+    }
+
+    /// Returns true if the spawned process has not exited.
+    fn is_running(&self) -> bool {
+        self.child
+            .lock()
+            .unwrap()
+            .as_mut()
+            .is_some_and(|(child, _)| matches!(child.try_wait(), Ok(None)))
+    }
+
+    /// Connects to the process, spawning it first if it is not running.
+    async fn connect(&self) -> Result<PluginServiceClient<Channel>, GrpcPluginError> {
+        let spawned_at = self.ensure_running()?;
+        let uri = self.endpoint.uri();
+        loop {
+            match PluginServiceClient::connect(uri.clone()).await {
+                Ok(client) => return Ok(client),
+                // A process that was just spawned may not be listening yet
+                Err(_)
+                    if spawned_at.is_some_and(|at| at.elapsed() < STARTUP_TIMEOUT)
+                        && self.is_running() =>
+                {
+                    tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+                }
+                Err(e) => {
+                    return Err(GrpcPluginError::Connect {
+                        uri,
+                        message: error_message(&e),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Asks the process for the package it serves.
+    ///
+    /// # Errors
+    /// Returns a `GrpcPluginError` if the process cannot be reached, returns an error, or does
+    /// not return a package.
+    pub async fn describe_package(&self) -> Result<PluginPackage, GrpcPluginError> {
+        let mut client = self.connect().await?;
+        client
+            .describe_package(DescribePackageRequest {})
+            .await?
+            .into_inner()
+            .plugin_package
+            .ok_or_else(|| {
+                GrpcPluginError::InvalidResponse("plugin_package is not set".to_string())
+            })
+    }
+
+    /// Invokes a function of the package and collects the log entries of the invocation.
+    ///
+    /// Log entries are returned whether the invocation succeeds or not.
+    ///
+    /// # Arguments
+    /// * `function_id` - ID of the function
+    /// * `invocation_id` - Unique ID of the invocation
+    /// * `workflow_id` - ID of the workflow that invokes the function
+    /// * `args` - Arguments of the function
+    pub async fn invoke_function(
+        &self,
+        function_id: &str,
+        invocation_id: &str,
+        workflow_id: &str,
+        args: &[serde_json::Value],
+    ) -> (Result<serde_json::Value, GrpcPluginError>, Vec<LogEntry>) {
+        let mut client = match self.connect().await {
+            Ok(client) => client,
+            Err(e) => return (Err(e), Vec::new()),
+        };
+
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let mut collector = tokio::spawn(collect_logs(
+            client.clone(),
+            invocation_id.to_string(),
+            logs.clone(),
+        ));
+
+        let arguments = match self.encoding {
+            GrpcArgumentEncoding::Json => invoke_function_request::Arguments::JsonArguments(
+                serde_json::Value::Array(args.to_vec()).to_string(),
+            ),
+            GrpcArgumentEncoding::Proto => {
+                invoke_function_request::Arguments::ProtoArguments(prost_types::ListValue {
+                    values: args.iter().map(value_to_proto).collect(),
+                })
+            }
+        };
+        let request = InvokeFunctionRequest {
+            function_id: function_id.to_string(),
+            invocation_id: invocation_id.to_string(),
+            workflow_id: workflow_id.to_string(),
+            arguments: Some(arguments),
+        };
+        let result = match client.invoke_function(request).await {
+            Ok(response) => return_value(response.into_inner()),
+            Err(status) => Err(status.into()),
+        };
+
+        if tokio::time::timeout(LOG_TIMEOUT, &mut collector)
+            .await
+            .is_err()
+        {
+            collector.abort();
+        }
+        let logs = std::mem::take(&mut *logs.lock().unwrap());
+        (result, logs)
+    }
+}
+
+/// Streams the log entries of an invocation into `logs` until the stream ends.
+async fn collect_logs(
+    mut client: PluginServiceClient<Channel>,
+    invocation_id: String,
+    logs: Arc<Mutex<Vec<LogEntry>>>,
+) {
+    let Ok(response) = client
+        .stream_logs(StreamLogsRequest { invocation_id })
+        .await
+    else {
+        return;
+    };
+    let mut stream = response.into_inner();
+    while let Ok(Some(entry)) = stream.message().await {
+        logs.lock().unwrap().push(entry);
+    }
+}
+
+/// Returns the return value in a response of `InvokeFunction`.
+fn return_value(response: InvokeFunctionResponse) -> Result<serde_json::Value, GrpcPluginError> {
+    match response.result {
+        Some(invoke_function_response::Result::JsonResult(json)) => serde_json::from_str(&json)
+            .map_err(|e| {
+                GrpcPluginError::InvalidResponse(format!("json_result is not valid JSON: {e}"))
+            }),
+        Some(invoke_function_response::Result::ProtoResult(value)) => Ok(value_from_proto(&value)),
+        None => Ok(serde_json::Value::Null),
+    }
+}
+
+/// Converts a JSON value to a protobuf `Value`.
+///
+/// # Arguments
+/// * `value` - JSON value
+pub fn value_to_proto(value: &serde_json::Value) -> prost_types::Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(prost_types::NullValue::NullValue as i32),
+        serde_json::Value::Bool(b) => Kind::BoolValue(*b),
+        serde_json::Value::Number(n) => Kind::NumberValue(n.as_f64().unwrap_or_default()),
+        serde_json::Value::String(s) => Kind::StringValue(s.clone()),
+        serde_json::Value::Array(values) => Kind::ListValue(prost_types::ListValue {
+            values: values.iter().map(value_to_proto).collect(),
+        }),
+        serde_json::Value::Object(fields) => Kind::StructValue(prost_types::Struct {
+            fields: fields
+                .iter()
+                .map(|(key, value)| (key.clone(), value_to_proto(value)))
+                .collect(),
+        }),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+/// Converts a protobuf `Value` to a JSON value.
+///
+/// Whole numbers that JavaScript represents exactly become integers. Numbers that are not
+/// finite and values without a kind become `null`.
+///
+/// # Arguments
+/// * `value` - Protobuf value
+pub fn value_from_proto(value: &prost_types::Value) -> serde_json::Value {
+    const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+    match &value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::BoolValue(b)) => serde_json::Value::Bool(*b),
+        Some(Kind::NumberValue(n)) if n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER => {
+            serde_json::Value::from(*n as i64)
+        }
+        Some(Kind::NumberValue(n)) => serde_json::Number::from_f64(*n)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null),
+        Some(Kind::StringValue(s)) => serde_json::Value::String(s.clone()),
+        Some(Kind::ListValue(list)) => {
+            serde_json::Value::Array(list.values.iter().map(value_from_proto).collect())
+        }
+        Some(Kind::StructValue(fields)) => serde_json::Value::Object(
+            fields
+                .fields
+                .iter()
+                .map(|(key, value)| (key.clone(), value_from_proto(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// gRPC plugin processes of the functions of a run, placed into the `OpState` and keyed by
+/// function ID.
+#[derive(Default)]
+pub(crate) struct GrpcPluginProcesses(pub(crate) HashMap<String, Arc<GrpcPluginProcess>>);
+
+/// Prints a log entry of a plugin function to the output of the workflow, like `console.log`.
+fn print_log_entry(data: &mut OpStateWorkflowData, function_id: &str, entry: &LogEntry) {
+    let level = match entry.level() {
+        LogLevel::Unspecified => LogLevel::Info,
+        level => level,
+    };
+    let line = format!(
+        "[{function_id}] {} {}\n",
+        level.as_str_name().trim_start_matches("LOG_LEVEL_"),
+        entry.message
+    );
+    if data.is_capture_stdout() {
+        data.add_result(WorkflowStdout::Stdout(line));
+    } else if level == LogLevel::Error {
+        let _ = stderr().write_all(line.as_bytes());
+    } else {
+        let _ = stdout().write_all(line.as_bytes());
+    }
+}
+
+/// Invokes the function `function_id` of its gRPC plugin process, printing the log entries of
+/// the invocation to the output of the workflow.
+#[op2(async)]
+#[serde]
+pub(crate) async fn op_grpc_plugin_invoke(
+    state: Rc<RefCell<OpState>>,
+    #[string] function_id: String,
+    #[serde] args: Vec<serde_json::Value>,
+) -> Result<serde_json::Value, JsErrorBox> {
+    let (process, data) = {
+        let state = state.borrow();
+        let process = state
+            .try_borrow::<GrpcPluginProcesses>()
+            .and_then(|processes| processes.0.get(&function_id))
+            .cloned()
+            .ok_or_else(|| {
+                JsErrorBox::generic(format!(
+                    "Plugin function {function_id} is not served by a plugin process"
+                ))
+            })?;
+        let data = state.borrow::<Arc<Mutex<OpStateWorkflowData>>>().clone();
+        (process, data)
+    };
+    let workflow_id = data.lock().unwrap().get_workflow_id().to_string();
+    let invocation_id = format!(
+        "{workflow_id}-{}",
+        INVOCATION_SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1
+    );
+
+    let (result, logs) = process
+        .invoke_function(&function_id, &invocation_id, &workflow_id, &args)
+        .await;
+    {
+        let mut data = data.lock().unwrap();
+        for entry in &logs {
+            print_log_entry(&mut data, &function_id, entry);
+        }
+    }
+    result.map_err(|e| JsErrorBox::generic(format!("Plugin function {function_id} failed: {e}")))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::proto::sapphillon::plugin::v1::DescribePackageResponse;
+    use crate::proto::sapphillon::plugin::v1::plugin_service_server::{
+        PluginService, PluginServiceServer,
+    };
+    use crate::proto::sapphillon::v1::PluginFunction;
+    use serde_json::json;
+    use tokio::sync::oneshot;
+    use tonic::codegen::tokio_stream;
+    use tonic::{Request, Response, Status};
+
+    /// Plugin process that serves the `stub` package in tests:
+    /// - `stub.echo` returns its arguments and logs how many it got;
+    /// - `stub.fail` returns an error.
+    pub(crate) struct StubPlugin;
+
+    #[tonic::async_trait]
+    impl PluginService for StubPlugin {
+        async fn describe_package(
+            &self,
+            _request: Request<DescribePackageRequest>,
+        ) -> Result<Response<DescribePackageResponse>, Status> {
+            let function = |id: &str| PluginFunction {
+                function_id: id.to_string(),
+                function_name: id.to_string(),
+                description: String::new(),
+                permissions: vec![],
+            };
+            Ok(Response::new(DescribePackageResponse {
+                plugin_package: Some(PluginPackage {
+                    package_id: "stub".to_string(),
+                    package_name: "Stub".to_string(),
+                    package_version: "1.0.0".to_string(),
+                    functions: vec![function("stub.echo"), function("stub.fail")],
+                    ..Default::default()
+                }),
+            }))
+        }
+
+        async fn invoke_function(
+            &self,
+            request: Request<InvokeFunctionRequest>,
+        ) -> Result<Response<InvokeFunctionResponse>, Status> {
+            let request = request.into_inner();
+            let args = match request.arguments {
+                Some(invoke_function_request::Arguments::JsonArguments(json)) => {
+                    serde_json::from_str(&json)
+                        .map_err(|e| Status::invalid_argument(e.to_string()))?
+                }
+                Some(invoke_function_request::Arguments::ProtoArguments(list)) => {
+                    serde_json::Value::Array(list.values.iter().map(value_from_proto).collect())
+                }
+                None => json!([]),
+            };
+            match request.function_id.as_str() {
+                "stub.echo" => Ok(Response::new(InvokeFunctionResponse {
+                    result: Some(invoke_function_response::Result::ProtoResult(
+                        value_to_proto(&args),
+                    )),
+                })),
+                "stub.fail" => Err(Status::internal("stub failure")),
+                id => Err(Status::not_found(format!("{id} not found"))),
+            }
+        }
+
+        type StreamLogsStream = tokio_stream::Iter<std::vec::IntoIter<Result<LogEntry, Status>>>;
+
+        async fn stream_logs(
+            &self,
+            request: Request<StreamLogsRequest>,
+        ) -> Result<Response<Self::StreamLogsStream>, Status> {
+            let entry = LogEntry {
+                invocation_id: request.into_inner().invocation_id,
+                level: LogLevel::Info as i32,
+                message: "invoked".to_string(),
+                logged_at: None,
+            };
+            Ok(Response::new(tokio_stream::iter(vec![Ok(entry)])))
+        }
+    }
+
+    /// Serves `StubPlugin` on the listener until the returned sender is used or dropped.
+    pub(crate) fn serve_stub(listener: tokio::net::TcpListener) -> oneshot::Sender<()> {
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(PluginServiceServer::new(StubPlugin))
+                .serve_with_incoming_shutdown(
+                    tokio_stream::wrappers::TcpListenerStream::new(listener),
+                    async {
+                        let _ = stopped.await;
+                    },
+                ),
+        );
+        shutdown
+    }
+
+    #[test]
+    fn test_value_conversion() {
+        let value = json!({"a": [1, 2.5, "x", null, true], "b": {}});
+        assert_eq!(value_from_proto(&value_to_proto(&value)), value);
+        assert_eq!(
+            value_from_proto(&prost_types::Value {
+                kind: Some(Kind::NumberValue(f64::NAN))
+            }),
+            serde_json::Value::Null
+        );
+    }
+
+    #[tokio::test]
+    async fn test_grpc_plugin_process() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = serve_stub(listener);
+
+        for encoding in [GrpcArgumentEncoding::Json, GrpcArgumentEncoding::Proto] {
+            let process = GrpcPluginProcess::new_with_encoding(
+                GrpcPluginEndpoint::Connect(format!("http://{address}")),
+                encoding,
+            );
+            let package = process.describe_package().await.unwrap();
+            assert_eq!(package.package_id, "stub");
+            assert_eq!(package.functions.len(), 2);
+
+            let (result, logs) = process
+                .invoke_function("stub.echo", "i1", "wid", &[json!(1), json!({"a": "b"})])
+                .await;
+            assert_eq!(result, Ok(json!([1, {"a": "b"}])));
+            assert_eq!(logs.len(), 1);
+            assert_eq!(logs[0].invocation_id, "i1");
+
+            let (result, _) = process.invoke_function("stub.fail", "i2", "wid", &[]).await;
+            assert_eq!(
+                result,
+                Err(GrpcPluginError::Status {
+                    code: tonic::Code::Internal,
+                    message: "stub failure".to_string()
+                })
+            );
+        }
+
+        // Only the calls made while the process is down fail
+        let process =
+            GrpcPluginProcess::new(GrpcPluginEndpoint::Connect(format!("http://{address}")));
+        shutdown.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (result, logs) = process.invoke_function("stub.echo", "i3", "wid", &[]).await;
+        assert!(matches!(result, Err(GrpcPluginError::Connect { .. })));
+        assert!(logs.is_empty());
+
+        let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+        let _shutdown = serve_stub(listener);
+        let (result, _) = process
+            .invoke_function("stub.echo", "i4", "wid", &[json!("x")])
+            .await;
+        assert_eq!(result, Ok(json!(["x"])));
+    }
+
+    #[tokio::test]
+    async fn test_grpc_plugin_process_spawn_error() {
+        let process = GrpcPluginProcess::new(GrpcPluginEndpoint::Spawn {
+            program: "/nonexistent/sapphillon-plugin".to_string(),
+            args: vec![],
+            address: "127.0.0.1:1".to_string(),
+        });
+        assert!(matches!(
+            process.describe_package().await,
+            Err(GrpcPluginError::Spawn { .. })
+        ));
+    }
+}
@@ -18,10 +18,28 @@
 
 #![cfg(not(doctest))]
 
+pub mod analysis;
+pub mod audit;
+pub mod bindings;
+pub mod cel;
+pub mod config;
 pub mod core;
+pub mod declaration;
+pub mod grpc;
+pub mod lifecycle;
+pub mod native;
+pub mod permission;
 pub mod plugin;
+pub mod policy;
 pub mod proto;
+pub mod registry;
+pub mod revision;
 pub mod runtime;
+pub mod schema;
+pub mod signature;
+pub mod transpile;
+pub mod validation;
+pub mod wasm;
 pub mod workflow;
 
 pub fn add(left: u64, right: u64) -> u64 {
@@ -0,0 +1,393 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Lifecycle hooks of plugin packages.
+//!
+//! A package with a `CorePluginLifecycle` is initialized with its `PluginConfig` before the
+//! first run that uses it, and is set up and torn down around every run:
+//! 1. `initialize` is called once, and again on the next run if it failed.
+//! 2. `setup` is called after the `OpState` of the run is prepared and before any JavaScript is
+//!    evaluated, so it can put package-scoped state, such as a connection pool, into the
+//!    `OpState` for the ops of the package.
+//! 3. `teardown` is called after the run with the result of the run, even if it failed, timed
+//!    out, ran out of memory or was cancelled.
+//!
+//! Packages are set up in order and torn down in reverse order. If initializing or setting up a
+//! package fails, the run fails without evaluating the workflow, and the packages that were
+//! already set up are torn down.
+
+use crate::config::PluginConfig;
+use crate::runtime::WorkflowRunError;
+use deno_core::OpState;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Error returned by the lifecycle hooks of a plugin package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginLifecycleError {
+    /// Description of the error
+    pub message: String,
+}
+
+impl PluginLifecycleError {
+    /// Creates a new PluginLifecycleError.
+    ///
+    /// # Arguments
+    /// * `message` - Description of the error
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for PluginLifecycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for PluginLifecycleError {}
+
+/// Stage of the lifecycle of a plugin package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PluginLifecycleStage {
+    /// `PluginLifecycle::initialize`
+    Initialize,
+    /// `PluginLifecycle::setup`
+    Setup,
+}
+
+impl fmt::Display for PluginLifecycleStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginLifecycleStage::Initialize => write!(f, "initialize"),
+            PluginLifecycleStage::Setup => write!(f, "set up"),
+        }
+    }
+}
+
+/// Host-provided lifecycle hooks of a plugin package.
+///
+/// The hooks are called on the workflow thread. All of them have default implementations that
+/// do nothing, so a package only implements the ones it needs.
+pub trait PluginLifecycle: Send + Sync {
+    /// Initializes the package before the first run that uses it.
+    ///
+    /// # Arguments
+    /// * `config` - Configuration of the package
+    ///
+    /// # Errors
+    /// Returns a `PluginLifecycleError` if the package cannot be initialized. The run fails, and
+    /// `initialize` is called again on the next run.
+    fn initialize(&self, config: &PluginConfig) -> Result<(), PluginLifecycleError> {
+        let _ = config;
+        Ok(())
+    }
+
+    /// Prepares a run, e.g. by putting package-scoped state into the `OpState`.
+    ///
+    /// # Arguments
+    /// * `state` - `OpState` of the run
+    /// * `config` - Configuration of the package
+    ///
+    /// # Errors
+    /// Returns a `PluginLifecycleError` if the run cannot be prepared. The run fails, and
+    /// `teardown` is not called for this package.
+    fn setup(
+        &self,
+        state: &mut OpState,
+        config: &PluginConfig,
+    ) -> Result<(), PluginLifecycleError> {
+        let _ = (state, config);
+        Ok(())
+    }
+
+    /// Cleans up after a run, e.g. by taking the state put by `setup` out of the `OpState`.
+    ///
+    /// # Arguments
+    /// * `state` - `OpState` of the run
+    /// * `result` - Result of the run, or the error of the package set up after this one
+    fn teardown(&self, state: &mut OpState, result: Result<(), &WorkflowRunError>) {
+        let _ = (state, result);
+    }
+}
+
+/// Lifecycle of a plugin package: its hooks and whether it is initialized.
+///
+/// Clones share the initialization state, so clones of a package are initialized once.
+#[derive(Clone)]
+pub struct CorePluginLifecycle {
+    hooks: Arc<dyn PluginLifecycle>,
+    initialized: Arc<Mutex<bool>>,
+}
+
+impl fmt::Debug for CorePluginLifecycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CorePluginLifecycle")
+            .field("hooks", &"PluginLifecycle")
+            .field("initialized", &self.is_initialized())
+            .finish()
+    }
+}
+
+impl PartialEq for CorePluginLifecycle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.initialized, &other.initialized)
+    }
+}
+
+impl CorePluginLifecycle {
+    /// Creates a new CorePluginLifecycle that is not initialized yet.
+    ///
+    /// # Arguments
+    /// * `hooks` - Lifecycle hooks of the package
+    pub fn new(hooks: Arc<dyn PluginLifecycle>) -> Self {
+        Self {
+            hooks,
+            initialized: Arc::new(Mutex::new(false)),
+        }
+    }
+
+    /// Returns true if the package has been initialized.
+    pub fn is_initialized(&self) -> bool {
+        *self.initialized.lock().unwrap()
+    }
+
+    /// Initializes the package unless it is already initialized. Hosts may call this to
+    /// initialize a package eagerly; otherwise it is initialized by its first run.
+    ///
+    /// # Arguments
+    /// * `config` - Configuration of the package
+    ///
+    /// # Errors
+    /// Returns the `PluginLifecycleError` of `PluginLifecycle::initialize`.
+    pub fn initialize(&self, config: &PluginConfig) -> Result<(), PluginLifecycleError> {
+        // Hold the lock while initializing, so concurrent runs initialize the package once
+        let mut initialized = self.initialized.lock().unwrap();
+        if !*initialized {
+            self.hooks.initialize(config)?;
+            *initialized = true;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn setup(
+        &self,
+        state: &mut OpState,
+        config: &PluginConfig,
+    ) -> Result<(), PluginLifecycleError> {
+        self.hooks.setup(state, config)
+    }
+
+    pub(crate) fn teardown(&self, state: &mut OpState, result: Result<(), &WorkflowRunError>) {
+        self.hooks.teardown(state, result);
+    }
+}
+
+/// Initializes and sets up the packages of a run in order, tearing down the packages already set
+/// up if one fails.
+///
+/// # Arguments
+/// * `lifecycles` - Lifecycles of the packages, with their package IDs and configurations
+/// * `state` - `OpState` of the run
+///
+/// # Errors
+/// Returns a `WorkflowRunError::PluginLifecycle` for the first package that fails.
+pub(crate) fn setup_plugin_lifecycles(
+    lifecycles: &[(&str, &CorePluginLifecycle, &PluginConfig)],
+    state: &mut OpState,
+) -> Result<(), WorkflowRunError> {
+    for (index, (package_id, lifecycle, config)) in lifecycles.iter().enumerate() {
+        let result = lifecycle
+            .initialize(config)
+            .map_err(|e| (PluginLifecycleStage::Initialize, e))
+            .and_then(|()| {
+                lifecycle
+                    .setup(state, config)
+                    .map_err(|e| (PluginLifecycleStage::Setup, e))
+            });
+        if let Err((stage, error)) = result {
+            let error = WorkflowRunError::PluginLifecycle {
+                package_id: package_id.to_string(),
+                stage,
+                error,
+            };
+            teardown_plugin_lifecycles(&lifecycles[..index], state, Err(&error));
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Tears down the packages of a run in reverse order.
+///
+/// # Arguments
+/// * `lifecycles` - Lifecycles of the packages, with their package IDs and configurations
+/// * `state` - `OpState` of the run
+/// * `result` - Result of the run
+pub(crate) fn teardown_plugin_lifecycles(
+    lifecycles: &[(&str, &CorePluginLifecycle, &PluginConfig)],
+    state: &mut OpState,
+    result: Result<(), &WorkflowRunError>,
+) {
+    for (_, lifecycle, _) in lifecycles.iter().rev() {
+        lifecycle.teardown(state, result);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::PluginConfigValue;
+
+    /// Hooks that record their calls, failing the stages listed in `fail`.
+    pub(crate) struct RecordingLifecycle {
+        pub(crate) name: String,
+        pub(crate) calls: Arc<Mutex<Vec<String>>>,
+        pub(crate) fail: Mutex<Vec<PluginLifecycleStage>>,
+    }
+
+    impl RecordingLifecycle {
+        pub(crate) fn new(name: &str, calls: &Arc<Mutex<Vec<String>>>) -> Self {
+            Self {
+                name: name.to_string(),
+                calls: calls.clone(),
+                fail: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn record(
+            &self,
+            call: String,
+            stage: Option<PluginLifecycleStage>,
+        ) -> Result<(), PluginLifecycleError> {
+            self.calls.lock().unwrap().push(call);
+            let mut fail = self.fail.lock().unwrap();
+            match stage.and_then(|stage| fail.iter().position(|s| *s == stage)) {
+                Some(index) => {
+                    fail.remove(index);
+                    Err(PluginLifecycleError::new(format!("{} failed", self.name)))
+                }
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl PluginLifecycle for RecordingLifecycle {
+        fn initialize(&self, config: &PluginConfig) -> Result<(), PluginLifecycleError> {
+            self.record(
+                format!("{} initialize {config:?}", self.name),
+                Some(PluginLifecycleStage::Initialize),
+            )
+        }
+
+        fn setup(
+            &self,
+            _state: &mut OpState,
+            _config: &PluginConfig,
+        ) -> Result<(), PluginLifecycleError> {
+            self.record(
+                format!("{} setup", self.name),
+                Some(PluginLifecycleStage::Setup),
+            )
+        }
+
+        fn teardown(&self, _state: &mut OpState, result: Result<(), &WorkflowRunError>) {
+            let result = match result {
+                Ok(()) => "ok".to_string(),
+                Err(e) => e.to_string(),
+            };
+            let _ = self.record(format!("{} teardown {result}", self.name), None);
+        }
+    }
+
+    #[test]
+    fn test_core_plugin_lifecycle_initialize() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let hooks = RecordingLifecycle::new("db", &calls);
+        hooks
+            .fail
+            .lock()
+            .unwrap()
+            .push(PluginLifecycleStage::Initialize);
+        let lifecycle = CorePluginLifecycle::new(Arc::new(hooks));
+        let mut config = PluginConfig::new();
+        config.insert("pool".to_string(), PluginConfigValue::Integer(2));
+
+        assert_eq!(
+            lifecycle.initialize(&config),
+            Err(PluginLifecycleError::new("db failed"))
+        );
+        assert!(!lifecycle.is_initialized());
+        // Clones share the initialization state
+        let clone = lifecycle.clone();
+        assert_eq!(clone.initialize(&config), Ok(()));
+        assert_eq!(lifecycle.initialize(&config), Ok(()));
+        assert!(lifecycle.is_initialized());
+        assert_eq!(lifecycle, clone);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                r#"db initialize {"pool": Integer(2)}"#.to_string(),
+                r#"db initialize {"pool": Integer(2)}"#.to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_setup_plugin_lifecycles() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let db = CorePluginLifecycle::new(Arc::new(RecordingLifecycle::new("db", &calls)));
+        let cache = RecordingLifecycle::new("cache", &calls);
+        cache.fail.lock().unwrap().push(PluginLifecycleStage::Setup);
+        let cache = CorePluginLifecycle::new(Arc::new(cache));
+        let config = PluginConfig::new();
+        let lifecycles = [("db", &db, &config), ("cache", &cache, &config)];
+        let mut state = OpState::new(None);
+
+        let err = setup_plugin_lifecycles(&lifecycles, &mut state).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Plugin package cache failed to set up: cache failed"
+        );
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "db initialize {}",
+                "db setup",
+                "cache initialize {}",
+                "cache setup",
+                "db teardown Plugin package cache failed to set up: cache failed",
+            ]
+        );
+
+        calls.lock().unwrap().clear();
+        setup_plugin_lifecycles(&lifecycles, &mut state).unwrap();
+        teardown_plugin_lifecycles(&lifecycles, &mut state, Ok(()));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "db setup",
+                "cache setup",
+                "cache teardown ok",
+                "db teardown ok"
+            ]
+        );
+    }
+}
@@ -0,0 +1,579 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Native plugin packages loaded from shared libraries.
+//!
+//! A native plugin is a shared library that exports `NATIVE_PLUGIN_ENTRY_SYMBOL` as a
+//! `NativePluginEntry`. The entry point returns a `NativePluginDeclaration` that lives as long as
+//! the library, with:
+//! - the version of the C ABI and of sapphillon_core the plugin was built against;
+//! - the `PluginPackage` the plugin provides, encoded as protobuf;
+//! - an `invoke` function that calls a function of the package with a JSON array of arguments,
+//!   and writes the JSON of the return value, or an error message, to an output buffer;
+//! - a `free` function that releases the output buffers written by `invoke`.
+//!
+//! Only the types of this module cross the library boundary, so plugins do not have to be built
+//! with the same compiler or dependencies as the host. Functions of native plugins are called
+//! synchronously from JavaScript, and the library stays loaded as long as one of its packages
+//! or functions is alive.
+
+use crate::plugin::CorePluginPackage;
+use crate::proto::sapphillon::v1::PluginPackage;
+use deno_core::{OpState, op2};
+use deno_error::JsErrorBox;
+use prost::Message;
+use std::collections::HashMap;
+use std::ffi::{CStr, c_char};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Version of the C ABI of native plugins. Plugins built against another version are rejected.
+pub const NATIVE_PLUGIN_ABI_VERSION: u32 = 1;
+
+/// Name of the symbol native plugins export their entry point under.
+pub const NATIVE_PLUGIN_ENTRY_SYMBOL: &str = "sapphillon_plugin_entry";
+
+/// Extension of the shared libraries loaded from a plugins directory, e.g. `so` on Linux.
+pub const NATIVE_PLUGIN_EXTENSION: &str = std::env::consts::DLL_EXTENSION;
+
+/// Bytes passed across the C ABI. The bytes are owned by the side that wrote the buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NativePluginBuffer {
+    /// Pointer to the first byte, or null if the buffer is empty
+    pub data: *const u8,
+    /// Number of bytes
+    pub len: usize,
+}
+
+impl NativePluginBuffer {
+    /// Returns an empty buffer.
+    pub const fn empty() -> Self {
+        Self {
+            data: std::ptr::null(),
+            len: 0,
+        }
+    }
+
+    /// Returns a buffer that borrows the given bytes.
+    pub const fn new(bytes: &[u8]) -> Self {
+        Self {
+            data: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+
+    /// Copies the bytes of the buffer.
+    ///
+    /// # Safety
+    /// `data` must be null or point to `len` readable bytes.
+    pub unsafe fn to_vec(&self) -> Vec<u8> {
+        match self.data.is_null() {
+            true => Vec::new(),
+            // SAFETY: guaranteed by the caller
+            false => unsafe { std::slice::from_raw_parts(self.data, self.len) }.to_vec(),
+        }
+    }
+}
+
+/// Function of a native plugin that invokes a function of its package.
+///
+/// `function_id` is the UTF-8 ID of the function and `arguments` the UTF-8 JSON array of its
+/// arguments. The plugin writes the JSON of the return value to `output` and returns 0, or
+/// writes an error message and returns any other value. An empty output is `null`.
+pub type NativePluginInvoke = unsafe extern "C" fn(
+    function_id: NativePluginBuffer,
+    arguments: NativePluginBuffer,
+    output: *mut NativePluginBuffer,
+) -> i32;
+
+/// Function of a native plugin that releases a buffer written by its `NativePluginInvoke`.
+pub type NativePluginFree = unsafe extern "C" fn(buffer: NativePluginBuffer);
+
+/// Declaration of a native plugin, returned by its entry point.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct NativePluginDeclaration {
+    /// `NATIVE_PLUGIN_ABI_VERSION` the plugin was built against
+    pub abi_version: u32,
+    /// NUL-terminated version of sapphillon_core the plugin was built against, e.g. `0.4.1`
+    pub core_version: *const c_char,
+    /// `PluginPackage` the plugin provides, encoded as protobuf
+    pub manifest: NativePluginBuffer,
+    /// Invokes a function of the package
+    pub invoke: NativePluginInvoke,
+    /// Releases the output buffers written by `invoke`
+    pub free: NativePluginFree,
+}
+
+/// Entry point of a native plugin, exported as `NATIVE_PLUGIN_ENTRY_SYMBOL`.
+pub type NativePluginEntry = unsafe extern "C" fn() -> *const NativePluginDeclaration;
+
+/// Error returned when a native plugin cannot be loaded or one of its functions fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NativePluginError {
+    /// The plugins directory cannot be read.
+    ReadDir { path: String, message: String },
+    /// The library cannot be opened, or does not export the entry point.
+    Load { path: String, message: String },
+    /// The plugin was built against another version of the C ABI.
+    AbiMismatch {
+        path: String,
+        found: u32,
+        expected: u32,
+    },
+    /// The plugin was built against a version of sapphillon_core that is not compatible with
+    /// the host.
+    IncompatibleCoreVersion {
+        path: String,
+        found: String,
+        expected: String,
+    },
+    /// The manifest of the plugin is not a valid `PluginPackage`.
+    InvalidManifest { path: String, message: String },
+    /// A function of the plugin returned an error.
+    Function {
+        function_id: String,
+        message: String,
+    },
+    /// A function of the plugin returned something that is not JSON.
+    InvalidOutput {
+        function_id: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for NativePluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NativePluginError::ReadDir { path, message } => {
+                write!(f, "Failed to read plugins directory {path}: {message}")
+            }
+            NativePluginError::Load { path, message } => {
+                write!(f, "Failed to load native plugin {path}: {message}")
+            }
+            NativePluginError::AbiMismatch {
+                path,
+                found,
+                expected,
+            } => write!(
+                f,
+                "Native plugin {path} uses ABI version {found}, expected {expected}"
+            ),
+            NativePluginError::IncompatibleCoreVersion {
+                path,
+                found,
+                expected,
+            } => write!(
+                f,
+                "Native plugin {path} was built for sapphillon_core {found}, which is not compatible with {expected}"
+            ),
+            NativePluginError::InvalidManifest { path, message } => {
+                write!(f, "Native plugin {path} has an invalid manifest: {message}")
+            }
+            NativePluginError::Function {
+                function_id,
+                message,
+            } => write!(f, "Plugin function {function_id} failed: {message}"),
+            NativePluginError::InvalidOutput {
+                function_id,
+                message,
+            } => write!(
+                f,
+                "Plugin function {function_id} returned invalid JSON: {message}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NativePluginError {}
+
+/// Shared library of a native plugin, shared by the functions of its package.
+pub struct NativePluginLibrary {
+    path: PathBuf,
+    invoke: NativePluginInvoke,
+    free: NativePluginFree,
+    /// Keeps the code of `invoke` and `free` loaded. `None` for plugins linked into the host.
+    _library: Option<libloading::Library>,
+}
+
+impl fmt::Debug for NativePluginLibrary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativePluginLibrary")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for NativePluginLibrary {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path && std::ptr::fn_addr_eq(self.invoke, other.invoke)
+    }
+}
+
+impl Eq for NativePluginLibrary {}
+
+impl NativePluginLibrary {
+    /// Checks a declaration and decodes its manifest.
+    ///
+    /// # Safety
+    /// The pointers of the declaration must be valid as described by `NativePluginDeclaration`.
+    unsafe fn new_from_declaration(
+        path: &Path,
+        declaration: &NativePluginDeclaration,
+        library: Option<libloading::Library>,
+    ) -> Result<(Self, PluginPackage), NativePluginError> {
+        let display = path.display().to_string();
+        if declaration.abi_version != NATIVE_PLUGIN_ABI_VERSION {
+            return Err(NativePluginError::AbiMismatch {
+                path: display,
+                found: declaration.abi_version,
+                expected: NATIVE_PLUGIN_ABI_VERSION,
+            });
+        }
+
+        let expected = env!("CARGO_PKG_VERSION");
+        let found = match declaration.core_version.is_null() {
+            true => String::new(),
+            // SAFETY: guaranteed by the caller
+            false => unsafe { CStr::from_ptr(declaration.core_version) }
+                .to_string_lossy()
+                .into_owned(),
+        };
+        // A plugin is compatible with the versions a caret requirement on its version allows
+        let compatible = semver::VersionReq::parse(&format!("^{found}"))
+            .is_ok_and(|req| req.matches(&semver::Version::parse(expected).unwrap()));
+        if !compatible {
+            return Err(NativePluginError::IncompatibleCoreVersion {
+                path: display,
+                found,
+                expected: expected.to_string(),
+            });
+        }
+
+        // SAFETY: guaranteed by the caller
+        let manifest = unsafe { declaration.manifest.to_vec() };
+        let plugin_package = PluginPackage::decode(manifest.as_slice()).map_err(|e| {
+            NativePluginError::InvalidManifest {
+                path: display,
+                message: e.to_string(),
+            }
+        })?;
+        let library = Self {
+            path: path.to_path_buf(),
+            invoke: declaration.invoke,
+            free: declaration.free,
+            _library: library,
+        };
+        Ok((library, plugin_package))
+    }
+
+    /// Returns the path of the library.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Invokes a function of the package.
+    ///
+    /// # Arguments
+    /// * `function_id` - ID of the function
+    /// * `args` - Arguments of the function
+    ///
+    /// # Errors
+    /// Returns a `NativePluginError` if the function returns an error or invalid JSON.
+    pub fn invoke_function(
+        &self,
+        function_id: &str,
+        args: &[serde_json::Value],
+    ) -> Result<serde_json::Value, NativePluginError> {
+        let arguments = serde_json::Value::Array(args.to_vec()).to_string();
+        let mut output = NativePluginBuffer::empty();
+        // SAFETY: the buffers outlive the call, and the output is released by the plugin
+        // after it has been copied
+        let (status, output) = unsafe {
+            let status = (self.invoke)(
+                NativePluginBuffer::new(function_id.as_bytes()),
+                NativePluginBuffer::new(arguments.as_bytes()),
+                &mut output,
+            );
+            let bytes = output.to_vec();
+            if !output.data.is_null() {
+                (self.free)(output);
+            }
+            (status, bytes)
+        };
+        match status {
+            0 if output.is_empty() => Ok(serde_json::Value::Null),
+            0 => serde_json::from_slice(&output).map_err(|e| NativePluginError::InvalidOutput {
+                function_id: function_id.to_string(),
+                message: e.to_string(),
+            }),
+            _ => Err(NativePluginError::Function {
+                function_id: function_id.to_string(),
+                message: String::from_utf8_lossy(&output).into_owned(),
+            }),
+        }
+    }
+}
+
+/// Loads a native plugin from a shared library.
+///
+/// # Arguments
+/// * `path` - Path of the library
+///
+/// # Errors
+/// Returns a `NativePluginError` if the library cannot be opened, does not export the entry
+/// point, was built against an incompatible ABI or sapphillon_core, or has an invalid manifest.
+///
+/// # Safety
+/// Loading a library runs its initialization code, and its functions run in the host process
+/// without any isolation. Only load libraries that are trusted to implement the ABI correctly.
+pub unsafe fn load_native_plugin(path: &Path) -> Result<CorePluginPackage, NativePluginError> {
+    let load_error = |message: String| NativePluginError::Load {
+        path: path.display().to_string(),
+        message,
+    };
+    // SAFETY: guaranteed by the caller
+    let library =
+        unsafe { libloading::Library::new(path) }.map_err(|e| load_error(e.to_string()))?;
+    // SAFETY: the entry point has the signature of `NativePluginEntry` by contract
+    let declaration = unsafe {
+        let entry = library
+            .get::<NativePluginEntry>(NATIVE_PLUGIN_ENTRY_SYMBOL.as_bytes())
+            .map_err(|e| load_error(e.to_string()))?;
+        entry()
+    };
+    if declaration.is_null() {
+        return Err(load_error("the entry point returned null".to_string()));
+    }
+    // SAFETY: the declaration lives as long as the library, which is moved into the result
+    let (library, plugin_package) = unsafe {
+        let declaration = *declaration;
+        NativePluginLibrary::new_from_declaration(path, &declaration, Some(library))?
+    };
+    Ok(CorePluginPackage::new_from_native_plugin(
+        &plugin_package,
+        Arc::new(library),
+    ))
+}
+
+/// Loads the native plugins of a directory, in order of their file names.
+///
+/// Only files with the `NATIVE_PLUGIN_EXTENSION` extension are loaded, and subdirectories are
+/// not searched. Loading stops at the first plugin that fails, so a broken plugin is not
+/// silently missing.
+///
+/// # Arguments
+/// * `dir` - Plugins directory
+///
+/// # Errors
+/// Returns a `NativePluginError` if the directory cannot be read or a plugin cannot be loaded.
+///
+/// # Safety
+/// See `load_native_plugin`. Every library in the directory must be trusted.
+pub unsafe fn load_native_plugins(dir: &Path) -> Result<Vec<CorePluginPackage>, NativePluginError> {
+    let read_dir_error = |e: std::io::Error| NativePluginError::ReadDir {
+        path: dir.display().to_string(),
+        message: e.to_string(),
+    };
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(read_dir_error)? {
+        let path = entry.map_err(read_dir_error)?.path();
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|extension| extension == NATIVE_PLUGIN_EXTENSION)
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    paths
+        .iter()
+        // SAFETY: guaranteed by the caller
+        .map(|path| unsafe { load_native_plugin(path) })
+        .collect()
+}
+
+/// Native plugin libraries of the functions of a run, placed into the `OpState` and keyed by
+/// function ID.
+#[derive(Default)]
+pub(crate) struct NativePluginLibraries(pub(crate) HashMap<String, Arc<NativePluginLibrary>>);
+
+/// Invokes the function `function_id` of its native plugin.
+#[op2]
+#[serde]
+pub(crate) fn op_native_plugin_invoke(
+    state: &mut OpState,
+    #[string] function_id: &str,
+    #[serde] args: Vec<serde_json::Value>,
+) -> Result<serde_json::Value, JsErrorBox> {
+    let library = state
+        .try_borrow::<NativePluginLibraries>()
+        .and_then(|libraries| libraries.0.get(function_id))
+        .cloned()
+        .ok_or_else(|| {
+            JsErrorBox::generic(format!(
+                "Plugin function {function_id} is not provided by a native plugin"
+            ))
+        })?;
+    library
+        .invoke_function(function_id, &args)
+        .map_err(|e| JsErrorBox::generic(e.to_string()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::proto::sapphillon::v1::PluginFunction;
+    use serde_json::json;
+    use std::ffi::CString;
+
+    /// Invoke function of the test plugin: `math.sum` returns the sum of its arguments, and
+    /// every other function fails.
+    unsafe extern "C" fn invoke(
+        function_id: NativePluginBuffer,
+        arguments: NativePluginBuffer,
+        output: *mut NativePluginBuffer,
+    ) -> i32 {
+        let (function_id, arguments) = unsafe { (function_id.to_vec(), arguments.to_vec()) };
+        let (status, bytes) = match function_id.as_slice() {
+            b"math.sum" => {
+                let args: Vec<f64> = serde_json::from_slice(&arguments).unwrap_or_default();
+                (0, args.iter().sum::<f64>().to_string().into_bytes())
+            }
+            _ => (1, b"unknown function".to_vec()),
+        };
+        let bytes = bytes.into_boxed_slice();
+        unsafe {
+            *output = NativePluginBuffer {
+                len: bytes.len(),
+                data: Box::into_raw(bytes) as *const u8,
+            }
+        };
+        status
+    }
+
+    unsafe extern "C" fn free(buffer: NativePluginBuffer) {
+        let bytes = std::ptr::slice_from_raw_parts_mut(buffer.data as *mut u8, buffer.len);
+        drop(unsafe { Box::from_raw(bytes) });
+    }
+
+    fn manifest() -> Vec<u8> {
+        PluginPackage {
+            package_id: "math".to_string(),
+            package_name: "Math".to_string(),
+            package_version: "1.0.0".to_string(),
+            functions: vec![PluginFunction {
+                function_id: "math.sum".to_string(),
+                function_name: "Sum".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    /// Returns the test plugin as a library linked into the host.
+    pub(crate) fn math_library() -> (NativePluginLibrary, PluginPackage) {
+        let manifest = manifest();
+        let core_version = CString::new(env!("CARGO_PKG_VERSION")).unwrap();
+        let declaration = NativePluginDeclaration {
+            abi_version: NATIVE_PLUGIN_ABI_VERSION,
+            core_version: core_version.as_ptr(),
+            manifest: NativePluginBuffer::new(&manifest),
+            invoke,
+            free,
+        };
+        unsafe { NativePluginLibrary::new_from_declaration(Path::new("math"), &declaration, None) }
+            .unwrap()
+    }
+
+    #[test]
+    fn test_native_plugin_library() {
+        let (library, plugin_package) = math_library();
+        assert_eq!(plugin_package.package_id, "math");
+        assert_eq!(
+            library.invoke_function("math.sum", &[json!(1), json!(2.5)]),
+            Ok(json!(3.5))
+        );
+        assert_eq!(
+            library.invoke_function("math.div", &[]),
+            Err(NativePluginError::Function {
+                function_id: "math.div".to_string(),
+                message: "unknown function".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_native_plugin_compatibility() {
+        let manifest = manifest();
+        let core_version = CString::new(env!("CARGO_PKG_VERSION")).unwrap();
+        let declaration = |abi_version: u32, core_version: &CStr| NativePluginDeclaration {
+            abi_version,
+            core_version: core_version.as_ptr(),
+            manifest: NativePluginBuffer::new(&manifest),
+            invoke,
+            free,
+        };
+        let check = |declaration: NativePluginDeclaration| unsafe {
+            NativePluginLibrary::new_from_declaration(Path::new("math"), &declaration, None)
+                .map(|_| ())
+        };
+        assert!(matches!(
+            check(declaration(NATIVE_PLUGIN_ABI_VERSION + 1, &core_version)),
+            Err(NativePluginError::AbiMismatch { found: 2, .. })
+        ));
+        assert!(check(declaration(NATIVE_PLUGIN_ABI_VERSION, &core_version)).is_ok());
+        for version in [c"0.0.1", c"999.0.0", c"latest", c""] {
+            assert!(matches!(
+                check(declaration(NATIVE_PLUGIN_ABI_VERSION, version)),
+                Err(NativePluginError::IncompatibleCoreVersion { .. })
+            ));
+        }
+
+        let invalid = [0xff];
+        let mut declaration = declaration(NATIVE_PLUGIN_ABI_VERSION, &core_version);
+        declaration.manifest = NativePluginBuffer::new(&invalid);
+        assert!(matches!(
+            check(declaration),
+            Err(NativePluginError::InvalidManifest { .. })
+        ));
+    }
+
+    #[test]
+    fn test_load_native_plugins() {
+        let dir = std::env::temp_dir().join(format!("sapphillon-native-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("README.md"), "not a plugin").unwrap();
+        assert!(unsafe { load_native_plugins(&dir) }.unwrap().is_empty());
+
+        let library = dir.join(format!("broken.{NATIVE_PLUGIN_EXTENSION}"));
+        std::fs::write(&library, "not a library").unwrap();
+        let err = unsafe { load_native_plugins(&dir) }.err().unwrap();
+        assert!(matches!(err, NativePluginError::Load { ref path, .. } if path.contains("broken")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            unsafe { load_native_plugins(&dir) }.err().unwrap(),
+            NativePluginError::ReadDir { .. }
+        ));
+    }
+}
@@ -0,0 +1,1256 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::audit::{PermissionCheckReason, permission_check_events};
+use crate::policy::{PermissionPolicy, PolicyAction};
+use crate::proto::sapphillon::v1::{Permission, PermissionType};
+use crate::runtime::{OpStateWorkflowData, WorkflowRunOptions};
+use deno_core::url::Url;
+use deno_core::{OpDecl, OpState, futures, op2};
+use deno_error::{JsErrorBox, JsErrorClass};
+use deno_permissions::{
+    OpenAccessKind, PermissionCheckError, Permissions, PermissionsContainer, PermissionsOptions,
+    RunQueryDescriptor,
+};
+use deno_runtime::permissions::RuntimePermissionDescriptorParser;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::future::Future;
+use std::path::{Component, Path};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use sys_traits::impls::RealSys;
+
+/// JavaScript error class thrown when a workflow touches a resource it has not declared.
+pub const PERMISSION_DENIED_ERROR_CLASS: &str = "PermissionDenied";
+
+/// Script that registers the `PermissionDenied` error class in the runtime.
+pub(crate) const PERMISSION_DENIED_ERROR_SCRIPT: &str = r#"
+class PermissionDenied extends Error {
+  constructor(message) {
+    super(message);
+    this.name = "PermissionDenied";
+  }
+}
+Deno.core.registerErrorClass("PermissionDenied", PermissionDenied);
+"#;
+
+/// Resources granted for one kind of access.
+#[derive(Default)]
+struct Grant {
+    all: bool,
+    resources: Vec<String>,
+}
+
+impl Grant {
+    fn into_option(self) -> Option<Vec<String>> {
+        if self.all {
+            // An empty list grants every resource in deno_permissions
+            Some(Vec::new())
+        } else if self.resources.is_empty() {
+            None
+        } else {
+            Some(self.resources)
+        }
+    }
+}
+
+/// Parses a network URL, or returns `None` if the resource is not one.
+///
+/// Only `http`, `https`, `ws` and `wss` URLs are treated as network resources.
+fn net_url(resource: &str) -> Option<Url> {
+    let url = Url::parse(resource).ok()?;
+    if !matches!(url.scheme(), "http" | "https" | "ws" | "wss") || url.host_str().is_none() {
+        return None;
+    }
+    Some(url)
+}
+
+/// Returns the `host[:port]` of a network resource, or `None` if the resource is not a URL.
+fn net_resource(resource: &str) -> Option<String> {
+    let url = net_url(resource)?;
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    })
+}
+
+/// Translates the given permissions into deno_permissions options.
+///
+/// # Overview
+/// - `Read` grants read access to paths.
+/// - `Write` grants write access to paths.
+/// - `Execute` grants running the given commands, and network access to the hosts of the given
+///   `http`, `https`, `ws` and `wss` URLs.
+/// - A permission without resources grants every resource of its type. An `Execute` permission
+///   without resources grants every command, but no network access.
+/// - `Unspecified` permissions grant nothing.
+///
+/// Prompting is disabled, anything not granted is denied.
+///
+/// # Arguments
+/// * `permissions` - Permissions declared by the workflow
+pub fn permissions_options_from_proto(permissions: &[Permission]) -> PermissionsOptions {
+    let mut read = Grant::default();
+    let mut write = Grant::default();
+    let mut net = Grant::default();
+    let mut run = Grant::default();
+
+    for permission in permissions {
+        let grant = match permission.permission_type() {
+            PermissionType::Read => &mut read,
+            PermissionType::Write => &mut write,
+            PermissionType::Execute => {
+                if permission.resource.is_empty() {
+                    run.all = true;
+                }
+                for resource in &permission.resource {
+                    match net_resource(resource) {
+                        Some(host) => net.resources.push(host),
+                        None => run.resources.push(resource.clone()),
+                    }
+                }
+                continue;
+            }
+            PermissionType::Unspecified => continue,
+        };
+
+        if permission.resource.is_empty() {
+            grant.all = true;
+        }
+        grant.resources.extend(permission.resource.iter().cloned());
+    }
+
+    PermissionsOptions {
+        allow_read: read.into_option(),
+        allow_write: write.into_option(),
+        allow_net: net.into_option(),
+        allow_run: run.into_option(),
+        prompt: false,
+        ..Default::default()
+    }
+}
+
+/// Creates a `PermissionsContainer` that grants exactly the given permissions.
+///
+/// # Arguments
+/// * `permissions` - Permissions declared by the workflow
+///
+/// # Errors
+/// Returns an error if a resource cannot be parsed, e.g. an empty command name.
+pub fn permissions_container_from_proto(
+    permissions: &[Permission],
+) -> Result<PermissionsContainer, JsErrorBox> {
+    let parser = Arc::new(RuntimePermissionDescriptorParser::new(RealSys));
+    let options = permissions_options_from_proto(permissions);
+    let permissions = Permissions::from_options(parser.as_ref(), &options)
+        .map_err(|e| JsErrorBox::type_error(format!("Invalid permission declaration: {e}")))?;
+    Ok(PermissionsContainer::new(parser, permissions))
+}
+
+/// Returns true if access to `required` is included in access to `granted`.
+///
+/// URLs are compared by host, and a granted URL without a port covers every port.
+/// Paths are covered by themselves and by any parent directory. Paths with `..` components are
+/// only covered by themselves, as they may resolve outside of the granted directory.
+fn is_resource_covered(granted: &str, required: &str) -> bool {
+    if granted == required {
+        return true;
+    }
+    match (net_url(granted), net_url(required)) {
+        (Some(granted), Some(required)) => {
+            granted.host_str() == required.host_str()
+                && (granted.port().is_none() || granted.port() == required.port())
+        }
+        (None, None) => {
+            let (granted, required) = (Path::new(granted), Path::new(required));
+            !has_parent_dir(granted) && !has_parent_dir(required) && required.starts_with(granted)
+        }
+        _ => false,
+    }
+}
+
+/// Returns true if a path has `..` components.
+fn has_parent_dir(path: &Path) -> bool {
+    path.components()
+        .any(|component| component == Component::ParentDir)
+}
+
+/// Returns true if a resource of the given type is covered by a grant without resources.
+/// `Execute` grants without resources do not cover network access.
+fn is_covered_by_all(permission_type: PermissionType, resource: &str) -> bool {
+    permission_type != PermissionType::Execute || net_url(resource).is_none()
+}
+
+/// Returns true if the `required` permission is covered by the `granted` permissions.
+///
+/// A granted permission without resources covers every resource of its type, except for the
+/// network access of `Execute` permissions. `Unspecified` permissions need no grant.
+///
+/// # Arguments
+/// * `granted` - Permissions granted to the workflow
+/// * `required` - Permission to look up
+pub fn is_permission_granted(granted: &[Permission], required: &Permission) -> bool {
+    if required.permission_type() == PermissionType::Unspecified {
+        return true;
+    }
+    let grants = granted
+        .iter()
+        .filter(|g| g.permission_type() == required.permission_type());
+    if required.resource.is_empty() {
+        return grants.clone().any(|g| g.resource.is_empty());
+    }
+    required.resource.iter().all(|resource| {
+        grants.clone().any(|g| {
+            if g.resource.is_empty() {
+                return is_covered_by_all(required.permission_type(), resource);
+            }
+            g.resource.iter().any(|r| is_resource_covered(r, resource))
+        })
+    })
+}
+
+/// Returns the permissions of `required` that are not covered by `granted`.
+///
+/// # Arguments
+/// * `granted` - Permissions granted to the workflow
+/// * `required` - Permissions to look up
+pub fn missing_permissions(granted: &[Permission], required: &[Permission]) -> Vec<Permission> {
+    required
+        .iter()
+        .filter(|p| !is_permission_granted(granted, p))
+        .cloned()
+        .collect()
+}
+
+/// Returns the smallest subset of `permissions` that covers all of them.
+///
+/// Permissions covered by another permission of the list, e.g. a path whose parent directory is
+/// also listed, are dropped. `Unspecified` permissions need no grant and are dropped as well.
+///
+/// # Arguments
+/// * `permissions` - Permissions to reduce, the first of equal permissions is kept
+pub fn minimal_permissions<'a>(
+    permissions: impl IntoIterator<Item = &'a Permission>,
+) -> Vec<Permission> {
+    let mut minimal: Vec<Permission> = Vec::new();
+    for permission in permissions {
+        if is_permission_granted(&minimal, permission) {
+            continue;
+        }
+        minimal.retain(|kept| !is_permission_granted(std::slice::from_ref(permission), kept));
+        minimal.push(permission.clone());
+    }
+    minimal
+}
+
+// No fast call, the stub stands in for ops with any signature
+#[op2(nofast)]
+pub(crate) fn op_permission_denied() -> Result<(), JsErrorBox> {
+    Err(JsErrorBox::new(
+        PERMISSION_DENIED_ERROR_CLASS,
+        "This plugin function requires permissions that are not granted to the workflow",
+    ))
+}
+
+/// Returns an op with the name of `op` that throws a `PermissionDenied` error when invoked.
+///
+/// Used in place of plugin functions whose permissions are not granted to the workflow,
+/// so that calling them fails with a clear error instead of a missing function.
+///
+/// # Arguments
+/// * `op` - Op of the denied plugin function
+pub(crate) fn permission_denied_op(op: &OpDecl) -> OpDecl {
+    let mut denied = op_permission_denied();
+    denied.name = op.name;
+    denied.name_fast = op.name_fast;
+    denied
+}
+
+/// Maps a failed permission check to the error thrown into JavaScript.
+fn to_js_error(e: PermissionCheckError) -> JsErrorBox {
+    match e {
+        PermissionCheckError::PermissionDenied(denied) => JsErrorBox::new(
+            PERMISSION_DENIED_ERROR_CLASS,
+            format!(
+                "Requires {}, which is not declared in the workflow's required permissions",
+                denied.access
+            ),
+        ),
+        e => JsErrorBox::from_err(e),
+    }
+}
+
+/// Checks a single resource against the permissions granted up front.
+fn check_granted(
+    permissions: &mut PermissionsContainer,
+    permission_type: PermissionType,
+    resource: Option<&str>,
+    api_name: &str,
+) -> Result<(), JsErrorBox> {
+    let access_kind = match permission_type {
+        PermissionType::Read => OpenAccessKind::Read,
+        PermissionType::Write => OpenAccessKind::Write,
+        PermissionType::Execute => {
+            let Some(resource) = resource else {
+                return permissions.check_run_all(api_name).map_err(to_js_error);
+            };
+            if let Some(url) = net_url(resource) {
+                return permissions
+                    .check_net_url(&url, api_name)
+                    .map_err(to_js_error);
+            }
+            let command =
+                RunQueryDescriptor::parse(resource, &RealSys).map_err(|e| to_js_error(e.into()))?;
+            return permissions
+                .check_run(&command, api_name)
+                .map_err(to_js_error);
+        }
+        PermissionType::Unspecified => {
+            return Err(JsErrorBox::type_error(format!(
+                "{api_name}: permission type must be specified"
+            )));
+        }
+    };
+
+    let Some(resource) = resource else {
+        return match permission_type {
+            PermissionType::Write => permissions.check_write_all(api_name),
+            _ => permissions.check_read_all(api_name),
+        }
+        .map_err(to_js_error);
+    };
+    permissions
+        .check_open(
+            Cow::Borrowed(Path::new(resource)),
+            access_kind,
+            Some(api_name),
+        )
+        .map(|_| ())
+        .map_err(to_js_error)
+}
+
+/// Checks that the current workflow has been granted access to a resource.
+///
+/// Plugin ops call this before touching a file, host or command on behalf of the workflow.
+/// Network access is checked as `Execute` access to the URL the op connects to.
+/// A denied check returns a `PermissionDenied` error that fails the run when it is not caught.
+/// If a `PermissionPolicy` or `PermissionApprover` is set for the run, it decides the access
+/// before it is denied. Granted and denied checks are recorded as audit events of the run.
+///
+/// # Arguments
+/// * `state` - OpState of the running workflow
+/// * `permission_type` - Kind of access the op needs
+/// * `resource` - Path, URL or command the op accesses
+/// * `api_name` - Name of the op, used in error messages
+///
+/// # Errors
+/// Returns a `PermissionDenied` error if the access is not granted.
+pub fn check_permission(
+    state: &mut OpState,
+    permission_type: PermissionType,
+    resource: &str,
+    api_name: &str,
+) -> Result<(), JsErrorBox> {
+    let permission = Permission {
+        permission_type: permission_type as i32,
+        resource: vec![resource.to_string()],
+        ..Default::default()
+    };
+    check_requested_permission(state, &permission, api_name)
+}
+
+/// Checks that the current workflow has been granted the given permission.
+///
+/// Like `check_permission`, but checks every resource of `permission` and passes the whole
+/// permission, including its display name and `permission_level`, to the `PermissionPolicy` and
+/// `PermissionApprover`.
+/// A permission without resources requires access to every resource of its type.
+///
+/// # Arguments
+/// * `state` - OpState of the running workflow
+/// * `permission` - Permission the op needs
+/// * `api_name` - Name of the op, used in error messages
+///
+/// # Errors
+/// Returns a `PermissionDenied` error if the permission is not granted.
+pub fn check_requested_permission(
+    state: &mut OpState,
+    permission: &Permission,
+    api_name: &str,
+) -> Result<(), JsErrorBox> {
+    let (result, reason) = match check_declared(state, permission, api_name) {
+        Ok(()) => (Ok(()), PermissionCheckReason::Granted),
+        Err(e) if e.get_class() == PERMISSION_DENIED_ERROR_CLASS => {
+            let (approval, workflow_data) = approval_of(state);
+            // A sync op cannot yield to the event loop, so the run waits here for the decision
+            futures::executor::block_on(request_approval(
+                approval,
+                workflow_data,
+                permission,
+                api_name,
+                e,
+            ))
+        }
+        // Invalid resources are not permission decisions and are not audited
+        Err(e) => return Err(e),
+    };
+    record_permission_check(state, permission, api_name, result.is_ok(), &reason);
+    result
+}
+
+/// Checks that the current workflow has been granted access to a resource, from an async op.
+///
+/// Like `check_permission`, but the event loop keeps running while the `PermissionApprover`
+/// decides, so other ops and timers of the workflow are not blocked by the decision.
+///
+/// # Arguments
+/// * `state` - OpState of the running workflow
+/// * `permission_type` - Kind of access the op needs
+/// * `resource` - Path, URL or command the op accesses
+/// * `api_name` - Name of the op, used in error messages
+///
+/// # Errors
+/// Returns a `PermissionDenied` error if the access is not granted.
+pub async fn check_permission_async(
+    state: Rc<RefCell<OpState>>,
+    permission_type: PermissionType,
+    resource: &str,
+    api_name: &str,
+) -> Result<(), JsErrorBox> {
+    let permission = Permission {
+        permission_type: permission_type as i32,
+        resource: vec![resource.to_string()],
+        ..Default::default()
+    };
+    check_requested_permission_async(state, &permission, api_name).await
+}
+
+/// Checks that the current workflow has been granted the given permission, from an async op.
+///
+/// Like `check_requested_permission`, but awaits the decision of the `PermissionApprover`
+/// instead of blocking the workflow thread.
+///
+/// # Arguments
+/// * `state` - OpState of the running workflow
+/// * `permission` - Permission the op needs
+/// * `api_name` - Name of the op, used in error messages
+///
+/// # Errors
+/// Returns a `PermissionDenied` error if the permission is not granted.
+pub async fn check_requested_permission_async(
+    state: Rc<RefCell<OpState>>,
+    permission: &Permission,
+    api_name: &str,
+) -> Result<(), JsErrorBox> {
+    let checked = check_declared(&mut state.borrow_mut(), permission, api_name);
+    let (result, reason) = match checked {
+        Ok(()) => (Ok(()), PermissionCheckReason::Granted),
+        Err(e) if e.get_class() == PERMISSION_DENIED_ERROR_CLASS => {
+            let (approval, workflow_data) = approval_of(&state.borrow());
+            request_approval(approval, workflow_data, permission, api_name, e).await
+        }
+        Err(e) => return Err(e),
+    };
+    record_permission_check(
+        &state.borrow(),
+        permission,
+        api_name,
+        result.is_ok(),
+        &reason,
+    );
+    result
+}
+
+/// Checks `permission` against the permissions declared by the workflow.
+fn check_declared(
+    state: &mut OpState,
+    permission: &Permission,
+    api_name: &str,
+) -> Result<(), JsErrorBox> {
+    let permissions = state
+        .try_borrow_mut::<PermissionsContainer>()
+        .ok_or_else(|| JsErrorBox::generic("No permissions are set for this workflow"))?;
+    check_container(permissions, permission, api_name)
+}
+
+/// Checks every resource of `permission` against a `PermissionsContainer`.
+fn check_container(
+    permissions: &mut PermissionsContainer,
+    permission: &Permission,
+    api_name: &str,
+) -> Result<(), JsErrorBox> {
+    if permission.resource.is_empty() {
+        return check_granted(permissions, permission.permission_type(), None, api_name);
+    }
+    permission.resource.iter().try_for_each(|resource| {
+        check_granted(
+            permissions,
+            permission.permission_type(),
+            Some(resource),
+            api_name,
+        )
+    })
+}
+
+/// Records the audit events of a permission check in the workflow data of the run.
+fn record_permission_check(
+    state: &OpState,
+    permission: &Permission,
+    api_name: &str,
+    granted: bool,
+    reason: &PermissionCheckReason,
+) {
+    let Some(data) = state.try_borrow::<Arc<Mutex<OpStateWorkflowData>>>() else {
+        return;
+    };
+    let mut data = data.lock().unwrap();
+    let events = permission_check_events(
+        data.get_workflow_id(),
+        data.get_plugin_function_id(api_name),
+        api_name,
+        permission,
+        granted,
+        reason,
+    );
+    data.add_audit_events(events);
+}
+
+/// Decision of a `PermissionApprover` about a permission request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermissionDecision {
+    /// Allow the requested access this time only.
+    AllowOnce,
+    /// Allow the requested access for the rest of the run, and grant it to later runs of the workflow.
+    AllowAlways,
+    /// Deny the requested access.
+    Deny,
+}
+
+/// Request passed to a `PermissionApprover` when a workflow needs a permission it was not granted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermissionRequest {
+    /// ID of the workflow that requests the permission
+    pub workflow_id: String,
+    /// Name of the op or plugin function that requests the permission
+    pub api_name: String,
+    /// Requested permission, including its `permission_level`
+    pub permission: Permission,
+}
+
+/// Who decided a permission request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PermissionDecisionSource {
+    /// The `PermissionApprover` of the run.
+    Approver,
+    /// The rule of the `PermissionPolicy` of the run with the given name.
+    Policy(String),
+}
+
+/// Decision made about a permission request during a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermissionDecisionRecord {
+    pub request: PermissionRequest,
+    pub decision: PermissionDecision,
+    pub decided_by: PermissionDecisionSource,
+}
+
+/// Future resolving to the decision of a `PermissionApprover`.
+pub type PermissionDecisionFuture<'a> =
+    Pin<Box<dyn Future<Output = PermissionDecision> + Send + 'a>>;
+
+/// Host-provided approval of permissions that were not granted up front.
+///
+/// The runtime calls `approve` whenever a workflow or plugin op touches a resource not covered by
+/// the granted permissions, and the op waits until the returned future resolves, e.g. while the
+/// user decides. Async ops checking with `check_permission_async` keep the event loop running in
+/// the meantime; sync ops block the workflow thread, so the future must not rely on the runtime
+/// of the workflow to make progress.
+pub trait PermissionApprover: Send + Sync {
+    /// Decides whether the requested permission is granted.
+    ///
+    /// # Arguments
+    /// * `request` - Permission requested by the workflow
+    fn approve<'a>(&'a self, request: &'a PermissionRequest) -> PermissionDecisionFuture<'a>;
+}
+
+/// Approval state of a run, placed into the `OpState` when a `PermissionPolicy` or
+/// `PermissionApprover` is set.
+///
+/// Clones share the permissions allowed during the run, so async checks can take a clone out of
+/// the `OpState` while they wait for a decision.
+#[derive(Clone)]
+pub(crate) struct PermissionApproval {
+    approver: Option<Arc<dyn PermissionApprover>>,
+    policy: Option<Arc<PermissionPolicy>>,
+    /// Permissions allowed with `AllowAlways` during this run
+    granted: Arc<Mutex<Vec<Permission>>>,
+}
+
+impl PermissionApproval {
+    /// Returns true if `permission` was allowed with `AllowAlways` during this run.
+    ///
+    /// The allowed permissions are checked like the declared ones, with a `PermissionsContainer`,
+    /// so paths are resolved by deno_permissions instead of being compared as they are written.
+    fn is_allowed_for_run(&self, permission: &Permission, api_name: &str) -> bool {
+        let granted = self.granted.lock().unwrap();
+        if granted.is_empty() {
+            return false;
+        }
+        permissions_container_from_proto(&granted)
+            .and_then(|mut container| check_container(&mut container, permission, api_name))
+            .is_ok()
+    }
+}
+
+impl PermissionApproval {
+    /// Creates a new `PermissionApproval` without any permissions allowed yet.
+    pub(crate) fn new(
+        approver: Option<Arc<dyn PermissionApprover>>,
+        policy: Option<Arc<PermissionPolicy>>,
+    ) -> Self {
+        Self {
+            approver,
+            policy,
+            granted: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Creates the approval state of a run, or `None` if the options set neither a policy nor an approver.
+    pub(crate) fn new_from_options(options: &WorkflowRunOptions) -> Option<Self> {
+        if options.permission_approver.is_none() && options.permission_policy.is_none() {
+            return None;
+        }
+        Some(Self::new(
+            options.permission_approver.clone(),
+            options.permission_policy.clone(),
+        ))
+    }
+
+    /// Decides a permission request with the policy, escalating to the approver.
+    /// Returns `None` if the request is escalated and there is no approver.
+    pub(crate) async fn decide(
+        &self,
+        request: PermissionRequest,
+    ) -> Option<PermissionDecisionRecord> {
+        if let Some(policy) = &self.policy {
+            let decision = policy.evaluate(&request);
+            let decided_by = PermissionDecisionSource::Policy(decision.rule.unwrap_or_default());
+            let decision = match decision.action {
+                PolicyAction::Approve => Some(PermissionDecision::AllowOnce),
+                PolicyAction::Deny => Some(PermissionDecision::Deny),
+                PolicyAction::Escalate => None,
+            };
+            if let Some(decision) = decision {
+                return Some(PermissionDecisionRecord {
+                    request,
+                    decision,
+                    decided_by,
+                });
+            }
+        }
+        let decision = self.approver.as_ref()?.approve(&request).await;
+        Some(PermissionDecisionRecord {
+            request,
+            decision,
+            decided_by: PermissionDecisionSource::Approver,
+        })
+    }
+}
+
+/// Checks the permissions declared by a plugin function against `granted` before a run starts,
+/// deciding missing ones with the policy or approver of the run, and records an audit event for
+/// each of them. Returns true if all of them are allowed, in which case the missing ones are added
+/// to `granted`.
+///
+/// The decisions are awaited without holding the lock of `workflow_data`.
+pub(crate) async fn check_plugin_function(
+    approval: Option<&PermissionApproval>,
+    workflow_data: &Mutex<OpStateWorkflowData>,
+    function_id: &str,
+    api_name: &str,
+    permissions: &[Permission],
+    granted: &mut Vec<Permission>,
+) -> bool {
+    let workflow_id = workflow_data.lock().unwrap().get_workflow_id().to_string();
+    for permission in permissions {
+        let (allowed, reason) = if is_permission_granted(granted, permission) {
+            (true, PermissionCheckReason::Granted)
+        } else {
+            let request = PermissionRequest {
+                workflow_id: workflow_id.clone(),
+                api_name: api_name.to_string(),
+                permission: permission.clone(),
+            };
+            let record = match approval {
+                Some(approval) => approval.decide(request).await,
+                None => None,
+            };
+            match record {
+                Some(record) => {
+                    workflow_data
+                        .lock()
+                        .unwrap()
+                        .add_permission_decision(record.clone());
+                    (
+                        record.decision != PermissionDecision::Deny,
+                        PermissionCheckReason::Decided(record),
+                    )
+                }
+                None => (false, PermissionCheckReason::NotGranted),
+            }
+        };
+        workflow_data
+            .lock()
+            .unwrap()
+            .add_audit_events(permission_check_events(
+                &workflow_id,
+                Some(function_id),
+                api_name,
+                permission,
+                allowed,
+                &reason,
+            ));
+        if !allowed {
+            return false;
+        }
+        if reason != PermissionCheckReason::Granted {
+            granted.push(permission.clone());
+        }
+    }
+    true
+}
+
+/// Returns the approval state and workflow data of the run.
+fn approval_of(
+    state: &OpState,
+) -> (
+    Option<PermissionApproval>,
+    Option<Arc<Mutex<OpStateWorkflowData>>>,
+) {
+    (
+        state.try_borrow::<PermissionApproval>().cloned(),
+        state
+            .try_borrow::<Arc<Mutex<OpStateWorkflowData>>>()
+            .cloned(),
+    )
+}
+
+/// Decides a denied permission with the policy or approver of the run and records the decision.
+/// Returns `denied` when no one allows the permission, together with how the check was decided.
+async fn request_approval(
+    approval: Option<PermissionApproval>,
+    workflow_data: Option<Arc<Mutex<OpStateWorkflowData>>>,
+    permission: &Permission,
+    api_name: &str,
+    denied: JsErrorBox,
+) -> (Result<(), JsErrorBox>, PermissionCheckReason) {
+    let Some(approval) = approval else {
+        return (Err(denied), PermissionCheckReason::NotGranted);
+    };
+    if approval.is_allowed_for_run(permission, api_name) {
+        return (Ok(()), PermissionCheckReason::AllowedForRun);
+    }
+
+    let request = PermissionRequest {
+        workflow_id: workflow_data
+            .as_ref()
+            .map(|data| data.lock().unwrap().get_workflow_id().to_string())
+            .unwrap_or_default(),
+        api_name: api_name.to_string(),
+        permission: permission.clone(),
+    };
+    let Some(record) = approval.decide(request).await else {
+        return (Err(denied), PermissionCheckReason::NotGranted);
+    };
+    let decision = record.decision;
+    if decision == PermissionDecision::AllowAlways {
+        approval.granted.lock().unwrap().push(permission.clone());
+    }
+    if let Some(data) = workflow_data {
+        data.lock().unwrap().add_permission_decision(record.clone());
+    }
+
+    let result = match decision {
+        PermissionDecision::Deny => Err(denied),
+        PermissionDecision::AllowOnce | PermissionDecision::AllowAlways => Ok(()),
+    };
+    (result, PermissionCheckReason::Decided(record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::sapphillon::v1::PermissionLevel;
+
+    fn permission(permission_type: PermissionType, resource: &[&str]) -> Permission {
+        Permission {
+            display_name: "test".to_string(),
+            description: "test permission".to_string(),
+            permission_type: permission_type as i32,
+            resource: resource.iter().map(|r| r.to_string()).collect(),
+            permission_level: 0,
+        }
+    }
+
+    fn state_with(permissions: &[Permission]) -> OpState {
+        let mut state = OpState::new(None);
+        state.put(permissions_container_from_proto(permissions).unwrap());
+        state
+    }
+
+    #[test]
+    fn test_permissions_options_from_proto() {
+        let options = permissions_options_from_proto(&[
+            permission(PermissionType::Read, &["/tmp/data"]),
+            permission(PermissionType::Write, &[]),
+            permission(
+                PermissionType::Execute,
+                &[
+                    "git",
+                    "https://example.com:8443/api",
+                    "wss://api.example.com",
+                ],
+            ),
+            permission(PermissionType::Unspecified, &["/etc"]),
+        ]);
+        assert_eq!(options.allow_read, Some(vec!["/tmp/data".to_string()]));
+        assert_eq!(options.allow_write, Some(vec![]));
+        assert_eq!(
+            options.allow_net,
+            Some(vec![
+                "example.com:8443".to_string(),
+                "api.example.com".to_string()
+            ])
+        );
+        assert_eq!(options.allow_run, Some(vec!["git".to_string()]));
+        assert!(!options.prompt);
+    }
+
+    #[test]
+    fn test_permissions_options_from_proto_read_grants_no_net() {
+        let options = permissions_options_from_proto(&[
+            permission(PermissionType::Read, &[]),
+            permission(PermissionType::Write, &["https://example.com"]),
+        ]);
+        assert_eq!(options.allow_read, Some(vec![]));
+        assert_eq!(options.allow_net, None);
+
+        let mut state = state_with(&[permission(PermissionType::Read, &[])]);
+        assert!(
+            check_permission(&mut state, PermissionType::Read, "/etc/hosts", "op_read").is_ok()
+        );
+        let err = check_permission(
+            &mut state,
+            PermissionType::Execute,
+            "https://example.com/",
+            "op_fetch",
+        )
+        .unwrap_err();
+        assert_eq!(err.get_class(), PERMISSION_DENIED_ERROR_CLASS);
+
+        // Running every command grants no network access
+        let options = permissions_options_from_proto(&[permission(PermissionType::Execute, &[])]);
+        assert_eq!(options.allow_run, Some(vec![]));
+        assert_eq!(options.allow_net, None);
+    }
+
+    #[test]
+    fn test_permissions_options_from_proto_empty() {
+        let options = permissions_options_from_proto(&[]);
+        assert_eq!(options.allow_read, None);
+        assert_eq!(options.allow_write, None);
+        assert_eq!(options.allow_net, None);
+        assert_eq!(options.allow_run, None);
+    }
+
+    #[test]
+    fn test_check_permission_declared_resources() {
+        let mut state = state_with(&[
+            permission(PermissionType::Read, &["/tmp/data"]),
+            permission(PermissionType::Execute, &["https://example.com"]),
+        ]);
+        assert!(
+            check_permission(
+                &mut state,
+                PermissionType::Read,
+                "/tmp/data/a.txt",
+                "op_read"
+            )
+            .is_ok()
+        );
+        assert!(
+            check_permission(
+                &mut state,
+                PermissionType::Execute,
+                "https://example.com/index.html",
+                "op_fetch"
+            )
+            .is_ok()
+        );
+        assert!(
+            check_permission(
+                &mut state,
+                PermissionType::Execute,
+                "wss://example.com/socket",
+                "op_connect"
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_check_permission_denied() {
+        let mut state = state_with(&[permission(PermissionType::Read, &["/tmp/data"])]);
+
+        let err = check_permission(&mut state, PermissionType::Read, "/etc/passwd", "op_read")
+            .unwrap_err();
+        assert_eq!(err.get_class(), PERMISSION_DENIED_ERROR_CLASS);
+        assert!(err.get_message().contains("/etc/passwd"));
+
+        let err = check_permission(
+            &mut state,
+            PermissionType::Write,
+            "/tmp/data/a.txt",
+            "op_write",
+        )
+        .unwrap_err();
+        assert_eq!(err.get_class(), PERMISSION_DENIED_ERROR_CLASS);
+
+        let err = check_permission(
+            &mut state,
+            PermissionType::Execute,
+            "https://evil.example.com/",
+            "op_fetch",
+        )
+        .unwrap_err();
+        assert_eq!(err.get_class(), PERMISSION_DENIED_ERROR_CLASS);
+        assert!(err.get_message().contains("evil.example.com"));
+    }
+
+    #[test]
+    fn test_is_permission_granted() {
+        let granted = vec![
+            permission(PermissionType::Read, &["/tmp/data"]),
+            permission(PermissionType::Write, &[]),
+            permission(PermissionType::Execute, &["https://example.com"]),
+        ];
+        assert!(is_permission_granted(
+            &granted,
+            &permission(PermissionType::Read, &["/tmp/data/a.txt"])
+        ));
+        assert!(is_permission_granted(
+            &granted,
+            &permission(PermissionType::Execute, &["https://example.com:8443/api"])
+        ));
+        assert!(!is_permission_granted(
+            &[permission(PermissionType::Execute, &[])],
+            &permission(PermissionType::Execute, &["https://example.com"])
+        ));
+        assert!(!is_permission_granted(
+            &granted,
+            &permission(PermissionType::Read, &["https://example.com"])
+        ));
+        assert!(is_permission_granted(
+            &granted,
+            &permission(PermissionType::Write, &["/etc/hosts"])
+        ));
+        assert!(!is_permission_granted(
+            &granted,
+            &permission(PermissionType::Read, &["/tmp/database"])
+        ));
+        // `..` may resolve outside of the granted directory
+        assert!(!is_permission_granted(
+            &granted,
+            &permission(PermissionType::Read, &["/tmp/data/../../etc/passwd"])
+        ));
+        assert!(!is_permission_granted(
+            &[permission(PermissionType::Read, &["/tmp/data/.."])],
+            &permission(PermissionType::Read, &["/tmp/data/../other"])
+        ));
+        assert!(!is_permission_granted(
+            &granted,
+            &permission(PermissionType::Read, &[])
+        ));
+        assert!(!is_permission_granted(
+            &granted,
+            &permission(PermissionType::Execute, &["git"])
+        ));
+        assert!(is_permission_granted(
+            &[],
+            &permission(PermissionType::Unspecified, &["/"])
+        ));
+    }
+
+    #[test]
+    fn test_missing_permissions() {
+        let granted = vec![permission(PermissionType::Read, &["/tmp"])];
+        let required = vec![
+            permission(PermissionType::Read, &["/tmp/a"]),
+            permission(PermissionType::Write, &["/tmp/a"]),
+        ];
+        assert_eq!(
+            missing_permissions(&granted, &required),
+            vec![permission(PermissionType::Write, &["/tmp/a"])]
+        );
+    }
+
+    #[test]
+    fn test_minimal_permissions() {
+        let permissions = vec![
+            permission(PermissionType::Read, &["/tmp/a"]),
+            permission(PermissionType::Write, &["/tmp/a"]),
+            permission(PermissionType::Read, &["/tmp"]),
+            permission(PermissionType::Read, &["/tmp/b"]),
+            permission(PermissionType::Write, &["/tmp/a"]),
+            permission(PermissionType::Unspecified, &[]),
+        ];
+        assert_eq!(
+            minimal_permissions(&permissions),
+            vec![
+                permission(PermissionType::Write, &["/tmp/a"]),
+                permission(PermissionType::Read, &["/tmp"]),
+            ]
+        );
+        assert!(minimal_permissions(&[]).is_empty());
+    }
+
+    struct FixedApprover(PermissionDecision);
+
+    impl PermissionApprover for FixedApprover {
+        fn approve<'a>(&'a self, _request: &'a PermissionRequest) -> PermissionDecisionFuture<'a> {
+            Box::pin(std::future::ready(self.0))
+        }
+    }
+
+    fn state_with_approver(
+        decision: PermissionDecision,
+    ) -> (OpState, Arc<Mutex<OpStateWorkflowData>>) {
+        let data = Arc::new(Mutex::new(OpStateWorkflowData::new("wid", false)));
+        let mut state = state_with(&[]);
+        state.put(data.clone());
+        state.put(PermissionApproval::new(
+            Some(Arc::new(FixedApprover(decision))),
+            None,
+        ));
+        (state, data)
+    }
+
+    #[test]
+    fn test_check_permission_approver_allow_once() {
+        let (mut state, data) = state_with_approver(PermissionDecision::AllowOnce);
+        assert!(
+            check_permission(&mut state, PermissionType::Read, "/etc/hosts", "op_read").is_ok()
+        );
+        assert!(
+            check_permission(&mut state, PermissionType::Read, "/etc/hosts", "op_read").is_ok()
+        );
+
+        // Allowing once asks again on the next access
+        let data = data.lock().unwrap();
+        let decisions = data.get_permission_decisions();
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[0].decision, PermissionDecision::AllowOnce);
+        assert_eq!(decisions[0].request.workflow_id, "wid");
+        assert_eq!(decisions[0].request.api_name, "op_read");
+        assert_eq!(decisions[0].request.permission.resource, vec!["/etc/hosts"]);
+    }
+
+    /// Approver that allows once after waiting on a timer of the workflow runtime.
+    struct DelayedApprover;
+
+    impl PermissionApprover for DelayedApprover {
+        fn approve<'a>(&'a self, _request: &'a PermissionRequest) -> PermissionDecisionFuture<'a> {
+            Box::pin(async {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                PermissionDecision::AllowOnce
+            })
+        }
+    }
+
+    #[test]
+    fn test_check_permission_async_approver() {
+        use crate::runtime::{CancellationToken, WorkflowStdout, run_module};
+        use crate::transpile::WorkflowModule;
+
+        #[op2(async)]
+        async fn op_read_async(
+            state: Rc<RefCell<OpState>>,
+            #[string] path: String,
+        ) -> Result<(), JsErrorBox> {
+            check_permission_async(state, PermissionType::Read, &path, "op_read_async").await
+        }
+
+        let data = Arc::new(Mutex::new(OpStateWorkflowData::new("wid", true)));
+        let options = WorkflowRunOptions {
+            permission_approver: Some(Arc::new(DelayedApprover)),
+            ..Default::default()
+        };
+        let result = run_module(
+            &WorkflowModule::from_javascript(
+                "const read = Deno.core.ops.op_read_async('/etc/hosts'); \
+                 console.log('waiting'); await read; console.log('read');",
+            ),
+            vec![op_read_async()],
+            &[],
+            Some(data.clone()),
+            &[],
+            &options,
+            &CancellationToken::new(),
+        );
+        assert!(result.is_ok());
+
+        // The workflow keeps running while the approver decides
+        let data = data.lock().unwrap();
+        assert_eq!(
+            data.get_results(),
+            &vec![
+                WorkflowStdout::Stdout("waiting\n".to_string()),
+                WorkflowStdout::Stdout("read\n".to_string()),
+            ]
+        );
+        let decisions = data.get_permission_decisions();
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].request.api_name, "op_read_async");
+    }
+
+    #[test]
+    fn test_check_permission_approver_allow_always() {
+        let (mut state, data) = state_with_approver(PermissionDecision::AllowAlways);
+        assert!(check_permission(&mut state, PermissionType::Read, "/etc", "op_read").is_ok());
+        assert!(
+            check_permission(&mut state, PermissionType::Read, "/etc/hosts", "op_read").is_ok()
+        );
+        assert_eq!(data.lock().unwrap().get_permission_decisions().len(), 1);
+
+        // Paths escaping the allowed directory are decided again
+        assert!(
+            check_permission(
+                &mut state,
+                PermissionType::Read,
+                "/etc/../root/.ssh/id_ed25519",
+                "op_read"
+            )
+            .is_ok()
+        );
+        assert_eq!(data.lock().unwrap().get_permission_decisions().len(), 2);
+    }
+
+    #[test]
+    fn test_check_permission_approver_deny() {
+        let (mut state, data) = state_with_approver(PermissionDecision::Deny);
+        let permission = Permission {
+            permission_type: PermissionType::Write as i32,
+            resource: vec!["/etc/hosts".to_string()],
+            permission_level: PermissionLevel::Critical as i32,
+            ..Default::default()
+        };
+
+        let err = check_requested_permission(&mut state, &permission, "op_write").unwrap_err();
+        assert_eq!(err.get_class(), PERMISSION_DENIED_ERROR_CLASS);
+        let data = data.lock().unwrap();
+        let decisions = data.get_permission_decisions();
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].decision, PermissionDecision::Deny);
+        assert_eq!(decisions[0].request.permission, permission);
+    }
+
+    #[test]
+    fn test_check_permission_policy() {
+        let policy = PermissionPolicy::new_from_json(
+            r#"{"rules": [
+                {"title": "tmp", "expression": "resource.startsWith('/tmp/')", "action": "approve"},
+                {"title": "etc", "expression": "resource.startsWith('/etc/')", "action": "deny"}
+            ]}"#,
+            "policy.json",
+        )
+        .unwrap();
+        let data = Arc::new(Mutex::new(OpStateWorkflowData::new("wid", false)));
+        let mut state = state_with(&[]);
+        state.put(data.clone());
+        state.put(PermissionApproval::new(
+            Some(Arc::new(FixedApprover(PermissionDecision::AllowOnce))),
+            Some(Arc::new(policy)),
+        ));
+
+        assert!(check_permission(&mut state, PermissionType::Read, "/tmp/a", "op_read").is_ok());
+        assert!(
+            check_permission(&mut state, PermissionType::Read, "/etc/hosts", "op_read").is_err()
+        );
+        // Requests that no rule matches are escalated to the approver
+        assert!(check_permission(&mut state, PermissionType::Read, "/home", "op_read").is_ok());
+
+        let data = data.lock().unwrap();
+        let decided_by: Vec<_> = data
+            .get_permission_decisions()
+            .iter()
+            .map(|record| (record.decision, record.decided_by.clone()))
+            .collect();
+        assert_eq!(
+            decided_by,
+            vec![
+                (
+                    PermissionDecision::AllowOnce,
+                    PermissionDecisionSource::Policy("tmp".to_string())
+                ),
+                (
+                    PermissionDecision::Deny,
+                    PermissionDecisionSource::Policy("etc".to_string())
+                ),
+                (
+                    PermissionDecision::AllowOnce,
+                    PermissionDecisionSource::Approver
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_permission_audit_events() {
+        let data = Arc::new(Mutex::new(OpStateWorkflowData::new("wid", false)));
+        data.lock()
+            .unwrap()
+            .set_plugin_function_id("op_read", "fid");
+        let mut state = state_with(&[permission(PermissionType::Read, &["/tmp"])]);
+        state.put(data.clone());
+
+        assert!(check_permission(&mut state, PermissionType::Read, "/tmp/a", "op_read").is_ok());
+        assert!(check_permission(&mut state, PermissionType::Read, "/etc/a", "op_other").is_err());
+
+        let data = data.lock().unwrap();
+        let events = data.get_audit_events();
+        assert_eq!(events.len(), 2);
+        assert!(crate::audit::is_granted(&events[0]));
+        assert_eq!(events[0].resource.as_ref().unwrap().name, "/tmp/a");
+        assert_eq!(
+            events[0].source.as_ref().unwrap().labels["plugin_function_id"],
+            "fid"
+        );
+        assert!(!crate::audit::is_granted(&events[1]));
+        assert_eq!(events[1].request.as_ref().unwrap().reason, "not_granted");
+        assert!(events[1].source.as_ref().unwrap().labels.is_empty());
+    }
+
+    #[test]
+    fn test_check_permission_without_container() {
+        let mut state = OpState::new(None);
+        assert!(check_permission(&mut state, PermissionType::Read, "/tmp", "op_read").is_err());
+    }
+}
@@ -16,36 +16,246 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::proto::sapphillon::v1::{PluginFunction, PluginPackage};
+use crate::config::PluginConfig;
+use crate::grpc::{GrpcPluginError, GrpcPluginProcess};
+use crate::lifecycle::CorePluginLifecycle;
+use crate::native::NativePluginLibrary;
+use crate::permission::missing_permissions;
+use crate::proto::sapphillon::v1::{Permission, PluginFunction, PluginPackage, WorkflowLanguage};
+use crate::schema::FunctionSchema;
+use crate::signature::{PluginSignature, PluginTrustStore, SignatureError};
+use crate::wasm::{WasmError, WasmLimits, exported_functions, instrument};
 use deno_core::OpDecl;
+use prost_types::Timestamp;
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Body of a plugin function.
+#[derive(Clone)]
+pub enum CorePluginFunctionBody {
+    /// Deno op implemented in Rust
+    Op(Cow<'static, OpDecl>),
+    /// Function exported by a source module of the package
+    Export {
+        /// Path of the module in the package
+        module: String,
+        /// Name of the export
+        name: String,
+    },
+    /// Function exported by a WASM module of the package
+    WasmExport {
+        /// Path of the module in the package
+        module: String,
+        /// Name of the export
+        name: String,
+    },
+    /// Function served by a separate process over gRPC
+    Grpc(Arc<GrpcPluginProcess>),
+    /// Function of a native plugin loaded from a shared library
+    Native(Arc<NativePluginLibrary>),
+}
+
+/// JavaScript or TypeScript source module of a plugin package.
+///
+/// Modules are loaded as ES modules before the workflow code, and can import the other modules
+/// of their package with relative specifiers. They cannot be imported by workflow code, nor use
+/// `plugins` while they are evaluated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorePluginModule {
+    /// Path of the module in the package, e.g. `mod.ts`
+    pub path: String,
+    /// Source code of the module
+    pub code: String,
+    /// Language the module is written in
+    pub language: WorkflowLanguage,
+}
+
+impl CorePluginModule {
+    /// Creates a new CorePluginModule.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the module in the package
+    /// * `code` - Source code of the module
+    /// * `language` - Language the module is written in
+    pub fn new(path: String, code: String, language: WorkflowLanguage) -> Self {
+        Self {
+            path,
+            code,
+            language,
+        }
+    }
+}
+
+/// WebAssembly module of a plugin package.
+///
+/// The module is validated and instrumented when it is created, see the `wasm` module for the
+/// host functions it can import and how its limits are enforced. The binary and limits cannot
+/// be changed afterwards, so the instrumented binary that runs always matches them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorePluginWasmModule {
+    /// Path of the module in the package, e.g. `plugin.wasm`
+    pub path: String,
+    /// Binary of the module, as published
+    code: Vec<u8>,
+    /// Binary of the module, instrumented to enforce the limits
+    instrumented: Vec<u8>,
+    /// Names of the functions the module exports
+    exports: Vec<String>,
+    /// Limits applied to the module
+    limits: WasmLimits,
+}
+
+impl CorePluginWasmModule {
+    /// Creates a new CorePluginWasmModule from the binary of a WASM module.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the module in the package
+    /// * `bytes` - Binary of the module
+    /// * `limits` - Limits applied to the module
+    ///
+    /// # Errors
+    /// Returns a `WasmError` if the module is invalid, imports something else than a host
+    /// function, exports a reserved name, needs more memory than the limit, or if the fuel
+    /// limit exceeds `i64::MAX`.
+    pub fn new(path: String, bytes: &[u8], limits: WasmLimits) -> Result<Self, WasmError> {
+        Ok(Self {
+            path,
+            instrumented: instrument(bytes, &limits)?,
+            code: bytes.to_vec(),
+            exports: exported_functions(bytes)?,
+            limits,
+        })
+    }
+
+    /// Returns the binary of the module, as published.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// Returns the binary of the module, instrumented to enforce the limits.
+    pub(crate) fn instrumented(&self) -> &[u8] {
+        &self.instrumented
+    }
+
+    /// Returns the names of the functions the module exports.
+    pub fn exports(&self) -> &[String] {
+        &self.exports
+    }
+
+    /// Returns the limits applied to the module.
+    pub fn limits(&self) -> &WasmLimits {
+        &self.limits
+    }
+}
 
 /// Core representation of a plugin function.
-/// Holds the function's ID, name, and Deno operation.
+/// Holds the function's ID, name, body and the permissions it needs.
+#[derive(Clone)]
 pub struct CorePluginFunction {
     /// Unique ID of the function
     pub id: String,
     /// Function name
     pub name: String,
-    /// Deno OpDecl (function body)
-    pub func: Cow<'static, OpDecl>,
+    /// Body of the function. This was a `Cow<'static, OpDecl>` before functions could be
+    /// implemented by modules and plugin processes; `op` returns the op of functions implemented
+    /// by one, and the constructors that take an `OpDecl` are unchanged.
+    pub func: CorePluginFunctionBody,
     /// Description of the function
     pub description: String,
+    /// Permissions required to execute the function
+    pub permissions: Vec<Permission>,
+    /// Schemas of the arguments and return value, validated when the function is called
+    pub schema: Option<FunctionSchema>,
 }
 
 impl CorePluginFunction {
     /// Creates a new CorePluginFunction from the given ID, name, and function body.
+    /// The function requires no permissions.
     ///
     /// # Arguments
     /// * `id` - Unique ID of the function
     /// * `name` - Function name
     /// * `func` - Deno OpDecl (function body)
     pub fn new(id: String, name: String, description: String, func: OpDecl) -> Self {
+        Self::new_with_permissions(id, name, description, func, Vec::new())
+    }
+
+    /// Creates a new CorePluginFunction that requires the given permissions.
+    ///
+    /// # Arguments
+    /// * `id` - Unique ID of the function
+    /// * `name` - Function name
+    /// * `func` - Deno OpDecl (function body)
+    /// * `permissions` - Permissions required to execute the function
+    pub fn new_with_permissions(
+        id: String,
+        name: String,
+        description: String,
+        func: OpDecl,
+        permissions: Vec<Permission>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            func: CorePluginFunctionBody::Op(Cow::Owned(func)),
+            description,
+            permissions,
+            schema: None,
+        }
+    }
+
+    /// Creates a new CorePluginFunction implemented by a function exported from a source module
+    /// of its package.
+    ///
+    /// # Arguments
+    /// * `id` - Unique ID of the function
+    /// * `name` - Function name
+    /// * `module` - Path of the module in the package
+    /// * `export` - Name of the export
+    /// * `permissions` - Permissions required to execute the function
+    pub fn new_with_export(
+        id: String,
+        name: String,
+        description: String,
+        module: String,
+        export: String,
+        permissions: Vec<Permission>,
+    ) -> Self {
         Self {
             id,
             name,
-            func: Cow::Owned(func),
+            func: CorePluginFunctionBody::Export {
+                module,
+                name: export,
+            },
             description,
+            permissions,
+            schema: None,
+        }
+    }
+
+    /// Creates a new CorePluginFunction whose arguments and return value are validated against
+    /// the given schema when it is called.
+    ///
+    /// # Arguments
+    /// * `id` - Unique ID of the function
+    /// * `name` - Function name
+    /// * `func` - Deno OpDecl (function body)
+    /// * `permissions` - Permissions required to execute the function
+    /// * `schema` - Schemas of the arguments and return value
+    pub fn new_with_schema(
+        id: String,
+        name: String,
+        description: String,
+        func: OpDecl,
+        permissions: Vec<Permission>,
+        schema: FunctionSchema,
+    ) -> Self {
+        Self {
+            schema: Some(schema),
+            ..Self::new_with_permissions(id, name, description, func, permissions)
         }
     }
 
@@ -58,39 +268,278 @@ impl CorePluginFunction {
         Self {
             id: plugin_function.function_id.clone(),
             name: plugin_function.function_name.clone(),
-            func: Cow::Owned(function),
+            func: CorePluginFunctionBody::Op(Cow::Owned(function)),
             description: plugin_function.description.clone(),
+            permissions: plugin_function.permissions.clone(),
+            schema: None,
+        }
+    }
+
+    /// Returns the op of this function, or `None` if it is exported by a module or served by
+    /// a plugin process.
+    pub fn op(&self) -> Option<&OpDecl> {
+        match &self.func {
+            CorePluginFunctionBody::Op(op) => Some(op),
+            _ => None,
         }
     }
+
+    /// Returns the name this function is reported under in permission requests and audit
+    /// events: the name of its op, or its ID if it has no op.
+    pub fn api_name(&self) -> &str {
+        match &self.func {
+            CorePluginFunctionBody::Op(op) => op.name,
+            _ => &self.id,
+        }
+    }
+
+    /// Returns true if calling this function returns a promise.
+    pub fn is_async(&self) -> bool {
+        match &self.func {
+            CorePluginFunctionBody::Op(op) => op.is_async,
+            CorePluginFunctionBody::Grpc(_) => true,
+            CorePluginFunctionBody::Export { .. }
+            | CorePluginFunctionBody::WasmExport { .. }
+            | CorePluginFunctionBody::Native(_) => false,
+        }
+    }
+
+    /// Returns the permissions of this function that are not covered by `granted`.
+    ///
+    /// # Arguments
+    /// * `granted` - Permissions granted to the workflow
+    pub fn missing_permissions(&self, granted: &[Permission]) -> Vec<Permission> {
+        missing_permissions(granted, &self.permissions)
+    }
 }
 
 /// Core representation of a plugin package.
-/// Holds the package ID, name, and a list of functions.
+/// Holds the package ID, name, version, a list of functions and the source modules they are exported from.
+///
+/// Converts to and from a proto `PluginPackage` without losing its metadata. The proto has no
+/// function bodies, modules or configuration, so converting a package to a proto also returns
+/// a `CorePluginPackageImpl` holding them, and converting back takes it alongside the proto,
+/// see the `From` and `TryFrom` implementations.
+#[derive(Clone)]
 pub struct CorePluginPackage {
     /// Unique ID of the package
     pub id: String,
     /// Package name
     pub name: String,
+    /// Semantic version of the package, empty if it is not versioned
+    pub version: String,
+    /// Description of the package
+    pub description: String,
+    /// URL of the store page or documentation of the package, empty if it has none
+    pub plugin_store_url: String,
+    /// Whether the package is intended for internal use only, `None` if it is unset
+    pub internal: Option<bool>,
+    /// Whether the signature of the package has been verified, see `verified`
+    verified: bool,
+    /// Whether the package is deprecated and should not be used by new workflows, `None` if it
+    /// is unset
+    pub deprecated: Option<bool>,
+    /// Time when the package was installed
+    pub installed_at: Option<Timestamp>,
+    /// Time when the package was last updated
+    pub updated_at: Option<Timestamp>,
     /// List of functions included in the package
     pub functions: Vec<CorePluginFunction>,
+    /// Source modules of the functions implemented in JavaScript or TypeScript
+    pub modules: Vec<CorePluginModule>,
+    /// WASM modules of the functions implemented in WebAssembly
+    pub wasm_modules: Vec<CorePluginWasmModule>,
+    /// Hooks called around the runs that use the package, see the `lifecycle` module
+    pub lifecycle: Option<CorePluginLifecycle>,
+    /// Configuration of the package, readable by its ops and lifecycle hooks only, see the
+    /// `config` module
+    pub config: PluginConfig,
+    /// Signature of the package by its publisher, see the `signature` module
+    pub signature: Option<PluginSignature>,
 }
 
 impl CorePluginPackage {
     /// Creates a new CorePluginPackage from the given ID, name, and function list.
+    /// The package is not versioned.
     ///
     /// # Arguments
     /// * `id` - Unique ID of the package
     /// * `name` - Package name
     /// * `functions` - List of functions included in the package
     pub fn new(id: String, name: String, functions: Vec<CorePluginFunction>) -> Self {
+        Self::new_with_version(id, name, String::new(), functions)
+    }
+
+    /// Creates a new CorePluginPackage with the given semantic version.
+    ///
+    /// # Arguments
+    /// * `id` - Unique ID of the package
+    /// * `name` - Package name
+    /// * `version` - Semantic version of the package, e.g. `1.2.0`
+    /// * `functions` - List of functions included in the package
+    pub fn new_with_version(
+        id: String,
+        name: String,
+        version: String,
+        functions: Vec<CorePluginFunction>,
+    ) -> Self {
         Self {
             id,
             name,
+            version,
+            description: String::new(),
+            plugin_store_url: String::new(),
+            internal: None,
+            verified: false,
+            deprecated: None,
+            installed_at: None,
+            updated_at: None,
             functions,
+            modules: Vec::new(),
+            wasm_modules: Vec::new(),
+            lifecycle: None,
+            config: PluginConfig::default(),
+            signature: None,
         }
     }
 
-    /// Creates a CorePluginPackage from a proto PluginPackage and function list.
+    /// Creates a new CorePluginPackage from a WASM module, with a function for each function the
+    /// module exports. The ID of each function is `<id>.<export>`.
+    ///
+    /// # Arguments
+    /// * `id` - Unique ID of the package
+    /// * `name` - Package name
+    /// * `version` - Semantic version of the package, e.g. `1.2.0`
+    /// * `module` - WASM module of the package
+    /// * `permissions` - Permissions required by the exported functions, keyed by export name
+    pub fn new_from_wasm_module(
+        id: String,
+        name: String,
+        version: String,
+        module: CorePluginWasmModule,
+        permissions: &HashMap<String, Vec<Permission>>,
+    ) -> Self {
+        let functions = module
+            .exports()
+            .iter()
+            .map(|export| CorePluginFunction {
+                id: format!("{id}.{export}"),
+                name: export.clone(),
+                func: CorePluginFunctionBody::WasmExport {
+                    module: module.path.clone(),
+                    name: export.clone(),
+                },
+                description: String::new(),
+                permissions: permissions.get(export).cloned().unwrap_or_default(),
+                schema: None,
+            })
+            .collect();
+        Self {
+            wasm_modules: vec![module],
+            ..Self::new_with_version(id, name, version, functions)
+        }
+    }
+
+    /// Creates a new CorePluginPackage whose functions may be exported by the given source modules.
+    ///
+    /// # Arguments
+    /// * `id` - Unique ID of the package
+    /// * `name` - Package name
+    /// * `version` - Semantic version of the package, e.g. `1.2.0`
+    /// * `functions` - List of functions included in the package
+    /// * `modules` - Source modules of the package
+    pub fn new_with_modules(
+        id: String,
+        name: String,
+        version: String,
+        functions: Vec<CorePluginFunction>,
+        modules: Vec<CorePluginModule>,
+    ) -> Self {
+        Self {
+            modules,
+            ..Self::new_with_version(id, name, version, functions)
+        }
+    }
+
+    /// Creates a CorePluginPackage whose functions are all served by a gRPC plugin process,
+    /// from the package the process describes.
+    ///
+    /// # Arguments
+    /// * `plugin_package` - PluginPackage described by the process
+    /// * `process` - Process that serves the package
+    pub fn new_from_grpc_plugin(
+        plugin_package: &PluginPackage,
+        process: Arc<GrpcPluginProcess>,
+    ) -> Self {
+        let functions = plugin_package
+            .functions
+            .iter()
+            .map(|plugin_function| CorePluginFunction {
+                id: plugin_function.function_id.clone(),
+                name: plugin_function.function_name.clone(),
+                func: CorePluginFunctionBody::Grpc(process.clone()),
+                description: plugin_function.description.clone(),
+                permissions: plugin_function.permissions.clone(),
+                schema: None,
+            })
+            .collect();
+        Self::new_from_plugin_package(plugin_package, functions)
+    }
+
+    /// Creates a CorePluginPackage served by a gRPC plugin process, asking the process for its
+    /// package. The process is spawned if it is not running.
+    ///
+    /// This blocks the current thread, and must not be called from within an async runtime.
+    /// Use `GrpcPluginProcess::describe_package` and `new_from_grpc_plugin` there instead.
+    ///
+    /// # Arguments
+    /// * `process` - Process that serves the package
+    ///
+    /// # Errors
+    /// Returns a `GrpcPluginError` if the process cannot describe its package.
+    pub fn new_from_grpc_process(process: Arc<GrpcPluginProcess>) -> Result<Self, GrpcPluginError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| GrpcPluginError::Connect {
+                uri: process.endpoint().uri(),
+                message: e.to_string(),
+            })?;
+        let plugin_package = runtime.block_on(process.describe_package())?;
+        Ok(Self::new_from_grpc_plugin(&plugin_package, process))
+    }
+
+    /// Creates a CorePluginPackage whose functions are all provided by a native plugin, from
+    /// the package its library declares. Native plugins are loaded with
+    /// `native::load_native_plugin` or `native::load_native_plugins`.
+    ///
+    /// # Arguments
+    /// * `plugin_package` - PluginPackage declared by the library
+    /// * `library` - Library that provides the functions
+    pub fn new_from_native_plugin(
+        plugin_package: &PluginPackage,
+        library: Arc<NativePluginLibrary>,
+    ) -> Self {
+        let functions = plugin_package
+            .functions
+            .iter()
+            .map(|plugin_function| CorePluginFunction {
+                id: plugin_function.function_id.clone(),
+                name: plugin_function.function_name.clone(),
+                func: CorePluginFunctionBody::Native(library.clone()),
+                description: plugin_function.description.clone(),
+                permissions: plugin_function.permissions.clone(),
+                schema: None,
+            })
+            .collect();
+        Self::new_from_plugin_package(plugin_package, functions)
+    }
+
+    /// Creates a CorePluginPackage from a proto PluginPackage and function list, keeping the
+    /// metadata of the proto. The functions of the proto are replaced by the given ones; use
+    /// `TryFrom` to match the functions of the proto to their bodies instead.
+    ///
+    /// The `verified` flag of the proto is ignored, see `CorePluginPackage::verified`.
     ///
     /// # Arguments
     /// * `plugin_package` - PluginPackage defined in proto
@@ -102,10 +551,293 @@ impl CorePluginPackage {
         Self {
             id: plugin_package.package_id.clone(),
             name: plugin_package.package_name.clone(),
+            version: plugin_package.package_version.clone(),
+            description: plugin_package.description.clone(),
+            plugin_store_url: plugin_package.plugin_store_url.clone(),
+            internal: plugin_package.internal_plugin,
+            verified: false,
+            deprecated: plugin_package.deprecated,
+            installed_at: plugin_package.installed_at,
+            updated_at: plugin_package.updated_at,
             functions,
+            modules: Vec::new(),
+            wasm_modules: Vec::new(),
+            lifecycle: None,
+            config: PluginConfig::default(),
+            signature: None,
+        }
+    }
+
+    /// Returns true if the signature of the package has been verified by the trust store of the
+    /// `PluginRegistry` it is registered in. Packages that are not registered are not verified,
+    /// whatever the `verified` flag of the proto they were created from.
+    pub fn verified(&self) -> bool {
+        self.verified
+    }
+
+    /// Verifies the signature of the package, and sets `verified` to the result.
+    ///
+    /// # Arguments
+    /// * `trust_store` - Publisher keys the signature is verified with
+    ///
+    /// # Errors
+    /// Returns the `SignatureError` of `PluginTrustStore::verify`.
+    pub(crate) fn verify_signature(
+        &mut self,
+        trust_store: &PluginTrustStore,
+    ) -> Result<(), SignatureError> {
+        let result = trust_store.verify(self);
+        self.verified = result.is_ok();
+        result
+    }
+}
+
+impl From<&CorePluginFunction> for PluginFunction {
+    fn from(function: &CorePluginFunction) -> Self {
+        Self {
+            function_id: function.id.clone(),
+            function_name: function.name.clone(),
+            description: function.description.clone(),
+            permissions: function.permissions.clone(),
+        }
+    }
+}
+
+/// Creates a CorePluginFunction from a proto PluginFunction and the OpDecl that implements it,
+/// like `CorePluginFunction::new_from_plugin_function`.
+impl From<(PluginFunction, OpDecl)> for CorePluginFunction {
+    fn from((plugin_function, function): (PluginFunction, OpDecl)) -> Self {
+        Self::new_from_plugin_function(&plugin_function, function)
+    }
+}
+
+/// Parts of a CorePluginFunction that a proto PluginFunction does not carry.
+#[derive(Clone)]
+pub struct CorePluginFunctionImpl {
+    /// Body of the function
+    pub func: CorePluginFunctionBody,
+    /// Schemas of the arguments and return value
+    pub schema: Option<FunctionSchema>,
+}
+
+impl From<OpDecl> for CorePluginFunctionImpl {
+    fn from(function: OpDecl) -> Self {
+        Self {
+            func: CorePluginFunctionBody::Op(Cow::Owned(function)),
+            schema: None,
+        }
+    }
+}
+
+impl From<(PluginFunction, CorePluginFunctionImpl)> for CorePluginFunction {
+    fn from((plugin_function, function): (PluginFunction, CorePluginFunctionImpl)) -> Self {
+        Self {
+            id: plugin_function.function_id,
+            name: plugin_function.function_name,
+            func: function.func,
+            description: plugin_function.description,
+            permissions: plugin_function.permissions,
+            schema: function.schema,
+        }
+    }
+}
+
+impl From<CorePluginFunction> for (PluginFunction, CorePluginFunctionImpl) {
+    fn from(function: CorePluginFunction) -> Self {
+        (
+            PluginFunction::from(&function),
+            CorePluginFunctionImpl {
+                func: function.func,
+                schema: function.schema,
+            },
+        )
+    }
+}
+
+/// Parts of a CorePluginPackage that a proto PluginPackage does not carry.
+#[derive(Clone, Default)]
+pub struct CorePluginPackageImpl {
+    /// Bodies and schemas of the functions, keyed by function ID
+    pub functions: HashMap<String, CorePluginFunctionImpl>,
+    /// Source modules of the functions implemented in JavaScript or TypeScript
+    pub modules: Vec<CorePluginModule>,
+    /// WASM modules of the functions implemented in WebAssembly
+    pub wasm_modules: Vec<CorePluginWasmModule>,
+    /// Hooks called around the runs that use the package
+    pub lifecycle: Option<CorePluginLifecycle>,
+    /// Configuration of the package
+    pub config: PluginConfig,
+    /// Signature of the package by its publisher
+    pub signature: Option<PluginSignature>,
+}
+
+/// Error returned when the functions of a proto PluginPackage cannot be matched to their bodies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginConversionError {
+    /// A function of the proto has no body
+    MissingFunction {
+        /// ID of the function
+        function_id: String,
+    },
+    /// A body was given for a function the proto does not declare
+    UnknownFunction {
+        /// ID of the function
+        function_id: String,
+    },
+    /// Several functions have the same ID
+    DuplicateFunction {
+        /// ID of the functions
+        function_id: String,
+    },
+}
+
+impl fmt::Display for PluginConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginConversionError::MissingFunction { function_id } => {
+                write!(f, "Function {function_id} has no body")
+            }
+            PluginConversionError::UnknownFunction { function_id } => {
+                write!(f, "Function {function_id} is not declared by the package")
+            }
+            PluginConversionError::DuplicateFunction { function_id } => {
+                write!(f, "Function {function_id} is declared more than once")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PluginConversionError {}
+
+/// Converts a CorePluginPackage to a proto PluginPackage. Flags that are unset in the package
+/// are unset in the proto, except `verified`, which is always set to `CorePluginPackage::verified`.
+impl From<&CorePluginPackage> for PluginPackage {
+    fn from(package: &CorePluginPackage) -> Self {
+        Self {
+            package_id: package.id.clone(),
+            package_name: package.name.clone(),
+            package_version: package.version.clone(),
+            description: package.description.clone(),
+            functions: package.functions.iter().map(PluginFunction::from).collect(),
+            plugin_store_url: package.plugin_store_url.clone(),
+            internal_plugin: package.internal,
+            verified: Some(package.verified),
+            deprecated: package.deprecated,
+            installed_at: package.installed_at,
+            updated_at: package.updated_at,
+        }
+    }
+}
+
+impl From<CorePluginPackage> for PluginPackage {
+    fn from(package: CorePluginPackage) -> Self {
+        Self::from(&package)
+    }
+}
+
+/// Splits a CorePluginPackage into a proto PluginPackage and the parts the proto does not
+/// carry, which `TryFrom<(PluginPackage, CorePluginPackageImpl)>` joins back together.
+impl From<CorePluginPackage> for (PluginPackage, CorePluginPackageImpl) {
+    fn from(package: CorePluginPackage) -> Self {
+        let plugin_package = PluginPackage::from(&package);
+        let functions = package
+            .functions
+            .into_iter()
+            .map(|function| {
+                let id = function.id.clone();
+                let (_, function) = function.into();
+                (id, function)
+            })
+            .collect();
+        (
+            plugin_package,
+            CorePluginPackageImpl {
+                functions,
+                modules: package.modules,
+                wasm_modules: package.wasm_modules,
+                lifecycle: package.lifecycle,
+                config: package.config,
+                signature: package.signature,
+            },
+        )
+    }
+}
+
+/// Creates a CorePluginPackage from a proto PluginPackage and the parts the proto does not
+/// carry. Each function of the proto is matched to its body by ID. The package is not verified
+/// until it is registered again.
+///
+/// # Errors
+/// Returns a `PluginConversionError` if a function of the proto has no body, a body does not
+/// belong to any function of the proto, or the proto declares a function more than once.
+impl TryFrom<(PluginPackage, CorePluginPackageImpl)> for CorePluginPackage {
+    type Error = PluginConversionError;
+
+    fn try_from(
+        (plugin_package, mut parts): (PluginPackage, CorePluginPackageImpl),
+    ) -> Result<Self, Self::Error> {
+        let mut functions: Vec<CorePluginFunction> = Vec::new();
+        for plugin_function in &plugin_package.functions {
+            let function_id = &plugin_function.function_id;
+            let Some(function) = parts.functions.remove(function_id) else {
+                return Err(if functions.iter().any(|f| &f.id == function_id) {
+                    PluginConversionError::DuplicateFunction {
+                        function_id: function_id.clone(),
+                    }
+                } else {
+                    PluginConversionError::MissingFunction {
+                        function_id: function_id.clone(),
+                    }
+                });
+            };
+            functions.push(CorePluginFunction::from((
+                plugin_function.clone(),
+                function,
+            )));
+        }
+        if let Some(function_id) = parts.functions.into_keys().min() {
+            return Err(PluginConversionError::UnknownFunction { function_id });
         }
+        Ok(Self {
+            modules: parts.modules,
+            wasm_modules: parts.wasm_modules,
+            lifecycle: parts.lifecycle,
+            config: parts.config,
+            signature: parts.signature,
+            ..Self::new_from_plugin_package(&plugin_package, functions)
+        })
     }
 }
+
+/// Creates a CorePluginPackage from a proto PluginPackage and the functions that implement it.
+/// Each function of the proto is matched to the function with the same ID, whose body and schema
+/// are kept; its name, description and permissions are taken from the proto.
+///
+/// # Errors
+/// Returns a `PluginConversionError` if a function of the proto has no match, a function does
+/// not match any function of the proto, or several functions have the same ID.
+impl TryFrom<(PluginPackage, Vec<CorePluginFunction>)> for CorePluginPackage {
+    type Error = PluginConversionError;
+
+    fn try_from(
+        (plugin_package, functions): (PluginPackage, Vec<CorePluginFunction>),
+    ) -> Result<Self, Self::Error> {
+        let mut parts = CorePluginPackageImpl::default();
+        for function in functions {
+            let function_id = function.id.clone();
+            let (_, function) = function.into();
+            if parts
+                .functions
+                .insert(function_id.clone(), function)
+                .is_some()
+            {
+                return Err(PluginConversionError::DuplicateFunction { function_id });
+            }
+        }
+        Self::try_from((plugin_package, parts))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -154,12 +886,78 @@ mod tests {
         assert_eq!(func.description, "description");
     }
 
+    #[test]
+    fn test_core_plugin_function_new_with_schema() {
+        let schema =
+            FunctionSchema::new(Vec::new(), Some(serde_json::json!({"type": "integer"}))).unwrap();
+        let func = CorePluginFunction::new_with_schema(
+            "id".to_string(),
+            "name".to_string(),
+            "description".to_string(),
+            dummy_op(),
+            vec![],
+            schema.clone(),
+        );
+        assert_eq!(func.schema, Some(schema));
+        assert!(func.permissions.is_empty());
+    }
+
+    #[test]
+    fn test_core_plugin_function_new_with_export() {
+        let func = CorePluginFunction::new_with_export(
+            "id".to_string(),
+            "name".to_string(),
+            "description".to_string(),
+            "mod.ts".to_string(),
+            "send".to_string(),
+            vec![],
+        );
+        assert!(func.op().is_none());
+        assert_eq!(func.api_name(), "id");
+        assert!(matches!(
+            func.func,
+            CorePluginFunctionBody::Export { ref module, ref name } if module == "mod.ts" && name == "send"
+        ));
+
+        let func = CorePluginFunction::new(
+            "id".to_string(),
+            "name".to_string(),
+            "description".to_string(),
+            dummy_op(),
+        );
+        assert_eq!(func.api_name(), "dummy_op");
+    }
+
     #[test]
     fn test_core_plugin_function_new_from_plugin_function() {
         let pf = dummy_plugin_function();
         let func = CorePluginFunction::new_from_plugin_function(&pf, dummy_op());
         assert_eq!(func.id, pf.function_id);
         assert_eq!(func.name, pf.function_name);
+        assert_eq!(func.permissions, pf.permissions);
+    }
+
+    #[test]
+    fn test_core_plugin_function_missing_permissions() {
+        use crate::proto::sapphillon::v1::PermissionType;
+
+        let read_tmp = Permission {
+            permission_type: PermissionType::Read as i32,
+            resource: vec!["/tmp".to_string()],
+            ..Default::default()
+        };
+        let func = CorePluginFunction::new_with_permissions(
+            "id".to_string(),
+            "name".to_string(),
+            "desc".to_string(),
+            dummy_op(),
+            vec![read_tmp.clone()],
+        );
+        assert!(
+            func.missing_permissions(std::slice::from_ref(&read_tmp))
+                .is_empty()
+        );
+        assert_eq!(func.missing_permissions(&[]), vec![read_tmp]);
     }
 
     #[test]
@@ -173,6 +971,7 @@ mod tests {
         let pkg = CorePluginPackage::new("pid".to_string(), "pname".to_string(), vec![f]);
         assert_eq!(pkg.id, "pid");
         assert_eq!(pkg.name, "pname");
+        assert!(pkg.version.is_empty());
         assert_eq!(pkg.functions.len(), 1);
     }
 
@@ -184,6 +983,191 @@ mod tests {
         let pkg = CorePluginPackage::new_from_plugin_package(&pp, vec![f]);
         assert_eq!(pkg.id, pp.package_id);
         assert_eq!(pkg.name, pp.package_name);
+        assert_eq!(pkg.version, pp.package_version);
+        assert_eq!(pkg.deprecated, None);
         assert_eq!(pkg.functions.len(), 1);
     }
+
+    #[test]
+    fn test_core_plugin_package_proto_round_trip() {
+        use crate::config::PluginConfigValue;
+        use crate::lifecycle::PluginLifecycle;
+        use crate::proto::sapphillon::v1::{Permission, PermissionLevel, PermissionType};
+        use crate::schema::ParameterSchema;
+
+        struct Hooks;
+        impl PluginLifecycle for Hooks {}
+
+        let pp = PluginPackage {
+            functions: vec![
+                PluginFunction {
+                    permissions: vec![Permission {
+                        display_name: "Read".to_string(),
+                        permission_type: PermissionType::Read as i32,
+                        resource: vec!["/tmp".to_string()],
+                        permission_level: PermissionLevel::Unspecified as i32,
+                        ..Default::default()
+                    }],
+                    ..dummy_plugin_function()
+                },
+                PluginFunction {
+                    function_id: "export".to_string(),
+                    ..dummy_plugin_function()
+                },
+            ],
+            plugin_store_url: "https://plugins.example.com/pid".to_string(),
+            internal_plugin: Some(true),
+            verified: Some(false),
+            deprecated: None,
+            installed_at: Some(Timestamp {
+                seconds: 1_700_000_000,
+                nanos: 1,
+            }),
+            updated_at: Some(Timestamp {
+                seconds: 1_700_000_100,
+                nanos: 2,
+            }),
+            ..dummy_plugin_package()
+        };
+        let schema = FunctionSchema::new(
+            vec![ParameterSchema::new(
+                "path".to_string(),
+                String::new(),
+                serde_json::json!({"type": "string"}),
+            )],
+            None,
+        )
+        .unwrap();
+        let module = CorePluginModule::new(
+            "mod.ts".to_string(),
+            "export function send() {}".to_string(),
+            WorkflowLanguage::Typescript,
+        );
+        let wasm_module = CorePluginWasmModule::new(
+            "empty.wasm".to_string(),
+            b"\0asm\x01\0\0\0",
+            WasmLimits {
+                max_memory_pages: 1,
+                fuel: Some(10),
+            },
+        )
+        .unwrap();
+        let lifecycle = CorePluginLifecycle::new(Arc::new(Hooks));
+        let mut config = PluginConfig::new();
+        config.insert("retries".to_string(), PluginConfigValue::Integer(3));
+        let signature = PluginSignature {
+            key_id: "publisher".to_string(),
+            signature: [7; 64],
+        };
+        let parts = CorePluginPackageImpl {
+            functions: HashMap::from([
+                (
+                    "fid".to_string(),
+                    CorePluginFunctionImpl {
+                        schema: Some(schema.clone()),
+                        ..CorePluginFunctionImpl::from(dummy_op())
+                    },
+                ),
+                (
+                    "export".to_string(),
+                    CorePluginFunctionImpl {
+                        func: CorePluginFunctionBody::Export {
+                            module: "mod.ts".to_string(),
+                            name: "send".to_string(),
+                        },
+                        schema: None,
+                    },
+                ),
+            ]),
+            modules: vec![module.clone()],
+            wasm_modules: vec![wasm_module.clone()],
+            lifecycle: Some(lifecycle.clone()),
+            config: config.clone(),
+            signature: Some(signature.clone()),
+        };
+
+        let pkg = CorePluginPackage::try_from((pp.clone(), parts)).unwrap();
+        assert_eq!(pkg.description, "desc");
+        assert_eq!((pkg.internal, pkg.deprecated), (Some(true), None));
+        assert!(!pkg.verified());
+        assert_eq!(PluginPackage::from(&pkg), pp);
+
+        // Splitting the package and joining it back loses nothing
+        let (proto, parts) = pkg.into();
+        assert_eq!(proto, pp);
+        let round_trip = CorePluginPackage::try_from((proto, parts)).unwrap();
+        assert_eq!(PluginPackage::from(&round_trip), pp);
+        assert_eq!(round_trip.functions[0].op().unwrap().name, "dummy_op");
+        assert_eq!(round_trip.functions[0].schema, Some(schema));
+        assert!(matches!(
+            round_trip.functions[1].func,
+            CorePluginFunctionBody::Export { ref module, ref name } if module == "mod.ts" && name == "send"
+        ));
+        assert!(round_trip.functions[1].schema.is_none());
+        assert_eq!(round_trip.modules, vec![module]);
+        assert_eq!(round_trip.wasm_modules, vec![wasm_module]);
+        assert!(round_trip.lifecycle == Some(lifecycle));
+        assert!(round_trip.config == config);
+        assert_eq!(round_trip.signature, Some(signature));
+
+        // Unset flags stay unset, and packages are not verified by converting them
+        let pkg = CorePluginPackage::new_from_plugin_package(
+            &PluginPackage {
+                verified: Some(true),
+                ..dummy_plugin_package()
+            },
+            vec![],
+        );
+        let converted = PluginPackage::from(&pkg);
+        assert_eq!(converted.internal_plugin, None);
+        assert_eq!(converted.verified, Some(false));
+        assert_eq!(converted.deprecated, None);
+    }
+
+    #[test]
+    fn test_core_plugin_package_try_from_functions() {
+        let pp = dummy_plugin_package();
+        let function = |id: &str| {
+            CorePluginFunction::new(
+                id.to_string(),
+                "other name".to_string(),
+                String::new(),
+                dummy_op(),
+            )
+        };
+
+        let pkg = CorePluginPackage::try_from((pp.clone(), vec![function("fid")])).unwrap();
+        assert_eq!(pkg.functions[0].name, "fname");
+        assert_eq!(pkg.functions[0].op().unwrap().name, "dummy_op");
+
+        assert_eq!(
+            CorePluginPackage::try_from((pp.clone(), vec![function("other")])).err(),
+            Some(PluginConversionError::MissingFunction {
+                function_id: "fid".to_string()
+            })
+        );
+        assert_eq!(
+            CorePluginPackage::try_from((pp.clone(), vec![function("fid"), function("other")]))
+                .err(),
+            Some(PluginConversionError::UnknownFunction {
+                function_id: "other".to_string()
+            })
+        );
+        assert_eq!(
+            CorePluginPackage::try_from((pp.clone(), vec![function("fid"), function("fid")])).err(),
+            Some(PluginConversionError::DuplicateFunction {
+                function_id: "fid".to_string()
+            })
+        );
+        let duplicated = PluginPackage {
+            functions: vec![dummy_plugin_function(), dummy_plugin_function()],
+            ..pp
+        };
+        assert_eq!(
+            CorePluginPackage::try_from((duplicated, vec![function("fid")])).err(),
+            Some(PluginConversionError::DuplicateFunction {
+                function_id: "fid".to_string()
+            })
+        );
+    }
 }
@@ -0,0 +1,476 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::cel::{self, Activation, CelError, map_value};
+use crate::permission::PermissionRequest;
+use crate::proto::google::api::expr::v1alpha1::{ParsedExpr, Value, value::Kind};
+use crate::proto::google::r#type::Expr;
+use crate::proto::sapphillon::v1::{PermissionLevel, PermissionType};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+/// Action taken on a permission request matched by a policy rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    /// Allow the requested access without asking.
+    Approve,
+    /// Deny the requested access without asking.
+    Deny,
+    /// Ask the `PermissionApprover` of the run.
+    Escalate,
+}
+
+/// Error returned when a permission policy cannot be loaded.
+#[derive(Debug)]
+pub enum PolicyError {
+    /// The policy file could not be read.
+    Io(std::io::Error),
+    /// The policy file is not a valid policy document.
+    Format(serde_json::Error),
+    /// The expression of a rule is not valid CEL.
+    Expression { location: String, source: CelError },
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Io(e) => write!(f, "Failed to read policy: {e}"),
+            PolicyError::Format(e) => write!(f, "Invalid policy: {e}"),
+            PolicyError::Expression { location, source } => {
+                write!(f, "Invalid policy expression at {location}: {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PolicyError::Io(e) => Some(e),
+            PolicyError::Format(e) => Some(e),
+            PolicyError::Expression { source, .. } => Some(source),
+        }
+    }
+}
+
+/// A CEL expression and the action taken when it evaluates to `true`.
+///
+/// Expressions are written in the subset of CEL documented in `crate::cel`.
+/// The expression is evaluated once per requested resource with the variables
+/// - `permission`: map with `type`, `level`, `display_name`, `description` and `resource`
+///   (list of all requested resources) of the requested permission
+/// - `resource`: the resource being decided, or `""` if the permission has no resources
+/// - `workflow`: map with the `id` of the requesting workflow
+/// - `api_name`: name of the op or plugin function that requests the permission
+/// - `READ`, `WRITE`, `EXECUTE`, `MEDIUM`, `HIGH` and `CRITICAL`: the values of the
+///   corresponding `PermissionType` and `PermissionLevel`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyRule {
+    /// CEL expression of the rule, with its title and description
+    pub expr: Expr,
+    /// Action taken when the expression evaluates to `true`
+    pub action: PolicyAction,
+    parsed: ParsedExpr,
+}
+
+impl PolicyRule {
+    /// Creates a new PolicyRule, parsing its expression.
+    ///
+    /// # Arguments
+    /// * `expr` - CEL expression of the rule
+    /// * `action` - Action taken when the expression evaluates to `true`
+    ///
+    /// # Errors
+    /// Returns `PolicyError::Expression` if the expression is not valid CEL.
+    pub fn new(expr: Expr, action: PolicyAction) -> Result<Self, PolicyError> {
+        let parsed = cel::parse(&expr.expression).map_err(|source| PolicyError::Expression {
+            location: expr.location.clone(),
+            source,
+        })?;
+        Ok(Self {
+            expr,
+            action,
+            parsed,
+        })
+    }
+
+    /// Returns the title of the rule, or its expression if it has no title.
+    pub fn name(&self) -> &str {
+        if self.expr.title.is_empty() {
+            &self.expr.expression
+        } else {
+            &self.expr.title
+        }
+    }
+
+    fn matches(&self, activation: &Activation) -> Result<bool, CelError> {
+        match cel::evaluate(&self.parsed, activation)?.kind {
+            Some(Kind::BoolValue(matched)) => Ok(matched),
+            _ => Err(CelError::Evaluation(
+                "policy expression must evaluate to a bool".to_string(),
+            )),
+        }
+    }
+}
+
+/// Outcome of evaluating a permission policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyDecision {
+    pub action: PolicyAction,
+    /// Name of the rule that decided the action, `None` if no rule matched
+    pub rule: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyDocument {
+    rules: Vec<PolicyRuleDocument>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyRuleDocument {
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    description: String,
+    expression: String,
+    action: PolicyAction,
+}
+
+/// Ordered CEL rules that decide permission requests before the user is asked.
+///
+/// # Policy Files
+/// Policies are JSON documents of the form
+/// `{"rules": [{"title": "...", "description": "...", "expression": "...", "action": "approve"}]}`,
+/// where `action` is one of `approve`, `deny` or `escalate`, and `title` and `description` are optional.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PermissionPolicy {
+    pub rules: Vec<PolicyRule>,
+}
+
+impl PermissionPolicy {
+    /// Creates a new PermissionPolicy from the given rules.
+    ///
+    /// # Arguments
+    /// * `rules` - Rules in the order they are evaluated
+    pub fn new(rules: Vec<PolicyRule>) -> Self {
+        Self { rules }
+    }
+
+    /// Parses a policy document.
+    ///
+    /// # Arguments
+    /// * `json` - Policy document
+    /// * `location` - Name of the document, recorded in the `location` of each rule
+    ///
+    /// # Errors
+    /// Returns `PolicyError::Format` if the document is malformed, and
+    /// `PolicyError::Expression` if an expression is not valid CEL.
+    pub fn new_from_json(json: &str, location: &str) -> Result<Self, PolicyError> {
+        let document: PolicyDocument = serde_json::from_str(json).map_err(PolicyError::Format)?;
+        let rules = document
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let expr = Expr {
+                    expression: rule.expression,
+                    title: rule.title,
+                    description: rule.description,
+                    location: format!("{location}:rules[{i}]"),
+                };
+                PolicyRule::new(expr, rule.action)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new(rules))
+    }
+
+    /// Loads a policy document from a file.
+    ///
+    /// # Arguments
+    /// * `path` - Path of the policy file
+    ///
+    /// # Errors
+    /// Returns `PolicyError::Io` if the file cannot be read, otherwise the errors of `new_from_json`.
+    pub fn new_from_file(path: impl AsRef<Path>) -> Result<Self, PolicyError> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).map_err(PolicyError::Io)?;
+        Self::new_from_json(&json, &path.display().to_string())
+    }
+
+    /// Decides a permission request.
+    ///
+    /// Each requested resource is decided by the first rule that matches it; a resource that no
+    /// rule matches, or for which a rule fails to evaluate, is escalated. The request is denied if
+    /// any resource is denied, escalated if any resource is escalated, and approved otherwise.
+    ///
+    /// # Arguments
+    /// * `request` - Permission request to decide
+    pub fn evaluate(&self, request: &PermissionRequest) -> PolicyDecision {
+        let resources: Vec<&str> = match request.permission.resource.is_empty() {
+            true => vec![""],
+            false => request
+                .permission
+                .resource
+                .iter()
+                .map(String::as_str)
+                .collect(),
+        };
+
+        let decisions: Vec<PolicyDecision> = resources
+            .into_iter()
+            .map(|resource| self.evaluate_resource(request, resource))
+            .collect();
+        [PolicyAction::Deny, PolicyAction::Escalate]
+            .iter()
+            .find_map(|action| decisions.iter().find(|d| d.action == *action))
+            .or(decisions.first())
+            .cloned()
+            .unwrap()
+    }
+
+    fn evaluate_resource(&self, request: &PermissionRequest, resource: &str) -> PolicyDecision {
+        let activation = activation(request, resource);
+        for rule in &self.rules {
+            match rule.matches(&activation) {
+                Ok(false) => continue,
+                Ok(true) => {
+                    return PolicyDecision {
+                        action: rule.action,
+                        rule: Some(rule.name().to_string()),
+                    };
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Escalating permission request, policy rule {} failed: {e}",
+                        rule.name()
+                    );
+                    return PolicyDecision {
+                        action: PolicyAction::Escalate,
+                        rule: Some(rule.name().to_string()),
+                    };
+                }
+            }
+        }
+        PolicyDecision {
+            action: PolicyAction::Escalate,
+            rule: None,
+        }
+    }
+}
+
+/// Builds the variables of a policy expression.
+fn activation(request: &PermissionRequest, resource: &str) -> Activation {
+    let permission = &request.permission;
+    let resources: Vec<Value> = permission
+        .resource
+        .iter()
+        .map(|r| r.as_str().into())
+        .collect();
+
+    let mut activation = Activation::new();
+    activation.insert(
+        "permission",
+        map_value([
+            ("type", (permission.permission_type as i64).into()),
+            ("level", (permission.permission_level as i64).into()),
+            ("display_name", permission.display_name.as_str().into()),
+            ("description", permission.description.as_str().into()),
+            ("resource", resources.into()),
+        ]),
+    );
+    activation.insert("resource", resource.into());
+    activation.insert(
+        "workflow",
+        map_value([("id", request.workflow_id.as_str().into())]),
+    );
+    activation.insert("api_name", request.api_name.as_str().into());
+    for (name, value) in [
+        ("READ", PermissionType::Read as i64),
+        ("WRITE", PermissionType::Write as i64),
+        ("EXECUTE", PermissionType::Execute as i64),
+        ("MEDIUM", PermissionLevel::Medium as i64),
+        ("HIGH", PermissionLevel::High as i64),
+        ("CRITICAL", PermissionLevel::Critical as i64),
+    ] {
+        activation.insert(name, value.into());
+    }
+    activation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::sapphillon::v1::Permission;
+
+    const POLICY: &str = r#"{
+        "rules": [
+            {
+                "title": "No critical writes",
+                "expression": "permission.type == WRITE && permission.level == CRITICAL",
+                "action": "deny"
+            },
+            {
+                "title": "Log buckets",
+                "description": "Reading logs is always fine",
+                "expression": "permission.level <= HIGH && resource.startsWith(\"buckets/logs\")",
+                "action": "approve"
+            },
+            {
+                "expression": "workflow.id == 'trusted' && api_name != 'op_exec'",
+                "action": "approve"
+            }
+        ]
+    }"#;
+
+    fn request(
+        permission_type: PermissionType,
+        level: PermissionLevel,
+        resource: &[&str],
+    ) -> PermissionRequest {
+        PermissionRequest {
+            workflow_id: "wid".to_string(),
+            api_name: "op_read".to_string(),
+            permission: Permission {
+                permission_type: permission_type as i32,
+                permission_level: level as i32,
+                resource: resource.iter().map(|r| r.to_string()).collect(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_permission_policy_new_from_json() {
+        let policy = PermissionPolicy::new_from_json(POLICY, "policy.json").unwrap();
+        assert_eq!(policy.rules.len(), 3);
+        assert_eq!(policy.rules[0].action, PolicyAction::Deny);
+        assert_eq!(
+            policy.rules[1].expr.description,
+            "Reading logs is always fine"
+        );
+        assert_eq!(policy.rules[1].expr.location, "policy.json:rules[1]");
+        assert_eq!(
+            policy.rules[2].name(),
+            "workflow.id == 'trusted' && api_name != 'op_exec'"
+        );
+    }
+
+    #[test]
+    fn test_permission_policy_new_from_json_errors() {
+        let err = PermissionPolicy::new_from_json(
+            r#"{"rules": [{"expression": "a &&", "action": "deny"}]}"#,
+            "policy.json",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            PolicyError::Expression { ref location, .. } if location == "policy.json:rules[0]"
+        ));
+
+        let err = PermissionPolicy::new_from_json(
+            r#"{"rules": [{"expression": "true", "action": "allow"}]}"#,
+            "policy.json",
+        )
+        .unwrap_err();
+        assert!(matches!(err, PolicyError::Format(_)));
+
+        assert!(matches!(
+            PermissionPolicy::new_from_file("/nonexistent/policy.json").unwrap_err(),
+            PolicyError::Io(_)
+        ));
+    }
+
+    #[test]
+    fn test_permission_policy_evaluate() {
+        let policy = PermissionPolicy::new_from_json(POLICY, "policy.json").unwrap();
+
+        let decision = policy.evaluate(&request(
+            PermissionType::Read,
+            PermissionLevel::High,
+            &["buckets/logs/2025"],
+        ));
+        assert_eq!(decision.action, PolicyAction::Approve);
+        assert_eq!(decision.rule.as_deref(), Some("Log buckets"));
+
+        let decision = policy.evaluate(&request(
+            PermissionType::Write,
+            PermissionLevel::Critical,
+            &["buckets/logs/2025"],
+        ));
+        assert_eq!(decision.action, PolicyAction::Deny);
+        assert_eq!(decision.rule.as_deref(), Some("No critical writes"));
+
+        let decision = policy.evaluate(&request(
+            PermissionType::Read,
+            PermissionLevel::Critical,
+            &["buckets/logs/2025"],
+        ));
+        assert_eq!(decision.action, PolicyAction::Escalate);
+        assert_eq!(decision.rule, None);
+    }
+
+    #[test]
+    fn test_permission_policy_evaluate_every_resource() {
+        let policy = PermissionPolicy::new_from_json(POLICY, "policy.json").unwrap();
+
+        // One resource that no rule matches escalates the whole request
+        let decision = policy.evaluate(&request(
+            PermissionType::Read,
+            PermissionLevel::Medium,
+            &["buckets/logs/a", "buckets/secrets"],
+        ));
+        assert_eq!(decision.action, PolicyAction::Escalate);
+
+        let mut trusted = request(PermissionType::Read, PermissionLevel::Medium, &[]);
+        trusted.workflow_id = "trusted".to_string();
+        assert_eq!(policy.evaluate(&trusted).action, PolicyAction::Approve);
+    }
+
+    #[test]
+    fn test_permission_policy_evaluate_error_escalates() {
+        let policy = PermissionPolicy::new(vec![
+            PolicyRule::new(
+                Expr {
+                    expression: "permission.owner == 'me'".to_string(),
+                    ..Default::default()
+                },
+                PolicyAction::Deny,
+            )
+            .unwrap(),
+            PolicyRule::new(
+                Expr {
+                    expression: "true".to_string(),
+                    ..Default::default()
+                },
+                PolicyAction::Approve,
+            )
+            .unwrap(),
+        ]);
+        let decision = policy.evaluate(&request(
+            PermissionType::Read,
+            PermissionLevel::Medium,
+            &["/tmp"],
+        ));
+        assert_eq!(decision.action, PolicyAction::Escalate);
+        assert_eq!(decision.rule.as_deref(), Some("permission.owner == 'me'"));
+    }
+}
@@ -7,6 +7,11 @@ pub mod sapphillon {
     pub mod v1 {
         include!("proto/sapphillon.v1.rs");
     }
+    pub mod plugin {
+        pub mod v1 {
+            include!("proto/sapphillon.plugin.v1.rs");
+        }
+    }
 }
 
 pub mod google {
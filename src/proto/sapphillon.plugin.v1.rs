@@ -0,0 +1,137 @@
+// @generated
+// This file is @generated by prost-build.
+/// Request of PluginService.DescribePackage.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct DescribePackageRequest {
+}
+/// Response of PluginService.DescribePackage.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DescribePackageResponse {
+    /// Package the process serves.
+    /// Behavior: Required. Every function of the package is invoked through InvokeFunction.
+    #[prost(message, optional, tag="1")]
+    pub plugin_package: ::core::option::Option<super::super::v1::PluginPackage>,
+}
+/// Request of PluginService.InvokeFunction.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InvokeFunctionRequest {
+    /// ID of the function to invoke, as described by DescribePackage.
+    #[prost(string, tag="1")]
+    pub function_id: ::prost::alloc::string::String,
+    /// Unique ID of the invocation, used to correlate log entries.
+    #[prost(string, tag="2")]
+    pub invocation_id: ::prost::alloc::string::String,
+    /// ID of the workflow that invokes the function.
+    #[prost(string, tag="3")]
+    pub workflow_id: ::prost::alloc::string::String,
+    /// Arguments the function is called with, in order.
+    #[prost(oneof="invoke_function_request::Arguments", tags="4, 5")]
+    pub arguments: ::core::option::Option<invoke_function_request::Arguments>,
+}
+/// Nested message and enum types in `InvokeFunctionRequest`.
+pub mod invoke_function_request {
+    /// Arguments the function is called with, in order.
+    #[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Arguments {
+        /// JSON array of the arguments.
+        #[prost(string, tag="4")]
+        JsonArguments(::prost::alloc::string::String),
+        /// List of the arguments.
+        #[prost(message, tag="5")]
+        ProtoArguments(::prost_types::ListValue),
+    }
+}
+/// Response of PluginService.InvokeFunction.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct InvokeFunctionResponse {
+    /// Return value of the function. The function returns `null` when unset.
+    #[prost(oneof="invoke_function_response::Result", tags="1, 2")]
+    pub result: ::core::option::Option<invoke_function_response::Result>,
+}
+/// Nested message and enum types in `InvokeFunctionResponse`.
+pub mod invoke_function_response {
+    /// Return value of the function. The function returns `null` when unset.
+    #[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Result {
+        /// JSON of the return value.
+        #[prost(string, tag="1")]
+        JsonResult(::prost::alloc::string::String),
+        /// Return value.
+        #[prost(message, tag="2")]
+        ProtoResult(::prost_types::Value),
+    }
+}
+/// Request of PluginService.StreamLogs.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StreamLogsRequest {
+    /// ID of the invocation whose log entries are streamed.
+    #[prost(string, tag="1")]
+    pub invocation_id: ::prost::alloc::string::String,
+}
+/// Log entry of an invocation.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LogEntry {
+    /// ID of the invocation that logged the entry.
+    #[prost(string, tag="1")]
+    pub invocation_id: ::prost::alloc::string::String,
+    /// Severity of the entry.
+    #[prost(enumeration="LogLevel", tag="2")]
+    pub level: i32,
+    /// Message of the entry.
+    #[prost(string, tag="3")]
+    pub message: ::prost::alloc::string::String,
+    /// Time when the entry was logged.
+    #[prost(message, optional, tag="4")]
+    pub logged_at: ::core::option::Option<::prost_types::Timestamp>,
+}
+/// Severity of a log entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum LogLevel {
+    /// Default value, treated as LOG_LEVEL_INFO.
+    Unspecified = 0,
+    /// Debugging information.
+    Debug = 1,
+    /// Informational message.
+    Info = 2,
+    /// Something unexpected that the function recovered from.
+    Warn = 3,
+    /// Failure of the function.
+    Error = 4,
+}
+impl LogLevel {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            LogLevel::Unspecified => "LOG_LEVEL_UNSPECIFIED",
+            LogLevel::Debug => "LOG_LEVEL_DEBUG",
+            LogLevel::Info => "LOG_LEVEL_INFO",
+            LogLevel::Warn => "LOG_LEVEL_WARN",
+            LogLevel::Error => "LOG_LEVEL_ERROR",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "LOG_LEVEL_UNSPECIFIED" => Some(Self::Unspecified),
+            "LOG_LEVEL_DEBUG" => Some(Self::Debug),
+            "LOG_LEVEL_INFO" => Some(Self::Info),
+            "LOG_LEVEL_WARN" => Some(Self::Warn),
+            "LOG_LEVEL_ERROR" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+include!("sapphillon.plugin.v1.tonic.rs");
+// @@protoc_insertion_point(module)
\ No newline at end of file
@@ -0,0 +1,582 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Registry of installed plugin packages.
+
+use crate::plugin::CorePluginPackage;
+use crate::proto::sapphillon::v1::{PluginPackage, WorkflowCode};
+use crate::signature::{PluginTrustStore, SignatureError, SignaturePolicy};
+use semver::{Version, VersionReq};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Error returned when a plugin package cannot be registered or resolved.
+#[derive(Debug)]
+pub enum RegistryError {
+    /// The version of a package is not a semantic version.
+    InvalidVersion {
+        package_id: String,
+        version: String,
+        source: semver::Error,
+    },
+    /// A workflow requires a version of a package that is not a semantic version requirement.
+    InvalidRequirement {
+        package_id: String,
+        requirement: String,
+        source: semver::Error,
+    },
+    /// The same version of a package is already registered.
+    DuplicatePackage { package_id: String, version: String },
+    /// A function ID is used twice in a package, or by another package.
+    DuplicateFunctionId {
+        function_id: String,
+        package_id: String,
+    },
+    /// No registered version of a package matches the requirement of a workflow.
+    PackageNotFound {
+        package_id: String,
+        requirement: String,
+    },
+    /// A function required by a workflow is not in any of its resolved packages.
+    FunctionNotFound { function_id: String },
+    /// The signature of a package is refused by the signature policy of the registry.
+    Unverified {
+        package_id: String,
+        version: String,
+        source: SignatureError,
+    },
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::InvalidVersion {
+                package_id,
+                version,
+                source,
+            } => write!(f, "Invalid version {version:?} of {package_id}: {source}"),
+            RegistryError::InvalidRequirement {
+                package_id,
+                requirement,
+                source,
+            } => write!(
+                f,
+                "Invalid version requirement {requirement:?} for {package_id}: {source}"
+            ),
+            RegistryError::DuplicatePackage {
+                package_id,
+                version,
+            } => write!(f, "{package_id} {version} is already registered"),
+            RegistryError::DuplicateFunctionId {
+                function_id,
+                package_id,
+            } => write!(
+                f,
+                "Function ID {function_id} is already used by {package_id}"
+            ),
+            RegistryError::PackageNotFound {
+                package_id,
+                requirement,
+            } => write!(f, "No version of {package_id} matches {requirement:?}"),
+            RegistryError::FunctionNotFound { function_id } => {
+                write!(f, "Plugin function {function_id} is not available")
+            }
+            RegistryError::Unverified {
+                package_id,
+                version,
+                source,
+            } => write!(f, "{package_id} {version} cannot be verified: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RegistryError::InvalidVersion { source, .. }
+            | RegistryError::InvalidRequirement { source, .. } => Some(source),
+            RegistryError::Unverified { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Plugin packages resolved for a workflow.
+pub struct ResolvedPlugins {
+    /// Resolved packages, in the order of the workflow's `plugin_packages`, followed by the
+    /// packages of `plugin_function_ids` that are not in them
+    pub packages: Vec<CorePluginPackage>,
+    /// IDs and versions of the resolved packages that are deprecated
+    pub deprecated: Vec<(String, String)>,
+}
+
+/// Registry that holds multiple versions of each plugin package.
+///
+/// Function IDs are unique across packages: every version of a package may reuse its own
+/// function IDs, but no other package may register them.
+///
+/// The `verified` flag of registered packages is computed from their signatures, see the
+/// `signature` module.
+#[derive(Clone, Default)]
+pub struct PluginRegistry {
+    /// Versions of each package, in ascending order
+    packages: BTreeMap<String, Vec<(Version, CorePluginPackage)>>,
+    /// ID of the package that registered each function ID
+    function_owners: HashMap<String, String>,
+    /// Publisher keys the signatures of packages are verified with
+    trust_store: PluginTrustStore,
+    /// Packages refused because of their signature
+    signature_policy: SignaturePolicy,
+}
+
+impl PluginRegistry {
+    /// Creates a new empty PluginRegistry. No publisher key is trusted, so no package is
+    /// verified.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new empty PluginRegistry that verifies packages with the given publisher keys.
+    ///
+    /// # Arguments
+    /// * `trust_store` - Publisher keys trusted to sign packages
+    /// * `signature_policy` - Packages refused because of their signature
+    pub fn new_with_trust_store(
+        trust_store: PluginTrustStore,
+        signature_policy: SignaturePolicy,
+    ) -> Self {
+        Self {
+            trust_store,
+            signature_policy,
+            ..Self::default()
+        }
+    }
+
+    /// Registers a version of a plugin package, setting its `verified` flag to whether its
+    /// signature is verified by the trust store of the registry.
+    ///
+    /// # Arguments
+    /// * `package` - Plugin package with a semantic `version`
+    ///
+    /// # Errors
+    /// Returns a `RegistryError` if the version is not a semantic version, the version is already
+    /// registered, a function ID is used twice in the package or by another package, or the
+    /// signature policy refuses the package.
+    pub fn register(&mut self, mut package: CorePluginPackage) -> Result<(), RegistryError> {
+        let version =
+            Version::parse(&package.version).map_err(|source| RegistryError::InvalidVersion {
+                package_id: package.id.clone(),
+                version: package.version.clone(),
+                source,
+            })?;
+        let versions = self.packages.get(&package.id);
+        if versions.is_some_and(|versions| versions.iter().any(|(v, _)| *v == version)) {
+            return Err(RegistryError::DuplicatePackage {
+                package_id: package.id,
+                version: package.version,
+            });
+        }
+        for (i, func) in package.functions.iter().enumerate() {
+            let owner = match self.function_owners.get(&func.id) {
+                Some(owner) if *owner != package.id => Some(owner),
+                _ if package.functions[..i].iter().any(|f| f.id == func.id) => Some(&package.id),
+                _ => None,
+            };
+            if let Some(owner) = owner {
+                return Err(RegistryError::DuplicateFunctionId {
+                    function_id: func.id.clone(),
+                    package_id: owner.clone(),
+                });
+            }
+        }
+
+        if let Err(source) = package.verify_signature(&self.trust_store)
+            && self.signature_policy.refuses(&source)
+        {
+            return Err(RegistryError::Unverified {
+                package_id: package.id,
+                version: package.version,
+                source,
+            });
+        }
+
+        for func in &package.functions {
+            self.function_owners
+                .insert(func.id.clone(), package.id.clone());
+        }
+        let versions = self.packages.entry(package.id.clone()).or_default();
+        let index = versions.partition_point(|(v, _)| *v < version);
+        versions.insert(index, (version, package));
+        Ok(())
+    }
+
+    /// Returns the registered versions of a package, in ascending order.
+    ///
+    /// # Arguments
+    /// * `package_id` - ID of the package
+    pub fn versions(&self, package_id: &str) -> Vec<&Version> {
+        self.packages
+            .get(package_id)
+            .map(|versions| versions.iter().map(|(v, _)| v).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the highest registered version of a package that matches the requirement.
+    ///
+    /// # Arguments
+    /// * `package_id` - ID of the package
+    /// * `requirement` - Version requirement, e.g. `^1.2`
+    pub fn get(&self, package_id: &str, requirement: &VersionReq) -> Option<&CorePluginPackage> {
+        self.packages.get(package_id).and_then(|versions| {
+            versions
+                .iter()
+                .rev()
+                .find(|(v, _)| requirement.matches(v))
+                .map(|(_, package)| package)
+        })
+    }
+
+    /// Returns every registered version of every package as a proto PluginPackage, ordered by
+    /// package ID and then by version, e.g. to list the installed plugins over gRPC.
+    pub fn plugin_packages(&self) -> Vec<PluginPackage> {
+        self.packages
+            .values()
+            .flatten()
+            .map(|(_, package)| PluginPackage::from(package))
+            .collect()
+    }
+
+    /// Resolves the plugin packages of a workflow.
+    ///
+    /// Each of the workflow's `plugin_packages` is resolved to the highest registered version
+    /// that matches its `package_version`, which is read as a Cargo-style requirement: `1.2.0`
+    /// means `^1.2.0`, and an empty version matches any version. The functions listed in the
+    /// package must exist in the resolved version. Functions in `plugin_function_ids` that are
+    /// not in these packages are resolved to the highest version of the package that registered
+    /// them. Deprecated packages are resolved, but logged and reported in `deprecated`.
+    ///
+    /// # Arguments
+    /// * `workflow_code` - WorkflowCode defined in proto
+    ///
+    /// # Errors
+    /// Returns a `RegistryError` if a requirement is invalid, no version of a package matches,
+    /// or a required function is not available.
+    pub fn resolve(&self, workflow_code: &WorkflowCode) -> Result<ResolvedPlugins, RegistryError> {
+        let mut packages: Vec<&CorePluginPackage> = Vec::new();
+        for required in &workflow_code.plugin_packages {
+            let requirement = match required.package_version.trim() {
+                "" => VersionReq::STAR,
+                requirement => VersionReq::parse(requirement).map_err(|source| {
+                    RegistryError::InvalidRequirement {
+                        package_id: required.package_id.clone(),
+                        requirement: required.package_version.clone(),
+                        source,
+                    }
+                })?,
+            };
+            let package = self
+                .get(&required.package_id, &requirement)
+                .ok_or_else(|| RegistryError::PackageNotFound {
+                    package_id: required.package_id.clone(),
+                    requirement: required.package_version.clone(),
+                })?;
+            if let Some(missing) = required
+                .functions
+                .iter()
+                .find(|f| !package.functions.iter().any(|g| g.id == f.function_id))
+            {
+                return Err(RegistryError::FunctionNotFound {
+                    function_id: missing.function_id.clone(),
+                });
+            }
+            if !packages.iter().any(|p| p.id == package.id) {
+                packages.push(package);
+            }
+        }
+
+        for function_id in &workflow_code.plugin_function_ids {
+            let provided = packages
+                .iter()
+                .any(|p| p.functions.iter().any(|f| f.id == *function_id));
+            if provided {
+                continue;
+            }
+            let package = self
+                .function_owners
+                .get(function_id)
+                .and_then(|owner| self.packages.get(owner))
+                .and_then(|versions| {
+                    versions
+                        .iter()
+                        .rev()
+                        .map(|(_, package)| package)
+                        .find(|p| p.functions.iter().any(|f| f.id == *function_id))
+                })
+                .filter(|package| !packages.iter().any(|p| p.id == package.id))
+                .ok_or_else(|| RegistryError::FunctionNotFound {
+                    function_id: function_id.clone(),
+                })?;
+            packages.push(package);
+        }
+
+        let deprecated: Vec<(String, String)> = packages
+            .iter()
+            .filter(|p| p.deprecated == Some(true))
+            .map(|p| (p.id.clone(), p.version.clone()))
+            .collect();
+        for (id, version) in &deprecated {
+            log::warn!(
+                "Workflow {} uses the deprecated plugin package {id} {version}",
+                workflow_code.id
+            );
+        }
+        Ok(ResolvedPlugins {
+            packages: packages.into_iter().cloned().collect(),
+            deprecated,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::CorePluginFunction;
+    use crate::proto::sapphillon::v1::PluginFunction;
+    use deno_core::op2;
+
+    #[op2(fast)]
+    fn dummy_op() -> u32 {
+        42
+    }
+
+    fn package(id: &str, version: &str, function_ids: &[&str]) -> CorePluginPackage {
+        CorePluginPackage::new_with_version(
+            id.to_string(),
+            id.to_string(),
+            version.to_string(),
+            function_ids
+                .iter()
+                .map(|f| {
+                    CorePluginFunction::new(
+                        f.to_string(),
+                        f.to_string(),
+                        "".to_string(),
+                        dummy_op(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn required(id: &str, version: &str, function_ids: &[&str]) -> PluginPackage {
+        PluginPackage {
+            package_id: id.to_string(),
+            package_version: version.to_string(),
+            functions: function_ids
+                .iter()
+                .map(|f| PluginFunction {
+                    function_id: f.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn registry() -> PluginRegistry {
+        let mut registry = PluginRegistry::new();
+        registry
+            .register(package("fs", "1.2.0", &["fs.read"]))
+            .unwrap();
+        registry
+            .register(package("fs", "1.0.0", &["fs.read"]))
+            .unwrap();
+        registry
+            .register(package("fs", "2.0.0", &["fs.read", "fs.write"]))
+            .unwrap();
+        let mut net = package("net", "0.3.1", &["net.fetch"]);
+        net.deprecated = Some(true);
+        registry.register(net).unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_plugin_registry_plugin_packages() {
+        let packages = registry().plugin_packages();
+        assert_eq!(
+            packages
+                .iter()
+                .map(|p| (p.package_id.as_str(), p.package_version.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("fs", "1.0.0"),
+                ("fs", "1.2.0"),
+                ("fs", "2.0.0"),
+                ("net", "0.3.1")
+            ]
+        );
+        assert_eq!(packages[2].functions.len(), 2);
+        assert_eq!(packages[3].deprecated, Some(true));
+    }
+
+    #[test]
+    fn test_plugin_registry_register_verified() {
+        use crate::signature::tests::{SECRET_KEY, trust_store};
+        use crate::signature::{PluginSignature, SignatureError, SignaturePolicy};
+
+        let signed = |id: &str| {
+            let mut package = package(id, "1.0.0", &[]);
+            package.signature = Some(PluginSignature::sign(
+                &package,
+                "publisher".to_string(),
+                &SECRET_KEY,
+            ));
+            package
+        };
+        let verified = |registry: &PluginRegistry, id: &str| {
+            registry
+                .get(id, &VersionReq::STAR)
+                .map(CorePluginPackage::verified)
+        };
+
+        // Caller-supplied flags are replaced by the result of the verification
+        let mut registry =
+            PluginRegistry::new_with_trust_store(trust_store(), SignaturePolicy::AllowUnsigned);
+        registry.register(signed("signed")).unwrap();
+        let unsigned = CorePluginPackage::new_from_plugin_package(
+            &PluginPackage {
+                package_id: "unsigned".to_string(),
+                package_version: "1.0.0".to_string(),
+                verified: Some(true),
+                ..Default::default()
+            },
+            vec![],
+        );
+        registry.register(unsigned).unwrap();
+        assert_eq!(verified(&registry, "signed"), Some(true));
+        assert_eq!(verified(&registry, "unsigned"), Some(false));
+
+        let mut tampered = signed("tampered");
+        tampered.description = "changed".to_string();
+        let err = registry.register(tampered).unwrap_err();
+        assert!(matches!(
+            err,
+            RegistryError::Unverified {
+                source: SignatureError::InvalidSignature { .. },
+                ..
+            }
+        ));
+        assert!(verified(&registry, "tampered").is_none());
+
+        // Without the publisher key, signed packages are not verified
+        let mut registry = PluginRegistry::new();
+        registry.register(signed("signed")).unwrap();
+        assert_eq!(verified(&registry, "signed"), Some(false));
+
+        let mut registry =
+            PluginRegistry::new_with_trust_store(trust_store(), SignaturePolicy::RequireVerified);
+        registry.register(signed("signed")).unwrap();
+        let err = registry
+            .register(package("unsigned", "1.0.0", &[]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unsigned 1.0.0 cannot be verified: Package is not signed"
+        );
+    }
+
+    #[test]
+    fn test_plugin_registry_register() {
+        let mut registry = registry();
+        assert_eq!(
+            registry.versions("fs"),
+            vec![
+                &Version::new(1, 0, 0),
+                &Version::new(1, 2, 0),
+                &Version::new(2, 0, 0)
+            ]
+        );
+
+        let err = registry.register(package("fs", "1.2.0", &[])).unwrap_err();
+        assert!(matches!(err, RegistryError::DuplicatePackage { .. }));
+        let err = registry
+            .register(package("shell", "1.0.0", &["fs.write"]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Function ID fs.write is already used by fs"
+        );
+        let err = registry
+            .register(package("shell", "1.0.0", &["shell.exec", "shell.exec"]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Function ID shell.exec is already used by shell"
+        );
+        let err = registry.register(package("shell", "1", &[])).unwrap_err();
+        assert!(matches!(err, RegistryError::InvalidVersion { .. }));
+        assert!(registry.versions("shell").is_empty());
+    }
+
+    #[test]
+    fn test_plugin_registry_resolve() {
+        let registry = registry();
+        let workflow_code = WorkflowCode {
+            id: "wid".to_string(),
+            plugin_packages: vec![required("fs", "1.1", &["fs.read"])],
+            plugin_function_ids: vec!["fs.read".to_string(), "net.fetch".to_string()],
+            ..Default::default()
+        };
+        let resolved = registry.resolve(&workflow_code).unwrap();
+        let versions: Vec<(&str, &str)> = resolved
+            .packages
+            .iter()
+            .map(|p| (p.id.as_str(), p.version.as_str()))
+            .collect();
+        assert_eq!(versions, vec![("fs", "1.2.0"), ("net", "0.3.1")]);
+        assert_eq!(
+            resolved.deprecated,
+            vec![("net".to_string(), "0.3.1".to_string())]
+        );
+
+        let resolve = |packages: Vec<PluginPackage>, function_ids: &[&str]| {
+            registry.resolve(&WorkflowCode {
+                plugin_packages: packages,
+                plugin_function_ids: function_ids.iter().map(|f| f.to_string()).collect(),
+                ..Default::default()
+            })
+        };
+        let resolved = resolve(vec![required("fs", "", &[])], &[]).unwrap();
+        assert_eq!(resolved.packages[0].version, "2.0.0");
+        // fs.write only exists in 2.0.0
+        let err = resolve(vec![required("fs", "^1", &["fs.write"])], &[])
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Plugin function fs.write is not available");
+        let err = resolve(vec![required("fs", "^1", &[])], &["fs.write"])
+            .err()
+            .unwrap();
+        assert!(matches!(err, RegistryError::FunctionNotFound { .. }));
+        let err = resolve(vec![required("fs", "^3", &[])], &[]).err().unwrap();
+        assert_eq!(err.to_string(), "No version of fs matches \"^3\"");
+        let err = resolve(vec![required("fs", "one", &[])], &[])
+            .err()
+            .unwrap();
+        assert!(matches!(err, RegistryError::InvalidRequirement { .. }));
+    }
+}
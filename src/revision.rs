@@ -0,0 +1,379 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Comparison of workflow code revisions.
+
+use crate::permission::is_permission_granted;
+use crate::proto::sapphillon::v1::{Permission, PermissionLevel, PermissionType, WorkflowCode};
+use std::fmt;
+
+/// Kind of change of a permission between two revisions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermissionChangeKind {
+    /// The permission is not granted by the previous revision.
+    Added,
+    /// The permission is not granted by the current revision anymore.
+    Removed,
+    /// The permission is granted by the previous revision, but at a lower `PermissionLevel`.
+    Escalated,
+}
+
+/// Change of a permission between two revisions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermissionChange {
+    pub kind: PermissionChangeKind,
+    /// Permission of the current revision, or of the previous revision if it was removed
+    pub permission: Permission,
+    /// Level at which the previous revision granted the permission, set for escalations
+    pub previous_level: Option<PermissionLevel>,
+}
+
+impl fmt::Display for PermissionChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            PermissionChangeKind::Added => "added",
+            PermissionChangeKind::Removed => "removed",
+            PermissionChangeKind::Escalated => "escalated",
+        };
+        write!(
+            f,
+            "{kind} {} {:?}",
+            self.permission.permission_type().as_str_name(),
+            self.permission.resource
+        )?;
+        match self.previous_level {
+            Some(previous) => write!(
+                f,
+                " from {} to {}",
+                previous.as_str_name(),
+                self.permission.permission_level().as_str_name()
+            ),
+            None => write!(
+                f,
+                " at {}",
+                self.permission.permission_level().as_str_name()
+            ),
+        }
+    }
+}
+
+/// Differences in permissions and plugins between two revisions of a workflow code.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RevisionDiff {
+    /// `code_revision` of the previous revision
+    pub previous_revision: i32,
+    /// `code_revision` of the current revision
+    pub revision: i32,
+    /// Changes of `required_permissions`, additions and escalations first in the order of the current revision
+    pub permission_changes: Vec<PermissionChange>,
+    /// IDs of plugin packages only used by the current revision
+    pub added_plugin_packages: Vec<String>,
+    /// IDs of plugin packages only used by the previous revision
+    pub removed_plugin_packages: Vec<String>,
+    /// Plugin function IDs only used by the current revision
+    pub added_plugin_function_ids: Vec<String>,
+    /// Plugin function IDs only used by the previous revision
+    pub removed_plugin_function_ids: Vec<String>,
+}
+
+/// Returns the level at which `granted` grants `permission`, or `None` if it does not grant it.
+///
+/// For permissions with several resources, the lowest level among the resources is returned.
+fn granted_level(granted: &[Permission], permission: &Permission) -> Option<i32> {
+    if !is_permission_granted(granted, permission) {
+        return None;
+    }
+    let level_for = |required: &Permission| {
+        granted
+            .iter()
+            .filter(|g| is_permission_granted(std::slice::from_ref(g), required))
+            .map(|g| g.permission_level)
+            .max()
+    };
+    if permission.resource.is_empty() {
+        return level_for(permission);
+    }
+    permission
+        .resource
+        .iter()
+        .map(|resource| {
+            level_for(&Permission {
+                resource: vec![resource.clone()],
+                ..permission.clone()
+            })
+        })
+        .min()
+        .flatten()
+}
+
+/// Returns the items of `current` that are not in `previous`.
+fn added(previous: &[String], current: &[String]) -> Vec<String> {
+    let mut added: Vec<String> = Vec::new();
+    for item in current {
+        if !previous.contains(item) && !added.contains(item) {
+            added.push(item.clone());
+        }
+    }
+    added
+}
+
+impl RevisionDiff {
+    /// Compares two revisions of a workflow code.
+    ///
+    /// A permission of the current revision is added if the previous revision does not grant it,
+    /// and escalated if the previous revision grants it at a lower `PermissionLevel`, e.g.
+    /// `/tmp` at `MEDIUM` before and `/tmp/data` at `HIGH` now. Lowering the level of a permission
+    /// is not a change. `Unspecified` permissions are ignored.
+    ///
+    /// # Arguments
+    /// * `previous` - Revision that was approved
+    /// * `current` - Revision to compare with it
+    pub fn new(previous: &WorkflowCode, current: &WorkflowCode) -> Self {
+        let is_specified = |p: &&Permission| p.permission_type() != PermissionType::Unspecified;
+
+        let mut permission_changes = Vec::new();
+        for permission in current.required_permissions.iter().filter(is_specified) {
+            match granted_level(&previous.required_permissions, permission) {
+                None => permission_changes.push(PermissionChange {
+                    kind: PermissionChangeKind::Added,
+                    permission: permission.clone(),
+                    previous_level: None,
+                }),
+                Some(level) if permission.permission_level > level => {
+                    permission_changes.push(PermissionChange {
+                        kind: PermissionChangeKind::Escalated,
+                        permission: permission.clone(),
+                        previous_level: Some(PermissionLevel::try_from(level).unwrap_or_default()),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        for permission in previous.required_permissions.iter().filter(is_specified) {
+            if !is_permission_granted(&current.required_permissions, permission) {
+                permission_changes.push(PermissionChange {
+                    kind: PermissionChangeKind::Removed,
+                    permission: permission.clone(),
+                    previous_level: None,
+                });
+            }
+        }
+
+        let package_ids = |code: &WorkflowCode| -> Vec<String> {
+            code.plugin_packages
+                .iter()
+                .map(|p| p.package_id.clone())
+                .collect()
+        };
+        let previous_packages = package_ids(previous);
+        let current_packages = package_ids(current);
+
+        Self {
+            previous_revision: previous.code_revision,
+            revision: current.code_revision,
+            permission_changes,
+            added_plugin_packages: added(&previous_packages, &current_packages),
+            removed_plugin_packages: added(&current_packages, &previous_packages),
+            added_plugin_function_ids: added(
+                &previous.plugin_function_ids,
+                &current.plugin_function_ids,
+            ),
+            removed_plugin_function_ids: added(
+                &current.plugin_function_ids,
+                &previous.plugin_function_ids,
+            ),
+        }
+    }
+
+    /// Returns true if the current revision needs to be approved again before it runs.
+    ///
+    /// Re-approval is needed when permissions are added or escalated, or when plugin
+    /// packages or plugin functions are added. Removals alone never need re-approval.
+    pub fn requires_reapproval(&self) -> bool {
+        self.permission_changes
+            .iter()
+            .any(|c| c.kind != PermissionChangeKind::Removed)
+            || !self.added_plugin_packages.is_empty()
+            || !self.added_plugin_function_ids.is_empty()
+    }
+}
+
+impl fmt::Display for RevisionDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Changes from revision {} to revision {}:",
+            self.previous_revision, self.revision
+        )?;
+        for change in &self.permission_changes {
+            write!(f, "\n- {change}")?;
+        }
+        for (label, ids) in [
+            ("added plugin package", &self.added_plugin_packages),
+            ("removed plugin package", &self.removed_plugin_packages),
+            ("added plugin function", &self.added_plugin_function_ids),
+            ("removed plugin function", &self.removed_plugin_function_ids),
+        ] {
+            for id in ids {
+                write!(f, "\n- {label} {id}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::sapphillon::v1::PluginPackage;
+
+    fn permission(
+        permission_type: PermissionType,
+        level: PermissionLevel,
+        resource: &[&str],
+    ) -> Permission {
+        Permission {
+            permission_type: permission_type as i32,
+            permission_level: level as i32,
+            resource: resource.iter().map(|r| r.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn revision(code_revision: i32, permissions: Vec<Permission>) -> WorkflowCode {
+        WorkflowCode {
+            id: "wid".to_string(),
+            code_revision,
+            required_permissions: permissions,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_revision_diff_permissions() {
+        let previous = revision(
+            1,
+            vec![
+                permission(PermissionType::Read, PermissionLevel::Medium, &["/tmp"]),
+                permission(PermissionType::Write, PermissionLevel::High, &["/tmp/out"]),
+                permission(PermissionType::Execute, PermissionLevel::High, &["git"]),
+            ],
+        );
+        let current = revision(
+            2,
+            vec![
+                // Covered by /tmp, but at a higher level
+                permission(PermissionType::Read, PermissionLevel::High, &["/tmp/data"]),
+                // Lowered levels are not changes
+                permission(
+                    PermissionType::Write,
+                    PermissionLevel::Medium,
+                    &["/tmp/out"],
+                ),
+                permission(
+                    PermissionType::Read,
+                    PermissionLevel::Medium,
+                    &["/etc/hosts"],
+                ),
+                permission(PermissionType::Unspecified, PermissionLevel::Critical, &[]),
+            ],
+        );
+
+        let diff = RevisionDiff::new(&previous, &current);
+        assert_eq!(diff.previous_revision, 1);
+        assert_eq!(diff.revision, 2);
+        assert_eq!(
+            diff.permission_changes,
+            vec![
+                PermissionChange {
+                    kind: PermissionChangeKind::Escalated,
+                    permission: current.required_permissions[0].clone(),
+                    previous_level: Some(PermissionLevel::Medium),
+                },
+                PermissionChange {
+                    kind: PermissionChangeKind::Added,
+                    permission: current.required_permissions[2].clone(),
+                    previous_level: None,
+                },
+                PermissionChange {
+                    kind: PermissionChangeKind::Removed,
+                    permission: previous.required_permissions[0].clone(),
+                    previous_level: None,
+                },
+                PermissionChange {
+                    kind: PermissionChangeKind::Removed,
+                    permission: previous.required_permissions[2].clone(),
+                    previous_level: None,
+                },
+            ]
+        );
+        assert!(diff.requires_reapproval());
+        assert_eq!(
+            diff.permission_changes[0].to_string(),
+            r#"escalated PERMISSION_TYPE_READ ["/tmp/data"] from PERMISSION_LEVEL_MEDIUM to PERMISSION_LEVEL_HIGH"#
+        );
+    }
+
+    #[test]
+    fn test_revision_diff_removals_only() {
+        let previous = revision(
+            1,
+            vec![permission(
+                PermissionType::Read,
+                PermissionLevel::High,
+                &["/tmp"],
+            )],
+        );
+        let current = revision(2, vec![]);
+        let diff = RevisionDiff::new(&previous, &current);
+        assert_eq!(diff.permission_changes.len(), 1);
+        assert!(!diff.requires_reapproval());
+
+        let diff = RevisionDiff::new(&previous, &previous);
+        assert!(diff.permission_changes.is_empty());
+        assert!(!diff.requires_reapproval());
+    }
+
+    #[test]
+    fn test_revision_diff_plugins() {
+        let package = |id: &str| PluginPackage {
+            package_id: id.to_string(),
+            ..Default::default()
+        };
+        let mut previous = revision(1, vec![]);
+        previous.plugin_packages = vec![package("fs"), package("net")];
+        previous.plugin_function_ids = vec!["fs.read".to_string()];
+        let mut current = revision(2, vec![]);
+        current.plugin_packages = vec![package("fs"), package("shell")];
+        current.plugin_function_ids = vec!["fs.read".to_string(), "shell.exec".to_string()];
+
+        let diff = RevisionDiff::new(&previous, &current);
+        assert_eq!(diff.added_plugin_packages, vec!["shell"]);
+        assert_eq!(diff.removed_plugin_packages, vec!["net"]);
+        assert_eq!(diff.added_plugin_function_ids, vec!["shell.exec"]);
+        assert!(diff.removed_plugin_function_ids.is_empty());
+        assert!(diff.requires_reapproval());
+        assert_eq!(
+            diff.to_string(),
+            "Changes from revision 1 to revision 2:\n\
+             - added plugin package shell\n\
+             - removed plugin package net\n\
+             - added plugin function shell.exec"
+        );
+    }
+}
@@ -18,10 +18,51 @@
 
 #![warn(clippy::field_reassign_with_default)]
 
+use crate::audit::AuditSink;
+use crate::bindings::{
+    PLUGIN_SOURCE_SCHEME, PLUGINS_MODULE_SPECIFIER, PluginBinding, PluginFunctionTarget,
+    grpc_plugin_processes, native_plugin_libraries, plugin_configs, plugin_function_schemas,
+    plugin_module_specifier, plugin_ops_with_schemas, plugin_secrets, plugins_module_source,
+    wasm_plugins,
+};
+use crate::config::{CallingOps, calling_op_metrics};
 use crate::core::op_print_wrapper;
-use deno_core::{Extension, JsRuntime, OpDecl, RuntimeOptions, error::JsError};
+use crate::grpc::op_grpc_plugin_invoke;
+use crate::lifecycle::{
+    PluginLifecycleError, PluginLifecycleStage, setup_plugin_lifecycles, teardown_plugin_lifecycles,
+};
+use crate::native::op_native_plugin_invoke;
+use crate::permission::{
+    PERMISSION_DENIED_ERROR_SCRIPT, PermissionApproval, PermissionApprover,
+    PermissionDecisionRecord, check_plugin_function, op_permission_denied, permission_denied_op,
+    permissions_container_from_proto,
+};
+use crate::policy::PermissionPolicy;
+use crate::proto::google::rpc::context::AttributeContext;
+use crate::proto::sapphillon::v1::Permission;
+use crate::schema::{op_validated_plugin_function, validate_plugin_ops};
+use crate::transpile::WorkflowModule;
+use crate::validation::{PLUGIN_FUNCTION_NOT_LISTED_ERROR_SCRIPT, op_plugin_function_not_listed};
+use crate::wasm::op_wasm_plugin_function;
+use deno_core::{
+    Extension, JsRuntime, ModuleLoadResponse, ModuleLoader, ModuleSource, ModuleSourceCode,
+    ModuleSpecifier, ModuleType, OpDecl, PollEventLoopOptions, RequestedModuleType, ResolutionKind,
+    RuntimeOptions,
+    error::{CoreError, ModuleLoaderError},
+    resolve_import, v8,
+};
+use deno_error::JsErrorBox;
+use std::borrow::Cow;
 use std::boxed::Box;
-use std::sync::{Arc, Mutex};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::Notify;
 
 /// Represents the standard output (stdout) of a workflow execution.
 /// Each variant holds the output as a string.
@@ -32,12 +73,16 @@ pub enum WorkflowStdout {
 }
 
 /// Stores workflow-related state for operations within the runtime.
-/// Includes workflow ID, captured stdout results, and a flag for capturing stdout.
+/// Includes workflow ID, captured stdout results, a flag for capturing stdout
+/// and the permission decisions made during the run.
 #[derive(Debug, Clone)]
 pub struct OpStateWorkflowData {
     workflow_id: String,
     result: Vec<WorkflowStdout>,
     capture_stdout: bool,
+    permission_decisions: Vec<PermissionDecisionRecord>,
+    plugin_function_ids: HashMap<String, String>,
+    audit_events: Vec<AttributeContext>,
 }
 
 impl OpStateWorkflowData {
@@ -47,6 +92,9 @@ impl OpStateWorkflowData {
             workflow_id: workflow_id.to_string(),
             result: Vec::new(),
             capture_stdout,
+            permission_decisions: Vec::new(),
+            plugin_function_ids: HashMap::new(),
+            audit_events: Vec::new(),
         }
     }
 
@@ -72,6 +120,37 @@ impl OpStateWorkflowData {
         self.capture_stdout
     }
 
+    /// Records a decision of the `PermissionApprover`.
+    pub fn add_permission_decision(&mut self, record: PermissionDecisionRecord) {
+        self.permission_decisions.push(record);
+    }
+
+    /// Returns the permission decisions made during the run, in order.
+    pub fn get_permission_decisions(&self) -> &Vec<PermissionDecisionRecord> {
+        &self.permission_decisions
+    }
+
+    /// Registers the plugin function behind an op, so that its permission checks are attributed to it.
+    pub fn set_plugin_function_id(&mut self, op_name: &str, plugin_function_id: &str) {
+        self.plugin_function_ids
+            .insert(op_name.to_string(), plugin_function_id.to_string());
+    }
+
+    /// Returns the ID of the plugin function behind an op, if one is registered.
+    pub fn get_plugin_function_id(&self, op_name: &str) -> Option<&str> {
+        self.plugin_function_ids.get(op_name).map(String::as_str)
+    }
+
+    /// Records audit events of permission checks.
+    pub fn add_audit_events(&mut self, events: impl IntoIterator<Item = AttributeContext>) {
+        self.audit_events.extend(events);
+    }
+
+    /// Returns the audit events of the permission checks made during the run, in order.
+    pub fn get_audit_events(&self) -> &Vec<AttributeContext> {
+        &self.audit_events
+    }
+
     pub fn stdout_to_string(&self) -> String {
         self.result
             .iter()
@@ -84,33 +163,560 @@ impl OpStateWorkflowData {
     }
 }
 
+/// Options that control how a workflow is executed.
+#[derive(Clone, Default)]
+pub struct WorkflowRunOptions {
+    /// Wall-clock limit for a single run. `None` disables the limit.
+    pub timeout: Option<Duration>,
+    /// Hard limit of the V8 heap in bytes. `None` uses the V8 default.
+    /// The initial heap size is left to V8.
+    pub max_heap_size: Option<usize>,
+    /// Host callback asked about permissions that were not granted up front.
+    /// `None` denies them without asking.
+    pub permission_approver: Option<Arc<dyn PermissionApprover>>,
+    /// CEL policy that decides permissions that were not granted up front before
+    /// `permission_approver` is asked. `None` escalates every request to the approver.
+    pub permission_policy: Option<Arc<PermissionPolicy>>,
+    /// Receiver of the audit events of each run of a `CoreWorkflowCode`. `None` keeps them
+    /// in `CoreWorkflowCode::audit_log` only.
+    pub audit_sink: Option<Arc<dyn AuditSink>>,
+}
+
+impl fmt::Debug for WorkflowRunOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkflowRunOptions")
+            .field("timeout", &self.timeout)
+            .field("max_heap_size", &self.max_heap_size)
+            .field(
+                "permission_approver",
+                &self
+                    .permission_approver
+                    .as_ref()
+                    .map(|_| "PermissionApprover"),
+            )
+            .field("permission_policy", &self.permission_policy)
+            .field("audit_sink", &self.audit_sink.as_ref().map(|_| "AuditSink"))
+            .finish()
+    }
+}
+
+/// Exit code recorded for workflows that ran to completion.
+pub const EXIT_CODE_SUCCESS: i32 = 0;
+/// Exit code recorded for workflows that failed with an error.
+pub const EXIT_CODE_FAILURE: i32 = 1;
+/// Exit code recorded for workflows that exceeded their timeout.
+pub const EXIT_CODE_TIMED_OUT: i32 = 124;
+/// Exit code recorded for workflows that exceeded their heap limit.
+pub const EXIT_CODE_MEMORY_LIMIT_EXCEEDED: i32 = 137;
+/// Exit code recorded for workflows that were cancelled.
+pub const EXIT_CODE_CANCELLED: i32 = 130;
+/// Exit code recorded for workflows that were not run because their revision awaits re-approval.
+pub const EXIT_CODE_REAPPROVAL_REQUIRED: i32 = 126;
+
+/// Error returned when a workflow run does not complete successfully.
+#[derive(Debug)]
+pub enum WorkflowRunError {
+    /// The workflow threw an exception, rejected a promise or failed to load.
+    Js(CoreError),
+    /// The workflow was terminated because it exceeded the given timeout.
+    TimedOut(Duration),
+    /// The workflow was terminated because it exceeded the given heap limit in bytes.
+    MemoryLimitExceeded(usize),
+    /// The workflow was terminated through its `CancellationToken`.
+    Cancelled,
+    /// A plugin package failed to initialize or set up, so the workflow was not evaluated.
+    PluginLifecycle {
+        /// ID of the package
+        package_id: String,
+        /// Stage that failed
+        stage: PluginLifecycleStage,
+        /// Error returned by the hook
+        error: PluginLifecycleError,
+    },
+}
+
+impl WorkflowRunError {
+    /// Returns the exit code recorded in the `WorkflowResult` for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            WorkflowRunError::Js(_) => EXIT_CODE_FAILURE,
+            WorkflowRunError::TimedOut(_) => EXIT_CODE_TIMED_OUT,
+            WorkflowRunError::MemoryLimitExceeded(_) => EXIT_CODE_MEMORY_LIMIT_EXCEEDED,
+            WorkflowRunError::Cancelled => EXIT_CODE_CANCELLED,
+            WorkflowRunError::PluginLifecycle { .. } => EXIT_CODE_FAILURE,
+        }
+    }
+}
+
+impl fmt::Display for WorkflowRunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkflowRunError::Js(e) => write!(f, "{e}"),
+            WorkflowRunError::TimedOut(timeout) => {
+                write!(f, "Workflow execution timed out after {timeout:?}")
+            }
+            WorkflowRunError::MemoryLimitExceeded(limit) => {
+                write!(f, "Workflow memory limit exceeded ({limit} bytes)")
+            }
+            WorkflowRunError::Cancelled => write!(f, "Workflow execution was cancelled"),
+            WorkflowRunError::PluginLifecycle {
+                package_id,
+                stage,
+                error,
+            } => write!(f, "Plugin package {package_id} failed to {stage}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for WorkflowRunError {}
+
+impl From<CoreError> for WorkflowRunError {
+    fn from(e: CoreError) -> Self {
+        WorkflowRunError::Js(e)
+    }
+}
+
+/// Token used to cancel an in-flight workflow run from another thread.
+///
+/// Cloning the token shares the same cancellation state. The token of the current run is
+/// placed into the `OpState`, so async plugin ops can await `cancelled()` and stop early.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<CancellationState>,
+}
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: AtomicBool,
+    notify: Notify,
+    isolate_handle: Mutex<Option<v8::IsolateHandle>>,
+}
+
+impl CancellationToken {
+    /// Creates a new token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the run. Running JavaScript is terminated and waiters on `cancelled()` are woken.
+    /// Cancelling a token before the run starts makes the run fail immediately.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.inner.isolate_handle.lock().unwrap().as_ref() {
+            handle.terminate_execution();
+        }
+        self.inner.notify.notify_waiters();
+    }
+
+    /// Returns true if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the token is cancelled.
+    pub async fn cancelled(&self) {
+        let notified = self.inner.notify.notified();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+
+    /// Attaches the isolate of the current run, terminating it right away if already cancelled.
+    fn attach_isolate(&self, handle: v8::IsolateHandle) {
+        let mut isolate_handle = self.inner.isolate_handle.lock().unwrap();
+        if self.is_cancelled() {
+            handle.terminate_execution();
+        }
+        *isolate_handle = Some(handle);
+    }
+
+    /// Detaches the isolate once the run has finished.
+    fn detach_isolate(&self) {
+        *self.inner.isolate_handle.lock().unwrap() = None;
+    }
+}
+
+/// Watchdog thread that terminates the isolate once the timeout elapses.
+///
+/// A busy loop in JavaScript never yields back to the event loop, so the deadline
+/// has to be enforced from another thread through the isolate handle.
+struct Watchdog {
+    done_tx: mpsc::Sender<()>,
+    handle: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    /// Spawns a watchdog that terminates the isolate behind `isolate_handle` after `timeout`.
+    fn spawn(isolate_handle: v8::IsolateHandle, timeout: Duration) -> Self {
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || match done_rx.recv_timeout(timeout) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                isolate_handle.terminate_execution();
+                true
+            }
+            _ => false,
+        });
+        Self { done_tx, handle }
+    }
+
+    /// Stops the watchdog and returns true if it fired.
+    fn finish(self) -> bool {
+        let _ = self.done_tx.send(());
+        self.handle.join().unwrap_or(false)
+    }
+}
+
+/// Module loader used for workflow modules.
+///
+/// Workflows are loaded from code, so the loader only has to resolve specifiers, load the
+/// `sapphillon:plugin/` modules of plugin packages and hand out the source maps of transpiled
+/// modules. Loading any other module is refused.
+pub(crate) struct WorkflowModuleLoader {
+    source_maps: RefCell<HashMap<String, Vec<u8>>>,
+    plugin_modules: RefCell<HashMap<String, String>>,
+}
+
+impl WorkflowModuleLoader {
+    /// Creates a new `WorkflowModuleLoader` without any source maps.
+    pub(crate) fn new() -> Self {
+        Self {
+            source_maps: RefCell::new(HashMap::new()),
+            plugin_modules: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Registers the source map of a module so that error locations are mapped back to the original source.
+    pub(crate) fn add_source_map(&self, specifier: &str, source_map: Vec<u8>) {
+        self.source_maps
+            .borrow_mut()
+            .insert(specifier.to_string(), source_map);
+    }
+
+    /// Registers the `sapphillon:plugin/` module and the source modules of a plugin package.
+    /// Packages whose ID cannot be part of a specifier are only available through `plugins`.
+    pub(crate) fn add_plugin_module(&self, binding: &PluginBinding) {
+        if let Some(specifier) = plugin_module_specifier(&binding.package_id) {
+            self.plugin_modules
+                .borrow_mut()
+                .insert(specifier.to_string(), binding.module_source());
+        }
+        for module in &binding.modules {
+            if let Some(source_map) = &module.source_map {
+                self.add_source_map(&module.specifier, source_map.clone());
+            }
+            self.plugin_modules
+                .borrow_mut()
+                .insert(module.specifier.clone(), module.code.clone());
+        }
+    }
+}
+
+/// Returns true if the module `referrer` may import the source module `specifier` of a plugin
+/// package: only the `plugins` module and the modules of the same package may.
+fn may_import_plugin_source(specifier: &ModuleSpecifier, referrer: &str) -> bool {
+    referrer == PLUGINS_MODULE_SPECIFIER
+        || ModuleSpecifier::parse(referrer).is_ok_and(|referrer| {
+            referrer.scheme() == PLUGIN_SOURCE_SCHEME && referrer.host_str() == specifier.host_str()
+        })
+}
+
+impl ModuleLoader for WorkflowModuleLoader {
+    fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _kind: ResolutionKind,
+    ) -> Result<ModuleSpecifier, ModuleLoaderError> {
+        let resolved = resolve_import(specifier, referrer).map_err(JsErrorBox::from_err)?;
+        if resolved.scheme() == PLUGIN_SOURCE_SCHEME
+            && !may_import_plugin_source(&resolved, referrer)
+        {
+            return Err(JsErrorBox::type_error(format!(
+                "{resolved} can only be imported by the modules of its plugin package"
+            )));
+        }
+        Ok(resolved)
+    }
+
+    fn load(
+        &self,
+        module_specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<&ModuleSpecifier>,
+        _is_dyn_import: bool,
+        _requested_module_type: RequestedModuleType,
+    ) -> ModuleLoadResponse {
+        match self.plugin_modules.borrow().get(module_specifier.as_str()) {
+            Some(source) => ModuleLoadResponse::Sync(Ok(ModuleSource::new(
+                ModuleType::JavaScript,
+                ModuleSourceCode::String(source.clone().into()),
+                module_specifier,
+                None,
+            ))),
+            None => ModuleLoadResponse::Sync(Err(JsErrorBox::generic(format!(
+                "Module not found: {module_specifier}"
+            )))),
+        }
+    }
+
+    fn get_source_map(&self, file_name: &str) -> Option<Cow<'_, [u8]>> {
+        self.source_maps
+            .borrow()
+            .get(file_name)
+            .map(|v| Cow::Owned(v.clone()))
+    }
+}
+
 /// Executes the given JavaScript code within a `JsRuntime` configured with custom operations.
 ///
-/// # Overview
-/// Runs the provided JavaScript `script` in a new `JsRuntime` instance, registering the supplied vector of `OpDecl` as custom operations (ops) via an extension. Use `op2` to define these operations.
+/// This is a shorthand for `run_module` with a JavaScript `WorkflowModule`, no granted permissions,
+/// default options and a token that is never cancelled.
 ///
 /// # Arguments
 /// - `script`: The JavaScript code to execute as a string.
 /// - `ext`: A vector of `OpDecl` representing custom operations to be registered in the runtime.
+/// - `workflow_data`: Workflow state placed into the `OpState`. A default one is created if `None`.
+#[allow(unused)]
+pub(crate) fn run_script(
+    script: &str,
+    ext: Vec<OpDecl>,
+    workflow_data: Option<Arc<Mutex<OpStateWorkflowData>>>,
+) -> Result<Arc<Mutex<OpStateWorkflowData>>, WorkflowRunError> {
+    run_module(
+        &WorkflowModule::from_javascript(script),
+        ext,
+        &[],
+        workflow_data,
+        &[],
+        &WorkflowRunOptions::default(),
+        &CancellationToken::new(),
+    )
+}
+
+/// Executes the given workflow module within a `JsRuntime` configured with custom operations.
 ///
-/// # Returns
-/// - `Ok(())`: If the script executes successfully.
-/// - `Err(Box<JsError>)`: If an error occurs during execution.
+/// # Overview
+/// Loads the provided `module` as the main ES module of a new `JsRuntime` instance,
+/// registering the supplied vector of `OpDecl` as custom operations (ops) via an extension.
+/// The module is evaluated and the event loop is driven to completion, so top-level `await`,
+/// Promises and async ops (`#[op2(async)]`) all settle before this function returns.
+/// Use `op2` to define these operations.
 ///
+/// # Arguments
+/// - `module`: The workflow module to execute. Transpiled modules carry a source map.
+/// - `ext`: A vector of `OpDecl` representing custom operations to be registered in the runtime.
+/// - `bindings`: Plugin packages exposed through the global `plugins` object and `sapphillon:plugin/`
+///   modules. The ops they call must be in `ext`. Their source modules are loaded and evaluated
+///   before `module`.
+/// - `workflow_data`: Workflow state placed into the `OpState`. A default one is created if `None`.
+/// - `permissions`: Permissions granted to the workflow. They are placed into the `OpState` as a
+///   `PermissionsContainer`, and plugin ops check them with `check_permission`.
+/// - `options`: Limits applied to this run.
+/// - `cancellation`: Token that cancels this run. It is placed into the `OpState` for async ops.
+///
+/// # Returns
+/// - `Ok(Arc<Mutex<OpStateWorkflowData>>)`: If the module and its event loop complete successfully.
+/// - `Err(WorkflowRunError)`: If an error occurs during loading, evaluation or the event loop,
+///   or if a limit is exceeded.
 ///
 /// # Notes
 /// - The extension is registered with the name "ext".
-/// - The script is always executed as the module "workflow.js".
+/// - The module is loaded under `module.specifier`, and its source map is used for error locations.
+/// - The event loop runs on a dedicated current-thread Tokio runtime. When called from within a
+///   Tokio runtime, the run is moved to a dedicated thread and the calling thread blocks until it
+///   finishes, so async callers should use `tokio::task::spawn_blocking`.
+/// - When `options.timeout` is set, a watchdog thread terminates the isolate once it elapses,
+///   and pending async ops are abandoned. The timeout starts before the plugin packages are set
+///   up. Every run uses a fresh isolate, so later runs are unaffected.
+/// - When `options.max_heap_size` is set, the isolate is terminated as soon as V8 reports that the
+///   heap limit is near, instead of letting V8 abort the process.
+/// - Cancelling `cancellation` terminates the isolate and abandons pending async ops.
+/// - Resources not covered by `permissions` are denied, unless `options.permission_policy` or
+///   `options.permission_approver` allows them. Their decisions are recorded in the returned workflow data.
+/// - Before the isolate is created, the permissions declared by the plugin functions of `bindings`
+///   are checked on the runtime of the run, awaiting the policy and approver. Functions whose
+///   permissions are not allowed throw a `PermissionDenied` error when invoked.
 ///
 /// # Errors
-/// - Any JavaScript execution error is returned as `Box<JsError>`.
-#[allow(unused)]
-pub(crate) fn run_script(
-    script: &str,
+/// - Invalid permission declarations and denied permission checks that are not caught are returned
+///   as `WorkflowRunError::Js`.
+/// - Thrown exceptions, rejected top-level promises and unhandled rejections are returned as `WorkflowRunError::Js`.
+/// - Exceeding the timeout is returned as `WorkflowRunError::TimedOut`.
+/// - Exceeding the heap limit is returned as `WorkflowRunError::MemoryLimitExceeded`.
+/// - Cancellation is returned as `WorkflowRunError::Cancelled`.
+/// - A plugin package that fails to initialize or set up is returned as
+///   `WorkflowRunError::PluginLifecycle`.
+pub(crate) fn run_module(
+    module: &WorkflowModule,
     ext: Vec<OpDecl>,
+    bindings: &[PluginBinding],
     workflow_data: Option<Arc<Mutex<OpStateWorkflowData>>>,
-) -> Result<Arc<Mutex<OpStateWorkflowData>>, Box<JsError>> {
+    permissions: &[Permission],
+    options: &WorkflowRunOptions,
+    cancellation: &CancellationToken,
+) -> Result<Arc<Mutex<OpStateWorkflowData>>, WorkflowRunError> {
+    // A runtime cannot be started, nor blocked on, from a thread that already drives one
+    if tokio::runtime::Handle::try_current().is_ok() {
+        let ext = SendOpDecls(ext);
+        return thread::scope(|scope| {
+            scope
+                .spawn(move || {
+                    run_module_on_current_thread(
+                        module,
+                        ext.into_inner(),
+                        bindings,
+                        workflow_data,
+                        permissions,
+                        options,
+                        cancellation,
+                    )
+                })
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        });
+    }
+    run_module_on_current_thread(
+        module,
+        ext,
+        bindings,
+        workflow_data,
+        permissions,
+        options,
+        cancellation,
+    )
+}
+
+/// Operations handed to the thread that runs a workflow.
+struct SendOpDecls(Vec<OpDecl>);
+
+// SAFETY: An `OpDecl` only holds `'static` function pointers and static metadata. The raw
+// pointer of its fast-call info refers to immutable static data, so moving it to another
+// thread is sound.
+unsafe impl Send for SendOpDecls {}
+
+impl SendOpDecls {
+    /// Returns the operations. Taking `self` keeps closures from capturing the inner vector.
+    fn into_inner(self) -> Vec<OpDecl> {
+        self.0
+    }
+}
+
+/// Drives the event loop of a run to completion. The futures of pending async ops are polled
+/// outside of their dispatch, so the plugin ops being dispatched are forgotten before each poll.
+async fn run_event_loop(
+    runtime: &mut JsRuntime,
+    calling_ops: &CallingOps,
+) -> Result<(), CoreError> {
+    std::future::poll_fn(|cx| {
+        calling_ops.clear();
+        runtime.poll_event_loop(cx, PollEventLoopOptions::default())
+    })
+    .await
+}
+
+/// Checks the permissions declared by the plugin functions of a run before it starts.
+///
+/// Functions whose permissions are not allowed are replaced with stubs that throw a
+/// `PermissionDenied` error: their op in `ext`, and their binding if they have no op. Permissions
+/// allowed by the policy or approver of the run are added to `granted`. Unlisted functions cannot
+/// be invoked, so their permissions are not checked.
+async fn check_plugin_functions(
+    bindings: &mut [PluginBinding],
+    ext: &mut [OpDecl],
+    approval: Option<&PermissionApproval>,
+    workflow_data: &Mutex<OpStateWorkflowData>,
+    granted: &mut Vec<Permission>,
+) {
+    for func in bindings
+        .iter_mut()
+        .flat_map(|binding| &mut binding.functions)
+    {
+        let api_name = match &func.target {
+            PluginFunctionTarget::NotListed | PluginFunctionTarget::Denied => continue,
+            PluginFunctionTarget::Op(op_name) => op_name.clone(),
+            _ => func.function_id.clone(),
+        };
+        let allowed = check_plugin_function(
+            approval,
+            workflow_data,
+            &func.function_id,
+            &api_name,
+            &func.permissions,
+            granted,
+        )
+        .await;
+        if allowed {
+            continue;
+        }
+        match ext.iter_mut().find(|op| op.name == api_name) {
+            Some(op) if matches!(func.target, PluginFunctionTarget::Op(_)) => {
+                *op = permission_denied_op(op);
+            }
+            _ => func.target = PluginFunctionTarget::Denied,
+        }
+    }
+}
+
+/// Executes `run_module` on the calling thread, which must not drive a Tokio runtime.
+fn run_module_on_current_thread(
+    module: &WorkflowModule,
+    ext: Vec<OpDecl>,
+    bindings: &[PluginBinding],
+    workflow_data: Option<Arc<Mutex<OpStateWorkflowData>>>,
+    permissions: &[Permission],
+    options: &WorkflowRunOptions,
+    cancellation: &CancellationToken,
+) -> Result<Arc<Mutex<OpStateWorkflowData>>, WorkflowRunError> {
+    let tokio_runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(CoreError::from)?;
+
+    // If no workflow data is provided, create a default one
+    let data = workflow_data.unwrap_or_else(|| {
+        Arc::new(Mutex::new(OpStateWorkflowData::new(
+            "default_workflow",
+            false,
+        )))
+    });
+
+    // Check the plugin functions on the runtime of the run, so approvers can wait on timers and
+    // channels while they decide
+    let approval = PermissionApproval::new_from_options(options);
+    let mut ext = ext;
+    let mut bindings = bindings.to_vec();
+    let mut granted = permissions.to_vec();
+    let checked = tokio_runtime.block_on(async {
+        tokio::select! {
+            () = check_plugin_functions(
+                &mut bindings,
+                &mut ext,
+                approval.as_ref(),
+                &data,
+                &mut granted,
+            ) => true,
+            _ = cancellation.cancelled() => false,
+        }
+    });
+    if !checked {
+        return Err(WorkflowRunError::Cancelled);
+    }
+    let bindings = bindings.as_slice();
+    let permissions = permissions_container_from_proto(&granted).map_err(CoreError::from)?;
+
     // Register the extension with the provided operations
+    let mut wasm_plugins = wasm_plugins(bindings);
+    ext.extend([
+        op_validated_plugin_function(),
+        op_permission_denied(),
+        op_plugin_function_not_listed(),
+        op_grpc_plugin_invoke(),
+        op_native_plugin_invoke(),
+    ]);
+    // WASM plugin functions can reach the host, so runs without them do not get the op
+    if !wasm_plugins.is_empty() {
+        ext.push(op_wasm_plugin_function());
+    }
     let extension = Extension {
         name: "ext",
         ops: std::borrow::Cow::Owned(ext),
@@ -121,29 +727,174 @@ pub(crate) fn run_script(
         ..Default::default()
     };
 
-    // Create a new JsRuntime with the extension
+    let module_loader = Rc::new(WorkflowModuleLoader::new());
+    if let Some(source_map) = &module.source_map {
+        module_loader.add_source_map(&module.specifier, source_map.clone());
+    }
+    for binding in bindings {
+        module_loader.add_plugin_module(binding);
+    }
+
+    // Create a new JsRuntime with the extension, tracking the plugin ops being dispatched so that
+    // they can only read the configuration of their own package
+    let configs = plugin_configs(bindings);
+    let calling_ops = CallingOps::default();
     let mut runtime = JsRuntime::new(RuntimeOptions {
         extensions: vec![extension],
+        module_loader: Some(module_loader),
+        op_metrics_factory_fn: Some(calling_op_metrics(&calling_ops, &configs)),
+        create_params: options
+            .max_heap_size
+            .map(|max| v8::CreateParams::default().heap_limits(0, max)),
         ..Default::default()
     });
 
-    let mut data: Arc<Mutex<OpStateWorkflowData>>;
-    match workflow_data {
-        Some(d) => data = d,
-        None => {
-            // If no workflow data is provided, create a default one
-            data = Arc::new(Mutex::new(OpStateWorkflowData::new(
-                "default_workflow",
-                false,
-            )));
-        }
+    // Terminate the isolate before V8 aborts the whole process with an out-of-memory error
+    let heap_limit_reached = Arc::new(AtomicBool::new(false));
+    if options.max_heap_size.is_some() {
+        let isolate_handle = runtime.v8_isolate().thread_safe_handle();
+        let heap_limit_reached = heap_limit_reached.clone();
+        runtime.add_near_heap_limit_callback(move |current_limit, _initial_limit| {
+            heap_limit_reached.store(true, Ordering::SeqCst);
+            isolate_handle.terminate_execution();
+            // Give the isolate room to unwind the terminated execution
+            current_limit * 2
+        });
     }
+
     runtime.op_state().borrow_mut().put(data.clone());
+    runtime.op_state().borrow_mut().put(cancellation.clone());
+    runtime.op_state().borrow_mut().put(permissions);
+    runtime
+        .op_state()
+        .borrow_mut()
+        .put(plugin_function_schemas(bindings));
+    validate_plugin_ops(
+        &mut runtime.handle_scope(),
+        &plugin_ops_with_schemas(bindings),
+    )
+    .map_err(CoreError::from)?;
+    if !wasm_plugins.is_empty() {
+        wasm_plugins
+            .capture_intrinsics(&mut runtime.handle_scope())
+            .map_err(CoreError::from)?;
+        runtime.op_state().borrow_mut().put(wasm_plugins);
+    }
+    runtime
+        .op_state()
+        .borrow_mut()
+        .put(grpc_plugin_processes(bindings));
+    runtime
+        .op_state()
+        .borrow_mut()
+        .put(native_plugin_libraries(bindings));
+    runtime.op_state().borrow_mut().put(configs);
+    runtime.op_state().borrow_mut().put(calling_ops.clone());
+    runtime
+        .op_state()
+        .borrow_mut()
+        .put(plugin_secrets(bindings));
+    if let Some(approval) = approval {
+        runtime.op_state().borrow_mut().put(approval);
+    }
+    runtime
+        .execute_script(
+            "ext:sapphillon/permission.js",
+            PERMISSION_DENIED_ERROR_SCRIPT,
+        )
+        .map_err(CoreError::from)?;
+    runtime
+        .execute_script(
+            "ext:sapphillon/validation.js",
+            PLUGIN_FUNCTION_NOT_LISTED_ERROR_SCRIPT,
+        )
+        .map_err(CoreError::from)?;
 
-    // Execute the provided script in the runtime
-    let result = runtime.execute_script("workflow.js", script.to_string())?;
+    // Set up the plugin packages before any code of the run is evaluated
+    let lifecycles: Vec<_> = bindings
+        .iter()
+        .filter_map(|binding| {
+            binding
+                .lifecycle
+                .as_ref()
+                .map(|lifecycle| (binding.package_id.as_str(), lifecycle, &binding.config))
+        })
+        .collect();
+    // Time spent in the setup hooks counts against the timeout of the run
+    let deadline = options
+        .timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+    let watchdog = options
+        .timeout
+        .map(|timeout| Watchdog::spawn(runtime.v8_isolate().thread_safe_handle(), timeout));
+    cancellation.attach_isolate(runtime.v8_isolate().thread_safe_handle());
+    if let Err(e) = setup_plugin_lifecycles(&lifecycles, &mut runtime.op_state().borrow_mut()) {
+        cancellation.detach_isolate();
+        if let Some(watchdog) = watchdog {
+            watchdog.finish();
+        }
+        return Err(e);
+    }
 
-    Ok(data)
+    // Define `plugins` after evaluating the source modules of the plugins, then load the module
+    // as the main module, evaluate it and drive the event loop to completion
+    let execution = async {
+        let plugins_specifier = ModuleSpecifier::parse(PLUGINS_MODULE_SPECIFIER).unwrap();
+        let plugins_id = runtime
+            .load_side_es_module_from_code(&plugins_specifier, plugins_module_source(bindings))
+            .await?;
+        let evaluation = runtime.mod_evaluate(plugins_id);
+        run_event_loop(&mut runtime, &calling_ops).await?;
+        evaluation.await?;
+
+        let specifier = ModuleSpecifier::parse(&module.specifier).map_err(CoreError::from)?;
+        let module_id = runtime
+            .load_main_es_module_from_code(&specifier, module.code.clone())
+            .await?;
+        let evaluation = runtime.mod_evaluate(module_id);
+        run_event_loop(&mut runtime, &calling_ops).await?;
+        evaluation.await
+    };
+    let result = tokio_runtime.block_on(async {
+        let limited = async {
+            match deadline {
+                // Async ops that never settle keep the event loop pending without running any JavaScript
+                Some(deadline) => tokio::time::timeout_at(deadline, execution).await.ok(),
+                None => Some(execution.await),
+            }
+        };
+        tokio::select! {
+            result = limited => result,
+            _ = cancellation.cancelled() => None,
+        }
+    });
+
+    cancellation.detach_isolate();
+    calling_ops.clear();
+    let watchdog_fired = watchdog.map(Watchdog::finish).unwrap_or(false);
+    let result = if heap_limit_reached.load(Ordering::SeqCst) {
+        Err(WorkflowRunError::MemoryLimitExceeded(
+            options.max_heap_size.unwrap_or_default(),
+        ))
+    } else if cancellation.is_cancelled() && !matches!(result, Some(Ok(()))) {
+        Err(WorkflowRunError::Cancelled)
+    } else {
+        match result {
+            Some(Ok(())) => Ok(()),
+            Some(Err(e)) if !watchdog_fired => Err(WorkflowRunError::Js(e)),
+            // Either the watchdog terminated the isolate or the event loop did not finish in time
+            _ => Err(WorkflowRunError::TimedOut(
+                options.timeout.unwrap_or_default(),
+            )),
+        }
+    };
+
+    teardown_plugin_lifecycles(
+        &lifecycles,
+        &mut runtime.op_state().borrow_mut(),
+        result.as_ref().copied(),
+    );
+    result.map(|()| data)
 }
 
 #[cfg(test)]
@@ -177,12 +928,285 @@ mod tests {
     }
     #[test]
     fn test_run_script_hello() {
-        let script = "a = 1 + 1; console.log('Hello, world!');console.log(a);";
+        let script = "const a = 1 + 1; console.log('Hello, world!');console.log(a);";
 
         let result = run_script(script, vec![], None);
         assert!(result.is_ok(), "Script should run successfully");
     }
 
+    #[test]
+    fn test_run_script_top_level_await() {
+        #[op2(async)]
+        async fn op_async_double(x: u32) -> u32 {
+            x * 2
+        }
+
+        let workflow_data_arc = Arc::new(Mutex::new(OpStateWorkflowData::new("w", true)));
+        let script = r#"
+            const value = await Deno.core.ops.op_async_double(21);
+            const delayed = await new Promise((resolve) => resolve(value + 1));
+            console.log(value, delayed);
+        "#;
+
+        let result = run_script(
+            script,
+            vec![op_async_double()],
+            Some(workflow_data_arc.clone()),
+        );
+        assert!(result.is_ok(), "Async script should run successfully");
+        assert_eq!(
+            workflow_data_arc.lock().unwrap().get_results(),
+            &vec![WorkflowStdout::Stdout("42 43\n".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_run_script_rejected_promise() {
+        let script = "await Promise.reject(new Error('rejected'));";
+
+        let result = run_script(script, vec![], None);
+        assert!(result.unwrap_err().to_string().contains("rejected"));
+    }
+
+    #[test]
+    fn test_run_script_unhandled_rejection() {
+        let script = "Promise.reject(new Error('unhandled'));";
+
+        let result = run_script(script, vec![], None);
+        assert!(result.unwrap_err().to_string().contains("unhandled"));
+    }
+
+    #[test]
+    fn test_run_module_typescript_source_mapped_error() {
+        use crate::proto::sapphillon::v1::WorkflowLanguage;
+
+        let code = "interface Payload {\n  value: number;\n}\n\nconst payload: Payload = { value: 1 };\nthrow new Error(`ts fail ${payload.value}`);\n";
+        let module = WorkflowModule::new(code, WorkflowLanguage::Typescript).unwrap();
+
+        let err = run_module(
+            &module,
+            vec![],
+            &[],
+            None,
+            &[],
+            &WorkflowRunOptions::default(),
+            &CancellationToken::new(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("ts fail 1"));
+        assert!(err.contains("file:///workflow.ts:6:7"), "{err}");
+    }
+
+    #[test]
+    fn test_run_module_timeout_busy_loop() {
+        let options = WorkflowRunOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        let result = run_module(
+            &WorkflowModule::from_javascript("while (true) {}"),
+            vec![],
+            &[],
+            None,
+            &[],
+            &options,
+            &CancellationToken::new(),
+        );
+        let err = result.unwrap_err();
+        assert!(matches!(err, WorkflowRunError::TimedOut(_)));
+        assert_eq!(err.exit_code(), EXIT_CODE_TIMED_OUT);
+
+        // The process stays healthy for the next run
+        let result = run_module(
+            &WorkflowModule::from_javascript("console.log('next');"),
+            vec![],
+            &[],
+            None,
+            &[],
+            &options,
+            &CancellationToken::new(),
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_run_module_timeout_pending_async_op() {
+        #[op2(async)]
+        async fn op_never_settles() {
+            std::future::pending::<()>().await
+        }
+
+        let options = WorkflowRunOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let result = run_module(
+            &WorkflowModule::from_javascript("await Deno.core.ops.op_never_settles();"),
+            vec![op_never_settles()],
+            &[],
+            None,
+            &[],
+            &options,
+            &CancellationToken::new(),
+        );
+        assert!(matches!(result, Err(WorkflowRunError::TimedOut(_))));
+    }
+
+    #[test]
+    fn test_run_module_within_timeout() {
+        let options = WorkflowRunOptions {
+            timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let result = run_module(
+            &WorkflowModule::from_javascript("await Promise.resolve(1);"),
+            vec![],
+            &[],
+            None,
+            &[],
+            &options,
+            &CancellationToken::new(),
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_run_module_inside_tokio_runtime() {
+        #[op2(fast)]
+        fn op_answer() -> u32 {
+            42
+        }
+
+        let data = Arc::new(Mutex::new(OpStateWorkflowData::new("wid", true)));
+        let tokio_runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let result = tokio_runtime.block_on(async {
+            run_script(
+                "console.log(Deno.core.ops.op_answer());",
+                vec![op_answer()],
+                Some(data.clone()),
+            )
+        });
+        assert!(result.is_ok());
+        assert_eq!(
+            data.lock().unwrap().get_results(),
+            &vec![WorkflowStdout::Stdout("42\n".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_run_module_memory_limit_exceeded() {
+        let options = WorkflowRunOptions {
+            max_heap_size: Some(32 * 1024 * 1024),
+            ..Default::default()
+        };
+        let script = r#"
+            const chunks = [];
+            while (true) {
+                chunks.push(new Array(100000).fill("leak"));
+            }
+        "#;
+
+        let result = run_module(
+            &WorkflowModule::from_javascript(script),
+            vec![],
+            &[],
+            None,
+            &[],
+            &options,
+            &CancellationToken::new(),
+        );
+        let err = result.unwrap_err();
+        assert!(matches!(err, WorkflowRunError::MemoryLimitExceeded(_)));
+        assert_eq!(err.exit_code(), EXIT_CODE_MEMORY_LIMIT_EXCEEDED);
+
+        // The process survives and can run the next workflow
+        let result = run_module(
+            &WorkflowModule::from_javascript("console.log('next');"),
+            vec![],
+            &[],
+            None,
+            &[],
+            &options,
+            &CancellationToken::new(),
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_run_module_cancel_busy_loop() {
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            canceller.cancel();
+        });
+
+        let result = run_module(
+            &WorkflowModule::from_javascript("while (true) {}"),
+            vec![],
+            &[],
+            None,
+            &[],
+            &WorkflowRunOptions::default(),
+            &token,
+        );
+        handle.join().unwrap();
+        let err = result.unwrap_err();
+        assert!(matches!(err, WorkflowRunError::Cancelled));
+        assert_eq!(err.exit_code(), EXIT_CODE_CANCELLED);
+    }
+
+    #[test]
+    fn test_run_module_cancel_observed_by_async_op() {
+        #[op2(async)]
+        async fn op_wait_for_cancel(state: Rc<RefCell<OpState>>) -> Result<(), JsErrorBox> {
+            let token = state.borrow().borrow::<CancellationToken>().clone();
+            token.cancelled().await;
+            Err(JsErrorBox::generic("cancelled"))
+        }
+
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            canceller.cancel();
+        });
+
+        let result = run_module(
+            &WorkflowModule::from_javascript("await Deno.core.ops.op_wait_for_cancel();"),
+            vec![op_wait_for_cancel()],
+            &[],
+            None,
+            &[],
+            &WorkflowRunOptions::default(),
+            &token,
+        );
+        handle.join().unwrap();
+        assert!(matches!(result, Err(WorkflowRunError::Cancelled)));
+    }
+
+    #[test]
+    fn test_run_module_cancelled_before_start() {
+        let token = CancellationToken::new();
+        token.cancel();
+
+        let result = run_module(
+            &WorkflowModule::from_javascript("console.log('never');"),
+            vec![],
+            &[],
+            None,
+            &[],
+            &WorkflowRunOptions::default(),
+            &token,
+        );
+        assert!(matches!(result, Err(WorkflowRunError::Cancelled)));
+    }
+
     #[test]
     fn test_run_script_opstate_workflow_data() {
         // テスト用op: opstateからworkflow_idを取得
@@ -202,6 +1226,9 @@ mod tests {
             workflow_id: "test_id_123".to_string(),
             result: vec![],
             capture_stdout: false,
+            permission_decisions: vec![],
+            plugin_function_ids: HashMap::new(),
+            audit_events: vec![],
         };
         let workflow_data_arc = Arc::new(Mutex::new(workflow_data.clone()));
 
@@ -241,6 +1268,9 @@ mod tests {
             workflow_id: "test_id_123".to_string(),
             result: vec![WorkflowStdout::Stdout("Initial stdout".to_string())],
             capture_stdout: true,
+            permission_decisions: vec![],
+            plugin_function_ids: HashMap::new(),
+            audit_events: vec![],
         };
         let workflow_data_arc = Arc::new(Mutex::new(workflow_data.clone()));
 
@@ -278,6 +1308,9 @@ mod tests {
             workflow_id: "test_id_123".to_string(),
             result: vec![],
             capture_stdout: true,
+            permission_decisions: vec![],
+            plugin_function_ids: HashMap::new(),
+            audit_events: vec![],
         };
         let workflow_data_arc = Arc::new(Mutex::new(workflow_data.clone()));
 
@@ -314,6 +1347,9 @@ mod tests {
             workflow_id: "w".to_string(),
             result: vec![],
             capture_stdout: true,
+            permission_decisions: vec![],
+            plugin_function_ids: HashMap::new(),
+            audit_events: vec![],
         };
         assert_eq!(data.stdout_to_string(), "");
     }
@@ -324,6 +1360,9 @@ mod tests {
             workflow_id: "w".to_string(),
             result: vec![WorkflowStdout::Stdout("Hello".to_string())],
             capture_stdout: true,
+            permission_decisions: vec![],
+            plugin_function_ids: HashMap::new(),
+            audit_events: vec![],
         };
         assert_eq!(data.stdout_to_string(), "Hello");
     }
@@ -338,6 +1377,9 @@ mod tests {
                 WorkflowStdout::Stdout("Three".to_string()),
             ],
             capture_stdout: true,
+            permission_decisions: vec![],
+            plugin_function_ids: HashMap::new(),
+            audit_events: vec![],
         };
         assert_eq!(data.stdout_to_string(), "One\nTwo\nThree");
     }
@@ -350,6 +1392,9 @@ mod tests {
             workflow_id: "test_id_123".to_string(),
             result: vec![],
             capture_stdout: true,
+            permission_decisions: vec![],
+            plugin_function_ids: HashMap::new(),
+            audit_events: vec![],
         };
         let workflow_data_arc = Arc::new(Mutex::new(workflow_data.clone()));
 
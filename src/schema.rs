@@ -0,0 +1,1012 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Argument and return value schemas of plugin functions.
+//!
+//! Schemas are written in a subset of JSON Schema. The supported keywords are `type`, `enum`,
+//! `const`, `anyOf`, `properties`, `required`, `additionalProperties`, `items`, `minItems`,
+//! `maxItems`, `minLength`, `maxLength`, `pattern`, `minimum` and `maximum`; other keywords,
+//! such as `description`, are ignored.
+//!
+//! Schemas are checked, and their patterns compiled, when a `FunctionSchema` is created.
+//!
+//! Plugin functions with a schema are validated in Rust: before any code of a run is evaluated,
+//! their ops are replaced in `Deno.core.ops` by V8 functions that validate the arguments before
+//! entering the op and the return value after it returns, and the other plugin functions are
+//! wrapped the same way where they are exposed. Mismatches are thrown into JavaScript as
+//! `TypeError`s.
+
+use crate::declaration::{FunctionParameter, FunctionSignature};
+use deno_core::{JsRuntime, op2, serde_v8, v8};
+use deno_error::JsErrorBox;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Schema of a parameter of a plugin function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterSchema {
+    /// Parameter name, used in error messages and declarations
+    pub name: String,
+    /// Description of the parameter
+    pub description: String,
+    /// JSON Schema of the argument
+    pub schema: Value,
+    /// Whether the argument may be omitted or `undefined`
+    pub optional: bool,
+}
+
+impl ParameterSchema {
+    /// Creates a new required ParameterSchema.
+    ///
+    /// # Arguments
+    /// * `name` - Parameter name
+    /// * `description` - Description of the parameter
+    /// * `schema` - JSON Schema of the argument
+    pub fn new(name: String, description: String, schema: Value) -> Self {
+        Self {
+            name,
+            description,
+            schema,
+            optional: false,
+        }
+    }
+}
+
+/// Argument and return value schemas of a plugin function.
+#[derive(Debug, Clone, Default)]
+pub struct FunctionSchema {
+    /// Schemas of the parameters, in order. Calls with more arguments are rejected.
+    parameters: Vec<ParameterSchema>,
+    /// JSON Schema of the return value, or `None` if it is not validated
+    returns: Option<Value>,
+    /// Compiled `pattern`s of the schemas, keyed by pattern
+    patterns: HashMap<String, Regex>,
+}
+
+impl PartialEq for FunctionSchema {
+    fn eq(&self, other: &Self) -> bool {
+        // The patterns are derived from the schemas
+        self.parameters == other.parameters && self.returns == other.returns
+    }
+}
+
+/// Value that does not match a schema.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaError {
+    /// Path of the mismatching value, e.g. `options.targets[1]`
+    pub path: String,
+    /// What is wrong with the value
+    pub message: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+/// Schema that is not valid, e.g. with a `pattern` that is not a regular expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSchemaError {
+    /// Path of the invalid keyword, e.g. `options.properties.name.pattern`
+    pub path: String,
+    /// What is wrong with the keyword
+    pub message: String,
+}
+
+impl fmt::Display for InvalidSchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid schema: {} {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for InvalidSchemaError {}
+
+/// Types a schema may require with `type`.
+const TYPES: [&str; 7] = [
+    "null", "boolean", "integer", "number", "string", "array", "object",
+];
+
+/// Checks that a JSON Schema is valid, compiling its patterns into `patterns`.
+///
+/// # Arguments
+/// * `schema` - JSON Schema to check
+/// * `path` - Path of the schema, used in the error
+/// * `patterns` - Compiled patterns, keyed by pattern
+fn check_schema(
+    schema: &Value,
+    path: &str,
+    patterns: &mut HashMap<String, Regex>,
+) -> Result<(), InvalidSchemaError> {
+    let invalid = |keyword: &str, message: &str| InvalidSchemaError {
+        path: match keyword {
+            "" => path.to_string(),
+            keyword => format!("{path}.{keyword}"),
+        },
+        message: message.to_string(),
+    };
+    let schema = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(schema) => schema,
+        _ => return Err(invalid("", "must be an object or a boolean")),
+    };
+
+    for (keyword, value) in schema {
+        match keyword.as_str() {
+            "type" => {
+                let valid = match value {
+                    Value::String(t) => TYPES.contains(&t.as_str()),
+                    Value::Array(ts) => ts
+                        .iter()
+                        .all(|t| t.as_str().is_some_and(|t| TYPES.contains(&t))),
+                    _ => false,
+                };
+                if !valid {
+                    return Err(invalid(
+                        keyword,
+                        "must be a type name or an array of type names",
+                    ));
+                }
+            }
+            "enum" if !value.is_array() => return Err(invalid(keyword, "must be an array")),
+            "anyOf" => {
+                let options = value
+                    .as_array()
+                    .filter(|options| !options.is_empty())
+                    .ok_or_else(|| invalid(keyword, "must be a non-empty array"))?;
+                for (i, option) in options.iter().enumerate() {
+                    check_schema(option, &format!("{path}.anyOf[{i}]"), patterns)?;
+                }
+            }
+            "properties" => {
+                let properties = value
+                    .as_object()
+                    .ok_or_else(|| invalid(keyword, "must be an object"))?;
+                for (key, property) in properties {
+                    check_schema(property, &format!("{path}.properties.{key}"), patterns)?;
+                }
+            }
+            "required"
+                if !value
+                    .as_array()
+                    .is_some_and(|keys| keys.iter().all(Value::is_string)) =>
+            {
+                return Err(invalid(keyword, "must be an array of strings"));
+            }
+            "additionalProperties" | "items" => {
+                check_schema(value, &format!("{path}.{keyword}"), patterns)?
+            }
+            "minItems" | "maxItems" | "minLength" | "maxLength" if !value.is_u64() => {
+                return Err(invalid(keyword, "must be a non-negative integer"));
+            }
+            "minimum" | "maximum" if !value.is_number() => {
+                return Err(invalid(keyword, "must be a number"));
+            }
+            "pattern" => {
+                let pattern = value
+                    .as_str()
+                    .ok_or_else(|| invalid(keyword, "must be a string"))?;
+                let regex = Regex::new(pattern).map_err(|e| {
+                    invalid(keyword, &format!("is not a valid regular expression: {e}"))
+                })?;
+                patterns.insert(pattern.to_string(), regex);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns the JSON Schema type name of a value.
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(n) if n.as_f64().is_some_and(|f| f.fract() == 0.0) => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "number" => value.is_number(),
+        expected => type_name(value) == expected,
+    }
+}
+
+/// Compiled patterns of a `FunctionSchema`, keyed by pattern.
+type Patterns = HashMap<String, Regex>;
+
+/// Validates a value against a JSON Schema that was checked by `check_schema`.
+///
+/// # Arguments
+/// * `schema` - JSON Schema to validate against
+/// * `value` - Value to validate
+/// * `path` - Path of the value, used in the error
+/// * `patterns` - Compiled patterns of the schema
+fn validate(
+    schema: &Value,
+    value: &Value,
+    path: &str,
+    patterns: &Patterns,
+) -> Result<(), SchemaError> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(error(path, "is not allowed".to_string())),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|t| has_type(value, t)) {
+            let actual = match type_name(value) {
+                "integer" => "number",
+                actual => actual,
+            };
+            return Err(error(
+                path,
+                format!("must be of type {}, got {actual}", types.join(" or ")),
+            ));
+        }
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum")
+        && !allowed.contains(value)
+    {
+        return Err(error(
+            path,
+            format!("must be one of {}", Value::from(allowed.clone())),
+        ));
+    }
+    if let Some(expected) = schema.get("const")
+        && expected != value
+    {
+        return Err(error(path, format!("must be {expected}")));
+    }
+    if let Some(Value::Array(options)) = schema.get("anyOf")
+        && !options
+            .iter()
+            .any(|option| validate(option, value, path, patterns).is_ok())
+    {
+        return Err(error(
+            path,
+            "does not match any of the allowed schemas".to_string(),
+        ));
+    }
+
+    match value {
+        Value::String(s) => validate_string(schema, s, path, patterns),
+        Value::Number(n) => validate_number(schema, n.as_f64().unwrap_or_default(), path),
+        Value::Array(items) => validate_array(schema, items, path, patterns),
+        Value::Object(object) => validate_object(schema, object, path, patterns),
+        _ => Ok(()),
+    }
+}
+
+fn error(path: &str, message: String) -> SchemaError {
+    SchemaError {
+        path: path.to_string(),
+        message,
+    }
+}
+
+fn validate_string(
+    schema: &Map<String, Value>,
+    s: &str,
+    path: &str,
+    patterns: &Patterns,
+) -> Result<(), SchemaError> {
+    let length = s.chars().count() as u64;
+    if let Some(min) = schema.get("minLength").and_then(Value::as_u64)
+        && length < min
+    {
+        return Err(error(
+            path,
+            format!("must be at least {min} characters long"),
+        ));
+    }
+    if let Some(max) = schema.get("maxLength").and_then(Value::as_u64)
+        && length > max
+    {
+        return Err(error(
+            path,
+            format!("must be at most {max} characters long"),
+        ));
+    }
+    if let Some(pattern) = schema.get("pattern").and_then(Value::as_str)
+        && patterns.get(pattern).is_some_and(|re| !re.is_match(s))
+    {
+        return Err(error(path, format!("must match the pattern {pattern}")));
+    }
+    Ok(())
+}
+
+fn validate_number(schema: &Map<String, Value>, n: f64, path: &str) -> Result<(), SchemaError> {
+    if let Some(min) = schema.get("minimum").and_then(Value::as_f64)
+        && n < min
+    {
+        return Err(error(path, format!("must be at least {min}")));
+    }
+    if let Some(max) = schema.get("maximum").and_then(Value::as_f64)
+        && n > max
+    {
+        return Err(error(path, format!("must be at most {max}")));
+    }
+    Ok(())
+}
+
+fn validate_array(
+    schema: &Map<String, Value>,
+    items: &[Value],
+    path: &str,
+    patterns: &Patterns,
+) -> Result<(), SchemaError> {
+    let length = items.len() as u64;
+    if let Some(min) = schema.get("minItems").and_then(Value::as_u64)
+        && length < min
+    {
+        return Err(error(path, format!("must have at least {min} items")));
+    }
+    if let Some(max) = schema.get("maxItems").and_then(Value::as_u64)
+        && length > max
+    {
+        return Err(error(path, format!("must have at most {max} items")));
+    }
+    if let Some(item_schema) = schema.get("items") {
+        for (i, item) in items.iter().enumerate() {
+            validate(item_schema, item, &format!("{path}[{i}]"), patterns)?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_object(
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+    patterns: &Patterns,
+) -> Result<(), SchemaError> {
+    if let Some(Value::Array(required)) = schema.get("required") {
+        for key in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(key) {
+                return Err(error(&format!("{path}.{key}"), "is required".to_string()));
+            }
+        }
+    }
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (key, value) in object {
+        let property_path = format!("{path}.{key}");
+        match properties.and_then(|p| p.get(key)) {
+            Some(property_schema) => validate(property_schema, value, &property_path, patterns)?,
+            None => {
+                if let Some(additional) = schema.get("additionalProperties") {
+                    validate(additional, value, &property_path, patterns)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the TypeScript type of the values a JSON Schema accepts.
+///
+/// # Arguments
+/// * `schema` - JSON Schema to convert
+pub fn typescript_type(schema: &Value) -> String {
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        Value::Bool(false) => return "never".to_string(),
+        _ => return "unknown".to_string(),
+    };
+    if let Some(expected) = schema.get("const") {
+        return expected.to_string();
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        return union(allowed.iter().map(Value::to_string));
+    }
+    if let Some(Value::Array(options)) = schema.get("anyOf") {
+        return union(options.iter().map(typescript_type));
+    }
+    match schema.get("type") {
+        Some(Value::String(t)) => typescript_type_of(schema, t),
+        Some(Value::Array(ts)) => union(
+            ts.iter()
+                .filter_map(Value::as_str)
+                .map(|t| typescript_type_of(schema, t)),
+        ),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn union(types: impl Iterator<Item = String>) -> String {
+    let types: Vec<String> = types.collect();
+    match types.is_empty() {
+        true => "never".to_string(),
+        false => types.join(" | "),
+    }
+}
+
+fn typescript_type_of(schema: &Map<String, Value>, json_type: &str) -> String {
+    match json_type {
+        "string" => "string".to_string(),
+        "number" | "integer" => "number".to_string(),
+        "boolean" => "boolean".to_string(),
+        "null" => "null".to_string(),
+        "array" => format!(
+            "Array<{}>",
+            schema
+                .get("items")
+                .map(typescript_type)
+                .unwrap_or_else(|| "unknown".to_string())
+        ),
+        "object" => {
+            let required: Vec<&str> = schema
+                .get("required")
+                .and_then(Value::as_array)
+                .map(|r| r.iter().filter_map(Value::as_str).collect())
+                .unwrap_or_default();
+            let additional = match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => None,
+                Some(additional @ Value::Object(_)) => Some(typescript_type(additional)),
+                _ => Some("unknown".to_string()),
+            };
+            let properties = schema.get("properties").and_then(Value::as_object);
+            match (properties, additional) {
+                (Some(properties), additional) => {
+                    let mut members: Vec<String> = properties
+                        .iter()
+                        .map(|(key, property)| {
+                            let optional = if required.contains(&key.as_str()) {
+                                ""
+                            } else {
+                                "?"
+                            };
+                            format!(
+                                "{}{optional}: {}",
+                                serde_json::to_string(key).unwrap(),
+                                typescript_type(property)
+                            )
+                        })
+                        .collect();
+                    if let Some(additional) = additional.filter(|a| a != "unknown") {
+                        members.push(format!("[key: string]: {additional}"));
+                    }
+                    format!("{{ {} }}", members.join("; "))
+                }
+                (None, Some(additional)) => format!("Record<string, {additional}>"),
+                (None, None) => "Record<string, never>".to_string(),
+            }
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+impl FunctionSchema {
+    /// Creates a new FunctionSchema, compiling the patterns of its schemas.
+    ///
+    /// # Arguments
+    /// * `parameters` - Schemas of the parameters, in order
+    /// * `returns` - JSON Schema of the return value, or `None` if it is not validated
+    ///
+    /// # Errors
+    /// Returns an `InvalidSchemaError` if a schema uses a supported keyword with an invalid
+    /// value, e.g. a `pattern` that is not a regular expression.
+    pub fn new(
+        parameters: Vec<ParameterSchema>,
+        returns: Option<Value>,
+    ) -> Result<Self, InvalidSchemaError> {
+        let mut patterns = HashMap::new();
+        for parameter in &parameters {
+            check_schema(&parameter.schema, &parameter.name, &mut patterns)?;
+        }
+        if let Some(returns) = &returns {
+            check_schema(returns, "return value", &mut patterns)?;
+        }
+        Ok(Self {
+            parameters,
+            returns,
+            patterns,
+        })
+    }
+
+    /// Returns the schemas of the parameters, in order.
+    pub fn parameters(&self) -> &[ParameterSchema] {
+        &self.parameters
+    }
+
+    /// Returns the JSON Schema of the return value, or `None` if it is not validated.
+    pub fn returns(&self) -> Option<&Value> {
+        self.returns.as_ref()
+    }
+
+    /// Validates the arguments of a call. Omitted and `undefined` arguments are passed as `null`.
+    ///
+    /// # Arguments
+    /// * `args` - Arguments of the call, in order
+    pub fn validate_arguments(&self, args: &[Value]) -> Result<(), SchemaError> {
+        if args.len() > self.parameters.len() {
+            return Err(error(
+                "arguments",
+                format!(
+                    "must have at most {} items, got {}",
+                    self.parameters.len(),
+                    args.len()
+                ),
+            ));
+        }
+        for (i, parameter) in self.parameters.iter().enumerate() {
+            match args.get(i).unwrap_or(&Value::Null) {
+                Value::Null if parameter.optional => {}
+                Value::Null
+                    if validate(&parameter.schema, &Value::Null, "", &self.patterns).is_err() =>
+                {
+                    return Err(error(&parameter.name, "is required".to_string()));
+                }
+                arg => validate(&parameter.schema, arg, &parameter.name, &self.patterns)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates the return value of a call, or of the promise it returned.
+    ///
+    /// # Arguments
+    /// * `value` - Return value of the call
+    pub fn validate_return_value(&self, value: &Value) -> Result<(), SchemaError> {
+        match &self.returns {
+            Some(schema) => validate(schema, value, "return value", &self.patterns),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the TypeScript signature of the function described by this schema.
+    pub fn signature(&self) -> FunctionSignature {
+        let parameters = self
+            .parameters
+            .iter()
+            .map(|p| FunctionParameter {
+                name: p.name.clone(),
+                type_name: typescript_type(&p.schema),
+                description: p.description.clone(),
+                optional: p.optional,
+            })
+            .collect();
+        let return_type = match &self.returns {
+            Some(schema) => typescript_type(schema),
+            None => "unknown".to_string(),
+        };
+        let return_description = self
+            .returns
+            .as_ref()
+            .and_then(|schema| schema.get("description"))
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        FunctionSignature {
+            parameters,
+            return_type,
+            return_description,
+        }
+    }
+}
+
+/// Schemas of the plugin functions of a run, placed into the `OpState` and keyed by function ID.
+#[derive(Default)]
+pub(crate) struct PluginFunctionSchemas(pub(crate) HashMap<String, Rc<FunctionSchema>>);
+
+/// Looks up the schema of the plugin function with the given ID.
+fn lookup(isolate: &v8::Isolate, function_id: &str) -> Option<Rc<FunctionSchema>> {
+    let state = JsRuntime::op_state_from(isolate);
+    let state = state.borrow();
+    state
+        .try_borrow::<PluginFunctionSchemas>()?
+        .0
+        .get(function_id)
+        .cloned()
+}
+
+/// Throws a `TypeError` with the given message into JavaScript.
+fn throw_type_error(scope: &mut v8::HandleScope, message: &str) {
+    let message = v8::String::new(scope, message).unwrap();
+    let exception = v8::Exception::type_error(scope, message);
+    scope.throw_exception(exception);
+}
+
+/// Returns the ID of the plugin function and the function that a validated function was created
+/// for, from the data of the validated function.
+fn validated_target<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    data: v8::Local<v8::Value>,
+) -> Option<(String, v8::Local<'s, v8::Function>)> {
+    let data = v8::Local::<v8::Array>::try_from(data).ok()?;
+    let function_id = data.get_index(scope, 0)?.to_rust_string_lossy(scope);
+    let target = data.get_index(scope, 1)?.try_into().ok()?;
+    Some((function_id, target))
+}
+
+/// Validates a return value, throwing a `TypeError` on mismatch. Returns whether it is valid.
+fn check_return_value(
+    scope: &mut v8::HandleScope,
+    schema: &FunctionSchema,
+    function_id: &str,
+    value: v8::Local<v8::Value>,
+) -> bool {
+    let result = serde_v8::from_v8::<Value>(scope, value)
+        .map_err(|e| error("return value", format!("is not JSON: {e}")))
+        .and_then(|value| schema.validate_return_value(&value));
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            throw_type_error(
+                scope,
+                &format!("Invalid return value of {function_id}: {e}"),
+            );
+            false
+        }
+    }
+}
+
+/// Callback of validated functions, see `validated_function`.
+fn call_validated<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    args: v8::FunctionCallbackArguments<'s>,
+    mut rv: v8::ReturnValue,
+) {
+    let Some((function_id, target)) = validated_target(scope, args.data()) else {
+        return;
+    };
+    let Some(schema) = lookup(scope, &function_id) else {
+        return throw_type_error(scope, &format!("No schema is registered for {function_id}"));
+    };
+
+    let values: Vec<_> = (0..args.length()).map(|i| args.get(i)).collect();
+    let result = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            serde_v8::from_v8::<Value>(scope, *value)
+                .map_err(|e| error(&format!("arguments[{i}]"), format!("is not JSON: {e}")))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|json| schema.validate_arguments(&json));
+    if let Err(e) = result {
+        return throw_type_error(scope, &format!("Invalid arguments for {function_id}: {e}"));
+    }
+
+    let Some(value) = target.call(scope, args.this().into(), &values) else {
+        // The target threw
+        return;
+    };
+    if schema.returns.is_none() {
+        return rv.set(value);
+    }
+    match v8::Local::<v8::Promise>::try_from(value) {
+        Ok(promise) => {
+            if let Some(promise) = v8::Function::builder(resolve_validated)
+                .data(args.data())
+                .build(scope)
+                .and_then(|on_fulfilled| promise.then(scope, on_fulfilled))
+            {
+                rv.set(promise.into());
+            }
+        }
+        Err(_) => {
+            if check_return_value(scope, &schema, &function_id, value) {
+                rv.set(value);
+            }
+        }
+    }
+}
+
+/// Fulfillment handler of the promises returned by validated functions, which validates the
+/// value the promise is fulfilled with.
+fn resolve_validated<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    args: v8::FunctionCallbackArguments<'s>,
+    mut rv: v8::ReturnValue,
+) {
+    let Some((function_id, _)) = validated_target(scope, args.data()) else {
+        return;
+    };
+    let Some(schema) = lookup(scope, &function_id) else {
+        return throw_type_error(scope, &format!("No schema is registered for {function_id}"));
+    };
+    let value = args.get(0);
+    if check_return_value(scope, &schema, &function_id, value) {
+        rv.set(value);
+    }
+}
+
+/// Returns a function that validates its arguments against the schema of a plugin function,
+/// calls `target` and validates the return value, or the value of the promise it returned.
+///
+/// The schema is looked up in the `PluginFunctionSchemas` of the `OpState` when the function
+/// is called.
+///
+/// # Arguments
+/// * `scope` - Scope to create the function in
+/// * `function_id` - ID of the plugin function
+/// * `target` - Function to call
+fn validated_function<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    function_id: &str,
+    target: v8::Local<v8::Function>,
+) -> Option<v8::Local<'s, v8::Function>> {
+    let function_id = v8::String::new(scope, function_id)?;
+    let data = v8::Array::new_with_elements(scope, &[function_id.into(), target.into()]);
+    v8::Function::builder(call_validated)
+        .data(data.into())
+        .build(scope)
+}
+
+/// Replaces ops in `Deno.core.ops` by functions that validate them against the schemas of their
+/// plugin functions.
+///
+/// # Arguments
+/// * `scope` - Scope of the main context of the runtime
+/// * `ops` - Plugin function IDs and the names of their ops
+///
+/// # Errors
+/// Returns a `JsErrorBox` if an op is not registered.
+pub(crate) fn validate_plugin_ops(
+    scope: &mut v8::HandleScope,
+    ops: &[(&str, &str)],
+) -> Result<(), JsErrorBox> {
+    fn get<'s>(
+        scope: &mut v8::HandleScope<'s>,
+        object: v8::Local<v8::Object>,
+        key: &str,
+    ) -> Option<v8::Local<'s, v8::Value>> {
+        let key = v8::String::new(scope, key)?;
+        object.get(scope, key.into())
+    }
+
+    let global = scope.get_current_context().global(scope);
+    let ops_object = get(scope, global, "Deno")
+        .and_then(|deno| get(scope, deno.try_into().ok()?, "core"))
+        .and_then(|core| get(scope, core.try_into().ok()?, "ops"))
+        .and_then(|ops| v8::Local::<v8::Object>::try_from(ops).ok())
+        .ok_or_else(|| JsErrorBox::generic("Deno.core.ops is not defined"))?;
+
+    for (function_id, op_name) in ops {
+        let not_registered = || {
+            JsErrorBox::type_error(format!(
+                "Op {op_name} of plugin function {function_id} is not registered"
+            ))
+        };
+        let op = get(scope, ops_object, op_name)
+            .and_then(|op| v8::Local::<v8::Function>::try_from(op).ok())
+            .ok_or_else(not_registered)?;
+        let key = v8::String::new(scope, op_name).ok_or_else(not_registered)?;
+        let validated = validated_function(scope, function_id, op).ok_or_else(not_registered)?;
+        ops_object.set(scope, key.into(), validated.into());
+    }
+    Ok(())
+}
+
+/// Returns a function that validates the arguments and return value of the given plugin
+/// function, for plugin functions that are not ops.
+#[op2]
+pub(crate) fn op_validated_plugin_function<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    #[string] function_id: &str,
+    target: v8::Local<v8::Function>,
+) -> Result<v8::Local<'s, v8::Function>, JsErrorBox> {
+    validated_function(scope, function_id, target).ok_or_else(|| {
+        JsErrorBox::type_error(format!("Plugin function {function_id} cannot be validated"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn send_schema() -> FunctionSchema {
+        FunctionSchema::new(
+            vec![
+                ParameterSchema::new(
+                    "title".to_string(),
+                    "Title of the notification".to_string(),
+                    json!({"type": "string", "minLength": 1}),
+                ),
+                ParameterSchema {
+                    optional: true,
+                    ..ParameterSchema::new(
+                        "options".to_string(),
+                        "".to_string(),
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "priority": {"enum": ["low", "high"]},
+                                "retries": {"type": "integer", "minimum": 0},
+                                "targets": {"type": "array", "items": {"type": "string"}},
+                            },
+                            "required": ["priority"],
+                            "additionalProperties": false,
+                        }),
+                    )
+                },
+            ],
+            Some(json!({"type": "boolean", "description": "Whether it was sent"})),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_arguments() {
+        let schema = send_schema();
+        assert!(schema.validate_arguments(&[json!("hi")]).is_ok());
+        assert!(
+            schema
+                .validate_arguments(&[json!("hi"), json!({"priority": "low", "retries": 2})])
+                .is_ok()
+        );
+
+        let message = |args: &[Value]| schema.validate_arguments(args).unwrap_err().to_string();
+        assert_eq!(message(&[]), "title is required");
+        assert_eq!(
+            message(&[json!(1)]),
+            "title must be of type string, got number"
+        );
+        assert_eq!(
+            message(&[json!("")]),
+            "title must be at least 1 characters long"
+        );
+        assert_eq!(
+            message(&[json!("hi"), json!({})]),
+            "options.priority is required"
+        );
+        assert_eq!(
+            message(&[json!("hi"), json!({"priority": "urgent"})]),
+            r#"options.priority must be one of ["low","high"]"#
+        );
+        assert_eq!(
+            message(&[json!("hi"), json!({"priority": "low", "retries": 1.5})]),
+            "options.retries must be of type integer, got number"
+        );
+        assert_eq!(
+            message(&[json!("hi"), json!({"priority": "low", "targets": ["a", 2]})]),
+            "options.targets[1] must be of type string, got number"
+        );
+        assert_eq!(
+            message(&[json!("hi"), json!({"priority": "low", "color": "red"})]),
+            "options.color is not allowed"
+        );
+        assert_eq!(
+            message(&[json!("hi"), json!(null), json!(1)]),
+            "arguments must have at most 2 items, got 3"
+        );
+
+        assert!(schema.validate_return_value(&json!(true)).is_ok());
+        assert_eq!(
+            schema
+                .validate_return_value(&json!("yes"))
+                .unwrap_err()
+                .to_string(),
+            "return value must be of type boolean, got string"
+        );
+    }
+
+    #[test]
+    fn test_function_schema_new_invalid() {
+        let parameter = |schema: Value| {
+            FunctionSchema::new(
+                vec![ParameterSchema::new(
+                    "id".to_string(),
+                    "".to_string(),
+                    schema,
+                )],
+                None,
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            parameter(json!({"type": "string", "pattern": "^[a-z]+$"})),
+            Ok(())
+        );
+        assert!(
+            parameter(json!({"type": "object", "properties": {"name": {"pattern": "("}}}))
+                .unwrap_err()
+                .starts_with(
+                    "Invalid schema: id.properties.name.pattern is not a valid regular expression"
+                )
+        );
+        assert_eq!(
+            parameter(json!({"type": "text"})),
+            Err(
+                "Invalid schema: id.type must be a type name or an array of type names".to_string()
+            )
+        );
+        assert_eq!(
+            parameter(json!({"anyOf": [{"type": "string"}, 1]})),
+            Err("Invalid schema: id.anyOf[1] must be an object or a boolean".to_string())
+        );
+        assert_eq!(
+            parameter(json!({"type": "array", "minItems": -1})),
+            Err("Invalid schema: id.minItems must be a non-negative integer".to_string())
+        );
+        assert_eq!(
+            parameter(json!({"required": "name"})),
+            Err("Invalid schema: id.required must be an array of strings".to_string())
+        );
+        assert!(
+            FunctionSchema::new(Vec::new(), Some(json!({"maximum": "10"})))
+                .unwrap_err()
+                .to_string()
+                .starts_with("Invalid schema: return value.maximum")
+        );
+    }
+
+    #[test]
+    fn test_validate_pattern() {
+        let schema = FunctionSchema::new(
+            vec![ParameterSchema::new(
+                "ids".to_string(),
+                "".to_string(),
+                json!({"type": "array", "items": {"type": "string", "pattern": "^[a-z]+$"}}),
+            )],
+            None,
+        )
+        .unwrap();
+        assert_eq!(schema.patterns.len(), 1);
+        assert!(schema.validate_arguments(&[json!(["abc", "def"])]).is_ok());
+        assert_eq!(
+            schema
+                .validate_arguments(&[json!(["abc", "D"])])
+                .unwrap_err()
+                .to_string(),
+            "ids[1] must match the pattern ^[a-z]+$"
+        );
+    }
+
+    #[test]
+    fn test_signature() {
+        let signature = send_schema().signature();
+        assert_eq!(signature.parameters[0].type_name, "string");
+        assert_eq!(
+            signature.parameters[1].type_name,
+            r#"{ "priority": "low" | "high"; "retries"?: number; "targets"?: Array<string> }"#
+        );
+        assert!(signature.parameters[1].optional);
+        assert_eq!(signature.return_type, "boolean");
+        assert_eq!(signature.return_description, "Whether it was sent");
+        assert_eq!(
+            typescript_type(&json!({"type": ["string", "null"]})),
+            "string | null"
+        );
+        assert_eq!(
+            typescript_type(&json!({"type": "object", "additionalProperties": {"type": "number"}})),
+            "Record<string, number>"
+        );
+    }
+}
@@ -0,0 +1,492 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Signatures of plugin packages.
+//!
+//! Publishers sign a package with an ed25519 key, and hosts verify it against the publisher keys
+//! of a `PluginTrustStore`. The signed message is made of:
+//! - `SIGNATURE_CONTEXT`, so signatures of packages cannot be reused for other messages;
+//! - the SHA-256 digest of the canonical manifest, made of:
+//!   - the proto `PluginPackage` of the package encoded as protobuf, without `verified`,
+//!     `installed_at` and `updated_at`, which are set by the host that installs the package;
+//!   - the body of each function: the name of its op, the module and name of its export, or
+//!     whether it is served by a gRPC or native plugin, and its argument and return value
+//!     schemas, with the keys of JSON objects sorted;
+//!   - the names and types of the configuration entries. Their values are set by the host and
+//!     are not covered;
+//!   - the path and canonical limits of each WASM module;
+//!   - whether the package has lifecycle hooks;
+//! - the SHA-256 digest of the artifacts: the path, language and code of each source module,
+//!   and the path and published binary of each WASM module, in order.
+//!
+//! Changing the metadata, functions, permissions, schemas or code of a signed package
+//! invalidates its signature. The ops of a package, its lifecycle hooks, and the libraries and
+//! processes of native and gRPC plugins, are not part of the package and are not covered. Signatures are verified
+//! with `verify_strict`, so non-canonical signatures and weak keys are rejected.
+
+use crate::plugin::{CorePluginFunctionBody, CorePluginPackage};
+use crate::proto::sapphillon::v1::PluginPackage;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use prost::Message;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Prefix of the messages signed for plugin packages.
+pub const SIGNATURE_CONTEXT: &[u8] = b"sapphillon-plugin-package-v1\0";
+
+/// Signature of a plugin package by a publisher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginSignature {
+    /// ID of the publisher key the package is signed with
+    pub key_id: String,
+    /// ed25519 signature of the package
+    pub signature: [u8; 64],
+}
+
+impl PluginSignature {
+    /// Signs a plugin package. The package must not be changed after it is signed.
+    ///
+    /// # Arguments
+    /// * `package` - Package to sign
+    /// * `key_id` - ID the publisher key is registered under in trust stores
+    /// * `secret_key` - ed25519 secret key of the publisher
+    pub fn sign(package: &CorePluginPackage, key_id: String, secret_key: &[u8; 32]) -> Self {
+        let signature = SigningKey::from_bytes(secret_key).sign(&signed_message(package));
+        Self {
+            key_id,
+            signature: signature.to_bytes(),
+        }
+    }
+}
+
+/// Error returned when a plugin package cannot be verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The package has no signature.
+    Unsigned,
+    /// The package is signed with a key that is not in the trust store.
+    UnknownKey { key_id: String },
+    /// The signature does not match the package, which was changed after it was signed or
+    /// signed with another key.
+    InvalidSignature { key_id: String },
+    /// A publisher key is not a valid ed25519 public key.
+    InvalidKey { key_id: String },
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Unsigned => write!(f, "Package is not signed"),
+            SignatureError::UnknownKey { key_id } => {
+                write!(f, "Package is signed with unknown key {key_id}")
+            }
+            SignatureError::InvalidSignature { key_id } => {
+                write!(
+                    f,
+                    "Signature of key {key_id} does not match the package, which may have been tampered with"
+                )
+            }
+            SignatureError::InvalidKey { key_id } => {
+                write!(f, "Key {key_id} is not a valid ed25519 public key")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Packages a `PluginRegistry` refuses to register because of their signature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Register unsigned packages and packages signed with unknown keys as unverified, and
+    /// refuse packages whose signature does not match.
+    #[default]
+    AllowUnsigned,
+    /// Refuse every package that cannot be verified.
+    RequireVerified,
+}
+
+impl SignaturePolicy {
+    /// Returns true if a package that failed verification with the given error is refused.
+    ///
+    /// # Arguments
+    /// * `error` - Error of `PluginTrustStore::verify`
+    pub fn refuses(&self, error: &SignatureError) -> bool {
+        match self {
+            SignaturePolicy::AllowUnsigned => {
+                matches!(error, SignatureError::InvalidSignature { .. })
+            }
+            SignaturePolicy::RequireVerified => true,
+        }
+    }
+}
+
+/// Publisher keys trusted to sign plugin packages, keyed by ID.
+#[derive(Debug, Clone, Default)]
+pub struct PluginTrustStore {
+    keys: HashMap<String, VerifyingKey>,
+}
+
+impl PluginTrustStore {
+    /// Creates an empty PluginTrustStore.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts a publisher key, replacing the key previously registered under the same ID.
+    ///
+    /// # Arguments
+    /// * `key_id` - ID of the key, referenced by `PluginSignature::key_id`
+    /// * `public_key` - ed25519 public key of the publisher
+    ///
+    /// # Errors
+    /// Returns `SignatureError::InvalidKey` if the key is not a valid ed25519 public key.
+    pub fn add_key(&mut self, key_id: String, public_key: &[u8; 32]) -> Result<(), SignatureError> {
+        let key = VerifyingKey::from_bytes(public_key).map_err(|_| SignatureError::InvalidKey {
+            key_id: key_id.clone(),
+        })?;
+        self.keys.insert(key_id, key);
+        Ok(())
+    }
+
+    /// Returns true if no key is trusted.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Verifies the signature of a plugin package.
+    ///
+    /// # Arguments
+    /// * `package` - Package to verify
+    ///
+    /// # Errors
+    /// Returns a `SignatureError` if the package is unsigned, signed with an unknown key, or its
+    /// signature does not match it.
+    pub fn verify(&self, package: &CorePluginPackage) -> Result<(), SignatureError> {
+        let signature = package.signature.as_ref().ok_or(SignatureError::Unsigned)?;
+        let key = self
+            .keys
+            .get(&signature.key_id)
+            .ok_or_else(|| SignatureError::UnknownKey {
+                key_id: signature.key_id.clone(),
+            })?;
+        key.verify_strict(
+            &signed_message(package),
+            &Signature::from_bytes(&signature.signature),
+        )
+        .map_err(|_| SignatureError::InvalidSignature {
+            key_id: signature.key_id.clone(),
+        })
+    }
+}
+
+/// Hasher of length-prefixed fields, so fields cannot be split differently with the same digest.
+struct FieldHasher(Sha256);
+
+impl FieldHasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update((bytes.len() as u64).to_be_bytes());
+        self.0.update(bytes);
+    }
+
+    fn update_json(&mut self, value: &Value) {
+        self.update(canonical_json(value).as_bytes());
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        self.0.finalize().into()
+    }
+}
+
+/// Serializes a JSON value with the keys of its objects sorted, so the digest of a schema does
+/// not depend on the order its keys were inserted in.
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            let entries: Vec<_> = entries
+                .into_iter()
+                .map(|(key, value)| {
+                    format!("{}:{}", Value::from(key.as_str()), canonical_json(value))
+                })
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<_> = items.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Returns the digest of the canonical manifest of a package: its proto encoded as protobuf,
+/// without the fields set by the host that installs it, and the parts of the package the proto
+/// does not carry.
+fn manifest_digest(package: &CorePluginPackage) -> [u8; 32] {
+    let mut hasher = FieldHasher(Sha256::new());
+    let proto = PluginPackage {
+        verified: None,
+        installed_at: None,
+        updated_at: None,
+        ..PluginPackage::from(package)
+    };
+    hasher.update(&proto.encode_to_vec());
+    for func in &package.functions {
+        hasher.update(b"function");
+        hasher.update(func.id.as_bytes());
+        match &func.func {
+            CorePluginFunctionBody::Op(op) => {
+                hasher.update(b"op");
+                hasher.update(op.name.as_bytes());
+            }
+            CorePluginFunctionBody::Export { module, name } => {
+                hasher.update(b"export");
+                hasher.update(module.as_bytes());
+                hasher.update(name.as_bytes());
+            }
+            CorePluginFunctionBody::WasmExport { module, name } => {
+                hasher.update(b"wasm_export");
+                hasher.update(module.as_bytes());
+                hasher.update(name.as_bytes());
+            }
+            CorePluginFunctionBody::Grpc(_) => hasher.update(b"grpc"),
+            CorePluginFunctionBody::Native(_) => hasher.update(b"native"),
+        }
+        let Some(schema) = &func.schema else {
+            hasher.update(b"unvalidated");
+            continue;
+        };
+        hasher.update(b"schema");
+        hasher.update(&(schema.parameters().len() as u64).to_be_bytes());
+        for parameter in schema.parameters() {
+            hasher.update(parameter.name.as_bytes());
+            hasher.update(parameter.description.as_bytes());
+            hasher.update_json(&parameter.schema);
+            hasher.update(&[parameter.optional as u8]);
+        }
+        match schema.returns() {
+            Some(returns) => hasher.update_json(returns),
+            None => hasher.update(b"unvalidated"),
+        }
+    }
+    for key in package.config.keys() {
+        hasher.update(b"config");
+        hasher.update(key.as_bytes());
+        if let Some(value) = package.config.get(key) {
+            hasher.update(value.type_name().as_bytes());
+        }
+    }
+    for module in &package.wasm_modules {
+        hasher.update(b"wasm_limits");
+        hasher.update(module.path.as_bytes());
+        hasher.update(&module.limits().canonical_bytes());
+    }
+    hasher.update(b"lifecycle");
+    hasher.update(&[package.lifecycle.is_some() as u8]);
+    hasher.finalize()
+}
+
+/// Returns the digest of the source and WASM modules of a package.
+fn artifact_digest(package: &CorePluginPackage) -> [u8; 32] {
+    let mut hasher = FieldHasher(Sha256::new());
+    for module in &package.modules {
+        hasher.update(b"module");
+        hasher.update(module.path.as_bytes());
+        hasher.update(module.language.as_str_name().as_bytes());
+        hasher.update(module.code.as_bytes());
+    }
+    for module in &package.wasm_modules {
+        hasher.update(b"wasm");
+        hasher.update(module.path.as_bytes());
+        hasher.update(module.code());
+    }
+    hasher.finalize()
+}
+
+/// Returns the message signed for a package.
+fn signed_message(package: &CorePluginPackage) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend(manifest_digest(package));
+    message.extend(artifact_digest(package));
+    message
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::PluginConfigValue;
+    use crate::lifecycle::{CorePluginLifecycle, PluginLifecycle};
+    use crate::plugin::{CorePluginFunction, CorePluginModule, CorePluginWasmModule};
+    use crate::proto::sapphillon::v1::WorkflowLanguage;
+    use crate::schema::{FunctionSchema, ParameterSchema};
+    use crate::wasm::WasmLimits;
+    use std::sync::Arc;
+
+    pub(crate) const SECRET_KEY: [u8; 32] = [7; 32];
+
+    pub(crate) fn trust_store() -> PluginTrustStore {
+        let mut trust_store = PluginTrustStore::new();
+        let public_key = SigningKey::from_bytes(&SECRET_KEY).verifying_key();
+        trust_store
+            .add_key("publisher".to_string(), public_key.as_bytes())
+            .unwrap();
+        trust_store
+    }
+
+    fn schema(parameter: Value) -> Option<FunctionSchema> {
+        let parameter = ParameterSchema::new("name".to_string(), String::new(), parameter);
+        Some(FunctionSchema::new(vec![parameter], None).unwrap())
+    }
+
+    fn wasm_module(limits: WasmLimits) -> CorePluginWasmModule {
+        CorePluginWasmModule::new("empty.wasm".to_string(), b"\0asm\x01\0\0\0", limits).unwrap()
+    }
+
+    fn package() -> CorePluginPackage {
+        let mut greet = CorePluginFunction::new_with_export(
+            "com.example.greet".to_string(),
+            "greet".to_string(),
+            String::new(),
+            "mod.js".to_string(),
+            "greet".to_string(),
+            vec![],
+        );
+        greet.schema = schema(serde_json::json!({"type": "string", "minLength": 1}));
+        let mut package = CorePluginPackage::new_with_modules(
+            "com.example".to_string(),
+            "Example".to_string(),
+            "1.0.0".to_string(),
+            vec![greet],
+            vec![CorePluginModule::new(
+                "mod.js".to_string(),
+                "export const greet = (name) => `hello ${name}`;".to_string(),
+                WorkflowLanguage::Javascript,
+            )],
+        );
+        package.wasm_modules = vec![wasm_module(WasmLimits::default())];
+        package.config.insert(
+            "endpoint".to_string(),
+            PluginConfigValue::String(String::new()),
+        );
+        package.signature = Some(PluginSignature::sign(
+            &package,
+            "publisher".to_string(),
+            &SECRET_KEY,
+        ));
+        package
+    }
+
+    #[test]
+    fn test_plugin_trust_store_verify() {
+        let trust_store = trust_store();
+        let mut package = package();
+        assert_eq!(trust_store.verify(&package), Ok(()));
+
+        // Fields set by the host are not signed
+        package.installed_at = Some(prost_types::Timestamp::default());
+        package.config.insert(
+            "endpoint".to_string(),
+            PluginConfigValue::String("https://example.com".to_string()),
+        );
+        assert_eq!(trust_store.verify(&package), Ok(()));
+
+        // The order of the keys of schemas is not signed
+        package.functions[0].schema = schema(serde_json::json!({"minLength": 1, "type": "string"}));
+        assert_eq!(trust_store.verify(&package), Ok(()));
+
+        assert_eq!(
+            PluginTrustStore::new().verify(&package),
+            Err(SignatureError::UnknownKey {
+                key_id: "publisher".to_string()
+            })
+        );
+        package.signature = None;
+        assert_eq!(trust_store.verify(&package), Err(SignatureError::Unsigned));
+    }
+
+    #[test]
+    fn test_plugin_trust_store_verify_tampered() {
+        struct Hooks;
+        impl PluginLifecycle for Hooks {}
+
+        let trust_store = trust_store();
+        let tampered = |change: fn(&mut CorePluginPackage)| {
+            let mut package = package();
+            change(&mut package);
+            trust_store.verify(&package)
+        };
+        let invalid = Err(SignatureError::InvalidSignature {
+            key_id: "publisher".to_string(),
+        });
+        assert_eq!(tampered(|p| p.version = "1.0.1".to_string()), invalid);
+        assert_eq!(tampered(|p| p.internal = Some(true)), invalid);
+        assert_eq!(tampered(|p| p.modules[0].code.push(';')), invalid);
+        assert_eq!(
+            tampered(|p| p.modules[0].language = WorkflowLanguage::Typescript),
+            invalid
+        );
+        assert_eq!(
+            tampered(|p| p.lifecycle = Some(CorePluginLifecycle::new(Arc::new(Hooks)))),
+            invalid
+        );
+        assert_eq!(
+            tampered(|p| { p.functions[0].schema = schema(serde_json::json!({"type": "string"})) }),
+            invalid
+        );
+        assert_eq!(
+            tampered(|p| {
+                p.functions[0].func = CorePluginFunctionBody::Export {
+                    module: "mod.js".to_string(),
+                    name: "other".to_string(),
+                }
+            }),
+            invalid
+        );
+        assert_eq!(
+            tampered(|p| {
+                p.config
+                    .insert("endpoint".to_string(), PluginConfigValue::Integer(0))
+            }),
+            invalid
+        );
+        assert_eq!(
+            tampered(|p| {
+                p.wasm_modules[0] = wasm_module(WasmLimits {
+                    fuel: Some(1),
+                    ..WasmLimits::default()
+                })
+            }),
+            invalid
+        );
+        assert_eq!(
+            tampered(|p| p.signature.as_mut().unwrap().signature[0] ^= 1),
+            invalid
+        );
+        // A signature with another key does not match the trusted key
+        assert_eq!(
+            tampered(|p| {
+                p.signature = Some(PluginSignature::sign(p, "publisher".to_string(), &[8; 32]))
+            }),
+            invalid
+        );
+    }
+}
@@ -0,0 +1,260 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::proto::sapphillon::v1::WorkflowLanguage;
+use deno_ast::diagnostics::Diagnostic;
+use deno_ast::{
+    EmitOptions, ImportsNotUsedAsValues, MediaType, ModuleSpecifier, ParseDiagnostic, ParseParams,
+    SourceMapOption, TranspileModuleOptions, TranspileOptions,
+};
+use std::fmt;
+
+/// Specifier under which JavaScript workflow code is loaded as the main ES module.
+pub const JAVASCRIPT_MODULE_SPECIFIER: &str = "file:///workflow.js";
+/// Specifier under which TypeScript workflow code is loaded as the main ES module.
+/// The transpiled module keeps this specifier so that source-mapped locations point at the original file.
+pub const TYPESCRIPT_MODULE_SPECIFIER: &str = "file:///workflow.ts";
+
+/// A single diagnostic reported while parsing or transpiling TypeScript workflow code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranspileDiagnostic {
+    /// Specifier of the module the diagnostic belongs to
+    pub specifier: String,
+    /// 1-based line number in the original source
+    pub line: usize,
+    /// 1-based column number in the original source
+    pub column: usize,
+    /// Human-readable diagnostic message
+    pub message: String,
+}
+
+impl fmt::Display for TranspileDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{} - {}",
+            self.specifier, self.line, self.column, self.message
+        )
+    }
+}
+
+impl From<&ParseDiagnostic> for TranspileDiagnostic {
+    fn from(diagnostic: &ParseDiagnostic) -> Self {
+        let position = diagnostic.display_position();
+        Self {
+            specifier: diagnostic.specifier.to_string(),
+            line: position.line_number,
+            column: position.column_number,
+            message: diagnostic.message().to_string(),
+        }
+    }
+}
+
+/// Error returned when workflow code could not be transpiled to JavaScript.
+/// Holds every diagnostic reported for the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranspileError {
+    pub diagnostics: Vec<TranspileDiagnostic>,
+}
+
+impl fmt::Display for TranspileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = self
+            .diagnostics
+            .iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<String>>();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl std::error::Error for TranspileError {}
+
+/// Workflow code ready to be loaded into the runtime as an ES module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkflowModule {
+    /// Specifier the module is loaded under
+    pub specifier: String,
+    /// JavaScript source of the module
+    pub code: String,
+    /// Source map back to the original code, if the code was transpiled
+    pub source_map: Option<Vec<u8>>,
+}
+
+impl WorkflowModule {
+    /// Creates a WorkflowModule from plain JavaScript code.
+    ///
+    /// # Arguments
+    /// * `code` - JavaScript source of the workflow
+    pub fn from_javascript(code: &str) -> Self {
+        Self {
+            specifier: JAVASCRIPT_MODULE_SPECIFIER.to_string(),
+            code: code.to_string(),
+            source_map: None,
+        }
+    }
+
+    /// Creates a WorkflowModule from workflow code written in the given language.
+    ///
+    /// TypeScript code is transpiled to JavaScript, other languages are loaded as JavaScript.
+    /// `WorkflowLanguage::Unspecified` is treated as JavaScript.
+    ///
+    /// # Arguments
+    /// * `code` - Source of the workflow
+    /// * `language` - Language the source is written in
+    pub fn new(code: &str, language: WorkflowLanguage) -> Result<Self, TranspileError> {
+        match language {
+            WorkflowLanguage::Typescript => transpile_typescript(code),
+            WorkflowLanguage::Javascript | WorkflowLanguage::Unspecified => {
+                Ok(Self::from_javascript(code))
+            }
+        }
+    }
+
+    /// Creates a WorkflowModule loaded under the given specifier instead of the workflow's.
+    ///
+    /// # Arguments
+    /// * `specifier` - Specifier the module is loaded under
+    /// * `code` - Source of the module
+    /// * `language` - Language the source is written in
+    pub fn new_with_specifier(
+        specifier: &ModuleSpecifier,
+        code: &str,
+        language: WorkflowLanguage,
+    ) -> Result<Self, TranspileError> {
+        match language {
+            WorkflowLanguage::Typescript => transpile(specifier.clone(), code),
+            WorkflowLanguage::Javascript | WorkflowLanguage::Unspecified => Ok(Self {
+                specifier: specifier.to_string(),
+                code: code.to_string(),
+                source_map: None,
+            }),
+        }
+    }
+}
+
+/// Strips TypeScript types from the given code and returns it as a JavaScript module.
+///
+/// Only transpiles, it does not type check. The resulting module keeps the
+/// `file:///workflow.ts` specifier and carries a source map so that error
+/// locations point at the original TypeScript lines.
+///
+/// # Arguments
+/// * `code` - TypeScript source of the workflow
+///
+/// # Errors
+/// Returns a `TranspileError` with one diagnostic per syntax error.
+pub fn transpile_typescript(code: &str) -> Result<WorkflowModule, TranspileError> {
+    transpile(
+        ModuleSpecifier::parse(TYPESCRIPT_MODULE_SPECIFIER).unwrap(),
+        code,
+    )
+}
+
+/// Transpiles the TypeScript module with the given specifier.
+fn transpile(specifier: ModuleSpecifier, code: &str) -> Result<WorkflowModule, TranspileError> {
+    let parsed = deno_ast::parse_module(ParseParams {
+        specifier: specifier.clone(),
+        text: code.into(),
+        media_type: MediaType::TypeScript,
+        capture_tokens: false,
+        scope_analysis: false,
+        maybe_syntax: None,
+    })
+    .map_err(|d| TranspileError {
+        diagnostics: vec![TranspileDiagnostic::from(&d)],
+    })?;
+
+    // Recoverable syntax errors are not fatal for the parser, but they are for a workflow
+    if !parsed.diagnostics().is_empty() {
+        return Err(TranspileError {
+            diagnostics: parsed
+                .diagnostics()
+                .iter()
+                .map(TranspileDiagnostic::from)
+                .collect(),
+        });
+    }
+
+    let transpiled = parsed
+        .transpile(
+            &TranspileOptions {
+                imports_not_used_as_values: ImportsNotUsedAsValues::Remove,
+                ..Default::default()
+            },
+            &TranspileModuleOptions { module_kind: None },
+            &EmitOptions {
+                source_map: SourceMapOption::Separate,
+                inline_sources: true,
+                ..Default::default()
+            },
+        )
+        .map_err(|e| TranspileError {
+            diagnostics: vec![TranspileDiagnostic {
+                specifier: specifier.to_string(),
+                line: 0,
+                column: 0,
+                message: e.to_string(),
+            }],
+        })?
+        .into_source();
+
+    Ok(WorkflowModule {
+        specifier: specifier.to_string(),
+        code: transpiled.text,
+        source_map: transpiled.source_map.map(String::into_bytes),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workflow_module_javascript() {
+        let module = WorkflowModule::new("console.log(1);", WorkflowLanguage::Javascript).unwrap();
+        assert_eq!(module.specifier, JAVASCRIPT_MODULE_SPECIFIER);
+        assert_eq!(module.code, "console.log(1);");
+        assert!(module.source_map.is_none());
+    }
+
+    #[test]
+    fn test_workflow_module_unspecified_is_javascript() {
+        let module = WorkflowModule::new("console.log(1);", WorkflowLanguage::Unspecified).unwrap();
+        assert_eq!(module.specifier, JAVASCRIPT_MODULE_SPECIFIER);
+    }
+
+    #[test]
+    fn test_transpile_typescript_strips_types() {
+        let code = "interface A { a: number }\nconst x: A = { a: 1 };\nconsole.log(x.a as number);";
+        let module = WorkflowModule::new(code, WorkflowLanguage::Typescript).unwrap();
+        assert_eq!(module.specifier, TYPESCRIPT_MODULE_SPECIFIER);
+        assert!(!module.code.contains("interface"));
+        assert!(!module.code.contains(": A"));
+        assert!(module.source_map.is_some());
+    }
+
+    #[test]
+    fn test_transpile_typescript_diagnostics() {
+        let code = "const x: number = 1;\nconst y = (;";
+        let err = transpile_typescript(code).unwrap_err();
+        assert!(!err.diagnostics.is_empty());
+        assert_eq!(err.diagnostics[0].line, 2);
+        assert_eq!(err.diagnostics[0].specifier, TYPESCRIPT_MODULE_SPECIFIER);
+    }
+}
@@ -0,0 +1,257 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Pre-flight validation of workflow code.
+
+use crate::analysis::infer_permissions;
+use crate::plugin::CorePluginPackage;
+use crate::proto::google::rpc::BadRequest;
+use crate::proto::google::rpc::bad_request::FieldViolation;
+use crate::proto::sapphillon::v1::WorkflowCode;
+use crate::transpile::WorkflowModule;
+use deno_core::{OpDecl, op2};
+use deno_error::JsErrorBox;
+use std::collections::HashMap;
+
+/// Reason of a violation for a function in `plugin_function_ids` that no plugin package provides.
+pub const REASON_PLUGIN_FUNCTION_NOT_FOUND: &str = "PLUGIN_FUNCTION_NOT_FOUND";
+/// Reason of a violation for an op name registered by more than one plugin function.
+pub const REASON_DUPLICATE_OP_NAME: &str = "DUPLICATE_OP_NAME";
+/// Reason of a violation for a plugin function the code invokes but `plugin_function_ids` does not list.
+pub const REASON_PLUGIN_FUNCTION_NOT_LISTED: &str = "PLUGIN_FUNCTION_NOT_LISTED";
+/// Reason of a violation for code that cannot be transpiled.
+pub const REASON_INVALID_CODE: &str = "INVALID_CODE";
+
+/// JavaScript error class thrown when the code invokes a plugin function that
+/// `plugin_function_ids` does not list.
+pub const PLUGIN_FUNCTION_NOT_LISTED_ERROR_CLASS: &str = "PluginFunctionNotListed";
+
+/// Script that registers the `PluginFunctionNotListed` error class in the runtime.
+pub(crate) const PLUGIN_FUNCTION_NOT_LISTED_ERROR_SCRIPT: &str = r#"
+class PluginFunctionNotListed extends Error {
+  constructor(message) {
+    super(message);
+    this.name = "PluginFunctionNotListed";
+  }
+}
+Deno.core.registerErrorClass("PluginFunctionNotListed", PluginFunctionNotListed);
+"#;
+
+// No fast call, the stub stands in for ops with any signature
+#[op2(nofast)]
+pub(crate) fn op_plugin_function_not_listed() -> Result<(), JsErrorBox> {
+    Err(JsErrorBox::new(
+        PLUGIN_FUNCTION_NOT_LISTED_ERROR_CLASS,
+        "This plugin function is not listed in the workflow's plugin function IDs",
+    ))
+}
+
+/// Returns an op with the name of `op` that throws a `PluginFunctionNotListed` error when invoked.
+///
+/// Used in place of plugin functions that the workflow's `plugin_function_ids` does not list,
+/// so that the list is enforced at runtime even when the code was not validated beforehand.
+///
+/// # Arguments
+/// * `op` - Op of the unlisted plugin function
+pub(crate) fn not_listed_op(op: &OpDecl) -> OpDecl {
+    let mut not_listed = op_plugin_function_not_listed();
+    not_listed.name = op.name;
+    not_listed.name_fast = op.name_fast;
+    not_listed
+}
+
+fn field_violation(field: String, reason: &str, description: String) -> FieldViolation {
+    FieldViolation {
+        field,
+        description,
+        reason: reason.to_string(),
+        localized_message: None,
+    }
+}
+
+/// Validates the plugin functions of a workflow against the plugin packages supplied for it.
+///
+/// The following are reported as field violations, all of them at once:
+/// - `plugin_function_ids[i]`: the function is not in any of the plugin packages.
+/// - `plugin_packages[i]` (or `plugin_packages` if the package is not listed in the workflow):
+///   a function of the package has the same op name as a function registered before it.
+/// - `code`: the code invokes a plugin function that `plugin_function_ids` does not list,
+///   or it cannot be transpiled. Invocations are detected as in `analysis::infer_permissions`.
+///
+/// # Arguments
+/// * `workflow_code` - WorkflowCode defined in proto
+/// * `plugin_packages` - Plugin packages supplied for the workflow
+///
+/// # Errors
+/// Returns a `BadRequest` with one field violation per problem found.
+pub fn validate_plugin_functions(
+    workflow_code: &WorkflowCode,
+    plugin_packages: &[CorePluginPackage],
+) -> Result<(), BadRequest> {
+    let mut field_violations = Vec::new();
+
+    for (i, function_id) in workflow_code.plugin_function_ids.iter().enumerate() {
+        let provided = plugin_packages
+            .iter()
+            .any(|pkg| pkg.functions.iter().any(|f| f.id == *function_id));
+        if !provided {
+            field_violations.push(field_violation(
+                format!("plugin_function_ids[{i}]"),
+                REASON_PLUGIN_FUNCTION_NOT_FOUND,
+                format!("Plugin function {function_id} is not provided by any plugin package"),
+            ));
+        }
+    }
+
+    // ID of the function that registered each op name
+    let mut ops: HashMap<&str, &str> = HashMap::new();
+    for pkg in plugin_packages {
+        for func in &pkg.functions {
+            let Some(op) = func.op() else {
+                continue;
+            };
+            let op_name = op.name;
+            match ops.get(op_name) {
+                Some(registered_by) => {
+                    let field = workflow_code
+                        .plugin_packages
+                        .iter()
+                        .position(|p| p.package_id == pkg.id)
+                        .map(|j| format!("plugin_packages[{j}]"))
+                        .unwrap_or_else(|| "plugin_packages".to_string());
+                    field_violations.push(field_violation(
+                        field,
+                        REASON_DUPLICATE_OP_NAME,
+                        format!(
+                            "Op {op_name} of plugin function {} is already registered by {registered_by}",
+                            func.id
+                        ),
+                    ));
+                }
+                None => {
+                    ops.insert(op_name, &func.id);
+                }
+            }
+        }
+    }
+
+    match WorkflowModule::new(&workflow_code.code, workflow_code.language()) {
+        Ok(module) => {
+            let inference = infer_permissions(&module, plugin_packages, &[]);
+            for function_id in inference.invoked_function_ids {
+                if !workflow_code.plugin_function_ids.contains(&function_id) {
+                    field_violations.push(field_violation(
+                        "code".to_string(),
+                        REASON_PLUGIN_FUNCTION_NOT_LISTED,
+                        format!(
+                            "Code invokes plugin function {function_id}, which is not listed in plugin_function_ids"
+                        ),
+                    ));
+                }
+            }
+        }
+        Err(e) => field_violations.push(field_violation(
+            "code".to_string(),
+            REASON_INVALID_CODE,
+            e.to_string(),
+        )),
+    }
+
+    match field_violations.is_empty() {
+        true => Ok(()),
+        false => Err(BadRequest { field_violations }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::CorePluginFunction;
+    use deno_core::op2;
+
+    #[op2(fast)]
+    fn op_read() -> u32 {
+        0
+    }
+
+    #[op2(fast)]
+    fn op_write() -> u32 {
+        0
+    }
+
+    fn package(id: &str, functions: Vec<(&str, deno_core::OpDecl)>) -> CorePluginPackage {
+        CorePluginPackage::new(
+            id.to_string(),
+            id.to_string(),
+            functions
+                .into_iter()
+                .map(|(fid, op)| {
+                    CorePluginFunction::new(fid.to_string(), fid.to_string(), "".to_string(), op)
+                })
+                .collect(),
+        )
+    }
+
+    fn workflow_code(code: &str, function_ids: &[&str]) -> WorkflowCode {
+        WorkflowCode {
+            code: code.to_string(),
+            plugin_function_ids: function_ids.iter().map(|f| f.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate_plugin_functions_valid() {
+        let packages = [package(
+            "fs",
+            vec![("fs.read", op_read()), ("fs.write", op_write())],
+        )];
+        let code = workflow_code("plugins.fs.read('/tmp');", &["fs.read"]);
+        assert_eq!(validate_plugin_functions(&code, &packages), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_plugin_functions_violations() {
+        let packages = [
+            package("fs", vec![("fs.read", op_read())]),
+            package("net", vec![("net.fetch", op_read())]),
+        ];
+        let mut code = workflow_code("plugins.fs.read('/tmp');", &["fs.write"]);
+        code.plugin_packages = vec![Default::default(), Default::default()];
+        code.plugin_packages[1].package_id = "net".to_string();
+
+        let err = validate_plugin_functions(&code, &packages).unwrap_err();
+        let violations: Vec<(&str, &str)> = err
+            .field_violations
+            .iter()
+            .map(|v| (v.field.as_str(), v.reason.as_str()))
+            .collect();
+        assert_eq!(
+            violations,
+            vec![
+                ("plugin_function_ids[0]", REASON_PLUGIN_FUNCTION_NOT_FOUND),
+                ("plugin_packages[1]", REASON_DUPLICATE_OP_NAME),
+                ("code", REASON_PLUGIN_FUNCTION_NOT_LISTED),
+            ]
+        );
+        assert_eq!(
+            err.field_violations[1].description,
+            "Op op_read of plugin function net.fetch is already registered by fs.read"
+        );
+    }
+}
@@ -0,0 +1,1157 @@
+// Sapphillon-Core
+// Copyright 2025 Yuta Takahashi
+//
+// This file is part of Sapphillon-Core
+//
+// Sapphillon-Core is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! WebAssembly plugin modules.
+//!
+//! WASM modules run in the workflow's isolate and can only reach the host through the
+//! functions they import from the `sapphillon` module:
+//! - `log(ptr: i32, len: i32)` prints a UTF-8 string to the output of the workflow.
+//! - `read_file(path_ptr: i32, path_len: i32, buf_ptr: i32, buf_len: i32) -> i32` reads a file
+//!   into the buffer and returns its size, which may exceed `buf_len`. Requires `Read`.
+//! - `write_file(path_ptr: i32, path_len: i32, data_ptr: i32, data_len: i32) -> i32` writes
+//!   the data to a file and returns its size. Requires `Write`.
+//!
+//! Host functions that take pointers read and write the memory the module exports as `memory`.
+//! They are implemented in Rust and only run for the plugin function being called: a plugin
+//! function can only call the host functions whose permission type it declares, and their
+//! resources are checked against the permissions granted to the workflow like those of ops,
+//! under the ID of the plugin function. Exported functions are called with the arguments of
+//! the plugin function as they are, so they take and return numbers.
+
+use crate::core::print;
+use crate::permission::{PERMISSION_DENIED_ERROR_CLASS, check_permission};
+use crate::proto::sapphillon::v1::PermissionType;
+use deno_core::{JsRuntime, op2, v8};
+use deno_error::JsErrorBox;
+use std::collections::HashMap;
+use std::fmt;
+use wasm_encoder::reencode::{self, Reencode};
+use wasm_encoder::{
+    BlockType, CodeSection, ConstExpr, ExportKind, ExportSection, Function, FunctionSection,
+    GlobalSection, GlobalType, Instruction, MemoryType, Module, SectionId, TypeSection, ValType,
+};
+use wasmparser::{ExternalKind, Parser, Payload, TypeRef, Validator, WasmFeatures};
+
+/// Name of the module WASM plugins import host functions from.
+pub const WASM_HOST_MODULE: &str = "sapphillon";
+
+/// Prefix of the exports added to instrumented modules, which plugin modules cannot export.
+pub const RESERVED_EXPORT_PREFIX: &str = "__sapphillon_";
+
+/// Name of the export that resets the fuel of an instrumented module to its limit.
+pub const REFUEL_EXPORT: &str = "__sapphillon_refuel";
+
+/// Name of the export that returns 1 if an instrumented module ran out of fuel, 0 otherwise.
+pub const FUEL_EXHAUSTED_EXPORT: &str = "__sapphillon_fuel_exhausted";
+
+/// Host functions WASM plugins may import, with the permission type a plugin function must
+/// declare to call them.
+pub const WASM_HOST_FUNCTIONS: [(&str, Option<PermissionType>); 3] = [
+    ("log", None),
+    ("read_file", Some(PermissionType::Read)),
+    ("write_file", Some(PermissionType::Write)),
+];
+
+/// Size of a WASM memory page in bytes.
+const PAGE_SIZE: u32 = 64 * 1024;
+
+/// Limits applied to a WASM plugin module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WasmLimits {
+    /// Maximum number of 64 KiB pages the memory of the module can grow to
+    pub max_memory_pages: u32,
+    /// Fuel available to each call of a plugin function. One unit is consumed per function
+    /// call and loop iteration inside the module, and the call traps when it runs out.
+    /// The fuel is kept in a signed 64-bit global, so it cannot exceed `i64::MAX`.
+    /// `None` disables metering.
+    pub fuel: Option<u64>,
+}
+
+impl Default for WasmLimits {
+    /// 16 MiB of memory and 10 million units of fuel per call.
+    fn default() -> Self {
+        Self {
+            max_memory_pages: 16 * 1024 * 1024 / PAGE_SIZE,
+            fuel: Some(10_000_000),
+        }
+    }
+}
+
+impl WasmLimits {
+    /// Returns the canonical encoding of the limits, covered by the signature of a package:
+    /// the memory limit as a big-endian u32, then 0 if metering is disabled, or 1 followed by
+    /// the fuel as a big-endian u64.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.max_memory_pages.to_be_bytes().to_vec();
+        match self.fuel {
+            Some(fuel) => {
+                bytes.push(1);
+                bytes.extend(fuel.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+}
+
+/// Error returned when a WASM module cannot be used as a plugin module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WasmError {
+    /// The module is not valid WebAssembly, or uses features that are not supported.
+    Parse(String),
+    /// The module imports something other than a host function.
+    UnsupportedImport { module: String, name: String },
+    /// The module exports a name with `RESERVED_EXPORT_PREFIX`.
+    ReservedExport { name: String },
+    /// The initial memory of the module exceeds the limit.
+    MemoryLimitExceeded { initial: u32, limit: u32 },
+    /// The fuel limit exceeds `i64::MAX`.
+    FuelLimitExceeded { fuel: u64 },
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WasmError::Parse(message) => write!(f, "Invalid WASM module: {message}"),
+            WasmError::UnsupportedImport { module, name } => {
+                write!(
+                    f,
+                    "WASM module imports {module}.{name}, which is not a host function"
+                )
+            }
+            WasmError::ReservedExport { name } => {
+                write!(f, "WASM module exports {name}, which is a reserved name")
+            }
+            WasmError::MemoryLimitExceeded { initial, limit } => write!(
+                f,
+                "WASM module needs {initial} pages of memory, more than the limit of {limit}"
+            ),
+            WasmError::FuelLimitExceeded { fuel } => {
+                write!(f, "Fuel limit {fuel} exceeds the maximum of {}", i64::MAX)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WasmError {}
+
+fn parse_error(error: impl fmt::Display) -> WasmError {
+    WasmError::Parse(error.to_string())
+}
+
+/// Returns the names of the functions a WASM module exports, in order.
+///
+/// # Arguments
+/// * `bytes` - Binary of the module
+pub fn exported_functions(bytes: &[u8]) -> Result<Vec<String>, WasmError> {
+    let mut names = Vec::new();
+    for payload in Parser::new(0).parse_all(bytes) {
+        if let Payload::ExportSection(section) = payload.map_err(parse_error)? {
+            for export in section {
+                let export = export.map_err(parse_error)?;
+                if export.kind == ExternalKind::Func {
+                    names.push(export.name.to_string());
+                }
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Validates a module with the features of WebAssembly 2.0, so that the instrumented module
+/// means the same as the original one.
+fn validate(bytes: &[u8]) -> Result<(), WasmError> {
+    Validator::new_with_features(WasmFeatures::WASM2)
+        .validate_all(bytes)
+        .map(|_| ())
+        .map_err(parse_error)
+}
+
+/// Instructions that consume one unit of fuel from the global at `index`, trapping when the
+/// fuel runs out.
+fn metering(index: u32) -> Vec<Instruction<'static>> {
+    vec![
+        Instruction::GlobalGet(index),
+        Instruction::I64Const(1),
+        Instruction::I64Sub,
+        Instruction::GlobalSet(index),
+        Instruction::GlobalGet(index),
+        Instruction::I64Const(0),
+        Instruction::I64LtS,
+        Instruction::If(BlockType::Empty),
+        Instruction::Unreachable,
+        Instruction::End,
+    ]
+}
+
+/// Sections of a module in the order they appear in, which differs from the order of their IDs.
+const SECTION_ORDER: [SectionId; 13] = [
+    SectionId::Type,
+    SectionId::Import,
+    SectionId::Function,
+    SectionId::Table,
+    SectionId::Memory,
+    SectionId::Tag,
+    SectionId::Global,
+    SectionId::Export,
+    SectionId::Start,
+    SectionId::Element,
+    SectionId::DataCount,
+    SectionId::Code,
+    SectionId::Data,
+];
+
+fn section_position(id: SectionId) -> Option<usize> {
+    SECTION_ORDER.iter().position(|section| *section == id)
+}
+
+/// Fuel metering added to a module.
+struct Metering {
+    /// Fuel available to each call of a plugin function
+    fuel: i64,
+    /// Index of the fuel global
+    global: u32,
+    /// Index of the `() -> ()` type of the refuel function, followed by the `() -> i32` type of
+    /// the fuel exhausted function
+    types: u32,
+    /// Index of the refuel function, followed by the fuel exhausted function
+    functions: u32,
+}
+
+/// Re-encodes a module, capping its memories and adding fuel metering to it.
+struct Instrumenter {
+    max_memory_pages: u32,
+    metering: Option<Metering>,
+    /// Sections the metering added items to
+    extended: Vec<SectionId>,
+}
+
+impl Instrumenter {
+    /// Adds the items of the metering to a section of the module, which may be empty if the
+    /// module does not have one.
+    fn extend(&mut self, id: SectionId, section: &mut dyn MeteredSection) {
+        if let Some(metering) = &self.metering {
+            section.extend(metering);
+            self.extended.push(id);
+        }
+    }
+}
+
+/// Section the metering adds items to.
+trait MeteredSection {
+    fn extend(&mut self, metering: &Metering);
+}
+
+impl MeteredSection for TypeSection {
+    fn extend(&mut self, _metering: &Metering) {
+        self.ty().function([], []);
+        self.ty().function([], [ValType::I32]);
+    }
+}
+
+impl MeteredSection for FunctionSection {
+    fn extend(&mut self, metering: &Metering) {
+        self.function(metering.types);
+        self.function(metering.types + 1);
+    }
+}
+
+impl MeteredSection for GlobalSection {
+    fn extend(&mut self, metering: &Metering) {
+        self.global(
+            GlobalType {
+                val_type: ValType::I64,
+                mutable: true,
+                shared: false,
+            },
+            &ConstExpr::i64_const(metering.fuel),
+        );
+    }
+}
+
+impl MeteredSection for ExportSection {
+    fn extend(&mut self, metering: &Metering) {
+        self.export(REFUEL_EXPORT, ExportKind::Func, metering.functions);
+        self.export(
+            FUEL_EXHAUSTED_EXPORT,
+            ExportKind::Func,
+            metering.functions + 1,
+        );
+    }
+}
+
+impl MeteredSection for CodeSection {
+    fn extend(&mut self, metering: &Metering) {
+        let mut refuel = Function::new([]);
+        refuel
+            .instruction(&Instruction::I64Const(metering.fuel))
+            .instruction(&Instruction::GlobalSet(metering.global))
+            .instruction(&Instruction::End);
+        self.function(&refuel);
+        let mut fuel_exhausted = Function::new([]);
+        fuel_exhausted
+            .instruction(&Instruction::GlobalGet(metering.global))
+            .instruction(&Instruction::I64Const(0))
+            .instruction(&Instruction::I64LtS)
+            .instruction(&Instruction::End);
+        self.function(&fuel_exhausted);
+    }
+}
+
+impl Reencode for Instrumenter {
+    type Error = WasmError;
+
+    fn memory_type(
+        &mut self,
+        memory: wasmparser::MemoryType,
+    ) -> Result<MemoryType, reencode::Error<WasmError>> {
+        let limit = u64::from(self.max_memory_pages);
+        if memory.initial > limit {
+            return Err(reencode::Error::UserError(WasmError::MemoryLimitExceeded {
+                initial: u32::try_from(memory.initial).unwrap_or(u32::MAX),
+                limit: self.max_memory_pages,
+            }));
+        }
+        Ok(MemoryType {
+            maximum: Some(memory.maximum.map_or(limit, |maximum| maximum.min(limit))),
+            ..reencode::utils::memory_type(self, memory)
+        })
+    }
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Result<(), reencode::Error<WasmError>> {
+        reencode::utils::parse_type_section(self, types, section)?;
+        self.extend(SectionId::Type, types);
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut FunctionSection,
+        section: wasmparser::FunctionSectionReader<'_>,
+    ) -> Result<(), reencode::Error<WasmError>> {
+        reencode::utils::parse_function_section(self, functions, section)?;
+        self.extend(SectionId::Function, functions);
+        Ok(())
+    }
+
+    fn parse_global_section(
+        &mut self,
+        globals: &mut GlobalSection,
+        section: wasmparser::GlobalSectionReader<'_>,
+    ) -> Result<(), reencode::Error<WasmError>> {
+        reencode::utils::parse_global_section(self, globals, section)?;
+        self.extend(SectionId::Global, globals);
+        Ok(())
+    }
+
+    fn parse_export_section(
+        &mut self,
+        exports: &mut ExportSection,
+        section: wasmparser::ExportSectionReader<'_>,
+    ) -> Result<(), reencode::Error<WasmError>> {
+        reencode::utils::parse_export_section(self, exports, section)?;
+        self.extend(SectionId::Export, exports);
+        Ok(())
+    }
+
+    fn parse_code_section(
+        &mut self,
+        code: &mut CodeSection,
+        section: wasmparser::CodeSectionReader<'_>,
+    ) -> Result<(), reencode::Error<WasmError>> {
+        reencode::utils::parse_code_section(self, code, section)?;
+        self.extend(SectionId::Code, code);
+        Ok(())
+    }
+
+    /// Every function body and loop consumes fuel.
+    fn parse_function_body(
+        &mut self,
+        code: &mut CodeSection,
+        body: wasmparser::FunctionBody<'_>,
+    ) -> Result<(), reencode::Error<WasmError>> {
+        let Some(global) = self.metering.as_ref().map(|metering| metering.global) else {
+            return reencode::utils::parse_function_body(self, code, body);
+        };
+        let mut function = self.new_function_with_parsed_locals(&body)?;
+        metering(global).iter().for_each(|instruction| {
+            function.instruction(instruction);
+        });
+        let mut reader = body.get_operators_reader()?;
+        while !reader.eof() {
+            let instruction = self.parse_instruction(&mut reader)?;
+            function.instruction(&instruction);
+            if matches!(instruction, Instruction::Loop(_)) {
+                metering(global).iter().for_each(|instruction| {
+                    function.instruction(instruction);
+                });
+            }
+        }
+        code.function(&function);
+        Ok(())
+    }
+
+    /// Adds the sections the metering adds items to where the module does not have them.
+    fn intersperse_section_hook(
+        &mut self,
+        module: &mut Module,
+        _after: Option<SectionId>,
+        before: Option<SectionId>,
+    ) -> Result<(), reencode::Error<WasmError>> {
+        if self.metering.is_none() {
+            return Ok(());
+        }
+        let missing = |id: SectionId| {
+            before.is_none_or(|before| section_position(before) > section_position(id))
+        };
+        if !self.extended.contains(&SectionId::Type) && missing(SectionId::Type) {
+            let mut types = TypeSection::new();
+            self.extend(SectionId::Type, &mut types);
+            module.section(&types);
+        }
+        if !self.extended.contains(&SectionId::Function) && missing(SectionId::Function) {
+            let mut functions = FunctionSection::new();
+            self.extend(SectionId::Function, &mut functions);
+            module.section(&functions);
+        }
+        if !self.extended.contains(&SectionId::Global) && missing(SectionId::Global) {
+            let mut globals = GlobalSection::new();
+            self.extend(SectionId::Global, &mut globals);
+            module.section(&globals);
+        }
+        if !self.extended.contains(&SectionId::Export) && missing(SectionId::Export) {
+            let mut exports = ExportSection::new();
+            self.extend(SectionId::Export, &mut exports);
+            module.section(&exports);
+        }
+        if !self.extended.contains(&SectionId::Code) && missing(SectionId::Code) {
+            let mut code = CodeSection::new();
+            self.extend(SectionId::Code, &mut code);
+            module.section(&code);
+        }
+        Ok(())
+    }
+}
+
+/// Validates a WASM module against the limits and prepares it to be loaded as a plugin module.
+///
+/// The module is validated before it is changed. Imports other than host functions and exports
+/// with `RESERVED_EXPORT_PREFIX` are rejected. The maximum of every memory is capped at
+/// `limits.max_memory_pages`. If `limits.fuel` is set, an internal fuel global is added, every
+/// function body and loop consumes fuel from it, and the module exports `REFUEL_EXPORT` and
+/// `FUEL_EXHAUSTED_EXPORT`. The global itself is not exported, so only the host can refuel.
+///
+/// # Arguments
+/// * `bytes` - Binary of the module
+/// * `limits` - Limits applied to the module
+///
+/// # Errors
+/// Returns a `WasmError` if the module is invalid, imports something else than a host function,
+/// exports a reserved name, needs more memory than the limit, or if the fuel limit is too large.
+pub(crate) fn instrument(bytes: &[u8], limits: &WasmLimits) -> Result<Vec<u8>, WasmError> {
+    // Numbers of the types, functions and globals of the module
+    let (mut types, mut functions, mut globals) = (0, 0, 0);
+    for payload in Parser::new(0).parse_all(bytes) {
+        match payload.map_err(parse_error)? {
+            Payload::TypeSection(section) => {
+                for group in section {
+                    types += group.map_err(parse_error)?.types().len() as u32;
+                }
+            }
+            Payload::ImportSection(section) => {
+                for import in section {
+                    let import = import.map_err(parse_error)?;
+                    let host_function = import.module == WASM_HOST_MODULE
+                        && matches!(import.ty, TypeRef::Func(_))
+                        && WASM_HOST_FUNCTIONS
+                            .iter()
+                            .any(|(name, _)| *name == import.name);
+                    if !host_function {
+                        return Err(WasmError::UnsupportedImport {
+                            module: import.module.to_string(),
+                            name: import.name.to_string(),
+                        });
+                    }
+                    functions += 1;
+                }
+            }
+            Payload::FunctionSection(section) => functions += section.count(),
+            Payload::GlobalSection(section) => globals += section.count(),
+            Payload::ExportSection(section) => {
+                for export in section {
+                    let export = export.map_err(parse_error)?;
+                    if export.name.starts_with(RESERVED_EXPORT_PREFIX) {
+                        return Err(WasmError::ReservedExport {
+                            name: export.name.to_string(),
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    validate(bytes)?;
+
+    let metering = match limits.fuel {
+        Some(fuel) => Some(Metering {
+            fuel: i64::try_from(fuel).map_err(|_| WasmError::FuelLimitExceeded { fuel })?,
+            // Imports are host functions only, so the new global comes after the defined ones
+            global: globals,
+            types,
+            functions,
+        }),
+        None => None,
+    };
+    let mut instrumenter = Instrumenter {
+        max_memory_pages: limits.max_memory_pages,
+        metering,
+        extended: Vec::new(),
+    };
+    let mut module = Module::new();
+    instrumenter
+        .parse_core_module(&mut module, Parser::new(0), bytes)
+        .map_err(|e| match e {
+            reencode::Error::UserError(e) => e,
+            e => parse_error(e),
+        })?;
+    Ok(module.finish())
+}
+
+/// Export of a WASM plugin module that implements a plugin function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WasmPluginFunction {
+    /// Specifier of the module
+    pub(crate) specifier: String,
+    /// Name of the export
+    pub(crate) name: String,
+    /// Host functions the function may call, according to its permissions
+    pub(crate) host_functions: Vec<String>,
+}
+
+/// WASM plugin modules and functions of a run, placed into the `OpState` when the run has any.
+pub(crate) struct WasmPlugins {
+    /// Instrumented binaries of the modules, keyed by specifier
+    modules: HashMap<String, Vec<u8>>,
+    /// Plugin functions implemented by the modules, keyed by function ID
+    functions: HashMap<String, WasmPluginFunction>,
+    /// `WebAssembly.Instance` and the getter of its `exports`, captured before any code of the
+    /// run is evaluated so that workflows cannot replace them
+    intrinsics: Option<(v8::Global<v8::Function>, v8::Global<v8::Function>)>,
+    /// Exports of the instantiated modules, keyed by specifier
+    instances: HashMap<String, v8::Global<v8::Object>>,
+    /// ID of the plugin function being called, the only one host functions run for
+    caller: Option<String>,
+}
+
+impl WasmPlugins {
+    /// Creates the WASM plugins of a run.
+    ///
+    /// # Arguments
+    /// * `modules` - Instrumented binaries of the modules, keyed by specifier
+    /// * `functions` - Plugin functions implemented by the modules, keyed by function ID
+    pub(crate) fn new(
+        modules: HashMap<String, Vec<u8>>,
+        functions: HashMap<String, WasmPluginFunction>,
+    ) -> Self {
+        Self {
+            modules,
+            functions,
+            intrinsics: None,
+            instances: HashMap::new(),
+            caller: None,
+        }
+    }
+
+    /// Returns whether the run has any WASM plugin function.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    /// Captures `WebAssembly.Instance` and the getter of its `exports` from the global object.
+    ///
+    /// # Arguments
+    /// * `scope` - Scope of the main context of the runtime, before any code of the run is
+    ///   evaluated
+    ///
+    /// # Errors
+    /// Returns a `JsErrorBox` if WebAssembly is not available.
+    pub(crate) fn capture_intrinsics(
+        &mut self,
+        scope: &mut v8::HandleScope,
+    ) -> Result<(), JsErrorBox> {
+        let global = scope.get_current_context().global(scope);
+        let constructor = get(scope, global, "WebAssembly")
+            .and_then(|wasm| get(scope, wasm.try_into().ok()?, "Instance"))
+            .and_then(|instance| v8::Local::<v8::Function>::try_from(instance).ok());
+        let getter = constructor.and_then(|constructor| {
+            let prototype = get(scope, constructor.into(), "prototype")?;
+            let key = v8::String::new(scope, "exports")?;
+            let descriptor = v8::Local::<v8::Object>::try_from(prototype)
+                .ok()?
+                .get_own_property_descriptor(scope, key.into())?;
+            let getter = get(scope, descriptor.try_into().ok()?, "get")?;
+            v8::Local::<v8::Function>::try_from(getter).ok()
+        });
+        let (Some(constructor), Some(getter)) = (constructor, getter) else {
+            return Err(JsErrorBox::generic("WebAssembly.Instance is not defined"));
+        };
+        self.intrinsics = Some((
+            v8::Global::new(scope, constructor),
+            v8::Global::new(scope, getter),
+        ));
+        Ok(())
+    }
+}
+
+/// Returns a property of an object.
+fn get<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    object: v8::Local<v8::Object>,
+    key: &str,
+) -> Option<v8::Local<'s, v8::Value>> {
+    let key = v8::String::new(scope, key)?;
+    object.get(scope, key.into())
+}
+
+/// Returns a function property of an object.
+fn get_function<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    object: v8::Local<v8::Object>,
+    key: &str,
+) -> Option<v8::Local<'s, v8::Function>> {
+    get(scope, object, key)?.try_into().ok()
+}
+
+/// Throws a `JsErrorBox` into JavaScript, as an instance of its class if it is registered.
+fn throw(scope: &mut v8::HandleScope, error: JsErrorBox) {
+    let exception = deno_core::error::to_v8_error(scope, &error);
+    scope.throw_exception(exception);
+}
+
+/// Returns an object without prototype with the given data properties.
+fn plain_object<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    properties: &[(&str, v8::Local<v8::Value>)],
+) -> Option<v8::Local<'s, v8::Object>> {
+    let null = v8::null(scope).into();
+    let object = v8::Object::with_prototype_and_properties(scope, null, &[], &[]);
+    for (key, value) in properties {
+        let key = v8::String::new(scope, key)?;
+        object.create_data_property(scope, key.into(), *value)?;
+    }
+    Some(object)
+}
+
+/// Returns the exports of a WASM plugin module, instantiating it with the host functions the
+/// first time. Returns `None` if an exception was thrown.
+fn instance_exports<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    specifier: &str,
+) -> Option<v8::Local<'s, v8::Object>> {
+    let state = JsRuntime::op_state_from(scope);
+    let module = {
+        let state = state.borrow();
+        let plugins = state.borrow::<WasmPlugins>();
+        if let Some(exports) = plugins.instances.get(specifier) {
+            return Some(v8::Local::new(scope, exports));
+        }
+        match (plugins.modules.get(specifier), &plugins.intrinsics) {
+            (Some(bytes), Some((constructor, getter))) => Some((
+                bytes.clone(),
+                v8::Local::new(scope, constructor),
+                v8::Local::new(scope, getter),
+            )),
+            _ => None,
+        }
+    };
+    let Some((bytes, constructor, getter)) = module else {
+        throw(
+            scope,
+            JsErrorBox::generic(format!("Module not found: {specifier}")),
+        );
+        return None;
+    };
+
+    let module = v8::WasmModuleObject::compile(scope, &bytes)?;
+    let mut host = Vec::new();
+    for (name, _) in WASM_HOST_FUNCTIONS {
+        let data = [
+            v8::String::new(scope, name)?.into(),
+            v8::String::new(scope, specifier)?.into(),
+        ];
+        let data = v8::Array::new_with_elements(scope, &data);
+        let function = v8::Function::builder(call_host_function)
+            .data(data.into())
+            .build(scope)?;
+        host.push((name, function.into()));
+    }
+    let host = plain_object(scope, &host)?;
+    let imports = plain_object(scope, &[(WASM_HOST_MODULE, host.into())])?;
+    let instance = constructor.new_instance(scope, &[module.into(), imports.into()])?;
+    let exports = getter.call(scope, instance.into(), &[])?;
+    let exports = v8::Local::<v8::Object>::try_from(exports).ok()?;
+    let global = v8::Global::new(scope, exports);
+    state
+        .borrow_mut()
+        .borrow_mut::<WasmPlugins>()
+        .instances
+        .insert(specifier.to_string(), global);
+    Some(exports)
+}
+
+/// Callback of the functions returned by `op_wasm_plugin_function`, which calls the export of
+/// the plugin function whose ID is the data of the function.
+fn call_wasm_plugin_function<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    args: v8::FunctionCallbackArguments<'s>,
+    mut rv: v8::ReturnValue,
+) {
+    let function_id = args.data().to_rust_string_lossy(scope);
+    let state = JsRuntime::op_state_from(scope);
+    let function = state
+        .borrow()
+        .borrow::<WasmPlugins>()
+        .functions
+        .get(&function_id)
+        .cloned();
+    let Some(function) = function else {
+        return throw(
+            scope,
+            JsErrorBox::generic(format!(
+                "Plugin function {function_id} is not a WASM function"
+            )),
+        );
+    };
+
+    // Converting numbers and BigInts does not run JavaScript, which could call a host function
+    // while it runs for this plugin function
+    let values: Vec<_> = (0..args.length()).map(|i| args.get(i)).collect();
+    if values.iter().any(|value| value.is_object()) {
+        return throw(
+            scope,
+            JsErrorBox::type_error(format!(
+                "Arguments of plugin function {function_id} must be numbers"
+            )),
+        );
+    }
+    let Some(exports) = instance_exports(scope, &function.specifier) else {
+        return;
+    };
+    let Some(export) = get_function(scope, exports, &function.name) else {
+        return throw(
+            scope,
+            JsErrorBox::type_error(format!(
+                "Plugin function {function_id} is not exported as a function"
+            )),
+        );
+    };
+    let undefined = v8::undefined(scope).into();
+    if let Some(refuel) = get_function(scope, exports, REFUEL_EXPORT)
+        && refuel.call(scope, undefined, &[]).is_none()
+    {
+        return;
+    }
+
+    let previous = state
+        .borrow_mut()
+        .borrow_mut::<WasmPlugins>()
+        .caller
+        .replace(function_id.clone());
+    let scope = &mut v8::TryCatch::new(scope);
+    let result = export.call(scope, undefined, &values);
+    state.borrow_mut().borrow_mut::<WasmPlugins>().caller = previous;
+    if let Some(result) = result {
+        return rv.set(result);
+    }
+    if scope.has_terminated() {
+        return;
+    }
+    let exhausted = get_function(scope, exports, FUEL_EXHAUSTED_EXPORT)
+        .and_then(|exhausted| exhausted.call(scope, undefined, &[]))
+        .is_some_and(|exhausted| exhausted.int32_value(scope) == Some(1));
+    if exhausted {
+        let message = format!("Plugin function {function_id} ran out of fuel");
+        let message = v8::String::new(scope, &message).unwrap();
+        let exception = v8::Exception::range_error(scope, message);
+        scope.reset();
+        scope.throw_exception(exception);
+    }
+    scope.rethrow();
+}
+
+/// Returns the name of a host function and the specifier of its module, from the data of the
+/// host function.
+fn host_function_data(
+    scope: &mut v8::HandleScope,
+    data: v8::Local<v8::Value>,
+) -> Option<(String, String)> {
+    let data = v8::Local::<v8::Array>::try_from(data).ok()?;
+    let name = data.get_index(scope, 0)?.to_rust_string_lossy(scope);
+    let specifier = data.get_index(scope, 1)?.to_rust_string_lossy(scope);
+    Some((name, specifier))
+}
+
+/// Memory exported by an instantiated WASM plugin module.
+struct WasmMemory(v8::SharedRef<v8::BackingStore>);
+
+impl WasmMemory {
+    /// Returns the memory of the module with the given specifier.
+    fn of(scope: &mut v8::HandleScope, specifier: &str) -> Result<Self, JsErrorBox> {
+        let state = JsRuntime::op_state_from(scope);
+        let exports = state
+            .borrow()
+            .borrow::<WasmPlugins>()
+            .instances
+            .get(specifier)
+            .cloned();
+        let memory = exports
+            .and_then(|exports| {
+                let exports = v8::Local::new(scope, exports);
+                get(scope, exports, "memory")
+            })
+            .and_then(|memory| v8::Local::<v8::WasmMemoryObject>::try_from(memory).ok())
+            .ok_or_else(|| {
+                JsErrorBox::generic(format!(
+                    "WASM module {specifier} does not export its memory"
+                ))
+            })?;
+        Ok(Self(memory.buffer().get_backing_store()))
+    }
+
+    /// Returns `len` bytes at `ptr`.
+    fn read(&self, ptr: i32, len: i32) -> Result<Vec<u8>, JsErrorBox> {
+        let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+        self.0
+            .get(ptr..ptr.saturating_add(len))
+            .map(|cells| cells.iter().map(|cell| cell.get()).collect())
+            .ok_or_else(|| JsErrorBox::range_error("Memory access out of bounds"))
+    }
+
+    /// Returns the UTF-8 string of `len` bytes at `ptr`.
+    fn read_string(&self, ptr: i32, len: i32) -> Result<String, JsErrorBox> {
+        Ok(String::from_utf8_lossy(&self.read(ptr, len)?).into_owned())
+    }
+
+    /// Writes at most `len` bytes of `data` at `ptr`.
+    fn write(&self, ptr: i32, len: i32, data: &[u8]) -> Result<(), JsErrorBox> {
+        let data = &data[..data.len().min(len as u32 as usize)];
+        let ptr = ptr as u32 as usize;
+        let cells = self
+            .0
+            .get(ptr..ptr.saturating_add(data.len()))
+            .ok_or_else(|| JsErrorBox::range_error("Memory access out of bounds"))?;
+        for (cell, byte) in cells.iter().zip(data) {
+            cell.set(*byte);
+        }
+        Ok(())
+    }
+}
+
+/// Callback of the host functions WASM plugin modules import.
+fn call_host_function<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    args: v8::FunctionCallbackArguments<'s>,
+    mut rv: v8::ReturnValue,
+) {
+    let Some((name, specifier)) = host_function_data(scope, args.data()) else {
+        return;
+    };
+    let state = JsRuntime::op_state_from(scope);
+    let caller = {
+        let state = state.borrow();
+        let plugins = state.borrow::<WasmPlugins>();
+        plugins
+            .caller
+            .as_ref()
+            .filter(|caller| {
+                plugins.functions.get(*caller).is_some_and(|function| {
+                    function.specifier == specifier && function.host_functions.contains(&name)
+                })
+            })
+            .cloned()
+    };
+    let Some(caller) = caller else {
+        return throw(
+            scope,
+            JsErrorBox::new(
+                PERMISSION_DENIED_ERROR_CLASS,
+                format!(
+                    "Host function {name} is not available to the plugin function being called"
+                ),
+            ),
+        );
+    };
+
+    let mut int = |i: i32| args.get(i).int32_value(scope).unwrap_or_default();
+    let (a, b, c, d) = (int(0), int(1), int(2), int(3));
+    let result = WasmMemory::of(scope, &specifier).and_then(|memory| {
+        let mut state = state.borrow_mut();
+        match name.as_str() {
+            "log" => {
+                let text = memory.read_string(a, b)?;
+                print(&mut state, &format!("{text}\n"), false).map_err(JsErrorBox::from_err)?;
+                Ok(None)
+            }
+            "read_file" => {
+                let path = memory.read_string(a, b)?;
+                check_permission(&mut state, PermissionType::Read, &path, &caller)?;
+                let data = std::fs::read(&path).map_err(JsErrorBox::from_err)?;
+                memory.write(c, d, &data)?;
+                Ok(Some(data.len() as i32))
+            }
+            "write_file" => {
+                let path = memory.read_string(a, b)?;
+                let data = memory.read(c, d)?;
+                check_permission(&mut state, PermissionType::Write, &path, &caller)?;
+                std::fs::write(&path, &data).map_err(JsErrorBox::from_err)?;
+                Ok(Some(d))
+            }
+            _ => Err(JsErrorBox::generic(format!("Unknown host function {name}"))),
+        }
+    });
+    match result {
+        Ok(Some(value)) => rv.set_int32(value),
+        Ok(None) => {}
+        Err(e) => throw(scope, e),
+    }
+}
+
+/// Returns a function that calls the WASM plugin function with the given ID.
+///
+/// Only registered when the run has WASM plugin functions. Host functions run for the plugin
+/// function being called, which is tracked in Rust, so workflows cannot call them on behalf of
+/// another plugin function.
+#[op2]
+pub(crate) fn op_wasm_plugin_function<'s>(
+    scope: &mut v8::HandleScope<'s>,
+    #[string] function_id: &str,
+) -> Result<v8::Local<'s, v8::Function>, JsErrorBox> {
+    let state = JsRuntime::op_state_from(scope);
+    if !state
+        .borrow()
+        .try_borrow::<WasmPlugins>()
+        .is_some_and(|plugins| plugins.functions.contains_key(function_id))
+    {
+        return Err(JsErrorBox::generic(format!(
+            "Plugin function {function_id} is not a WASM function"
+        )));
+    }
+    let data = v8::String::new(scope, function_id)
+        .ok_or_else(|| JsErrorBox::generic("Function ID is too long"))?;
+    v8::Function::builder(call_wasm_plugin_function)
+        .data(data.into())
+        .build(scope)
+        .ok_or_else(|| JsErrorBox::generic("Failed to create the plugin function"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Module that exports `add(i32, i32) -> i32` and a memory of one page without a maximum.
+    const ADD: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+        0x01, 0x07, 0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f, // type (i32, i32) -> i32
+        0x03, 0x02, 0x01, 0x00, // function 0
+        0x05, 0x03, 0x01, 0x00, 0x01, // memory, 1 page
+        0x07, 0x10, 0x02, 0x03, b'a', b'd', b'd', 0x00, 0x00, 0x06, b'm', b'e', b'm', b'o', b'r',
+        b'y', 0x02, 0x00, // exports
+        0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b, // code
+    ];
+
+    #[test]
+    fn test_instrument() {
+        assert_eq!(exported_functions(ADD).unwrap(), vec!["add"]);
+
+        let limits = WasmLimits {
+            max_memory_pages: 4,
+            fuel: Some(100),
+        };
+        let instrumented = instrument(ADD, &limits).unwrap();
+        validate(&instrumented).unwrap();
+        let mut memories = Vec::new();
+        let mut exports = Vec::new();
+        let mut bodies = Vec::new();
+        for payload in Parser::new(0).parse_all(&instrumented) {
+            match payload.unwrap() {
+                Payload::MemorySection(s) => memories.extend(s.into_iter().map(Result::unwrap)),
+                Payload::ExportSection(s) => exports.extend(s.into_iter().map(Result::unwrap)),
+                Payload::CodeSectionEntry(body) => bodies.push(body.as_bytes()),
+                _ => {}
+            }
+        }
+        assert_eq!(memories[0].maximum, Some(4));
+        assert_eq!(
+            exported_functions(&instrumented).unwrap(),
+            vec!["add", REFUEL_EXPORT, FUEL_EXHAUSTED_EXPORT]
+        );
+        // The fuel global is internal
+        assert!(!exports.iter().any(|e| e.kind == ExternalKind::Global));
+        let body = |instructions: Vec<Instruction>| {
+            let mut function = Function::new([]);
+            for instruction in &instructions {
+                function.instruction(instruction);
+            }
+            function.into_raw_body()
+        };
+        let mut add = metering(0);
+        add.extend([
+            Instruction::LocalGet(0),
+            Instruction::LocalGet(1),
+            Instruction::I32Add,
+            Instruction::End,
+        ]);
+        assert_eq!(bodies[0], body(add));
+        assert_eq!(
+            bodies[1],
+            body(vec![
+                Instruction::I64Const(100),
+                Instruction::GlobalSet(0),
+                Instruction::End
+            ])
+        );
+
+        // The sections the metering adds to are added to modules without them
+        let instrumented = instrument(b"\0asm\x01\0\0\0", &limits).unwrap();
+        validate(&instrumented).unwrap();
+        assert_eq!(
+            exported_functions(&instrumented).unwrap(),
+            vec![REFUEL_EXPORT, FUEL_EXHAUSTED_EXPORT]
+        );
+        let limits = WasmLimits {
+            max_memory_pages: 4,
+            fuel: None,
+        };
+        assert_eq!(
+            instrument(b"\0asm\x01\0\0\0", &limits).unwrap(),
+            b"\0asm\x01\0\0\0"
+        );
+
+        let limits = WasmLimits {
+            max_memory_pages: 0,
+            fuel: None,
+        };
+        assert_eq!(
+            instrument(ADD, &limits).unwrap_err(),
+            WasmError::MemoryLimitExceeded {
+                initial: 1,
+                limit: 0
+            }
+        );
+
+        let limits = WasmLimits {
+            max_memory_pages: 1,
+            fuel: Some(u64::MAX),
+        };
+        assert_eq!(
+            instrument(ADD, &limits).unwrap_err(),
+            WasmError::FuelLimitExceeded { fuel: u64::MAX }
+        );
+    }
+
+    #[test]
+    fn test_instrument_invalid() {
+        // `add` reads a local that is not defined
+        let mut bytes = ADD.to_vec();
+        let len = bytes.len();
+        bytes[len - 3] = 0x02;
+        assert!(matches!(
+            instrument(&bytes, &WasmLimits::default()),
+            Err(WasmError::Parse(message)) if message.contains("local 2")
+        ));
+
+        // A function is exported under a reserved name
+        let mut types = TypeSection::new();
+        types.ty().function([], []);
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        let mut exports = ExportSection::new();
+        exports.export(REFUEL_EXPORT, ExportKind::Func, 0);
+        let mut code = CodeSection::new();
+        let mut function = Function::new([]);
+        function.instruction(&Instruction::End);
+        code.function(&function);
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&functions)
+            .section(&exports)
+            .section(&code);
+        let bytes = module.finish();
+        assert_eq!(
+            instrument(&bytes, &WasmLimits::default()).unwrap_err(),
+            WasmError::ReservedExport {
+                name: REFUEL_EXPORT.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_wasm_limits_canonical_bytes() {
+        let limits = WasmLimits {
+            max_memory_pages: 2,
+            fuel: Some(3),
+        };
+        assert_eq!(
+            limits.canonical_bytes(),
+            vec![0, 0, 0, 2, 1, 0, 0, 0, 0, 0, 0, 0, 3]
+        );
+        let limits = WasmLimits {
+            max_memory_pages: 2,
+            fuel: None,
+        };
+        assert_eq!(limits.canonical_bytes(), vec![0, 0, 0, 2, 0]);
+    }
+
+    #[test]
+    fn test_instrument_imports() {
+        let import = |module: &[u8], name: &[u8]| {
+            let mut entry = vec![module.len() as u8];
+            entry.extend_from_slice(module);
+            entry.push(name.len() as u8);
+            entry.extend_from_slice(name);
+            entry.extend_from_slice(&[0x00, 0x00]);
+            let mut bytes = vec![
+                0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+                0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type () -> ()
+                0x02,
+            ];
+            bytes.push(entry.len() as u8 + 1);
+            bytes.push(0x01);
+            bytes.extend(entry);
+            bytes
+        };
+        let instrumented = instrument(&import(b"sapphillon", b"log"), &WasmLimits::default());
+        validate(&instrumented.unwrap()).unwrap();
+        assert_eq!(
+            instrument(&import(b"env", b"log"), &WasmLimits::default()).unwrap_err(),
+            WasmError::UnsupportedImport {
+                module: "env".to_string(),
+                name: "log".to_string()
+            }
+        );
+        assert!(matches!(
+            instrument(b"not wasm", &WasmLimits::default()),
+            Err(WasmError::Parse(_))
+        ));
+    }
+}
@@ -16,11 +16,28 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::analysis::{PermissionInference, infer_permissions};
+use crate::audit::redact_audit_event;
+use crate::bindings::{PluginBinding, PluginFunctionTarget};
+use crate::config::redact_secrets;
+use crate::permission::{PermissionDecision, PermissionDecisionRecord, is_permission_granted};
 use crate::plugin::CorePluginPackage;
+use crate::proto::google::rpc::BadRequest;
+use crate::proto::google::rpc::context::AttributeContext;
 use crate::proto::sapphillon;
-use crate::proto::sapphillon::v1::{WorkflowResult, WorkflowResultType};
-use crate::runtime::{OpStateWorkflowData, run_script};
+use crate::proto::sapphillon::v1::{
+    Permission, WorkflowLanguage, WorkflowResult, WorkflowResultType,
+};
+use crate::registry::{PluginRegistry, RegistryError};
+use crate::revision::RevisionDiff;
+use crate::runtime::{
+    CancellationToken, EXIT_CODE_FAILURE, EXIT_CODE_REAPPROVAL_REQUIRED, EXIT_CODE_SUCCESS,
+    OpStateWorkflowData, WorkflowRunError, WorkflowRunOptions, run_module,
+};
+use crate::transpile::{TranspileError, WorkflowModule};
+use crate::validation::{not_listed_op, validate_plugin_functions};
 use prost_types::Timestamp;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -29,15 +46,31 @@ pub struct CoreWorkflowCode {
     pub id: String,
     /// Deno OpDecl (workflow code body)
     pub code: String,
+    /// Language the workflow code is written in
+    pub language: WorkflowLanguage,
     /// List of plugin packages used in the workflow
     pub plugin_packages: Vec<CorePluginPackage>,
+    /// Permissions granted to the workflow. Resources not listed here are denied at runtime.
+    pub required_permissions: Vec<Permission>,
+    /// IDs of the plugin functions the code may invoke, or `None` if it may invoke every function
+    /// of its plugin packages. Functions not listed throw a `PluginFunctionNotListed` error.
+    pub plugin_function_ids: Option<Vec<String>>,
 
     pub code_revision: i32,
     pub result: Vec<sapphillon::v1::WorkflowResult>,
+    /// Options applied to every run, such as the timeout and heap limit
+    pub run_options: WorkflowRunOptions,
+    /// Permission decisions made by `run_options.permission_policy` and `run_options.permission_approver`, keyed by the ID of the `WorkflowResult` of the run
+    pub permission_decisions: HashMap<String, Vec<PermissionDecisionRecord>>,
+    /// Audit events of the permission checks made during each run, keyed by the ID of the `WorkflowResult` of the run
+    pub audit_log: HashMap<String, Vec<AttributeContext>>,
+    /// Changes from the last approved revision that must be approved before the workflow can run again
+    pub pending_revision: Option<RevisionDiff>,
 }
 
 impl CoreWorkflowCode {
     /// Creates a new CoreWorkflowCode from the given ID, name, code, and plugin packages.
+    /// The code is treated as JavaScript and no permissions are granted.
     ///
     /// # Arguments
     /// * `id` - Unique ID of the workflow code
@@ -53,9 +86,16 @@ impl CoreWorkflowCode {
         Self {
             id,
             code,
+            language: WorkflowLanguage::Javascript,
             plugin_packages,
+            required_permissions: Vec::new(),
+            plugin_function_ids: None,
             code_revision,
             result: Vec::new(),
+            run_options: WorkflowRunOptions::default(),
+            permission_decisions: HashMap::new(),
+            audit_log: HashMap::new(),
+            pending_revision: None,
         }
     }
 
@@ -64,27 +104,78 @@ impl CoreWorkflowCode {
     /// This method collects all OpDecls from the associated plugin packages, executes the workflow code
     /// using these operations, and records the execution result. The result includes metadata such as
     /// execution time, revision, exit code, and result type (success or failure). The result is appended
-    /// to the `result` field of the struct.
+    /// to the `result` field of the struct. Limits such as the timeout are taken from `run_options`;
+    /// a run that times out is recorded as a failure with `EXIT_CODE_TIMED_OUT`, and a run that exceeds
+    /// its heap limit as a failure with `EXIT_CODE_MEMORY_LIMIT_EXCEEDED`. Plugin ops may only access
+    /// the resources declared in `required_permissions`; an uncaught denial fails the run.
+    /// Plugin functions whose declared permissions are not covered by `required_permissions` are
+    /// registered as stubs that throw a `PermissionDenied` error when invoked, and functions that
+    /// `plugin_function_ids` does not list as stubs that throw a `PluginFunctionNotListed` error.
+    ///
+    /// When `run_options.permission_policy` or `run_options.permission_approver` is set, missing
+    /// permissions are decided instead of denied right away: for plugin functions before the run
+    /// starts, and for resources checked by ops while the run is in progress. The policy is evaluated
+    /// first, and requests it escalates are passed to the approver. Allowing a plugin function grants its permissions
+    /// for the run. Permissions allowed with `AllowAlways` are added to `required_permissions`, and
+    /// every decision is recorded in `permission_decisions` under the ID of the run's `WorkflowResult`.
+    ///
+    /// While `pending_revision` is set, the code is not executed and no plugin function is checked:
+    /// the run is recorded as a failure with the description "Re-approval Required", the changes of
+    /// the revision as its result, and `EXIT_CODE_REAPPROVAL_REQUIRED`.
+    ///
+    /// Every permission check of the run, granted or denied, is recorded as an audit event in
+    /// `audit_log` under the ID of the run's `WorkflowResult`, and exported to `run_options.audit_sink`
+    /// when it is set. See the `audit` module for the contents of the events.
     ///
     /// # Execution Flow
-    /// 1. Collect OpDecls from all plugin packages.
-    /// 2. Generate execution metadata (ID, display name, timestamp, revision).
-    /// 3. Execute the workflow code using `run_script`.
-    /// 4. Construct a `WorkflowResult` based on the execution outcome.
-    /// 5. Append the result to the `result` vector.
+    /// 1. Generate execution metadata (ID, display name, timestamp, revision).
+    /// 2. Fail without running if `pending_revision` is set.
+    /// 3. Collect OpDecls from all plugin packages, replacing unlisted and unauthorized functions
+    ///    with stubs.
+    /// 4. Transpile the code and the source modules of plugin packages if they are TypeScript, then
+    ///    execute the code as an ES module using `run_module`, waiting for its event loop to finish.
+    ///    Plugin packages are exposed through the global `plugins` object and
+    ///    `sapphillon:plugin/<package_id>` modules, see the `bindings` module.
+    ///    Packages with a lifecycle are set up before and torn down after the run, see the
+    ///    `lifecycle` module.
+    /// 5. Construct a `WorkflowResult` based on the execution outcome, redacting the secrets of the
+    ///    plugin packages from its description and result.
+    /// 6. Append the result to the `result` vector.
     ///
     /// # Side Effects
     /// - Modifies the `result` field by adding a new `WorkflowResult`.
+    /// - Records the permission decisions and audit events of the run and may extend `required_permissions`.
     pub fn run(&mut self) {
-        // Collect OpDecls from plugin packages
-        let mut ops = Vec::new();
-        for pkg in &self.plugin_packages {
-            for func in &pkg.functions {
-                ops.push(func.func.clone().into_owned());
-            }
-        }
+        self.run_with_cancellation(&CancellationToken::new());
+    }
+
+    /// Infers the permissions the code needs from the plugin functions it may invoke,
+    /// and compares them with `required_permissions`.
+    ///
+    /// See `analysis::infer_permissions` for how invocations are detected.
+    ///
+    /// # Errors
+    /// Returns a `TranspileError` if the code is TypeScript and fails to transpile.
+    pub fn infer_permissions(&self) -> Result<PermissionInference, TranspileError> {
+        let module = WorkflowModule::new(&self.code, self.language)?;
+        Ok(infer_permissions(
+            &module,
+            &self.plugin_packages,
+            &self.required_permissions,
+        ))
+    }
+
+    /// Executes the workflow code like `run`, but can be cancelled through the given token.
+    ///
+    /// Cancelling the token from another thread terminates the run, and the appended
+    /// `WorkflowResult` is recorded with the description "Cancelled" and `EXIT_CODE_CANCELLED`.
+    /// The proto has no dedicated result type for cancellation, so `result_type` is `Failure`.
+    ///
+    /// # Arguments
+    /// * `cancellation` - Token used to cancel the run
+    pub fn run_with_cancellation(&mut self, cancellation: &CancellationToken) {
+        let workflow_data = Arc::new(Mutex::new(OpStateWorkflowData::new(&self.id, true)));
 
-        // Execute the workflow code and record the result
         let now = SystemTime::now();
         let epoch = now.duration_since(UNIX_EPOCH).unwrap();
         let id = format!("{}-{}", self.id, epoch.as_nanos());
@@ -99,28 +190,65 @@ impl CoreWorkflowCode {
             .map(|r| r.workflow_result_revision + 1)
             .unwrap_or(1);
 
-        let opstate_workflow_data = OpStateWorkflowData::new(&self.id, true);
-        let result = run_script(
-            &self.code,
-            ops,
-            Some(Arc::new(Mutex::new(opstate_workflow_data))),
-        );
+        // Secrets of the plugin packages may be printed, end up in error messages or be part of
+        // the resources of permission checks
+        let secrets: Vec<String> = self
+            .plugin_packages
+            .iter()
+            .flat_map(|pkg| pkg.config.secrets())
+            .map(str::to_string)
+            .collect();
+        let secrets: Vec<&str> = secrets.iter().map(String::as_str).collect();
 
-        let (description, result, result_type, exit_code) = match result {
-            Ok(data) => (
-                "Success".to_string(),
-                data.lock().unwrap().stdout_to_string(),
-                WorkflowResultType::SuccessUnspecified as i32,
-                0,
-            ),
-            Err(e) => (
-                format!("Error: {e}"),
-                format!("{e}"),
+        // Execute the workflow code unless its revision awaits re-approval, and record the result
+        let (description, result, result_type, exit_code) = match &self.pending_revision {
+            Some(diff) => (
+                "Re-approval Required".to_string(),
+                diff.to_string(),
                 WorkflowResultType::Failure as i32,
-                1,
+                EXIT_CODE_REAPPROVAL_REQUIRED,
             ),
+            None => {
+                let (description, result, result_type, exit_code) =
+                    self.execute(&workflow_data, cancellation);
+                (
+                    redact_secrets(&description, &secrets),
+                    redact_secrets(&result, &secrets),
+                    result_type,
+                    exit_code,
+                )
+            }
         };
 
+        // Permissions allowed with AllowAlways are granted to later runs
+        let decisions = workflow_data
+            .lock()
+            .unwrap()
+            .get_permission_decisions()
+            .clone();
+        for record in &decisions {
+            if record.decision == PermissionDecision::AllowAlways
+                && !is_permission_granted(&self.required_permissions, &record.request.permission)
+            {
+                self.required_permissions
+                    .push(record.request.permission.clone());
+            }
+        }
+        self.permission_decisions.insert(id.clone(), decisions);
+
+        // Attach the audit events to the run and export them
+        let mut events = workflow_data.lock().unwrap().get_audit_events().clone();
+        for event in &mut events {
+            redact_audit_event(event, &secrets);
+            if let Some(request) = event.request.as_mut() {
+                request.id = id.clone();
+            }
+        }
+        if let Some(sink) = &self.run_options.audit_sink {
+            sink.export(&id, &events);
+        }
+        self.audit_log.insert(id.clone(), events);
+
         let result_obj = WorkflowResult {
             id,
             display_name,
@@ -134,8 +262,98 @@ impl CoreWorkflowCode {
         self.result.push(result_obj);
     }
 
+    /// Checks the plugin functions and executes the workflow code, returning the description,
+    /// result, result type and exit code of the run.
+    fn execute(
+        &self,
+        workflow_data: &Arc<Mutex<OpStateWorkflowData>>,
+        cancellation: &CancellationToken,
+    ) -> (String, String, i32, i32) {
+        // Collect OpDecls from plugin packages, replacing unlisted functions. The permissions of
+        // the others are checked by `run_module` before the run starts.
+        let mut ops = Vec::new();
+        let mut unlisted = Vec::new();
+        for pkg in &self.plugin_packages {
+            for func in &pkg.functions {
+                workflow_data
+                    .lock()
+                    .unwrap()
+                    .set_plugin_function_id(func.api_name(), &func.id);
+                if let Some(listed) = &self.plugin_function_ids
+                    && !listed.contains(&func.id)
+                {
+                    if let Some(op) = func.op() {
+                        ops.push(not_listed_op(op));
+                    }
+                    unlisted.push(func.id.as_str());
+                } else if let Some(op) = func.op() {
+                    ops.push(*op);
+                }
+            }
+        }
+
+        let bindings = self
+            .plugin_packages
+            .iter()
+            .map(PluginBinding::new_from_plugin_package)
+            .collect::<Result<Vec<PluginBinding>, TranspileError>>()
+            .map(|mut bindings| {
+                for func in bindings.iter_mut().flat_map(|b| &mut b.functions) {
+                    if unlisted.contains(&func.function_id.as_str()) {
+                        func.target = PluginFunctionTarget::NotListed;
+                    }
+                }
+                bindings
+            });
+
+        match bindings.and_then(|bindings| {
+            WorkflowModule::new(&self.code, self.language).map(|module| (module, bindings))
+        }) {
+            Ok((module, bindings)) => {
+                let result = run_module(
+                    &module,
+                    ops,
+                    &bindings,
+                    Some(workflow_data.clone()),
+                    &self.required_permissions,
+                    &self.run_options,
+                    cancellation,
+                );
+                match result {
+                    Ok(data) => (
+                        "Success".to_string(),
+                        data.lock().unwrap().stdout_to_string(),
+                        WorkflowResultType::SuccessUnspecified as i32,
+                        EXIT_CODE_SUCCESS,
+                    ),
+                    Err(WorkflowRunError::Cancelled) => (
+                        "Cancelled".to_string(),
+                        format!("{}", WorkflowRunError::Cancelled),
+                        WorkflowResultType::Failure as i32,
+                        WorkflowRunError::Cancelled.exit_code(),
+                    ),
+                    Err(e) => (
+                        format!("Error: {e}"),
+                        format!("{e}"),
+                        WorkflowResultType::Failure as i32,
+                        e.exit_code(),
+                    ),
+                }
+            }
+            Err(e) => (
+                "Transpile Error".to_string(),
+                format!("{e}"),
+                WorkflowResultType::Failure as i32,
+                EXIT_CODE_FAILURE,
+            ),
+        }
+    }
+
     /// Creates a CoreWorkflowCode from a proto WorkflowCode.
     ///
+    /// The code may only invoke the plugin functions listed in its `plugin_function_ids`. An
+    /// empty list, the default of the proto, lists no functions and does not restrict them.
+    ///
     /// # Arguments
     /// * `workflow_code` - WorkflowCode defined in proto
     /// * `plugin_packages` - List of plugin packages used in the workflow
@@ -146,17 +364,97 @@ impl CoreWorkflowCode {
         Self {
             id: workflow_code.id.clone(),
             code: workflow_code.code.clone(),
+            language: workflow_code.language(),
             plugin_packages,
+            required_permissions: workflow_code.required_permissions.clone(),
+            plugin_function_ids: (!workflow_code.plugin_function_ids.is_empty())
+                .then(|| workflow_code.plugin_function_ids.clone()),
             code_revision: workflow_code.code_revision,
             result: Vec::new(),
+            run_options: WorkflowRunOptions::default(),
+            permission_decisions: HashMap::new(),
+            audit_log: HashMap::new(),
+            pending_revision: None,
+        }
+    }
+
+    /// Creates a CoreWorkflowCode from a proto WorkflowCode after validating its plugin functions.
+    ///
+    /// See `validation::validate_plugin_functions` for the checks made.
+    ///
+    /// # Arguments
+    /// * `workflow_code` - WorkflowCode defined in proto
+    /// * `plugin_packages` - List of plugin packages used in the workflow
+    ///
+    /// # Errors
+    /// Returns a `BadRequest` with a field violation for each problem found.
+    pub fn new_from_proto_validated(
+        workflow_code: &sapphillon::v1::WorkflowCode,
+        plugin_packages: Vec<CorePluginPackage>,
+    ) -> Result<Self, BadRequest> {
+        validate_plugin_functions(workflow_code, &plugin_packages)?;
+        Ok(Self::new_from_proto(workflow_code, plugin_packages))
+    }
+
+    /// Creates a CoreWorkflowCode from a proto WorkflowCode, with the plugin packages it uses
+    /// resolved from a registry.
+    ///
+    /// See `PluginRegistry::resolve` for how the packages are resolved.
+    ///
+    /// # Arguments
+    /// * `workflow_code` - WorkflowCode defined in proto
+    /// * `registry` - Registry of the installed plugin packages
+    ///
+    /// # Errors
+    /// Returns a `RegistryError` if a plugin package or plugin function cannot be resolved.
+    pub fn new_from_registry(
+        workflow_code: &sapphillon::v1::WorkflowCode,
+        registry: &PluginRegistry,
+    ) -> Result<Self, RegistryError> {
+        let resolved = registry.resolve(workflow_code)?;
+        Ok(Self::new_from_proto(workflow_code, resolved.packages))
+    }
+
+    /// Creates a CoreWorkflowCode from a new revision of a proto WorkflowCode.
+    ///
+    /// The revision is compared with the last approved one, and if it adds or escalates
+    /// permissions, or adds plugin packages or plugin functions, the changes are stored in
+    /// `pending_revision` and the workflow does not run until `approve_revision` is called.
+    ///
+    /// # Arguments
+    /// * `previous` - Last approved revision of the WorkflowCode
+    /// * `workflow_code` - New revision of the WorkflowCode
+    /// * `plugin_packages` - List of plugin packages used in the workflow
+    pub fn new_from_revision(
+        previous: &sapphillon::v1::WorkflowCode,
+        workflow_code: &sapphillon::v1::WorkflowCode,
+        plugin_packages: Vec<CorePluginPackage>,
+    ) -> Self {
+        let diff = RevisionDiff::new(previous, workflow_code);
+        let mut code = Self::new_from_proto(workflow_code, plugin_packages);
+        if diff.requires_reapproval() {
+            code.pending_revision = Some(diff);
         }
+        code
+    }
+
+    /// Approves the changes in `pending_revision`, allowing the workflow to run again.
+    /// Returns the approved changes, or `None` if there were none.
+    pub fn approve_revision(&mut self) -> Option<RevisionDiff> {
+        self.pending_revision.take()
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audit::{AuditSink, is_granted};
+    use crate::permission::{
+        PermissionApprover, PermissionDecisionFuture, PermissionDecisionSource, PermissionRequest,
+    };
     use crate::plugin::{CorePluginFunction, CorePluginPackage};
+    use crate::policy::PermissionPolicy;
     use crate::proto::sapphillon::v1::WorkflowCode;
+    use crate::validation::PLUGIN_FUNCTION_NOT_LISTED_ERROR_CLASS;
 
     // Generate a dummy CorePluginFunction for testing
     fn dummy_plugin_function() -> CorePluginFunction {
@@ -222,42 +520,1359 @@ mod tests {
         );
         assert!(res.result.contains("fail"));
     }
-    // Generate a dummy WorkflowCode (proto) for testing
-    fn dummy_proto_workflow_code() -> WorkflowCode {
-        WorkflowCode {
+
+    #[test]
+    fn test_core_workflow_code_run_async_failure() {
+        let pkg = dummy_plugin_package();
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "console.log('before');\nawait Promise.reject(new Error('async fail'));".to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 1);
+        assert_eq!(
+            res.result_type,
+            sapphillon::v1::WorkflowResultType::Failure as i32
+        );
+        assert!(res.result.contains("async fail"));
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_typescript() {
+        let proto = WorkflowCode {
             id: "wid".to_string(),
-            code: "console.log('hello');".to_string(),
+            code: "const n: number = 40;\nconsole.log(n + 2);".to_string(),
+            language: sapphillon::v1::WorkflowLanguage::Typescript as i32,
+            code_revision: 1,
+            ..Default::default()
+        };
+        let mut code = CoreWorkflowCode::new_from_proto(&proto, vec![]);
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 0);
+        assert_eq!(res.result, "42\n");
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_typescript_transpile_error() {
+        let proto = WorkflowCode {
+            id: "wid".to_string(),
+            code: "const n: number = ;".to_string(),
+            language: sapphillon::v1::WorkflowLanguage::Typescript as i32,
+            code_revision: 1,
+            ..Default::default()
+        };
+        let mut code = CoreWorkflowCode::new_from_proto(&proto, vec![]);
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 1);
+        assert_eq!(res.description, "Transpile Error");
+        assert!(res.result.contains("file:///workflow.ts:1:"));
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_timed_out() {
+        use crate::runtime::EXIT_CODE_TIMED_OUT;
+        use std::time::Duration;
+
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "while (true) {}".to_string(),
+            vec![dummy_plugin_package()],
+            1,
+        );
+        code.run_options.timeout = Some(Duration::from_millis(200));
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, EXIT_CODE_TIMED_OUT);
+        assert_eq!(
+            res.result_type,
+            sapphillon::v1::WorkflowResultType::Failure as i32
+        );
+        assert!(res.result.contains("timed out"));
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_with_cancellation() {
+        use crate::runtime::EXIT_CODE_CANCELLED;
+
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "while (true) {}".to_string(),
+            vec![dummy_plugin_package()],
+            1,
+        );
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            canceller.cancel();
+        });
+        code.run_with_cancellation(&token);
+        handle.join().unwrap();
+
+        let res = &code.result[0];
+        assert_eq!(res.description, "Cancelled");
+        assert_eq!(res.exit_code, EXIT_CODE_CANCELLED);
+        assert_eq!(
+            res.result_type,
+            sapphillon::v1::WorkflowResultType::Failure as i32
+        );
+    }
+
+    // Plugin package whose op checks read permission for the given path
+    fn read_check_plugin_package() -> CorePluginPackage {
+        use crate::permission::check_permission;
+        use crate::proto::sapphillon::v1::PermissionType;
+        use deno_core::{OpState, op2};
+        use deno_error::JsErrorBox;
+
+        #[op2(fast)]
+        fn op_check_read(state: &mut OpState, #[string] path: &str) -> Result<(), JsErrorBox> {
+            check_permission(state, PermissionType::Read, path, "op_check_read")
+        }
+        CorePluginPackage::new(
+            "pid".to_string(),
+            "pname".to_string(),
+            vec![CorePluginFunction::new(
+                "fid".to_string(),
+                "check_read".to_string(),
+                "desc".to_string(),
+                op_check_read(),
+            )],
+        )
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_permission_granted() {
+        let proto = WorkflowCode {
+            id: "wid".to_string(),
+            code: "Deno.core.ops.op_check_read('/tmp/sapphillon/data.txt'); console.log('read');"
+                .to_string(),
+            code_revision: 1,
+            required_permissions: vec![Permission {
+                permission_type: sapphillon::v1::PermissionType::Read as i32,
+                resource: vec!["/tmp/sapphillon".to_string()],
+                ..Default::default()
+            }],
+            plugin_function_ids: vec!["fid".to_string()],
+            ..Default::default()
+        };
+        let mut code = CoreWorkflowCode::new_from_proto(&proto, vec![read_check_plugin_package()]);
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 0, "{}", res.result);
+        assert_eq!(res.result, "read\n");
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_plugin_function_not_listed() {
+        let proto = WorkflowCode {
+            id: "wid".to_string(),
+            code: r#"
+            for (const call of [() => Deno.core.ops.dummy_op(), () => plugins.pid.fid()]) {
+                try {
+                    call();
+                } catch (e) {
+                    console.log(e.name);
+                }
+            }
+            "#
+            .to_string(),
             code_revision: 1,
             ..Default::default()
+        };
+        let mut code = CoreWorkflowCode::new_from_proto(&proto, vec![dummy_plugin_package()]);
+        code.plugin_function_ids = Some(Vec::new());
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 0, "{}", res.result);
+        assert_eq!(
+            res.result,
+            format!(
+                "{PLUGIN_FUNCTION_NOT_LISTED_ERROR_CLASS}\n\n{PLUGIN_FUNCTION_NOT_LISTED_ERROR_CLASS}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_permission_denied() {
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            r#"
+            try {
+                Deno.core.ops.op_check_read('/etc/passwd');
+            } catch (e) {
+                console.log(e.name);
+            }
+            Deno.core.ops.op_check_read('/etc/passwd');
+            "#
+            .to_string(),
+            vec![read_check_plugin_package()],
+            1,
+        );
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 1);
+        assert_eq!(
+            res.result_type,
+            sapphillon::v1::WorkflowResultType::Failure as i32
+        );
+        assert!(res.result.contains("PermissionDenied"), "{}", res.result);
+        assert!(res.result.contains("/etc/passwd"));
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_plugin_function_denied() {
+        use deno_core::op2;
+
+        #[op2(fast)]
+        fn op_write_file() -> u32 {
+            1
         }
+        let write_tmp = Permission {
+            permission_type: sapphillon::v1::PermissionType::Write as i32,
+            resource: vec!["/tmp".to_string()],
+            ..Default::default()
+        };
+        let pkg = CorePluginPackage::new(
+            "pid".to_string(),
+            "pname".to_string(),
+            vec![CorePluginFunction::new_with_permissions(
+                "fid".to_string(),
+                "write_file".to_string(),
+                "desc".to_string(),
+                op_write_file(),
+                vec![write_tmp.clone()],
+            )],
+        );
+        let script = r#"
+            try {
+                Deno.core.ops.op_write_file();
+            } catch (e) {
+                console.log(e.name);
+            }
+        "#;
+
+        let mut denied = CoreWorkflowCode::new("wid".to_string(), script.to_string(), vec![pkg], 1);
+        denied.run();
+        assert_eq!(denied.result[0].exit_code, 0);
+        assert_eq!(denied.result[0].result, "PermissionDenied\n");
+
+        let mut granted = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "console.log(Deno.core.ops.op_write_file());".to_string(),
+            denied.plugin_packages,
+            1,
+        );
+        granted.required_permissions = vec![write_tmp];
+        granted.run();
+        assert_eq!(granted.result[0].exit_code, 0);
+        assert_eq!(granted.result[0].result, "1\n");
     }
 
     #[test]
-    fn test_core_workflow_code_new() {
-        let pkg = dummy_plugin_package();
-        let code = CoreWorkflowCode::new(
+    fn test_core_workflow_code_run_plugin_bindings() {
+        let mut code = CoreWorkflowCode::new(
             "wid".to_string(),
-            "console.log('test');".to_string(),
+            r#"
+            import pid, { fid } from "sapphillon:plugin/pid";
+            console.log(plugins["pid"].fid(), fid(), pid === plugins.pid);
+            "#
+            .to_string(),
+            vec![dummy_plugin_package()],
+            1,
+        );
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(code.result[0].result, "42 42 true\n");
+
+        // Package objects are frozen, and assigning to them throws in module code
+        code.code = "plugins.pid.fid = () => 0;".to_string();
+        code.run();
+        assert_eq!(code.result[1].exit_code, EXIT_CODE_FAILURE);
+        assert!(code.result[1].result.contains("read only"));
+
+        code.code = "import { fid } from 'sapphillon:plugin/unknown';".to_string();
+        code.run();
+        assert!(code.result[2].result.contains("Module not found"));
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_script_plugin() {
+        use crate::plugin::CorePluginModule;
+
+        let write_tmp = Permission {
+            permission_type: sapphillon::v1::PermissionType::Write as i32,
+            resource: vec!["/tmp".to_string()],
+            ..Default::default()
+        };
+        let pkg = CorePluginPackage::new_with_modules(
+            "greeter".to_string(),
+            "Greeter".to_string(),
+            "1.0.0".to_string(),
+            vec![
+                CorePluginFunction::new_with_export(
+                    "greeter.greet".to_string(),
+                    "Greet".to_string(),
+                    "".to_string(),
+                    "mod.ts".to_string(),
+                    "greet".to_string(),
+                    vec![],
+                ),
+                CorePluginFunction::new_with_export(
+                    "greeter.save".to_string(),
+                    "Save".to_string(),
+                    "".to_string(),
+                    "mod.ts".to_string(),
+                    "save".to_string(),
+                    vec![write_tmp],
+                ),
+            ],
+            vec![
+                CorePluginModule::new(
+                    "mod.ts".to_string(),
+                    r#"
+                    import { prefix } from "./prefix.js";
+                    export const greet = (name: string): string => `${prefix}, ${name}`;
+                    export const save = (): boolean => true;
+                    "#
+                    .to_string(),
+                    WorkflowLanguage::Typescript,
+                ),
+                CorePluginModule::new(
+                    "prefix.js".to_string(),
+                    "export const prefix = 'Hello';".to_string(),
+                    WorkflowLanguage::Javascript,
+                ),
+            ],
+        );
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            r#"
+            import { greet } from "sapphillon:plugin/greeter";
+            console.log(greet("world"), plugins.greeter.greet === greet);
+            try {
+                plugins.greeter.save();
+            } catch (e) {
+                console.log(e.name);
+            }
+            "#
+            .to_string(),
             vec![pkg],
-            2,
+            1,
         );
-        assert_eq!(code.id, "wid");
-        assert_eq!(code.code, "console.log('test');");
-        assert_eq!(code.plugin_packages.len(), 1);
-        assert_eq!(code.code_revision, 2);
-        assert!(code.result.is_empty());
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(
+            code.result[0].result,
+            "Hello, world true\n\nPermissionDenied\n"
+        );
+
+        // Source modules are private to their package
+        code.code = "import { prefix } from 'sapphillon-plugin://greeter/prefix.js';".to_string();
+        code.run();
+        assert_eq!(code.result[1].exit_code, EXIT_CODE_FAILURE);
+        assert!(code.result[1].result.contains("can only be imported"));
     }
 
     #[test]
-    fn test_core_workflow_code_new_from_proto() {
-        let proto = dummy_proto_workflow_code();
-        let pkg = dummy_plugin_package();
-        let code = CoreWorkflowCode::new_from_proto(&proto, vec![pkg]);
-        assert_eq!(code.id, proto.id);
-        assert_eq!(code.code, proto.code);
-        assert_eq!(code.plugin_packages.len(), 1);
-        assert_eq!(code.code_revision, proto.code_revision);
-        assert!(code.result.is_empty());
+    fn test_core_workflow_code_run_wasm_plugin() {
+        use crate::plugin::CorePluginWasmModule;
+        use crate::wasm::WasmLimits;
+
+        // Exports `spin(n: i32) -> i32`, which loops n times and returns 0
+        let spin: &[u8] = &[
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+            0x01, 0x06, 0x01, 0x60, 0x01, 0x7f, 0x01, 0x7f, // type (i32) -> i32
+            0x03, 0x02, 0x01, 0x00, // function 0
+            0x07, 0x08, 0x01, 0x04, b's', b'p', b'i', b'n', 0x00, 0x00, // export
+            0x0a, 0x12, 0x01, 0x10, 0x00, 0x03, 0x40, 0x20, 0x00, 0x41, 0x01, 0x6b, 0x22, 0x00,
+            0x0d, 0x00, 0x0b, 0x20, 0x00, 0x0b, // code
+        ];
+        let limits = WasmLimits {
+            max_memory_pages: 1,
+            fuel: Some(100),
+        };
+        let module = CorePluginWasmModule::new("spin.wasm".to_string(), spin, limits).unwrap();
+        let pkg = CorePluginPackage::new_from_wasm_module(
+            "spinner".to_string(),
+            "Spinner".to_string(),
+            "1.0.0".to_string(),
+            module,
+            &HashMap::new(),
+        );
+        assert_eq!(pkg.functions[0].id, "spinner.spin");
+
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            r#"
+            console.log(plugins.spinner.spin(10), plugins.spinner.spin(90));
+            try {
+                plugins.spinner.spin(1000);
+            } catch (e) {
+                console.log(e.message);
+            }
+            "#
+            .to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(
+            code.result[0].result,
+            "0 0\n\nPlugin function spinner.spin ran out of fuel\n"
+        );
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_wasm_host_functions() {
+        use crate::plugin::CorePluginWasmModule;
+        use crate::wasm::WasmLimits;
+
+        // Imports `log` and `read_file`, exports `hello()`, which logs "hi", `read() -> i32`,
+        // which reads the file at an empty path, and its memory
+        let host: &[u8] = &[
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+            0x01, 0x15, 0x04, 0x60, 0x02, 0x7f, 0x7f, 0x00, 0x60, 0x00, 0x00, 0x60, 0x04, 0x7f,
+            0x7f, 0x7f, 0x7f, 0x01, 0x7f, 0x60, 0x00, 0x01, 0x7f, // types
+            0x02, 0x29, 0x02, 0x0a, b's', b'a', b'p', b'p', b'h', b'i', b'l', b'l', b'o', b'n',
+            0x03, b'l', b'o', b'g', 0x00, 0x00, 0x0a, b's', b'a', b'p', b'p', b'h', b'i', b'l',
+            b'l', b'o', b'n', 0x09, b'r', b'e', b'a', b'd', b'_', b'f', b'i', b'l', b'e', 0x00,
+            0x02, // imports
+            0x03, 0x03, 0x02, 0x01, 0x03, // functions
+            0x05, 0x03, 0x01, 0x00, 0x01, // memory, 1 page
+            0x07, 0x19, 0x03, 0x05, b'h', b'e', b'l', b'l', b'o', 0x00, 0x02, 0x04, b'r', b'e',
+            b'a', b'd', 0x00, 0x03, 0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02,
+            0x00, // exports
+            0x0a, 0x17, 0x02, 0x08, 0x00, 0x41, 0x00, 0x41, 0x02, 0x10, 0x00, 0x0b, 0x0c, 0x00,
+            0x41, 0x00, 0x41, 0x00, 0x41, 0x00, 0x41, 0x00, 0x10, 0x01, 0x0b, // code
+            0x0b, 0x08, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x02, b'h', b'i', // data
+        ];
+        let module =
+            CorePluginWasmModule::new("host.wasm".to_string(), host, WasmLimits::default())
+                .unwrap();
+        let pkg = CorePluginPackage::new_from_wasm_module(
+            "host".to_string(),
+            "Host".to_string(),
+            "1.0.0".to_string(),
+            module,
+            &HashMap::new(),
+        );
+
+        // `read` does not declare `Read`, so it cannot call `read_file`, and the host functions
+        // cannot be reached through ops
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            r#"
+            plugins.host.hello();
+            try {
+                plugins.host.read();
+            } catch (e) {
+                console.log(e.name);
+            }
+            console.log(typeof Deno.core.ops.op_wasm_read_file);
+            "#
+            .to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(
+            code.result[0].result,
+            "hi\n\nPermissionDenied\n\nundefined\n"
+        );
+
+        // Runs without WASM plugins do not get the op
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "console.log(typeof Deno.core.ops.op_wasm_plugin_function);".to_string(),
+            vec![],
+            1,
+        );
+        code.run();
+        assert_eq!(code.result[0].result, "undefined\n");
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_grpc_plugin() {
+        use crate::grpc::tests::serve_stub;
+        use crate::grpc::{GrpcPluginEndpoint, GrpcPluginProcess};
+
+        // Serve the stub plugin from a thread with its own runtime, like a separate process
+        let (address_tx, address_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                address_tx.send(listener.local_addr().unwrap()).unwrap();
+                let _shutdown = serve_stub(listener);
+                std::future::pending::<()>().await;
+            });
+        });
+        let address = address_rx.recv().unwrap();
+        let process =
+            GrpcPluginProcess::new(GrpcPluginEndpoint::Connect(format!("http://{address}")));
+        let pkg = CorePluginPackage::new_from_grpc_process(Arc::new(process)).unwrap();
+        assert_eq!(pkg.functions.len(), 2);
+
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            r#"
+            console.log(JSON.stringify(await plugins.stub.echo(1, { a: "b" })));
+            try {
+                await plugins.stub.fail();
+            } catch (e) {
+                console.log(e.message);
+            }
+            "#
+            .to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(
+            code.result[0].result,
+            "[stub.echo] INFO invoked\n\n\
+             [1,{\"a\":\"b\"}]\n\n\
+             [stub.fail] INFO invoked\n\n\
+             Plugin function stub.fail failed: Internal: stub failure\n"
+        );
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_native_plugin() {
+        let (library, plugin_package) = crate::native::tests::math_library();
+        let pkg = CorePluginPackage::new_from_native_plugin(&plugin_package, Arc::new(library));
+
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "console.log(plugins.math.sum(1, 2, 3.5));".to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(code.result[0].result, "6.5\n");
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_plugin_lifecycle() {
+        use crate::config::{PluginConfig, PluginConfigValue};
+        use crate::lifecycle::{CorePluginLifecycle, PluginLifecycle, PluginLifecycleError};
+        use crate::runtime::EXIT_CODE_TIMED_OUT;
+        use deno_core::{OpState, op2};
+        use std::time::Duration;
+
+        struct Connection(u32);
+        struct Database {
+            events: Arc<Mutex<Vec<String>>>,
+        }
+        impl PluginLifecycle for Database {
+            fn initialize(&self, config: &PluginConfig) -> Result<(), PluginLifecycleError> {
+                let url = config
+                    .get_string("url")
+                    .map_err(|e| PluginLifecycleError::new(e.to_string()))?;
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("initialize {url}"));
+                Ok(())
+            }
+
+            fn setup(
+                &self,
+                state: &mut OpState,
+                _config: &PluginConfig,
+            ) -> Result<(), PluginLifecycleError> {
+                state.put(Connection(0));
+                Ok(())
+            }
+
+            fn teardown(&self, state: &mut OpState, result: Result<(), &WorkflowRunError>) {
+                let queries = state.take::<Connection>().0;
+                let result = result.map_or_else(|e| e.exit_code(), |()| EXIT_CODE_SUCCESS);
+                self.events
+                    .lock()
+                    .unwrap()
+                    .push(format!("teardown {queries} {result}"));
+            }
+        }
+
+        #[op2(fast)]
+        fn op_query(state: &mut OpState) -> u32 {
+            let connection = state.borrow_mut::<Connection>();
+            connection.0 += 1;
+            connection.0
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut pkg = CorePluginPackage::new(
+            "db".to_string(),
+            "Database".to_string(),
+            vec![CorePluginFunction::new(
+                "db.query".to_string(),
+                "Query".to_string(),
+                "desc".to_string(),
+                op_query(),
+            )],
+        );
+        pkg.lifecycle = Some(CorePluginLifecycle::new(Arc::new(Database {
+            events: events.clone(),
+        })));
+        pkg.config.insert(
+            "url".to_string(),
+            PluginConfigValue::String("db://local".to_string()),
+        );
+
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "plugins.db.query(); console.log(plugins.db.query());".to_string(),
+            vec![pkg.clone()],
+            1,
+        );
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(code.result[0].result, "2\n");
+
+        // Teardown runs even when the run times out, and the package is initialized once
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "plugins.db.query(); while (true) {}".to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run_options.timeout = Some(Duration::from_millis(200));
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_TIMED_OUT);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "initialize db://local".to_string(),
+                format!("teardown 2 {EXIT_CODE_SUCCESS}"),
+                format!("teardown 1 {EXIT_CODE_TIMED_OUT}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_plugin_lifecycle_setup_failed() {
+        use crate::lifecycle::tests::RecordingLifecycle;
+        use crate::lifecycle::{CorePluginLifecycle, PluginLifecycleStage};
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let hooks = RecordingLifecycle::new("pid", &calls);
+        hooks.fail.lock().unwrap().push(PluginLifecycleStage::Setup);
+        let mut pkg = dummy_plugin_package();
+        pkg.lifecycle = Some(CorePluginLifecycle::new(Arc::new(hooks)));
+
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "console.log('unreachable');".to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, EXIT_CODE_FAILURE);
+        assert_eq!(
+            res.result,
+            "Plugin package pid failed to set up: pid failed"
+        );
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["pid initialize {}", "pid setup"]
+        );
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_plugin_lifecycle_setup_timed_out() {
+        use crate::config::PluginConfig;
+        use crate::lifecycle::{CorePluginLifecycle, PluginLifecycle, PluginLifecycleError};
+        use crate::runtime::EXIT_CODE_TIMED_OUT;
+        use deno_core::OpState;
+        use std::time::Duration;
+
+        struct SlowSetup;
+        impl PluginLifecycle for SlowSetup {
+            fn setup(
+                &self,
+                _state: &mut OpState,
+                _config: &PluginConfig,
+            ) -> Result<(), PluginLifecycleError> {
+                std::thread::sleep(Duration::from_millis(400));
+                Ok(())
+            }
+        }
+
+        let mut pkg = dummy_plugin_package();
+        pkg.lifecycle = Some(CorePluginLifecycle::new(Arc::new(SlowSetup)));
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "console.log('unreachable');".to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run_options.timeout = Some(Duration::from_millis(200));
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, EXIT_CODE_TIMED_OUT);
+        assert!(!res.result.contains("unreachable"));
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_plugin_config() {
+        use crate::config::{PluginConfigValue, plugin_config};
+        use crate::permission::check_permission;
+        use deno_core::{OpState, op2};
+        use deno_error::JsErrorBox;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        fn token(state: &OpState) -> Option<String> {
+            plugin_config(state)
+                .and_then(|config| config.get_secret("token").ok())
+                .map(str::to_string)
+        }
+
+        #[op2]
+        #[string]
+        fn op_api_header(state: &mut OpState) -> Result<String, JsErrorBox> {
+            let token = token(state).ok_or_else(|| JsErrorBox::generic("not configured"))?;
+            Ok(format!("Bearer {token}"))
+        }
+
+        #[op2]
+        #[string]
+        fn op_api_fail(state: &mut OpState) -> Result<String, JsErrorBox> {
+            let token = token(state).unwrap_or_default();
+            Err(JsErrorBox::generic(format!("Token {token} was rejected")))
+        }
+
+        #[op2(fast)]
+        fn op_api_read(state: &mut OpState) -> Result<(), JsErrorBox> {
+            let path = format!("/tokens/{}", token(state).unwrap_or_default());
+            check_permission(
+                state,
+                sapphillon::v1::PermissionType::Read,
+                &path,
+                op_api_read::name(),
+            )
+        }
+
+        // The configuration can be read before the first `.await` only
+        #[op2(async)]
+        #[string]
+        async fn op_api_async(state: Rc<RefCell<OpState>>) -> String {
+            let before = token(&state.borrow()).is_some();
+            tokio::task::yield_now().await;
+            let after = token(&state.borrow()).is_some();
+            format!("{before} {after}")
+        }
+
+        // Ops of other packages cannot read the configuration
+        #[op2]
+        #[string]
+        fn op_other_token(state: &mut OpState) -> String {
+            token(state).unwrap_or_else(|| "none".to_string())
+        }
+
+        let mut pkg = CorePluginPackage::new(
+            "api".to_string(),
+            "API".to_string(),
+            vec![
+                CorePluginFunction::new(
+                    "api.header".to_string(),
+                    "Header".to_string(),
+                    "desc".to_string(),
+                    op_api_header(),
+                ),
+                CorePluginFunction::new(
+                    "api.fail".to_string(),
+                    "Fail".to_string(),
+                    "desc".to_string(),
+                    op_api_fail(),
+                ),
+                CorePluginFunction::new(
+                    "api.read".to_string(),
+                    "Read".to_string(),
+                    "desc".to_string(),
+                    op_api_read(),
+                ),
+                CorePluginFunction::new(
+                    "api.async".to_string(),
+                    "Async".to_string(),
+                    "desc".to_string(),
+                    op_api_async(),
+                ),
+            ],
+        );
+        pkg.config.insert(
+            "token".to_string(),
+            PluginConfigValue::Secret("tok-123".to_string()),
+        );
+        let mut other = CorePluginPackage::new(
+            "other".to_string(),
+            "Other".to_string(),
+            vec![CorePluginFunction::new(
+                "other.token".to_string(),
+                "Token".to_string(),
+                "desc".to_string(),
+                op_other_token(),
+            )],
+        );
+        other.config.insert(
+            "endpoint".to_string(),
+            PluginConfigValue::String("https://other.example.com".to_string()),
+        );
+
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            r#"
+            console.log(plugins.api.header());
+            console.log(typeof plugins.api.config);
+            try {
+                plugins.api.fail();
+            } catch (e) {
+                console.log(e.message);
+            }
+            console.log(plugins.other.token());
+            console.log(await plugins.api.async());
+            "#
+            .to_string(),
+            vec![pkg.clone(), other],
+            1,
+        );
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(
+            code.result[0].result,
+            "Bearer [REDACTED]\n\nundefined\n\nToken [REDACTED] was rejected\n\nnone\n\ntrue false\n"
+        );
+
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "plugins.api.fail();".to_string(),
+            vec![pkg.clone()],
+            1,
+        );
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, EXIT_CODE_FAILURE);
+        assert!(res.result.contains("Token [REDACTED] was rejected"));
+        assert!(!res.result.contains("tok-123"));
+        assert!(!res.description.contains("tok-123"));
+
+        // Resources of permission checks are redacted from the audit events
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "try { plugins.api.read(); } catch {}".to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run();
+        let events = &code.audit_log[&code.result[0].id];
+        assert_eq!(events.len(), 1);
+        assert!(!is_granted(&events[0]));
+        assert_eq!(
+            events[0].resource.as_ref().unwrap().name,
+            "/tokens/[REDACTED]"
+        );
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_plugin_function_schema() {
+        use crate::schema::{FunctionSchema, ParameterSchema};
+        use deno_core::op2;
+        use serde_json::json;
+
+        #[op2(fast)]
+        fn op_repeat(#[string] text: &str, times: u32) -> u32 {
+            (text.len() as u32) * times
+        }
+        let pkg = CorePluginPackage::new(
+            "text".to_string(),
+            "Text".to_string(),
+            vec![CorePluginFunction::new_with_schema(
+                "text.repeat".to_string(),
+                "Repeat".to_string(),
+                "desc".to_string(),
+                op_repeat(),
+                vec![],
+                FunctionSchema::new(
+                    vec![
+                        ParameterSchema::new(
+                            "text".to_string(),
+                            "".to_string(),
+                            json!({"type": "string"}),
+                        ),
+                        ParameterSchema::new(
+                            "times".to_string(),
+                            "".to_string(),
+                            json!({"type": "integer", "minimum": 1}),
+                        ),
+                    ],
+                    Some(json!({"type": "integer", "maximum": 10})),
+                )
+                .unwrap(),
+            )],
+        );
+        let script = r#"
+            console.log(plugins.text.repeat("ab", 2));
+            for (const call of [
+                () => plugins.text.repeat(1, 2),
+                () => Deno.core.ops.op_repeat("ab", 0),
+                () => plugins.text.repeat("abcdef", 2),
+            ]) {
+                try {
+                    call();
+                } catch (e) {
+                    console.log(e.name, e.message);
+                }
+            }
+        "#;
+        let mut code = CoreWorkflowCode::new("wid".to_string(), script.to_string(), vec![pkg], 1);
+        code.run();
+        assert_eq!(code.result[0].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(
+            code.result[0].result,
+            "4\n\n\
+             TypeError Invalid arguments for text.repeat: text must be of type string, got number\n\n\
+             TypeError Invalid arguments for text.repeat: times must be at least 1\n\n\
+             TypeError Invalid return value of text.repeat: return value must be at most 10\n"
+        );
+    }
+
+    struct RecordingApprover {
+        decision: PermissionDecision,
+        requests: Mutex<Vec<PermissionRequest>>,
+    }
+
+    impl PermissionApprover for RecordingApprover {
+        fn approve<'a>(&'a self, request: &'a PermissionRequest) -> PermissionDecisionFuture<'a> {
+            self.requests.lock().unwrap().push(request.clone());
+            Box::pin(std::future::ready(self.decision))
+        }
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_approver_allow_always() {
+        let approver = Arc::new(RecordingApprover {
+            decision: PermissionDecision::AllowAlways,
+            requests: Mutex::new(Vec::new()),
+        });
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "Deno.core.ops.op_check_read('/etc/hosts'); console.log('read');".to_string(),
+            vec![read_check_plugin_package()],
+            1,
+        );
+        code.run_options.permission_approver = Some(approver.clone());
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 0, "{}", res.result);
+
+        let decisions = &code.permission_decisions[&res.id];
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].decision, PermissionDecision::AllowAlways);
+        assert_eq!(decisions[0].request.api_name, "op_check_read");
+        assert_eq!(code.required_permissions.len(), 1);
+
+        // The permission is granted to later runs without asking again
+        code.run();
+        assert_eq!(code.result[1].exit_code, 0);
+        assert_eq!(approver.requests.lock().unwrap().len(), 1);
+        assert!(code.permission_decisions[&code.result[1].id].is_empty());
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_approver_plugin_function() {
+        use deno_core::op2;
+
+        #[op2(fast)]
+        fn op_send_mail() -> u32 {
+            1
+        }
+        let pkg = CorePluginPackage::new(
+            "pid".to_string(),
+            "pname".to_string(),
+            vec![CorePluginFunction::new_with_permissions(
+                "fid".to_string(),
+                "send_mail".to_string(),
+                "desc".to_string(),
+                op_send_mail(),
+                vec![Permission {
+                    permission_type: sapphillon::v1::PermissionType::Execute as i32,
+                    resource: vec!["sendmail".to_string()],
+                    permission_level: sapphillon::v1::PermissionLevel::High as i32,
+                    ..Default::default()
+                }],
+            )],
+        );
+        let approver = Arc::new(RecordingApprover {
+            decision: PermissionDecision::Deny,
+            requests: Mutex::new(Vec::new()),
+        });
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "Deno.core.ops.op_send_mail();".to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run_options.permission_approver = Some(approver.clone());
+        code.run();
+        assert_eq!(code.result[0].exit_code, 1);
+        assert!(code.result[0].result.contains("PermissionDenied"));
+
+        let requests = approver.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].api_name, "op_send_mail");
+        assert_eq!(
+            requests[0].permission.permission_level(),
+            sapphillon::v1::PermissionLevel::High
+        );
+        assert!(code.required_permissions.is_empty());
+    }
+
+    /// Approver that allows once after waiting on a timer.
+    struct TimerApprover;
+
+    impl PermissionApprover for TimerApprover {
+        fn approve<'a>(&'a self, _request: &'a PermissionRequest) -> PermissionDecisionFuture<'a> {
+            Box::pin(async {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                PermissionDecision::AllowOnce
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_core_workflow_code_run_approver_plugin_function_in_async_runtime() {
+        use deno_core::op2;
+
+        #[op2(fast)]
+        fn op_send_mail() -> u32 {
+            1
+        }
+        let pkg = CorePluginPackage::new(
+            "pid".to_string(),
+            "pname".to_string(),
+            vec![CorePluginFunction::new_with_permissions(
+                "fid".to_string(),
+                "send_mail".to_string(),
+                "desc".to_string(),
+                op_send_mail(),
+                vec![Permission {
+                    permission_type: sapphillon::v1::PermissionType::Execute as i32,
+                    resource: vec!["sendmail".to_string()],
+                    ..Default::default()
+                }],
+            )],
+        );
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "console.log(plugins.pid.fid());".to_string(),
+            vec![pkg],
+            1,
+        );
+        code.run_options.permission_approver = Some(Arc::new(TimerApprover));
+        // The caller's runtime is blocked by the run, so the approver is awaited on the run's
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, EXIT_CODE_SUCCESS, "{}", res.result);
+        assert_eq!(res.result, "1\n");
+        let decisions = &code.permission_decisions[&res.id];
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].request.api_name, "op_send_mail");
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_permission_policy() {
+        let policy = PermissionPolicy::new_from_json(
+            r#"{"rules": [
+                {"title": "hosts", "expression": "resource == '/etc/hosts'", "action": "approve"}
+            ]}"#,
+            "policy.json",
+        )
+        .unwrap();
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "Deno.core.ops.op_check_read('/etc/hosts'); console.log('read');".to_string(),
+            vec![read_check_plugin_package()],
+            1,
+        );
+        code.run_options.permission_policy = Some(Arc::new(policy));
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 0, "{}", res.result);
+        let decisions = &code.permission_decisions[&res.id];
+        assert_eq!(
+            decisions[0].decided_by,
+            PermissionDecisionSource::Policy("hosts".to_string())
+        );
+        // Policy approvals are not granted to later runs
+        assert!(code.required_permissions.is_empty());
+
+        // Requests the policy escalates are denied without an approver
+        code.code = "Deno.core.ops.op_check_read('/etc/passwd');".to_string();
+        code.run();
+        assert_eq!(code.result[1].exit_code, 1);
+        assert!(code.result[1].result.contains("PermissionDenied"));
+        assert!(code.permission_decisions[&code.result[1].id].is_empty());
+    }
+
+    struct RecordingSink {
+        exports: Mutex<Vec<(String, Vec<AttributeContext>)>>,
+    }
+
+    impl AuditSink for RecordingSink {
+        fn export(&self, workflow_result_id: &str, events: &[AttributeContext]) {
+            self.exports
+                .lock()
+                .unwrap()
+                .push((workflow_result_id.to_string(), events.to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_audit_log() {
+        let sink = Arc::new(RecordingSink {
+            exports: Mutex::new(Vec::new()),
+        });
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            r#"
+            Deno.core.ops.op_check_read('/tmp/a');
+            try { Deno.core.ops.op_check_read('/etc/hosts'); } catch {}
+            "#
+            .to_string(),
+            vec![read_check_plugin_package()],
+            1,
+        );
+        code.required_permissions = vec![Permission {
+            permission_type: sapphillon::v1::PermissionType::Read as i32,
+            resource: vec!["/tmp".to_string()],
+            ..Default::default()
+        }];
+        code.run_options.audit_sink = Some(sink.clone());
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 0, "{}", res.result);
+
+        let events = &code.audit_log[&res.id];
+        let checks: Vec<_> = events
+            .iter()
+            .map(|e| (e.resource.as_ref().unwrap().name.as_str(), is_granted(e)))
+            .collect();
+        assert_eq!(checks, vec![("/tmp/a", true), ("/etc/hosts", false)]);
+        let event = &events[1];
+        assert_eq!(event.request.as_ref().unwrap().id, res.id);
+        assert_eq!(event.source.as_ref().unwrap().principal, "wid");
+        assert_eq!(
+            event.source.as_ref().unwrap().labels["plugin_function_id"],
+            "fid"
+        );
+
+        let exports = sink.exports.lock().unwrap();
+        assert_eq!(exports.len(), 1);
+        assert_eq!(exports[0].0, res.id);
+        assert_eq!(&exports[0].1, events);
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_audit_log_plugin_function() {
+        use deno_core::op2;
+
+        #[op2(fast)]
+        fn op_exec() -> u32 {
+            1
+        }
+        let pkg = CorePluginPackage::new(
+            "pid".to_string(),
+            "pname".to_string(),
+            vec![CorePluginFunction::new_with_permissions(
+                "exec_fid".to_string(),
+                "exec".to_string(),
+                "desc".to_string(),
+                op_exec(),
+                vec![Permission {
+                    permission_type: sapphillon::v1::PermissionType::Execute as i32,
+                    resource: vec!["git".to_string()],
+                    ..Default::default()
+                }],
+            )],
+        );
+        let mut code = CoreWorkflowCode::new("wid".to_string(), "1;".to_string(), vec![pkg], 1);
+        code.run();
+
+        // Plugin functions are checked before the run, even if they are never called
+        let events = &code.audit_log[&code.result[0].id];
+        assert_eq!(events.len(), 1);
+        assert!(!is_granted(&events[0]));
+        assert_eq!(events[0].resource.as_ref().unwrap().name, "git");
+        assert_eq!(events[0].api.as_ref().unwrap().operation, "op_exec");
+        assert_eq!(
+            events[0].source.as_ref().unwrap().labels["plugin_function_id"],
+            "exec_fid"
+        );
+    }
+
+    #[test]
+    fn test_core_workflow_code_infer_permissions() {
+        let mut code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "Deno.core.ops.op_check_read('/tmp');".to_string(),
+            vec![read_check_plugin_package(), dummy_plugin_package()],
+            1,
+        );
+        code.required_permissions = vec![Permission {
+            permission_type: sapphillon::v1::PermissionType::Write as i32,
+            ..Default::default()
+        }];
+        let inference = code.infer_permissions().unwrap();
+        assert_eq!(inference.invoked_function_ids, vec!["fid"]);
+        // The read check plugin declares no permissions, its resources are checked at runtime
+        assert!(inference.required_permissions.is_empty());
+        assert_eq!(inference.unused_permissions, code.required_permissions);
+
+        code.language = WorkflowLanguage::Typescript;
+        code.code = "const n: number = ;".to_string();
+        assert!(code.infer_permissions().is_err());
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_pending_revision() {
+        let previous = dummy_proto_workflow_code();
+        let mut current = dummy_proto_workflow_code();
+        current.code_revision = 2;
+        current.required_permissions = vec![Permission {
+            permission_type: sapphillon::v1::PermissionType::Read as i32,
+            resource: vec!["/tmp".to_string()],
+            ..Default::default()
+        }];
+
+        let mut code = CoreWorkflowCode::new_from_revision(
+            &previous,
+            &current,
+            vec![read_check_plugin_package()],
+        );
+        code.code = "Deno.core.ops.op_check_read('/tmp'); console.log('ran');".to_string();
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, EXIT_CODE_REAPPROVAL_REQUIRED);
+        assert_eq!(res.description, "Re-approval Required");
+        assert!(res.result.contains("added PERMISSION_TYPE_READ"));
+        // Nothing was checked because nothing ran
+        assert!(code.audit_log[&res.id].is_empty());
+
+        let approved = code.approve_revision().unwrap();
+        assert_eq!(approved.revision, 2);
+        code.run();
+        assert_eq!(code.result[1].exit_code, EXIT_CODE_SUCCESS);
+        assert_eq!(code.result[1].result, "ran\n");
+
+        // Revisions that only remove permissions run right away
+        let code = CoreWorkflowCode::new_from_revision(&current, &previous, vec![]);
+        assert!(code.pending_revision.is_none());
+    }
+
+    // Generate a dummy WorkflowCode (proto) for testing
+    fn dummy_proto_workflow_code() -> WorkflowCode {
+        WorkflowCode {
+            id: "wid".to_string(),
+            code: "console.log('hello');".to_string(),
+            code_revision: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_core_workflow_code_new() {
+        let pkg = dummy_plugin_package();
+        let code = CoreWorkflowCode::new(
+            "wid".to_string(),
+            "console.log('test');".to_string(),
+            vec![pkg],
+            2,
+        );
+        assert_eq!(code.id, "wid");
+        assert_eq!(code.code, "console.log('test');");
+        assert_eq!(code.plugin_packages.len(), 1);
+        assert_eq!(code.code_revision, 2);
+        assert!(code.result.is_empty());
+    }
+
+    #[test]
+    fn test_core_workflow_code_new_from_proto() {
+        let proto = dummy_proto_workflow_code();
+        let pkg = dummy_plugin_package();
+        let code = CoreWorkflowCode::new_from_proto(&proto, vec![pkg]);
+        assert_eq!(code.id, proto.id);
+        assert_eq!(code.code, proto.code);
+        assert_eq!(code.language, proto.language());
+        assert_eq!(code.required_permissions, proto.required_permissions);
+        assert_eq!(code.plugin_function_ids, None);
+        assert_eq!(code.plugin_packages.len(), 1);
+        assert_eq!(code.code_revision, proto.code_revision);
+        assert!(code.result.is_empty());
+
+        let mut proto = dummy_proto_workflow_code();
+        proto.plugin_function_ids = vec!["fid".to_string()];
+        let code = CoreWorkflowCode::new_from_proto(&proto, vec![dummy_plugin_package()]);
+        assert_eq!(code.plugin_function_ids, Some(vec!["fid".to_string()]));
+    }
+
+    #[test]
+    fn test_core_workflow_code_run_default_proto_plugin_functions() {
+        let mut proto = dummy_proto_workflow_code();
+        proto.code = "console.log(Deno.core.ops.dummy_op(), plugins.pid.fid());".to_string();
+        let mut code = CoreWorkflowCode::new_from_proto(&proto, vec![dummy_plugin_package()]);
+        code.run();
+        let res = &code.result[0];
+        assert_eq!(res.exit_code, 0, "{}", res.result);
+        assert_eq!(res.result, "42 42\n");
+    }
+
+    #[test]
+    fn test_core_workflow_code_new_from_proto_validated() {
+        let mut proto = dummy_proto_workflow_code();
+        proto.code = "Deno.core.ops.dummy_op();".to_string();
+        let err = CoreWorkflowCode::new_from_proto_validated(&proto, vec![dummy_plugin_package()])
+            .err()
+            .unwrap();
+        assert_eq!(err.field_violations.len(), 1);
+        assert_eq!(err.field_violations[0].field, "code");
+
+        proto.plugin_function_ids = vec!["fid".to_string()];
+        let code = CoreWorkflowCode::new_from_proto_validated(&proto, vec![dummy_plugin_package()])
+            .unwrap();
+        assert_eq!(code.plugin_packages.len(), 1);
+    }
+
+    #[test]
+    fn test_core_workflow_code_new_from_registry() {
+        let mut registry = PluginRegistry::new();
+        let mut pkg = dummy_plugin_package();
+        pkg.version = "1.0.0".to_string();
+        registry.register(pkg).unwrap();
+
+        let mut proto = dummy_proto_workflow_code();
+        proto.plugin_function_ids = vec!["fid".to_string()];
+        let code = CoreWorkflowCode::new_from_registry(&proto, &registry).unwrap();
+        assert_eq!(code.plugin_packages.len(), 1);
+        assert_eq!(code.plugin_packages[0].version, "1.0.0");
+
+        proto.plugin_function_ids.push("missing".to_string());
+        assert!(CoreWorkflowCode::new_from_registry(&proto, &registry).is_err());
     }
 
     #[test]